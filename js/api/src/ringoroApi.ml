@@ -3,4 +3,8 @@ module Core = RingoroApi_Core
 
 let getUser: Types.UserFindOneInput.t -> Types.UserOutput.t option Js.Promise.t = Core.call "user"
 
-let getUsers: unit -> Types.UserOutput.t array option Js.Promise.t = fun () -> Core.call "users" Js.Null.empty
+let getUsers: Types.UserListInput.t -> Types.UserOutput.t Types.Page.t option Js.Promise.t = Core.call "users"
+
+let searchUsers: Types.UserSearchInput.t -> Types.UserOutput.t Types.Page.t option Js.Promise.t = Core.call "search_users"
+
+let getAuditLog: Types.AuditLogInput.t -> Types.AuditEntryOutput.t Types.Page.t option Js.Promise.t = Core.call "audit_log"
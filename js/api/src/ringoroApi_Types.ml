@@ -19,3 +19,71 @@ module UserFindOneInput = struct
   let create id = { id=Js.Null.fromOption id}
   let empty = create None
 end
+
+module UserListInput = struct
+  type t = {
+    filter: string Js.Null.t;
+    sort: string Js.Null.t;
+    cursor: string Js.Null.t;
+    limit: int Js.Null.t;
+  }
+
+  let create ?filter ?sort ?cursor ?limit () = {
+    filter=Js.Null.fromOption filter;
+    sort=Js.Null.fromOption sort;
+    cursor=Js.Null.fromOption cursor;
+    limit=Js.Null.fromOption limit;
+  }
+  let empty = create ()
+end
+
+module UserSearchInput = struct
+  type t = {
+    q: string;
+    cursor: string Js.Null.t;
+    limit: int Js.Null.t;
+  }
+
+  let create ?cursor ?limit q = {
+    q;
+    cursor=Js.Null.fromOption cursor;
+    limit=Js.Null.fromOption limit;
+  }
+end
+
+module AuditLogInput = struct
+  type t = {
+    filter: string Js.Null.t;
+    sort: string Js.Null.t;
+    cursor: string Js.Null.t;
+    limit: int Js.Null.t;
+  }
+
+  let create ?filter ?sort ?cursor ?limit () = {
+    filter=Js.Null.fromOption filter;
+    sort=Js.Null.fromOption sort;
+    cursor=Js.Null.fromOption cursor;
+    limit=Js.Null.fromOption limit;
+  }
+  let empty = create ()
+end
+
+module AuditEntryOutput = struct
+  type t = {
+    id: Id.t;
+    actor: Id.t Js.Null.t;
+    operation: string;
+    collection: string;
+    target: string;
+    diff: Js.Json.t;
+    at: string;
+  }
+end
+
+module Page = struct
+  type 'a t = {
+    items: 'a array;
+    next_cursor: string Js.Null.t;
+    has_next: bool;
+  }
+end
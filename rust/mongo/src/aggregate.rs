@@ -0,0 +1,271 @@
+use std::marker::PhantomData;
+
+use futures::stream::BoxStream;
+use mongodm::{
+    bson::{from_bson, Bson, Document},
+    doc, CollectionConfig,
+};
+use serde::de::DeserializeOwned;
+
+use crate::{
+    fcomps::convert::Convertible,
+    migration::{upcast, Upcaster},
+    query::{Field, Filter, Sort},
+    schema::Schema,
+    softdelete::DELETED_AT,
+    utils::result::Result,
+    withid::ConvertibleStream,
+};
+
+pub struct AggregateDocument(pub Result<Document>);
+
+impl<T> Convertible<T> for AggregateDocument
+where
+    T: DeserializeOwned,
+{
+    #[inline]
+    fn convert(self) -> Result<T> {
+        Ok(from_bson(Bson::Document(self.0?))?)
+    }
+}
+
+pub type AggregateStream = BoxStream<'static, AggregateDocument>;
+
+pub type AggregateCursor<Out> = ConvertibleStream<Out, AggregateStream>;
+
+pub struct Pipeline<M> {
+    stages: Vec<Document>,
+    joined: Vec<(String, Upcaster)>,
+    p: PhantomData<fn() -> M>,
+}
+
+impl<M> Default for Pipeline<M> {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            stages: Vec::new(),
+            joined: Vec::new(),
+            p: PhantomData,
+        }
+    }
+}
+
+impl<M> Pipeline<M> {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[inline]
+    pub fn stage(mut self, stage: Document) -> Self {
+        self.stages.push(stage);
+        self
+    }
+
+    #[inline]
+    pub fn filter(self, filter: Filter<M>) -> Self {
+        self.stage(doc! { "$match": filter.into_document() })
+    }
+
+    #[inline]
+    pub fn sort(self, sort: Sort<M>) -> Self {
+        self.stage(doc! { "$sort": sort.into_document() })
+    }
+
+    #[inline]
+    pub fn skip(self, skip: i64) -> Self {
+        self.stage(doc! { "$skip": skip })
+    }
+
+    #[inline]
+    pub fn limit(self, limit: i64) -> Self {
+        self.stage(doc! { "$limit": limit })
+    }
+
+    #[inline]
+    pub fn project(self, projection: Document) -> Self {
+        self.stage(doc! { "$project": projection })
+    }
+
+    #[inline]
+    pub fn unwind(self, path: impl AsRef<str>) -> Self {
+        self.stage(doc! { "$unwind": format!("${}", path.as_ref()) })
+    }
+
+    pub fn group(self, id: impl Into<Bson>, accumulators: Document) -> Self {
+        let mut group = doc! { "_id": id.into() };
+        for (key, value) in accumulators {
+            group.insert(key, value);
+        }
+        self.stage(doc! { "$group": group })
+    }
+
+    #[inline]
+    pub fn group_by<T>(self, field: Field<M, T>, accumulators: Document) -> Self {
+        self.group(format!("${}", field.name()), accumulators)
+    }
+
+    // Soft-deleted documents are left out of the join, and the joined ones are
+    // upcast like any other read of `F`.
+    pub fn lookup<F, T>(
+        self,
+        local: Field<M, T>,
+        foreign: Field<F, T>,
+        as_: impl AsRef<str>,
+    ) -> Self
+    where
+        F: Schema,
+    {
+        let as_ = String::from(as_.as_ref());
+        let mut pipeline = self.stage(doc! {
+            "$lookup": {
+                "from": F::CollConf::collection_name(),
+                "let": { "local": format!("${}", local.name()) },
+                "pipeline": [{
+                    "$match": {
+                        DELETED_AT: Bson::Null,
+                        "$expr": { "$eq": [format!("${}", foreign.name()), "$$local"] },
+                    }
+                }],
+                "as": &as_,
+            }
+        });
+        pipeline.joined.push((as_, upcast::<F>));
+        pipeline
+    }
+
+    pub fn facet(self, facets: Vec<(&str, Pipeline<M>)>) -> Self {
+        let mut facet = Document::new();
+        for (name, pipeline) in facets {
+            facet.insert(name, pipeline.into_stages());
+        }
+        self.stage(doc! { "$facet": facet })
+    }
+
+    #[inline]
+    pub fn count(self, field: impl AsRef<str>) -> Self {
+        self.stage(doc! { "$count": field.as_ref() })
+    }
+
+    #[inline]
+    pub fn into_stages(self) -> Vec<Document> {
+        self.stages
+    }
+
+    #[inline]
+    pub(crate) fn into_parts(self) -> (Vec<Document>, Vec<(String, Upcaster)>) {
+        (self.stages, self.joined)
+    }
+}
+
+pub(crate) fn upcast_joined(mut doc: Document, joined: &[(String, Upcaster)]) -> Result<Document> {
+    for (path, upcaster) in joined {
+        match doc.get_mut(path) {
+            Some(Bson::Array(items)) => {
+                for item in items.iter_mut() {
+                    if let Bson::Document(item) = item {
+                        *item = upcaster(std::mem::take(item))?;
+                    }
+                }
+            }
+            // after an `$unwind`
+            Some(Bson::Document(item)) => *item = upcaster(std::mem::take(item))?,
+            _ => {}
+        }
+    }
+    Ok(doc)
+}
+
+impl<M> Convertible<Pipeline<M>> for Pipeline<M> {
+    #[inline]
+    fn convert(self) -> Result<Pipeline<M>> {
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mongodm::{Indexes, Model};
+    use pretty_assertions::assert_eq;
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    struct Unit {}
+
+    struct UnitCfg {}
+
+    impl CollectionConfig for UnitCfg {
+        fn collection_name() -> &'static str {
+            "Unit"
+        }
+
+        fn indexes() -> Indexes {
+            Indexes::new()
+        }
+    }
+
+    impl Model for Unit {
+        type CollConf = UnitCfg;
+    }
+
+    impl Schema for Unit {
+        fn upcasters() -> Vec<(i32, Upcaster)> {
+            vec![(1, mark_upcast)]
+        }
+    }
+
+    fn mark_upcast(mut doc: Document) -> Result<Document> {
+        doc.insert("upcast", true);
+        Ok(doc)
+    }
+
+    #[test]
+    fn test_pipeline() {
+        let cu = Field::<Unit, String>::new("cu");
+        let name = Field::<Unit, String>::new("unit");
+        let stages = Pipeline::<Unit>::new()
+            .filter(cu.eq("akari"))
+            .lookup(name, Field::<Unit, String>::new("name"), "units")
+            .group_by(cu, doc! { "count": { "$sum": 1 } })
+            .facet(vec![("top", Pipeline::new().limit(1))])
+            .into_stages();
+        assert_eq!(
+            vec![
+                doc! { "$match": { "cu": "akari" } },
+                doc! { "$lookup": {
+                    "from": "Unit",
+                    "let": { "local": "$unit" },
+                    "pipeline": [{ "$match": {
+                        "deleted_at": Bson::Null,
+                        "$expr": { "$eq": ["$name", "$$local"] },
+                    } }],
+                    "as": "units",
+                } },
+                doc! { "$group": { "_id": "$cu", "count": { "$sum": 1 } } },
+                doc! { "$facet": { "top": [{ "$limit": 1_i64 }] } },
+            ],
+            stages
+        );
+    }
+
+    #[test]
+    fn test_upcast_joined() {
+        let (_, joined) = Pipeline::<Unit>::new()
+            .lookup(
+                Field::<Unit, String>::new("unit"),
+                Field::<Unit, String>::new("name"),
+                "units",
+            )
+            .into_parts();
+        let doc = doc! { "units": [{ "name": "unibo" }, { "name": "bw", "_v": 1 }] };
+        assert_eq!(
+            doc! { "units": [
+                { "name": "unibo", "upcast": true, "_v": 1 },
+                { "name": "bw", "_v": 1 },
+            ] },
+            upcast_joined(doc, &joined).unwrap()
+        );
+    }
+}
@@ -0,0 +1,418 @@
+use std::marker::PhantomData;
+
+use async_trait::async_trait;
+use chrono::Utc;
+use log::error;
+use mongodb::options::FindOptions;
+use mongodm::{
+    bson::{to_document, Bson, DateTime, Document},
+    doc, CollectionConfig, Index, Indexes, Model,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    context::MongodmContext,
+    fcomps::behavior::Behavior,
+    id::IdType,
+    listquery::{FilterOp, FilterableField, ListConfig},
+    query::Field,
+    schema::Schema,
+    service::{DeleteId, Page, PageRequest, PageSort, RestoreId},
+    utils::result::Result,
+    withid::{Id, ModelWithIdCursor, RepositoryWithId, RepositoryWithIdBase, WithId},
+};
+
+pub const AUDIT_COLLECTION: &str = "_audit_log";
+
+// The log is append only. A pending entry is written before its operation runs
+// and an outcome entry pointing back at it once the operation has finished, so
+// an operation never goes unrecorded. A pending entry without an outcome is an
+// operation whose outcome could not be recorded.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditStatus {
+    Pending,
+    Done,
+    Failed,
+}
+
+impl Default for AuditStatus {
+    #[inline]
+    fn default() -> Self {
+        AuditStatus::Done
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuditEntry {
+    pub actor: Option<Id>,
+    pub operation: String,
+    pub collection: String,
+    // the target id as text, so every id type can be filtered the same way
+    pub target: String,
+    pub diff: Document,
+    pub at: DateTime,
+    #[serde(default)]
+    pub status: AuditStatus,
+    // the pending entry an outcome belongs to
+    #[serde(default)]
+    pub outcome_of: Option<Id>,
+    // why a failed operation failed
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+pub struct AuditEntryCfg {}
+
+impl CollectionConfig for AuditEntryCfg {
+    fn collection_name() -> &'static str {
+        AUDIT_COLLECTION
+    }
+
+    fn indexes() -> Indexes {
+        Indexes::new()
+            .with(Index::new("at"))
+            .with(Index::new("actor").with_key("at"))
+            .with(Index::new("collection").with_key("target"))
+            .with(Index::new("outcome_of"))
+    }
+}
+
+impl Model for AuditEntry {
+    type CollConf = AuditEntryCfg;
+}
+
+impl Schema for AuditEntry {}
+
+impl ListConfig for AuditEntry {
+    fn filterable() -> Vec<FilterableField<Self>> {
+        vec![
+            FilterableField::new(Field::<Self, Id>::new("actor"), &[FilterOp::Eq]),
+            FilterableField::new(
+                Field::<Self, String>::new("operation"),
+                &[FilterOp::Eq, FilterOp::In],
+            ),
+            FilterableField::new(Field::<Self, String>::new("collection"), &[FilterOp::Eq]),
+            FilterableField::new(Field::<Self, String>::new("target"), &[FilterOp::Eq]),
+            FilterableField::new(Field::<Self, String>::new("status"), &[FilterOp::Eq]),
+            FilterableField::new(Field::<Self, Id>::new("outcome_of"), &[FilterOp::Eq]),
+            FilterableField::new(
+                Field::<Self, chrono::DateTime<Utc>>::new("at"),
+                &[FilterOp::Gt, FilterOp::Gte, FilterOp::Lt, FilterOp::Lte],
+            ),
+        ]
+    }
+
+    fn sortable() -> Vec<&'static str> {
+        vec!["at"]
+    }
+}
+
+// Entries can only be read from outside the crate; `Audited` is the one writer
+// and it only ever inserts.
+pub struct AuditRepository<Ctx: MongodmContext> {
+    repo: RepositoryWithIdBase<AuditEntry, Ctx>,
+}
+
+impl<Ctx: MongodmContext> AuditRepository<Ctx> {
+    pub async fn new(ctx: &Ctx) -> Self {
+        Self {
+            repo: RepositoryWithIdBase::new(ctx).await,
+        }
+    }
+
+    pub async fn find_many(
+        &self,
+        query: Document,
+        option: Option<FindOptions>,
+    ) -> Result<ModelWithIdCursor<AuditEntry>> {
+        self.repo.find_many(query, option).await
+    }
+
+    pub async fn find_page(
+        &self,
+        query: Document,
+        sort: &PageSort,
+        request: &PageRequest,
+    ) -> Result<Page<WithId<AuditEntry>>> {
+        self.repo.find_page(query, sort, request).await
+    }
+
+    async fn insert(&self, entry: &AuditEntry) -> Result<Id> {
+        self.repo.create(entry).await
+    }
+
+    // The operation has finished either way, so a failure to record it is
+    // logged rather than returned.
+    async fn append_outcome(&self, entry: &AuditEntry) {
+        if let Err(err) = self.insert(entry).await {
+            error!(
+                "cannot record the outcome of audit entry {:?}: {}",
+                entry.outcome_of, err
+            );
+        }
+    }
+}
+
+fn target_key(id: &Bson) -> String {
+    match id {
+        Bson::ObjectId(id) => id.to_hex(),
+        Bson::String(id) => id.clone(),
+        id => id.to_string(),
+    }
+}
+
+// changed top-level fields as `{ field: { before, after } }`, leaving out
+// internal ones such as the schema version and the search grams
+pub fn diff(before: Option<&Document>, after: Option<&Document>) -> Document {
+    let empty = Document::new();
+    let (before, after) = (before.unwrap_or(&empty), after.unwrap_or(&empty));
+    let mut keys = before.keys().chain(after.keys()).collect::<Vec<_>>();
+    keys.sort();
+    keys.dedup();
+    let mut diff = Document::new();
+    for key in keys.into_iter().filter(|key| !key.starts_with('_')) {
+        let (old, new) = (before.get(key), after.get(key));
+        if old != new {
+            diff.insert(
+                key.clone(),
+                doc! {
+                    "before": old.cloned().unwrap_or(Bson::Null),
+                    "after": new.cloned().unwrap_or(Bson::Null),
+                },
+            );
+        }
+    }
+    diff
+}
+
+pub trait Auditor {
+    type Repo: RepositoryWithId;
+    type In;
+    type Out;
+
+    fn operation() -> &'static str;
+
+    fn target(input: &Self::In) -> Option<<Self::Repo as RepositoryWithId>::Id>;
+
+    #[inline]
+    fn created(_output: &Self::Out) -> Option<<Self::Repo as RepositoryWithId>::Id> {
+        None
+    }
+}
+
+pub struct CreateAuditor<Repo> {
+    p: PhantomData<fn() -> Repo>,
+}
+
+impl<Repo: RepositoryWithId> Auditor for CreateAuditor<Repo> {
+    type Repo = Repo;
+    type In = Repo::Model;
+    type Out = Repo::Id;
+
+    #[inline]
+    fn operation() -> &'static str {
+        "create"
+    }
+
+    #[inline]
+    fn target(_input: &Repo::Model) -> Option<Repo::Id> {
+        None
+    }
+
+    #[inline]
+    fn created(output: &Repo::Id) -> Option<Repo::Id> {
+        Some(output.clone())
+    }
+}
+
+pub struct UpdateAuditor<Repo> {
+    p: PhantomData<fn() -> Repo>,
+}
+
+impl<Repo: RepositoryWithId> Auditor for UpdateAuditor<Repo> {
+    type Repo = Repo;
+    type In = WithId<Repo::Model, Repo::Id>;
+    type Out = ();
+
+    #[inline]
+    fn operation() -> &'static str {
+        "update"
+    }
+
+    #[inline]
+    fn target(input: &Self::In) -> Option<Repo::Id> {
+        Some(input.0.clone())
+    }
+}
+
+pub struct DeleteAuditor<Repo> {
+    p: PhantomData<fn() -> Repo>,
+}
+
+impl<Repo: RepositoryWithId> Auditor for DeleteAuditor<Repo> {
+    type Repo = Repo;
+    type In = DeleteId<Repo::Id>;
+    type Out = ();
+
+    #[inline]
+    fn operation() -> &'static str {
+        "delete"
+    }
+
+    #[inline]
+    fn target(input: &Self::In) -> Option<Repo::Id> {
+        Some(input.0.clone())
+    }
+}
+
+pub struct RestoreAuditor<Repo> {
+    p: PhantomData<fn() -> Repo>,
+}
+
+impl<Repo: RepositoryWithId> Auditor for RestoreAuditor<Repo> {
+    type Repo = Repo;
+    type In = RestoreId<Repo::Id>;
+    type Out = ();
+
+    #[inline]
+    fn operation() -> &'static str {
+        "restore"
+    }
+
+    #[inline]
+    fn target(input: &Self::In) -> Option<Repo::Id> {
+        Some(input.0.clone())
+    }
+}
+
+async fn snapshot<Repo: RepositoryWithId>(repo: &Repo, id: &Repo::Id) -> Result<Option<Document>> {
+    match repo.find_one_by_id(id).await? {
+        Some(model) => Ok(Some(to_document(&model.1)?)),
+        None => Ok(None),
+    }
+}
+
+async fn run_audited<B, A>(input: B::In, ctx: &B::Ctx) -> Result<B::Out>
+where
+    B: Behavior,
+    B::Ctx: MongodmContext,
+    A: Auditor<In = B::In, Out = B::Out>,
+    A::Repo: RepositoryWithId<Ctx = B::Ctx>,
+{
+    let repo = A::Repo::new(ctx).await;
+    let log = AuditRepository::new(ctx).await;
+    let target = A::target(&input);
+    let before = match &target {
+        Some(id) => snapshot(&repo, id).await?,
+        None => None,
+    };
+    let pending = AuditEntry {
+        actor: ctx.actor_id(),
+        operation: String::from(A::operation()),
+        collection: String::from(
+            <<A::Repo as RepositoryWithId>::Model as Model>::CollConf::collection_name(),
+        ),
+        target: target
+            .as_ref()
+            .map(|id| target_key(&id.to_bson()))
+            .unwrap_or_default(),
+        diff: Document::new(),
+        at: Utc::now().into(),
+        status: AuditStatus::Pending,
+        outcome_of: None,
+        error: None,
+    };
+    let pending_id = log.insert(&pending).await?;
+    let mut outcome = AuditEntry {
+        at: Utc::now().into(),
+        outcome_of: Some(pending_id),
+        ..pending
+    };
+    let out = match B::apply(input, ctx).await.result() {
+        Ok(out) => out,
+        Err(err) => {
+            outcome.status = AuditStatus::Failed;
+            outcome.error = Some(err.to_string());
+            log.append_outcome(&outcome).await;
+            return Err(err);
+        }
+    };
+    outcome.status = AuditStatus::Done;
+    if let Some(target) = target.or_else(|| A::created(&out)) {
+        outcome.target = target_key(&target.to_bson());
+        match snapshot(&repo, &target).await {
+            Ok(after) => outcome.diff = diff(before.as_ref(), after.as_ref()),
+            Err(err) => error!(
+                "cannot read {} after {}: {}",
+                outcome.target, outcome.operation, err
+            ),
+        }
+    }
+    outcome.at = Utc::now().into();
+    log.append_outcome(&outcome).await;
+    Ok(out)
+}
+
+// Records the actor, the target and a before/after diff of a behavior as a
+// pending entry followed by its outcome, failures included.
+pub struct Audited<B, A>
+where
+    B: Behavior,
+    B::Ctx: MongodmContext,
+    A: Auditor<In = B::In, Out = B::Out>,
+    A::Repo: RepositoryWithId<Ctx = B::Ctx>,
+{
+    result: Result<B::Out>,
+    p: PhantomData<fn() -> A>,
+}
+
+#[async_trait(?Send)]
+impl<B, A> Behavior for Audited<B, A>
+where
+    B: Behavior,
+    B::Ctx: MongodmContext,
+    A: Auditor<In = B::In, Out = B::Out>,
+    A::Repo: RepositoryWithId<Ctx = B::Ctx>,
+{
+    type In = B::In;
+    type Out = B::Out;
+    type Ctx = B::Ctx;
+
+    #[inline]
+    async fn apply(input: Self::In, ctx: &Self::Ctx) -> Self {
+        Self {
+            result: run_audited::<B, A>(input, ctx).await,
+            p: PhantomData,
+        }
+    }
+
+    #[inline]
+    fn result(self) -> Result<Self::Out> {
+        self.result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        let before = doc! { "name": "akari", "cu": "akira", "_v": 1 };
+        let after = doc! { "name": "akari", "co": "riamu", "_v": 2 };
+        assert_eq!(
+            doc! {
+                "co": { "before": Bson::Null, "after": "riamu" },
+                "cu": { "before": "akira", "after": Bson::Null },
+            },
+            diff(Some(&before), Some(&after))
+        );
+        assert_eq!(
+            doc! { "name": { "before": Bson::Null, "after": "akari" } },
+            diff(None, Some(&doc! { "name": "akari" }))
+        );
+    }
+}
@@ -0,0 +1,64 @@
+use crate::utils::result::{Error, Result};
+
+#[derive(Debug)]
+pub struct BulkResult<K, T = ()> {
+    pub items: Vec<(K, Result<T>)>,
+}
+
+impl<K, T> Default for BulkResult<K, T> {
+    #[inline]
+    fn default() -> Self {
+        Self { items: Vec::new() }
+    }
+}
+
+impl<K, T> BulkResult<K, T> {
+    #[inline]
+    pub fn push(&mut self, key: K, result: Result<T>) {
+        self.items.push((key, result));
+    }
+
+    pub fn succeeded(&self) -> impl Iterator<Item = (&K, &T)> {
+        self.items.iter().filter_map(|(key, result)| match result {
+            Ok(value) => Some((key, value)),
+            Err(_) => None,
+        })
+    }
+
+    pub fn failed(&self) -> impl Iterator<Item = (&K, &Error)> {
+        self.items.iter().filter_map(|(key, result)| match result {
+            Ok(_) => None,
+            Err(err) => Some((key, err)),
+        })
+    }
+
+    #[inline]
+    pub fn is_complete(&self) -> bool {
+        self.items.iter().all(|(_, result)| result.is_ok())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::utils::simple_error;
+
+    #[test]
+    fn test_bulk_result() {
+        let mut result = BulkResult::<usize, &str>::default();
+        result.push(0, Ok("akari"));
+        result.push(1, Err(simple_error!("invalid")));
+        result.push(2, Ok("riamu"));
+        assert_eq!(
+            vec![(&0, &"akari"), (&2, &"riamu")],
+            result.succeeded().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![1],
+            result.failed().map(|(i, _)| *i).collect::<Vec<_>>()
+        );
+        assert!(!result.is_complete());
+    }
+}
@@ -1,30 +1,73 @@
+use std::sync::Arc;
+use std::time::Duration;
+
 use mongodm::{
+    doc,
     mongo::{options::ClientOptions, Client, Database},
-    Model, Repository, ToRepository,
+    sync_indexes, Model, Repository, ToRepository,
+};
+
+use crate::{
+    encrypted::Keyring,
+    service::{derive_cursor_key, TEST_CURSOR_KEY},
+    utils::{config::Config, result::Result},
+    withid::Id,
 };
 
-use crate::utils::{config::Config, result::Result};
+pub const TEST_ENCRYPTION_KEYS: &str =
+    "test:000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+const TEST_WATCH_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const WATCH_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
 pub trait MongodmContext
 where
     Self: Clone,
 {
     fn repo<M: Model>(&self) -> Repository<M>;
+
+    fn cursor_key(&self) -> &[u8];
+
+    #[inline]
+    fn actor_id(&self) -> Option<Id> {
+        None
+    }
+
+    #[inline]
+    fn watch_poll_interval(&self) -> Option<Duration> {
+        None
+    }
+
+    // seals and opens `Encrypted` fields
+    #[inline]
+    fn keyring(&self) -> Option<Arc<Keyring>> {
+        None
+    }
 }
 
 #[derive(Clone)]
 pub struct Context {
     client: Client,
     database_name: String,
+    cursor_key: Arc<Vec<u8>>,
+    watch_poll_interval: Option<Duration>,
+    keyring: Option<Arc<Keyring>>,
 }
 
 impl Context {
     pub async fn new(config: &Config) -> Result<Self> {
         let option = ClientOptions::parse(&config.db_uri).await?;
         let client = Client::with_options(option)?;
+        let watch_poll_interval = if is_replica_set(&client).await? {
+            None
+        } else {
+            Some(WATCH_POLL_INTERVAL)
+        };
         Ok(Context {
             client,
             database_name: config.db_database.clone(),
+            cursor_key: Arc::new(derive_cursor_key(&config.session_key_bin()?)?),
+            watch_poll_interval,
+            keyring: Keyring::from_config(config)?.map(Arc::new),
         })
     }
 
@@ -34,13 +77,46 @@ impl Context {
         Ok(Context {
             client,
             database_name: String::from(database_name),
+            cursor_key: Arc::new(TEST_CURSOR_KEY.to_vec()),
+            // a standalone mongod has no change streams
+            watch_poll_interval: Some(TEST_WATCH_POLL_INTERVAL),
+            keyring: Some(Arc::new(Keyring::parse(TEST_ENCRYPTION_KEYS)?)),
         })
     }
 
+    // `None` watches through change streams, which need a replica set.
+    #[inline]
+    pub fn with_watch_poll_interval(mut self, interval: Option<Duration>) -> Self {
+        self.watch_poll_interval = interval;
+        self
+    }
+
+    #[inline]
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
     #[inline]
     pub fn database(&self) -> Database {
         self.client.database(&self.database_name)
     }
+
+    pub async fn sync_indexes<M>(&self) -> Result<()>
+    where
+        M: Model,
+    {
+        sync_indexes::<M::CollConf>(&self.database()).await?;
+        Ok(())
+    }
+}
+
+// change streams need a replica set, so a standalone mongod is polled
+async fn is_replica_set(client: &Client) -> Result<bool> {
+    let reply = client
+        .database("admin")
+        .run_command(doc! { "isMaster": 1 }, None)
+        .await?;
+    Ok(reply.contains_key("setName"))
 }
 
 impl MongodmContext for Context {
@@ -51,4 +127,19 @@ impl MongodmContext for Context {
     {
         self.database().repository::<M>()
     }
+
+    #[inline]
+    fn cursor_key(&self) -> &[u8] {
+        &self.cursor_key
+    }
+
+    #[inline]
+    fn watch_poll_interval(&self) -> Option<Duration> {
+        self.watch_poll_interval
+    }
+
+    #[inline]
+    fn keyring(&self) -> Option<Arc<Keyring>> {
+        self.keyring.clone()
+    }
 }
@@ -0,0 +1,393 @@
+use std::{
+    collections::HashMap,
+    io::{BufRead, Write},
+    sync::Arc,
+};
+
+use futures::TryStreamExt;
+use mongodb::options::{FindOptions, ReplaceOptions};
+use mongodm::{
+    bson::{from_bson, oid::ObjectId, Bson, Document},
+    doc,
+    mongo::Database,
+    CollectionConfig,
+};
+use serde::de::DeserializeOwned;
+
+use crate::{
+    encrypted::{contains_sealed, Keyring, Sealer},
+    extjson::{decode_document, encode_document},
+    migration::upcast,
+    revision::REVISION_SUFFIX,
+    schema::Schema,
+    utils::{result::Result, simple_error},
+};
+
+pub type Checker = fn(Document) -> Result<()>;
+
+fn check_model<M>(doc: Document) -> Result<()>
+where
+    M: Schema + DeserializeOwned,
+{
+    from_bson::<M>(Bson::Document(upcast::<M>(doc)?))?;
+    Ok(())
+}
+
+// Maps collection names to the model their documents have to deserialize into.
+#[derive(Default)]
+pub struct ModelRegistry {
+    models: Vec<(&'static str, Checker)>,
+}
+
+impl ModelRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with<M>(mut self) -> Self
+    where
+        M: Schema + DeserializeOwned,
+    {
+        self.models
+            .push((M::CollConf::collection_name(), check_model::<M>));
+        self
+    }
+
+    pub fn collections(&self) -> Vec<&'static str> {
+        self.models.iter().map(|(name, _)| *name).collect()
+    }
+
+    pub fn collection(&self, name: &str) -> Option<&'static str> {
+        self.models
+            .iter()
+            .find(|(collection, _)| *collection == name)
+            .map(|(collection, _)| *collection)
+    }
+
+    pub fn checker(&self, collection: &str) -> Option<Checker> {
+        self.models
+            .iter()
+            .find(|(name, _)| *name == collection)
+            .map(|(_, checker)| *checker)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct CheckFailure {
+    pub collection: String,
+    pub id: Bson,
+    pub error: String,
+}
+
+pub async fn check_collection(
+    db: &Database,
+    registry: &ModelRegistry,
+    collection: &str,
+) -> Result<Vec<CheckFailure>> {
+    let checker = match registry.checker(collection) {
+        Some(checker) => checker,
+        None => return Err(simple_error!("no model registered for {}", collection)),
+    };
+    let mut cursor = db.collection(collection).find(None, None).await?;
+    let mut failures = Vec::new();
+    while let Some(doc) = cursor.try_next().await? {
+        let id = doc.get("_id").cloned().unwrap_or(Bson::Null);
+        if let Err(err) = checker(doc) {
+            failures.push(CheckFailure {
+                collection: String::from(collection),
+                id,
+                error: err.to_string(),
+            });
+        }
+    }
+    Ok(failures)
+}
+
+// Writes every document of the collection as a line of canonical Extended
+// JSON, soft deleted ones included. `visit` sees each document on the way out.
+pub async fn export_collection(
+    db: &Database,
+    collection: &str,
+    out: &mut impl Write,
+    mut visit: impl FnMut(&Document),
+) -> Result<usize> {
+    let mut cursor = db
+        .collection(collection)
+        .find(None, FindOptions::builder().sort(doc! { "_id": 1 }).build())
+        .await?;
+    let mut count = 0;
+    while let Some(doc) = cursor.try_next().await? {
+        visit(&doc);
+        writeln!(out, "{}", encode_document(&doc)?)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+pub fn read_documents(input: impl BufRead) -> Result<Vec<Document>> {
+    let mut docs = Vec::new();
+    for line in input.lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            docs.push(decode_document(&line)?);
+        }
+    }
+    Ok(docs)
+}
+
+// Documents are upserted by `_id`, so importing the same dump twice leaves a
+// single copy.
+pub async fn import_documents(
+    db: &Database,
+    collection: &str,
+    docs: Vec<Document>,
+) -> Result<usize> {
+    let coll = db.collection(collection);
+    let option = ReplaceOptions::builder().upsert(true).build();
+    let mut count = 0;
+    for doc in docs {
+        match doc.get("_id").cloned() {
+            Some(id) => {
+                coll.replace_one(doc! { "_id": id }, doc, option.clone())
+                    .await?;
+            }
+            None => {
+                coll.insert_one(doc, None).await?;
+            }
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+// Inserts the documents, failing on the first one whose id or unique value is
+// taken instead of replacing what is there.
+pub async fn insert_documents(
+    db: &Database,
+    collection: &str,
+    docs: Vec<Document>,
+) -> Result<usize> {
+    let coll = db.collection(collection);
+    let mut count = 0;
+    for doc in docs {
+        coll.insert_one(doc, None).await?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+fn lookup(doc: &Document, path: &str) -> Option<Bson> {
+    let mut parts = path.splitn(2, '.');
+    let value = doc.get(parts.next()?)?;
+    match (parts.next(), value) {
+        (None, value) => Some(value.clone()),
+        (Some(rest), Bson::Document(doc)) => lookup(doc, rest),
+        _ => None,
+    }
+}
+
+struct UniqueIndex {
+    keys: Vec<String>,
+    sparse: bool,
+    partial: Option<Document>,
+}
+
+async fn unique_indexes(db: &Database, collection: &str) -> Result<Vec<UniqueIndex>> {
+    let reply = db
+        .run_command(doc! { "listIndexes": collection }, None)
+        .await?;
+    let batch = reply.get_document("cursor")?.get_array("firstBatch")?;
+    Ok(batch
+        .iter()
+        .filter_map(|index| match index {
+            Bson::Document(index) if index.get_bool("unique").unwrap_or(false) => {
+                Some(UniqueIndex {
+                    keys: index
+                        .get_document("key")
+                        .map(|key| key.keys().cloned().collect())
+                        .unwrap_or_default(),
+                    sparse: index.get_bool("sparse").unwrap_or(false),
+                    partial: index.get_document("partialFilterExpression").ok().cloned(),
+                })
+            }
+            _ => None,
+        })
+        .collect())
+}
+
+// The ids of the documents an insert into the collection would reject, as
+// their id or the value of a unique index is already taken. Partial indexes
+// are checked against every document, so a document outside the filter may be
+// reported too.
+pub async fn find_conflicts(
+    db: &Database,
+    collection: &str,
+    docs: &[Document],
+) -> Result<Vec<Bson>> {
+    let names = db
+        .list_collection_names(doc! { "name": collection })
+        .await?;
+    if names.is_empty() {
+        return Ok(Vec::new());
+    }
+    let coll = db.collection(collection);
+    let indexes = unique_indexes(db, collection).await?;
+    let mut conflicts = Vec::new();
+    for doc in docs {
+        let id = doc.get("_id").cloned().unwrap_or(Bson::Null);
+        let mut queries = Vec::new();
+        if id != Bson::Null {
+            queries.push(doc! { "_id": id.clone() });
+        }
+        for index in &indexes {
+            let values = index
+                .keys
+                .iter()
+                .map(|key| (key.clone(), lookup(doc, key)))
+                .collect::<Vec<_>>();
+            if index.sparse && values.iter().any(|(_, value)| value.is_none()) {
+                continue;
+            }
+            let mut query = values
+                .into_iter()
+                .map(|(key, value)| (key, value.unwrap_or(Bson::Null)))
+                .collect::<Document>();
+            if let Some(partial) = &index.partial {
+                query = doc! { "$and": [partial.clone(), query] };
+            }
+            queries.push(query);
+        }
+        if queries.is_empty() {
+            continue;
+        }
+        if coll
+            .find_one(doc! { "$or": queries }, None)
+            .await?
+            .is_some()
+        {
+            conflicts.push(id);
+        }
+    }
+    Ok(conflicts)
+}
+
+// Gives every imported `ObjectId` key a fresh id. Object ids are unique across
+// collections, so any matching value, be it an `_id` or a reference, is
+// rewritten. Other id types are kept as they are.
+#[derive(Default, Debug)]
+pub struct IdMap {
+    ids: HashMap<ObjectId, ObjectId>,
+}
+
+impl IdMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn assign(&mut self, doc: &Document) {
+        if let Ok(id) = doc.get_object_id("_id") {
+            self.ids.entry(id.clone()).or_insert_with(ObjectId::new);
+        }
+    }
+
+    #[inline]
+    pub fn get(&self, id: &ObjectId) -> Option<&ObjectId> {
+        self.ids.get(id)
+    }
+
+    pub fn rewrite(&self, value: Bson) -> Bson {
+        match value {
+            Bson::ObjectId(id) => Bson::ObjectId(self.get(&id).cloned().unwrap_or(id)),
+            Bson::Array(values) => {
+                Bson::Array(values.into_iter().map(|v| self.rewrite(v)).collect())
+            }
+            Bson::Document(doc) => Bson::Document(self.rewrite_document(doc)),
+            value => value,
+        }
+    }
+
+    pub fn rewrite_document(&self, doc: Document) -> Document {
+        doc.into_iter()
+            .map(|(key, value)| (key, self.rewrite(value)))
+            .collect()
+    }
+
+    // `rewrite_document` for a document of `collection`. Sealed values are bound
+    // to the id of their document, so they are opened under the old id and
+    // sealed again under the new one; a revision holds those of its target.
+    // They can only be copied for the collections of the registry.
+    pub fn remap_document(
+        &self,
+        registry: &ModelRegistry,
+        keyring: Option<Arc<Keyring>>,
+        collection: &str,
+        mut doc: Document,
+    ) -> Result<Document> {
+        if contains_sealed(&doc) {
+            let (model, id_key) = match collection.strip_suffix(REVISION_SUFFIX) {
+                Some(model) => (model, "target"),
+                None => (collection, "_id"),
+            };
+            let model = registry
+                .collection(model)
+                .ok_or_else(|| simple_error!("cannot remap the sealed values of {}", collection))?;
+            let sealer = Sealer::new(keyring, model);
+            let from = doc.get(id_key).cloned().unwrap_or(Bson::Null);
+            let to = self.rewrite(from.clone());
+            if id_key == "_id" {
+                sealer.reseal(&mut doc, &from, &to)?;
+            } else if let Some(Bson::Document(document)) = doc.get_mut("document") {
+                sealer.reseal(document, &from, &to)?;
+            }
+        }
+        Ok(self.rewrite_document(doc))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_id_map() {
+        let user = ObjectId::new();
+        let other = ObjectId::new();
+        let doc = doc! {
+            "_id": user.clone(),
+            "friends": [user.clone(), other.clone()],
+            "profile": { "owner": user.clone(), "slug": "akari" },
+        };
+        let mut ids = IdMap::new();
+        ids.assign(&doc);
+        ids.assign(&doc! { "_id": "slug" });
+        let mapped = ids.get(&user).unwrap().clone();
+        assert_ne!(user, mapped);
+        assert_eq!(
+            doc! {
+                "_id": mapped.clone(),
+                "friends": [mapped.clone(), other],
+                "profile": { "owner": mapped, "slug": "akari" },
+            },
+            ids.rewrite_document(doc)
+        );
+    }
+
+    #[test]
+    fn test_lookup() {
+        let doc = doc! { "name": "akari", "profile": { "slug": "akari" } };
+        assert_eq!(Some(Bson::from("akari")), lookup(&doc, "profile.slug"));
+        assert_eq!(None, lookup(&doc, "name.slug"));
+        assert_eq!(None, lookup(&doc, "age"));
+    }
+
+    #[test]
+    fn test_read_documents() {
+        let text = "{\"_id\": {\"$oid\": \"5f9a4b4b0000000000000000\"}}\n\n{\"n\": 1}\n";
+        let docs = read_documents(text.as_bytes()).unwrap();
+        assert_eq!(2, docs.len());
+        assert_eq!(Bson::Int64(1), docs[1].get("n").cloned().unwrap());
+        assert!(read_documents("{\"n\": {\"$oid\": 1}}".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,641 @@
+use std::{
+    fmt,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+    sync::Arc,
+};
+
+use aes_gcm::{
+    aead::{generic_array::GenericArray, Aead, NewAead, Payload},
+    Aes256Gcm,
+};
+use hmac::{Hmac, Mac, NewMac};
+use mongodm::{
+    bson::{spec::BinarySubtype, to_bson, Binary, Bson, Document},
+    doc, CollectionConfig, Model,
+};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+use sha2::Sha256;
+use thiserror::Error;
+
+use crate::{
+    context::MongodmContext,
+    utils::{config::Config, result::Result},
+};
+
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 12;
+const NONCE_KEY_LABEL: &[u8] = b"ringoro-deterministic-nonce";
+// user defined binary subtype of sealed values
+const SEALED_SUBTYPE: u8 = 0x80;
+const MARKER: &str = "$encrypt";
+const RANDOMIZED: &str = "randomized";
+const DETERMINISTIC: &str = "deterministic";
+
+#[derive(Debug, Error)]
+pub enum EncryptionError {
+    #[error("no encryption keys are configured")]
+    NoKeyring,
+    #[error("invalid encryption key: {0}")]
+    InvalidKey(String),
+    #[error("unknown encryption key id: {0}")]
+    UnknownKey(String),
+    #[error("malformed ciphertext")]
+    Malformed,
+    #[error("cannot decrypt with key {0}")]
+    Decrypt(String),
+    #[error("cannot open the sealed values of {0}")]
+    Sealed(Bson),
+}
+
+pub trait EncryptionMode {
+    fn deterministic() -> bool;
+}
+
+pub struct Randomized {}
+
+impl EncryptionMode for Randomized {
+    #[inline]
+    fn deterministic() -> bool {
+        false
+    }
+}
+
+// Equal plaintexts give equal ciphertexts in the same field, which keeps the
+// field equality-queryable at the cost of revealing equality. The document id
+// is not bound to them for the same reason.
+pub struct Deterministic {}
+
+impl EncryptionMode for Deterministic {
+    #[inline]
+    fn deterministic() -> bool {
+        true
+    }
+}
+
+struct Key {
+    id: String,
+    cipher: Aes256Gcm,
+    nonce_key: Vec<u8>,
+}
+
+impl Key {
+    fn new(id: &str, secret: &[u8]) -> Result<Self> {
+        if id.is_empty() || id.len() > u8::MAX as usize || secret.len() != KEY_SIZE {
+            return Err(EncryptionError::InvalidKey(String::from(id)).into());
+        }
+        Ok(Self {
+            id: String::from(id),
+            cipher: Aes256Gcm::new(GenericArray::from_slice(secret)),
+            nonce_key: hmac(secret, NONCE_KEY_LABEL),
+        })
+    }
+
+    // `<deterministic flag><key id length><key id><nonce><ciphertext>`
+    fn seal(&self, plaintext: &[u8], aad: &[u8], deterministic: bool) -> Result<Vec<u8>> {
+        let nonce = if deterministic {
+            hmac(&self.nonce_key, &[aad, b"\0", plaintext].concat())[..NONCE_SIZE].to_vec()
+        } else {
+            rand::random::<[u8; NONCE_SIZE]>().to_vec()
+        };
+        let sealed = self
+            .cipher
+            .encrypt(
+                GenericArray::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .map_err(|_| EncryptionError::InvalidKey(self.id.clone()))?;
+        let mut payload = vec![deterministic as u8, self.id.len() as u8];
+        payload.extend_from_slice(self.id.as_bytes());
+        payload.extend_from_slice(&nonce);
+        payload.extend_from_slice(&sealed);
+        Ok(payload)
+    }
+
+    fn open(&self, payload: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        if payload.len() < NONCE_SIZE {
+            return Err(EncryptionError::Malformed.into());
+        }
+        let (nonce, sealed) = payload.split_at(NONCE_SIZE);
+        Ok(self
+            .cipher
+            .decrypt(
+                GenericArray::from_slice(nonce),
+                Payload { msg: sealed, aad },
+            )
+            .map_err(|_| EncryptionError::Decrypt(self.id.clone()))?)
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("hmac accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+// splits a payload into the flag, the key id and the rest
+fn parse_payload(payload: &[u8]) -> Result<(bool, &str, &[u8])> {
+    let (flag, len) = match payload {
+        [flag, len, ..] if *flag <= 1 => (*flag == 1, *len as usize),
+        _ => return Err(EncryptionError::Malformed.into()),
+    };
+    if payload.len() < 2 + len {
+        return Err(EncryptionError::Malformed.into());
+    }
+    let id = std::str::from_utf8(&payload[2..2 + len]).map_err(|_| EncryptionError::Malformed)?;
+    Ok((flag, id, &payload[2 + len..]))
+}
+
+// The first key seals randomized values, and a new key is rotated in by
+// putting it in front; old ones can be dropped once no document uses them.
+// Deterministic values are always sealed with the last key, so that a value
+// keeps its ciphertext, and a unique index on it keeps working, across
+// rotations. That key has to stay at the end of the ring for as long as any
+// deterministic field exists.
+pub struct Keyring {
+    keys: Vec<Key>,
+}
+
+impl Keyring {
+    // `<id>:<hex secret>[,<id>:<hex secret>...]`
+    pub fn parse(spec: &str) -> Result<Self> {
+        let keys = spec
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(|entry| {
+                let mut parts = entry.splitn(2, ':');
+                let id = parts.next().unwrap_or_default();
+                let secret = hex::decode(parts.next().unwrap_or_default())
+                    .map_err(|_| EncryptionError::InvalidKey(String::from(id)))?;
+                Key::new(id, &secret)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if keys.is_empty() {
+            return Err(EncryptionError::NoKeyring.into());
+        }
+        Ok(Self { keys })
+    }
+
+    pub fn from_config(config: &Config) -> Result<Option<Self>> {
+        if config.encryption_keys.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Self::parse(&config.encryption_keys)?))
+    }
+
+    #[inline]
+    pub fn active_key_id(&self) -> &str {
+        &self.keys[0].id
+    }
+
+    #[inline]
+    pub fn deterministic_key_id(&self) -> &str {
+        &self.keys[self.keys.len() - 1].id
+    }
+
+    fn key(&self, id: &str) -> Result<&Key> {
+        self.keys
+            .iter()
+            .find(|key| key.id == id)
+            .ok_or_else(|| EncryptionError::UnknownKey(String::from(id)).into())
+    }
+
+    pub fn seal(&self, plaintext: &[u8], aad: &[u8], deterministic: bool) -> Result<Vec<u8>> {
+        let key = if deterministic {
+            &self.keys[self.keys.len() - 1]
+        } else {
+            &self.keys[0]
+        };
+        key.seal(plaintext, aad, deterministic)
+    }
+
+    pub fn open(&self, payload: &[u8], aad: &[u8]) -> Result<Vec<u8>> {
+        let (_, id, rest) = parse_payload(payload)?;
+        self.key(id)?.open(rest, aad)
+    }
+}
+
+// A sealed value is bound to its collection, its field path and, unless it
+// is deterministic, the id of its document, so it cannot be moved elsewhere.
+// A document copied under a new id has to be read and written again through
+// the repository.
+fn aad(collection: &str, path: &str, id: Option<&Bson>) -> Vec<u8> {
+    let id = id.map(Bson::to_string).unwrap_or_default();
+    format!("{}\0{}\0{}", collection, path, id).into_bytes()
+}
+
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        String::from(key)
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+fn encode_value(value: Bson) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    doc! { "v": value }.to_writer(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn decode_value(bytes: &[u8]) -> Result<Bson> {
+    let mut doc = Document::from_reader(&mut &bytes[..])?;
+    doc.remove("v")
+        .ok_or_else(|| EncryptionError::Malformed.into())
+}
+
+fn sealed(payload: Vec<u8>) -> Bson {
+    Bson::Binary(Binary {
+        subtype: BinarySubtype::UserDefined(SEALED_SUBTYPE),
+        bytes: payload,
+    })
+}
+
+fn has_sealed(value: &Bson) -> bool {
+    match value {
+        Bson::Binary(Binary {
+            subtype: BinarySubtype::UserDefined(SEALED_SUBTYPE),
+            ..
+        }) => true,
+        Bson::Document(doc) => contains_sealed(doc),
+        Bson::Array(items) => items.iter().any(has_sealed),
+        _ => false,
+    }
+}
+
+// whether any value of the document is sealed
+pub fn contains_sealed(doc: &Document) -> bool {
+    doc.values().any(has_sealed)
+}
+
+// Seals and opens the documents of one collection.
+#[derive(Clone)]
+pub struct Sealer {
+    keyring: Option<Arc<Keyring>>,
+    collection: &'static str,
+}
+
+impl Sealer {
+    #[inline]
+    pub fn new(keyring: Option<Arc<Keyring>>, collection: &'static str) -> Self {
+        Self {
+            keyring,
+            collection,
+        }
+    }
+
+    fn seal_value(&self, value: &mut Bson, path: &str, id: &Bson) -> Result<()> {
+        match value {
+            Bson::Document(doc) => {
+                let deterministic = match doc.get_str(MARKER) {
+                    Ok(mode) => mode == DETERMINISTIC,
+                    Err(_) => return self.seal_fields(doc, path, id),
+                };
+                let keyring = self.keyring.as_ref().ok_or(EncryptionError::NoKeyring)?;
+                let plaintext = encode_value(doc.remove("value").unwrap_or(Bson::Null))?;
+                let aad = aad(self.collection, path, Some(id).filter(|_| !deterministic));
+                *value = sealed(keyring.seal(&plaintext, &aad, deterministic)?);
+            }
+            Bson::Array(items) => {
+                for (i, item) in items.iter_mut().enumerate() {
+                    self.seal_value(item, &join(path, &i.to_string()), id)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn seal_fields(&self, doc: &mut Document, path: &str, id: &Bson) -> Result<()> {
+        for key in doc.keys().cloned().collect::<Vec<_>>() {
+            if let Some(value) = doc.get_mut(&key) {
+                self.seal_value(value, &join(path, &key), id)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Replaces the plaintext left by `Encrypted` with sealed values, before the
+    // document with `id` is written.
+    #[inline]
+    pub fn seal(&self, doc: &mut Document, id: &Bson) -> Result<()> {
+        self.seal_fields(doc, "", id)
+    }
+
+    fn open_sealed(
+        &self,
+        keyring: &Keyring,
+        payload: &[u8],
+        path: &str,
+        id: &Bson,
+    ) -> Result<Document> {
+        let (deterministic, _, _) = parse_payload(payload)?;
+        let aad = aad(self.collection, path, Some(id).filter(|_| !deterministic));
+        let plaintext = keyring.open(payload, &aad)?;
+        let mode = if deterministic {
+            DETERMINISTIC
+        } else {
+            RANDOMIZED
+        };
+        Ok(doc! { MARKER: mode, "value": decode_value(&plaintext)? })
+    }
+
+    fn open_value(&self, keyring: &Keyring, value: &mut Bson, path: &str, id: &Bson) {
+        match value {
+            Bson::Binary(Binary {
+                subtype: BinarySubtype::UserDefined(SEALED_SUBTYPE),
+                bytes,
+            }) => {
+                if let Ok(marker) = self.open_sealed(keyring, bytes, path, id) {
+                    *value = Bson::Document(marker);
+                }
+            }
+            Bson::Document(doc) => self.open_fields(keyring, doc, path, id),
+            Bson::Array(items) => {
+                for (i, item) in items.iter_mut().enumerate() {
+                    self.open_value(keyring, item, &join(path, &i.to_string()), id);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn open_fields(&self, keyring: &Keyring, doc: &mut Document, path: &str, id: &Bson) {
+        for key in doc.keys().cloned().collect::<Vec<_>>() {
+            if let Some(value) = doc.get_mut(&key) {
+                self.open_value(keyring, value, &join(path, &key), id);
+            }
+        }
+    }
+
+    // The reverse of `seal` for a document read back. Values that cannot be
+    // opened, e.g. without keys or under a key since dropped, are left sealed
+    // and fail when the model is deserialized.
+    pub fn open(&self, doc: &mut Document, id: &Bson) {
+        if let Some(keyring) = &self.keyring {
+            self.open_fields(keyring, doc, "", id);
+        }
+    }
+
+    // Binds the sealed values of the document with id `from` to the id `to`
+    // instead, for a copy of it. Unlike `open` this fails on a value it cannot
+    // open, which would be lost otherwise.
+    pub fn reseal(&self, doc: &mut Document, from: &Bson, to: &Bson) -> Result<()> {
+        self.open(doc, from);
+        if contains_sealed(doc) {
+            return Err(EncryptionError::Sealed(from.clone()).into());
+        }
+        self.seal(doc, to)
+    }
+
+    // `open` for a document that carries its own `_id`
+    pub fn open_with_id(&self, doc: &mut Document) {
+        if let Some(id) = doc.get("_id").cloned() {
+            self.open(doc, &id);
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct MarkerRef<'a, T> {
+    #[serde(rename = "$encrypt")]
+    mode: &'static str,
+    value: &'a T,
+}
+
+#[derive(Deserialize)]
+struct Marker<T> {
+    #[serde(rename = "$encrypt")]
+    #[allow(dead_code)]
+    mode: String,
+    value: T,
+}
+
+// A model field encrypted at rest. It serializes to a plaintext marker, which
+// the repositories seal with the keys of their context before anything is
+// written and open again when reading, so the plaintext never reaches the
+// database. MongoDB refuses the `$`-prefixed marker, should one get through.
+pub struct Encrypted<T, M = Randomized> {
+    value: T,
+    p: PhantomData<fn() -> M>,
+}
+
+impl<T, M> Encrypted<T, M> {
+    #[inline]
+    pub fn new(value: T) -> Self {
+        Self {
+            value,
+            p: PhantomData,
+        }
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+}
+
+impl<T: Serialize> Encrypted<T, Deterministic> {
+    // `doc! { "email": Encrypted::<String, Deterministic>::query::<User, _>("email", &email, ctx)? }`
+    pub fn query<Mo, Ctx>(path: &str, value: &T, ctx: &Ctx) -> Result<Bson>
+    where
+        Mo: Model,
+        Ctx: MongodmContext,
+    {
+        let keyring = ctx.keyring().ok_or(EncryptionError::NoKeyring)?;
+        let aad = aad(Mo::CollConf::collection_name(), path, None);
+        let plaintext = encode_value(to_bson(value)?)?;
+        Ok(sealed(keyring.seal(&plaintext, &aad, true)?))
+    }
+}
+
+impl<T, M> From<T> for Encrypted<T, M> {
+    #[inline]
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<T, M> Deref for Encrypted<T, M> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T, M> DerefMut for Encrypted<T, M> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+impl<T: Clone, M> Clone for Encrypted<T, M> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self::new(self.value.clone())
+    }
+}
+
+impl<T: PartialEq, M> PartialEq for Encrypted<T, M> {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        self.value == other.value
+    }
+}
+
+// never prints the plaintext
+impl<T, M> fmt::Debug for Encrypted<T, M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Encrypted(..)")
+    }
+}
+
+impl<T: Serialize, M: EncryptionMode> Serialize for Encrypted<T, M> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        MarkerRef {
+            mode: if M::deterministic() {
+                DETERMINISTIC
+            } else {
+                RANDOMIZED
+            },
+            value: &self.value,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de, T: DeserializeOwned, M> Deserialize<'de> for Encrypted<T, M> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        Ok(Self::new(Marker::<T>::deserialize(deserializer)?.value))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mongodm::bson::{from_bson, to_bson};
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::context::TEST_ENCRYPTION_KEYS;
+
+    const OLD_KEY: &str = "old:0101010101010101010101010101010101010101010101010101010101010101";
+    const NEW_KEY: &str = "new:0202020202020202020202020202020202020202020202020202020202020202";
+
+    fn sealer(keys: &str, collection: &'static str) -> Sealer {
+        Sealer::new(Some(Arc::new(Keyring::parse(keys).unwrap())), collection)
+    }
+
+    #[test]
+    fn test_parse() {
+        let keyring = Keyring::parse(&format!("{}, {}", NEW_KEY, OLD_KEY)).unwrap();
+        assert_eq!("new", keyring.active_key_id());
+        assert_eq!("old", keyring.deterministic_key_id());
+        assert!(Keyring::parse("").is_err());
+        assert!(Keyring::parse("short:0101").is_err());
+    }
+
+    #[test]
+    fn test_rotation() {
+        let old = Keyring::parse(OLD_KEY).unwrap();
+        let rotated = Keyring::parse(&format!("{},{}", NEW_KEY, OLD_KEY)).unwrap();
+        let payload = old.seal(b"akari", b"aad", false).unwrap();
+        assert_eq!(b"akari".to_vec(), rotated.open(&payload, b"aad").unwrap());
+        let payload = rotated.seal(b"akari", b"aad", false).unwrap();
+        assert_eq!("new", parse_payload(&payload).unwrap().1);
+        assert!(old.open(&payload, b"aad").is_err());
+        // deterministic values keep their key and ciphertext
+        assert_eq!(
+            old.seal(b"akari", b"aad", true).unwrap(),
+            rotated.seal(b"akari", b"aad", true).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let keyring = Keyring::parse(OLD_KEY).unwrap();
+        assert_eq!(
+            keyring.seal(b"akari", b"aad", true).unwrap(),
+            keyring.seal(b"akari", b"aad", true).unwrap()
+        );
+        assert_ne!(
+            keyring.seal(b"akari", b"aad", true).unwrap(),
+            keyring.seal(b"akari", b"other", true).unwrap()
+        );
+        assert_ne!(
+            keyring.seal(b"akari", b"aad", false).unwrap(),
+            keyring.seal(b"akari", b"aad", false).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_tampered() {
+        let keyring = Keyring::parse(OLD_KEY).unwrap();
+        let mut payload = keyring.seal(b"akari", b"aad", false).unwrap();
+        assert!(keyring.open(&payload, b"other").is_err());
+        let last = payload.len() - 1;
+        payload[last] ^= 1;
+        assert!(keyring.open(&payload, b"aad").is_err());
+        assert!(keyring.open(b"\x00", b"aad").is_err());
+    }
+
+    #[test]
+    fn test_seal_and_open() {
+        let sealer = sealer(TEST_ENCRYPTION_KEYS, "Unit");
+        let id = Bson::from("1");
+        let plain = doc! {
+            "name": "akari",
+            "token": to_bson(&Encrypted::<String>::new(String::from("secret"))).unwrap(),
+        };
+        let mut doc = plain.clone();
+        sealer.seal(&mut doc, &id).unwrap();
+        assert!(matches!(doc.get("token"), Some(Bson::Binary(_))));
+
+        // bound to the document, the field and the collection
+        let mut moved = doc.clone();
+        sealer.open(&mut moved, &Bson::from("2"));
+        assert!(matches!(moved.get("token"), Some(Bson::Binary(_))));
+        let mut moved = doc! { "other": doc.get("token").unwrap().clone() };
+        sealer.open(&mut moved, &id);
+        assert!(matches!(moved.get("other"), Some(Bson::Binary(_))));
+        let mut moved = doc.clone();
+        self::sealer(TEST_ENCRYPTION_KEYS, "Other").open(&mut moved, &id);
+        assert!(matches!(moved.get("token"), Some(Bson::Binary(_))));
+
+        let mut copy = doc.clone();
+        sealer.reseal(&mut copy, &id, &Bson::from("2")).unwrap();
+        assert!(contains_sealed(&copy));
+        sealer.open(&mut copy, &Bson::from("2"));
+        assert_eq!(plain, copy);
+        let mut copy = doc.clone();
+        assert!(Sealer::new(None, "Unit")
+            .reseal(&mut copy, &id, &Bson::from("2"))
+            .is_err());
+
+        sealer.open(&mut doc, &id);
+        assert_eq!(plain, doc);
+        assert!(!contains_sealed(&doc));
+        assert!(Sealer::new(None, "Unit").seal(&mut doc, &id).is_err());
+    }
+
+    #[test]
+    fn test_serde() {
+        let token = Encrypted::<String>::new(String::from("secret"));
+        let bson = to_bson(&token).unwrap();
+        assert_eq!(
+            Bson::Document(doc! { "$encrypt": "randomized", "value": "secret" }),
+            bson
+        );
+        assert_eq!(token, from_bson::<Encrypted<String>>(bson).unwrap());
+        assert_eq!("Encrypted(..)", format!("{:?}", token));
+
+        let sealed = sealed(vec![0, 1, b'x']);
+        assert!(from_bson::<Encrypted<String>>(sealed).is_err());
+    }
+}
@@ -0,0 +1,167 @@
+use chrono::{TimeZone, Utc};
+use serde_json::{json, Map, Value};
+use thiserror::Error;
+
+use mongodm::bson::{
+    oid::ObjectId,
+    spec::{BinarySubtype, ElementType},
+    Binary, Bson, Document,
+};
+
+use crate::utils::result::Result;
+
+#[derive(Debug, Error)]
+pub enum JsonError {
+    #[error("cannot encode {0:?} as extended json")]
+    Unsupported(ElementType),
+    #[error("invalid extended json: {0}")]
+    Invalid(String),
+}
+
+fn invalid(value: &Value) -> JsonError {
+    JsonError::Invalid(value.to_string())
+}
+
+// Canonical Extended JSON, limited to the types models are made of.
+pub fn to_json(value: &Bson) -> Result<Value> {
+    Ok(match value {
+        Bson::Null => Value::Null,
+        Bson::Boolean(v) => Value::Bool(*v),
+        Bson::String(v) => Value::String(v.clone()),
+        Bson::Int32(v) => json!({ "$numberInt": v.to_string() }),
+        Bson::Int64(v) => json!({ "$numberLong": v.to_string() }),
+        Bson::Double(v) => json!({ "$numberDouble": v.to_string() }),
+        Bson::ObjectId(v) => json!({ "$oid": v.to_hex() }),
+        Bson::DateTime(v) => {
+            json!({ "$date": { "$numberLong": v.timestamp_millis().to_string() } })
+        }
+        Bson::Binary(v) => json!({ "$binary": {
+            "base64": base64::encode(&v.bytes),
+            "subType": format!("{:02x}", u8::from(v.subtype)),
+        } }),
+        Bson::Array(values) => Value::Array(values.iter().map(to_json).collect::<Result<_>>()?),
+        Bson::Document(doc) => Value::Object(
+            doc.iter()
+                .map(|(key, value)| Ok((key.clone(), to_json(value)?)))
+                .collect::<Result<Map<_, _>>>()?,
+        ),
+        value => return Err(JsonError::Unsupported(value.element_type()).into()),
+    })
+}
+
+fn wrapped<'a>(value: &'a Value, key: &str) -> Option<&'a str> {
+    value.as_object()?.get(key)?.as_str()
+}
+
+fn from_wrapper(key: &str, inner: &Value) -> Result<Option<Bson>> {
+    let text = || inner.as_str().ok_or_else(|| invalid(inner));
+    Ok(Some(match key {
+        "$numberInt" => Bson::Int32(text()?.parse().map_err(|_| invalid(inner))?),
+        "$numberLong" => Bson::Int64(text()?.parse().map_err(|_| invalid(inner))?),
+        "$numberDouble" => Bson::Double(text()?.parse().map_err(|_| invalid(inner))?),
+        "$oid" => Bson::ObjectId(ObjectId::with_string(text()?).map_err(|_| invalid(inner))?),
+        "$date" => {
+            let millis = wrapped(inner, "$numberLong")
+                .and_then(|millis| millis.parse().ok())
+                .ok_or_else(|| invalid(inner))?;
+            Bson::DateTime(Utc.timestamp_millis(millis))
+        }
+        "$binary" => {
+            let bytes = wrapped(inner, "base64")
+                .and_then(|bytes| base64::decode(bytes).ok())
+                .ok_or_else(|| invalid(inner))?;
+            let subtype = wrapped(inner, "subType")
+                .and_then(|subtype| u8::from_str_radix(subtype, 16).ok())
+                .ok_or_else(|| invalid(inner))?;
+            Bson::Binary(Binary {
+                subtype: BinarySubtype::from(subtype),
+                bytes,
+            })
+        }
+        _ => return Ok(None),
+    }))
+}
+
+pub fn from_json(value: Value) -> Result<Bson> {
+    Ok(match value {
+        Value::Null => Bson::Null,
+        Value::Bool(v) => Bson::Boolean(v),
+        Value::String(v) => Bson::String(v),
+        Value::Number(v) => match v.as_i64() {
+            Some(v) => Bson::Int64(v),
+            None => Bson::Double(v.as_f64().unwrap_or_default()),
+        },
+        Value::Array(values) => {
+            Bson::Array(values.into_iter().map(from_json).collect::<Result<_>>()?)
+        }
+        Value::Object(map) => {
+            if map.len() == 1 {
+                let (key, inner) = map.iter().next().unwrap();
+                if let Some(value) = from_wrapper(key, inner)? {
+                    return Ok(value);
+                }
+            }
+            Bson::Document(
+                map.into_iter()
+                    .map(|(key, value)| Ok((key, from_json(value)?)))
+                    .collect::<Result<Document>>()?,
+            )
+        }
+    })
+}
+
+pub fn encode_document(doc: &Document) -> Result<String> {
+    Ok(serde_json::to_string(&to_json(&Bson::Document(
+        doc.clone(),
+    ))?)?)
+}
+
+pub fn decode_document(text: &str) -> Result<Document> {
+    let value = serde_json::from_str::<Value>(text)?;
+    match from_json(value.clone())? {
+        Bson::Document(doc) => Ok(doc),
+        _ => Err(invalid(&value).into()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use mongodm::doc;
+
+    #[test]
+    fn test_roundtrip() {
+        let uuid = Binary {
+            subtype: BinarySubtype::Uuid,
+            bytes: vec![1, 2, 255],
+        };
+        let doc = doc! {
+            "_id": ObjectId::new(),
+            "name": "akari",
+            "age": 14,
+            "fans": 1_000_000_000_000i64,
+            "height": 150.5,
+            "admin": false,
+            "unit": Bson::Null,
+            "tags": ["a", 1],
+            "profile": { "kana": "あかり" },
+            "at": Bson::DateTime(Utc.timestamp_millis(1_610_000_000_123)),
+            "uuid": Bson::Binary(uuid),
+        };
+        assert_eq!(
+            doc,
+            decode_document(&encode_document(&doc).unwrap()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_unsupported() {
+        let doc = doc! { "code": Bson::JavaScriptCode(String::from("1")) };
+        assert!(encode_document(&doc).unwrap_err().is::<JsonError>());
+        assert!(decode_document(r#"{"n": {"$numberInt": "x"}}"#)
+            .unwrap_err()
+            .is::<JsonError>());
+    }
+}
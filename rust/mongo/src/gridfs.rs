@@ -0,0 +1,492 @@
+use chrono::Utc;
+use futures::{
+    future,
+    stream::{self, BoxStream},
+    Stream, StreamExt, TryStreamExt,
+};
+use mongodm::{
+    bson::{oid::ObjectId, spec::BinarySubtype, Binary, Bson, Document, Regex},
+    doc,
+    mongo::{
+        options::{FindOneAndUpdateOptions, FindOneOptions, FindOptions, ReturnDocument},
+        Collection, Database,
+    },
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::{
+    listquery::escape,
+    utils::result::{Error, Result},
+    withid::Id,
+};
+
+const DEFAULT_BUCKET: &str = "fs";
+const DEFAULT_CHUNK_SIZE: usize = 255 * 1024;
+
+#[derive(Debug, Error)]
+pub enum GridFsError {
+    #[error("blob not found: {0}")]
+    NotFound(Id),
+    #[error("blob {0} is missing chunk {1}")]
+    MissingChunk(Id, i64),
+}
+
+pub type ByteStream = BoxStream<'static, Result<Vec<u8>>>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BlobRef {
+    pub id: Id,
+    pub content_type: String,
+    pub size: i64,
+    pub sha256: String,
+}
+
+pub struct GridFs {
+    database: Database,
+    files: Collection,
+    chunks: Collection,
+    chunk_size: usize,
+}
+
+impl GridFs {
+    #[inline]
+    pub fn new(database: Database) -> Self {
+        Self::with_bucket(database, DEFAULT_BUCKET)
+    }
+
+    pub fn with_bucket(database: Database, bucket: impl AsRef<str>) -> Self {
+        let bucket = bucket.as_ref();
+        Self {
+            files: database.collection(&format!("{}.files", bucket)),
+            chunks: database.collection(&format!("{}.chunks", bucket)),
+            database,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+        }
+    }
+
+    #[inline]
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    pub async fn ensure_indexes(&self) -> Result<()> {
+        self.database
+            .run_command(
+                doc! {
+                    "createIndexes": self.chunks.name(),
+                    "indexes": [{
+                        "key": { "files_id": 1, "n": 1 },
+                        "name": "files_id_1_n_1",
+                        "unique": true,
+                    }],
+                },
+                None,
+            )
+            .await?;
+        self.database
+            .run_command(
+                doc! {
+                    "createIndexes": self.files.name(),
+                    "indexes": [{
+                        "key": { "filename": 1, "uploadDate": 1 },
+                        "name": "filename_1_uploadDate_1",
+                    }, {
+                        "key": { "metadata.sha256": 1 },
+                        "name": "metadata.sha256_1",
+                    }],
+                },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn upload<St>(&self, content_type: impl AsRef<str>, body: St) -> Result<BlobRef>
+    where
+        St: Stream<Item = Result<Vec<u8>>>,
+    {
+        let id = ObjectId::new();
+        let written = self.write_chunks(&id, body).await;
+        let (size, sha256) = match written {
+            Ok(written) => written,
+            Err(err) => {
+                let _ = self
+                    .chunks
+                    .delete_many(doc! { "files_id": id.clone() }, None)
+                    .await;
+                return Err(err);
+            }
+        };
+        let blob = BlobRef {
+            id,
+            content_type: String::from(content_type.as_ref()),
+            size,
+            sha256,
+        };
+        self.files
+            .insert_one(
+                doc! {
+                    "_id": blob.id.clone(),
+                    "length": blob.size,
+                    "chunkSize": self.chunk_size as i64,
+                    "uploadDate": Bson::DateTime(Utc::now()),
+                    "metadata": {
+                        "contentType": blob.content_type.clone(),
+                        "sha256": blob.sha256.clone(),
+                        "refs": 1_i64,
+                    },
+                },
+                None,
+            )
+            .await?;
+        Ok(blob)
+    }
+
+    #[inline]
+    pub async fn upload_bytes(
+        &self,
+        content_type: impl AsRef<str>,
+        data: Vec<u8>,
+    ) -> Result<BlobRef> {
+        self.upload(content_type, stream::once(async { Ok(data) }))
+            .await
+    }
+
+    async fn write_chunks<St>(&self, id: &Id, body: St) -> Result<(i64, String)>
+    where
+        St: Stream<Item = Result<Vec<u8>>>,
+    {
+        futures::pin_mut!(body);
+        let mut hasher = Sha256::new();
+        let mut size = 0;
+        let mut n = 0;
+        let mut buf = Vec::with_capacity(self.chunk_size);
+        while let Some(data) = body.try_next().await? {
+            hasher.update(&data);
+            size += data.len() as i64;
+            buf.extend_from_slice(&data);
+            while buf.len() >= self.chunk_size {
+                let rest = buf.split_off(self.chunk_size);
+                self.insert_chunk(id, n, std::mem::replace(&mut buf, rest))
+                    .await?;
+                n += 1;
+            }
+        }
+        if !buf.is_empty() {
+            self.insert_chunk(id, n, buf).await?;
+        }
+        Ok((size, format!("{:x}", hasher.finalize())))
+    }
+
+    async fn insert_chunk(&self, id: &Id, n: i64, data: Vec<u8>) -> Result<()> {
+        let data = Binary {
+            subtype: BinarySubtype::Generic,
+            bytes: data,
+        };
+        self.chunks
+            .insert_one(
+                doc! { "files_id": id.clone(), "n": n, "data": Bson::Binary(data) },
+                None,
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn head(&self, id: &Id) -> Result<Option<BlobRef>> {
+        match self
+            .files
+            .find_one(doc! { "_id": id.clone() }, None)
+            .await?
+        {
+            Some(doc) => Ok(Some(blob_of(&doc)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn find_by_sha256(&self, sha256: &str) -> Result<Option<BlobRef>> {
+        let option = FindOneOptions::builder()
+            .sort(doc! { "uploadDate": 1 })
+            .build();
+        match self
+            .files
+            .find_one(doc! { "metadata.sha256": sha256 }, option)
+            .await?
+        {
+            Some(doc) => Ok(Some(blob_of(&doc)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn list_sha256(&self, prefix: &str) -> Result<Vec<String>> {
+        let pattern = Regex {
+            pattern: format!("^{}", escape(prefix)),
+            options: String::new(),
+        };
+        let mut hashes = self
+            .files
+            .distinct(
+                "metadata.sha256",
+                doc! { "metadata.sha256": Bson::RegularExpression(pattern) },
+                None,
+            )
+            .await?
+            .into_iter()
+            .filter_map(|hash| match hash {
+                Bson::String(hash) => Some(hash),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        hashes.sort();
+        Ok(hashes)
+    }
+
+    pub async fn download(&self, id: &Id) -> Result<ByteStream> {
+        let file = self
+            .files
+            .find_one(doc! { "_id": id.clone() }, None)
+            .await?
+            .ok_or_else(|| GridFsError::NotFound(id.clone()))?;
+        let length = get_int(&file, "length")?;
+        let chunk_size = get_int(&file, "chunkSize")?.max(1);
+        let expected = (length + chunk_size - 1) / chunk_size;
+        let option = FindOptions::builder().sort(doc! { "n": 1 }).build();
+        let cursor = self
+            .chunks
+            .find(doc! { "files_id": id.clone() }, option)
+            .await?;
+        let id = id.clone();
+        // the end of the chunks is marked with `None`, so that missing
+        // trailing chunks are noticed as well
+        Ok(cursor
+            .map(Some)
+            .chain(stream::once(async { None }))
+            .enumerate()
+            .filter_map(move |(i, chunk)| {
+                let missing = || -> Result<Vec<u8>> {
+                    Err(GridFsError::MissingChunk(id.clone(), i as i64).into())
+                };
+                future::ready(match chunk {
+                    Some(chunk) => {
+                        Some(chunk.map_err(Error::from).and_then(|chunk| {
+                            match get_int(&chunk, "n") {
+                                Ok(n) if n == i as i64 => {
+                                    Ok(chunk.get_binary_generic("data")?.clone())
+                                }
+                                _ => missing(),
+                            }
+                        }))
+                    }
+                    None if (i as i64) < expected => Some(missing()),
+                    None => None,
+                })
+            })
+            .boxed())
+    }
+
+    pub async fn download_bytes(&self, id: &Id) -> Result<Vec<u8>> {
+        let chunks = self.download(id).await?.try_collect::<Vec<_>>().await?;
+        Ok(chunks.concat())
+    }
+
+    // Files shared by content count their holders in `metadata.refs`, starting
+    // with the uploader. A file whose count has dropped to zero is being
+    // removed and cannot be taken again.
+    pub async fn retain(&self, id: &Id) -> Result<bool> {
+        let result = self
+            .files
+            .update_one(
+                doc! { "_id": id.clone(), "metadata.refs": { "$gt": 0 } },
+                doc! { "$inc": { "metadata.refs": 1_i64 } },
+                None,
+            )
+            .await?;
+        Ok(result.matched_count == 1)
+    }
+
+    // Drops one holder of the content and removes the file with the last one.
+    // Files written before `refs` existed are never removed here.
+    pub async fn release_by_sha256(&self, sha256: &str) -> Result<()> {
+        let option = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+        let released = self
+            .files
+            .find_one_and_update(
+                doc! { "metadata.sha256": sha256, "metadata.refs": { "$gt": 0 } },
+                doc! { "$inc": { "metadata.refs": -1_i64 } },
+                option,
+            )
+            .await?;
+        let file = match released {
+            Some(file) if get_int(file.get_document("metadata")?, "refs")? <= 0 => file,
+            _ => return Ok(()),
+        };
+        let id = file.get_object_id("_id")?.clone();
+        let deleted = self
+            .files
+            .delete_one(
+                doc! { "_id": id.clone(), "metadata.refs": { "$lte": 0 } },
+                None,
+            )
+            .await?
+            .deleted_count;
+        if deleted == 1 {
+            self.chunks
+                .delete_many(doc! { "files_id": id }, None)
+                .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn delete(&self, id: &Id) -> Result<()> {
+        let deleted = self
+            .files
+            .delete_one(doc! { "_id": id.clone() }, None)
+            .await?
+            .deleted_count;
+        self.chunks
+            .delete_many(doc! { "files_id": id.clone() }, None)
+            .await?;
+        if deleted == 1 {
+            Ok(())
+        } else {
+            Err(GridFsError::NotFound(id.clone()).into())
+        }
+    }
+}
+
+fn blob_of(doc: &Document) -> Result<BlobRef> {
+    let metadata = doc.get_document("metadata")?;
+    Ok(BlobRef {
+        id: doc.get_object_id("_id")?.clone(),
+        content_type: String::from(metadata.get_str("contentType")?),
+        size: get_int(doc, "length")?,
+        sha256: String::from(metadata.get_str("sha256")?),
+    })
+}
+
+// other drivers may write `length` and `n` as int32
+fn get_int(doc: &Document, key: &str) -> Result<i64> {
+    match doc.get(key) {
+        Some(Bson::Int32(value)) => Ok(*value as i64),
+        _ => Ok(doc.get_i64(key)?),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::test_util::with_mongo;
+
+    const ABC_SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    #[tokio::test]
+    async fn test_upload_and_download() {
+        with_mongo(|ctx| async move {
+            let fs = GridFs::new(ctx.database()).chunk_size(2);
+            fs.ensure_indexes().await?;
+            let body = stream::iter(vec![Ok(b"a".to_vec()), Ok(b"bc".to_vec())]);
+            let blob = fs.upload("text/plain", body).await?;
+            assert_eq!(3, blob.size);
+            assert_eq!(ABC_SHA256, blob.sha256);
+            assert_eq!(
+                2,
+                ctx.database()
+                    .collection("fs.chunks")
+                    .count_documents(None, None)
+                    .await?
+            );
+            assert_eq!(Some(blob.clone()), fs.head(&blob.id).await?);
+            assert_eq!(b"abc".to_vec(), fs.download_bytes(&blob.id).await?);
+
+            // the last chunk is gone
+            let chunks = ctx.database().collection("fs.chunks");
+            let last = chunks
+                .find_one_and_delete(doc! { "files_id": blob.id.clone(), "n": 1_i64 }, None)
+                .await?
+                .unwrap();
+            let err = fs.download_bytes(&blob.id).await.unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<GridFsError>(),
+                Some(GridFsError::MissingChunk(_, 1))
+            ));
+            chunks.insert_one(last, None).await?;
+
+            assert_eq!(Some(blob.clone()), fs.find_by_sha256(ABC_SHA256).await?);
+            assert_eq!(vec![ABC_SHA256], fs.list_sha256("ba78").await?);
+            assert!(fs.list_sha256("0").await?.is_empty());
+
+            fs.delete(&blob.id).await?;
+            assert!(fs.head(&blob.id).await?.is_none());
+            assert!(fs.download(&blob.id).await.is_err());
+            assert_eq!(
+                0,
+                ctx.database()
+                    .collection("fs.chunks")
+                    .count_documents(None, None)
+                    .await?
+            );
+            Ok(())
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_retain_and_release() {
+        with_mongo(|ctx| async move {
+            let fs = GridFs::new(ctx.database());
+            let blob = fs.upload_bytes("text/plain", b"abc".to_vec()).await?;
+            assert!(fs.retain(&blob.id).await?);
+            fs.release_by_sha256(ABC_SHA256).await?;
+            assert!(fs.head(&blob.id).await?.is_some());
+            fs.release_by_sha256(ABC_SHA256).await?;
+            assert!(fs.head(&blob.id).await?.is_none());
+            assert!(!fs.retain(&blob.id).await?);
+            assert_eq!(
+                0,
+                ctx.database()
+                    .collection("fs.chunks")
+                    .count_documents(None, None)
+                    .await?
+            );
+            Ok(())
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_upload_failure_removes_chunks() {
+        with_mongo(|ctx| async move {
+            let fs = GridFs::new(ctx.database()).chunk_size(1);
+            let body = stream::iter(vec![
+                Ok(b"ab".to_vec()),
+                Err(crate::utils::simple_error!("broken body")),
+            ]);
+            assert!(fs.upload("text/plain", body).await.is_err());
+            assert_eq!(
+                0,
+                ctx.database()
+                    .collection("fs.chunks")
+                    .count_documents(None, None)
+                    .await?
+            );
+            assert_eq!(
+                0,
+                ctx.database()
+                    .collection("fs.files")
+                    .count_documents(None, None)
+                    .await?
+            );
+            Ok(())
+        })
+        .await
+        .unwrap()
+    }
+}
@@ -0,0 +1,194 @@
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+use mongodm::bson::{oid::ObjectId, spec::BinarySubtype, Binary, Bson};
+use serde::{de::DeserializeOwned, Serialize};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::utils::result::Result;
+
+#[derive(Debug, Error)]
+pub enum IdError {
+    #[error("invalid id: expected {0}")]
+    InvalidId(&'static str),
+    #[error("document doesn't have _id")]
+    MissingId,
+    #[error("id must be assigned")]
+    NotAssigned,
+}
+
+pub trait IdType: Clone + Debug + Eq + Hash + Serialize + DeserializeOwned + 'static {
+    fn to_bson(&self) -> Bson;
+
+    fn from_bson(bson: Bson) -> Result<Self>;
+}
+
+impl IdType for ObjectId {
+    #[inline]
+    fn to_bson(&self) -> Bson {
+        Bson::ObjectId(self.clone())
+    }
+
+    fn from_bson(bson: Bson) -> Result<Self> {
+        match bson {
+            Bson::ObjectId(oid) => Ok(oid),
+            _ => Err(IdError::InvalidId("ObjectId").into()),
+        }
+    }
+}
+
+impl IdType for String {
+    #[inline]
+    fn to_bson(&self) -> Bson {
+        Bson::String(self.clone())
+    }
+
+    fn from_bson(bson: Bson) -> Result<Self> {
+        match bson {
+            Bson::String(s) => Ok(s),
+            _ => Err(IdError::InvalidId("String").into()),
+        }
+    }
+}
+
+// Stored as a binary of subtype 4, as the drivers of other languages do.
+impl IdType for Uuid {
+    #[inline]
+    fn to_bson(&self) -> Bson {
+        Bson::Binary(Binary {
+            subtype: BinarySubtype::Uuid,
+            bytes: self.as_bytes().to_vec(),
+        })
+    }
+
+    fn from_bson(bson: Bson) -> Result<Self> {
+        match bson {
+            Bson::Binary(Binary {
+                subtype: BinarySubtype::Uuid,
+                bytes,
+            }) => Ok(Uuid::from_slice(&bytes).map_err(|_| IdError::InvalidId("Uuid"))?),
+            _ => Err(IdError::InvalidId("Uuid").into()),
+        }
+    }
+}
+
+pub trait IdGenerator {
+    type Id: IdType;
+
+    fn generate() -> Option<Self::Id>;
+}
+
+pub struct GenerateObjectId {}
+
+impl IdGenerator for GenerateObjectId {
+    type Id = ObjectId;
+
+    #[inline]
+    fn generate() -> Option<ObjectId> {
+        Some(ObjectId::new())
+    }
+}
+
+pub struct GenerateUuid {}
+
+impl IdGenerator for GenerateUuid {
+    type Id = Uuid;
+
+    #[inline]
+    fn generate() -> Option<Uuid> {
+        Some(Uuid::new_v4())
+    }
+}
+
+const SLUG_ALPHABET: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+const SLUG_LENGTH: usize = 16;
+
+pub struct GenerateSlug {}
+
+impl IdGenerator for GenerateSlug {
+    type Id = String;
+
+    fn generate() -> Option<String> {
+        let base = SLUG_ALPHABET.len() as u128;
+        let mut n = Uuid::new_v4().as_u128();
+        Some(
+            (0..SLUG_LENGTH)
+                .map(|_| {
+                    let c = SLUG_ALPHABET[(n % base) as usize];
+                    n /= base;
+                    c as char
+                })
+                .collect(),
+        )
+    }
+}
+
+pub struct AssignedId<I> {
+    p: PhantomData<fn() -> I>,
+}
+
+impl<I> IdGenerator for AssignedId<I>
+where
+    I: IdType,
+{
+    type Id = I;
+
+    #[inline]
+    fn generate() -> Option<I> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_bson_roundtrip() {
+        let oid = ObjectId::new();
+        assert_eq!(oid, ObjectId::from_bson(oid.to_bson()).unwrap());
+        let uuid = Uuid::new_v4();
+        assert_eq!(uuid, Uuid::from_bson(uuid.to_bson()).unwrap());
+        assert_eq!(
+            Bson::Binary(Binary {
+                subtype: BinarySubtype::Uuid,
+                bytes: uuid.as_bytes().to_vec(),
+            }),
+            uuid.to_bson()
+        );
+        let slug = String::from("akari");
+        assert_eq!(slug, String::from_bson(slug.to_bson()).unwrap());
+    }
+
+    #[test]
+    fn test_from_invalid_bson() {
+        let err = ObjectId::from_bson(Bson::String(String::from("akari"))).unwrap_err();
+        assert_eq!("invalid id: expected ObjectId", format!("{}", err));
+        let uuid = Uuid::new_v4();
+        assert!(Uuid::from_bson(Bson::String(uuid.to_hyphenated().to_string())).is_err());
+        assert!(Uuid::from_bson(Bson::Binary(Binary {
+            subtype: BinarySubtype::Generic,
+            bytes: uuid.as_bytes().to_vec(),
+        }))
+        .is_err());
+        assert!(Uuid::from_bson(Bson::Binary(Binary {
+            subtype: BinarySubtype::Uuid,
+            bytes: vec![1, 2, 3],
+        }))
+        .is_err());
+        assert!(String::from_bson(Bson::Int32(1)).is_err());
+    }
+
+    #[test]
+    fn test_generate_slug() {
+        let slug = GenerateSlug::generate().unwrap();
+        assert_eq!(SLUG_LENGTH, slug.len());
+        assert!(slug.bytes().all(|c| SLUG_ALPHABET.contains(&c)));
+        assert_ne!(slug, GenerateSlug::generate().unwrap());
+        assert!(AssignedId::<String>::generate().is_none());
+    }
+}
@@ -0,0 +1,158 @@
+use mongodm::{
+    bson::{doc, Bson},
+    mongo::{
+        error::{BulkWriteError, ErrorKind, WriteFailure},
+        options::{Collation, CollationStrength},
+    },
+    prelude::MongoError,
+    IndexOption,
+};
+use thiserror::Error;
+
+use crate::{
+    softdelete::DELETED_AT,
+    utils::{result::Error, simple_error},
+};
+
+const DUPLICATE_KEY: i32 = 11000;
+
+#[derive(Debug, Error)]
+#[error("not unique: {field}")]
+pub struct NotUnique {
+    pub field: String,
+}
+
+impl NotUnique {
+    pub fn new(field: impl AsRef<str>) -> Self {
+        Self {
+            field: String::from(field.as_ref()),
+        }
+    }
+}
+
+const CASE_INSENSITIVE_LOCALE: &str = "en";
+
+#[inline]
+pub fn case_insensitive() -> IndexOption {
+    IndexOption::Collation(doc! { "locale": CASE_INSENSITIVE_LOCALE, "strength": 2 })
+}
+
+// The same collation as `case_insensitive()`, for queries that have to agree
+// with such an index.
+#[inline]
+pub fn case_insensitive_collation() -> Collation {
+    Collation::builder()
+        .locale(String::from(CASE_INSENSITIVE_LOCALE))
+        .strength(CollationStrength::Secondary)
+        .build()
+}
+
+// Soft-deleted documents stay in the collection, so unique indexes on
+// soft-deletable models must only cover the live ones.
+#[inline]
+pub fn not_deleted() -> IndexOption {
+    IndexOption::PartialFilterExpression(doc! { DELETED_AT: Bson::Null })
+}
+
+#[inline]
+pub fn ttl(seconds: i32) -> IndexOption {
+    IndexOption::ExpireAfterSeconds(seconds)
+}
+
+pub(crate) fn translate_write_error(err: MongoError) -> Error {
+    let duplicated = match err.kind.as_ref() {
+        ErrorKind::WriteError(WriteFailure::WriteError(e)) if e.code == DUPLICATE_KEY => {
+            Some(e.message.clone())
+        }
+        ErrorKind::BulkWriteError(failure) => failure
+            .write_errors
+            .as_ref()
+            .and_then(|errors| errors.iter().find(|e| e.code == DUPLICATE_KEY))
+            .map(|e| e.message.clone()),
+        ErrorKind::CommandError(e) if e.code == DUPLICATE_KEY => Some(e.message.clone()),
+        _ => None,
+    };
+    match duplicated {
+        Some(message) => duplicate_key_error(&message),
+        None => err.into(),
+    }
+}
+
+pub(crate) fn translate_bulk_write_error(err: &BulkWriteError) -> Error {
+    if err.code == DUPLICATE_KEY {
+        duplicate_key_error(&err.message)
+    } else {
+        simple_error!("{}", err.message)
+    }
+}
+
+pub(crate) fn duplicate_key_error(message: &str) -> Error {
+    NotUnique::new(duplicate_key_fields(message).unwrap_or_else(|| String::from("unknown"))).into()
+}
+
+fn duplicate_key_fields(message: &str) -> Option<String> {
+    let start = message.find("dup key: {")? + "dup key: {".len();
+    let mut chars = message[start..].chars().peekable();
+    let mut fields = Vec::new();
+    loop {
+        while chars
+            .peek()
+            .map_or(false, |c| c.is_whitespace() || *c == ',')
+        {
+            chars.next();
+        }
+        match chars.peek() {
+            None | Some('}') => break,
+            _ => {}
+        }
+        let field: String = chars.by_ref().take_while(|c| *c != ':').collect();
+        fields.push(String::from(field.trim()));
+        while chars.peek().map_or(false, |c| c.is_whitespace()) {
+            chars.next();
+        }
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            let mut escaped = false;
+            for c in chars.by_ref() {
+                match (escaped, c) {
+                    (false, '\\') => escaped = true,
+                    (false, '"') => break,
+                    _ => escaped = false,
+                }
+            }
+        } else {
+            while chars.peek().map_or(false, |c| *c != ',' && *c != '}') {
+                chars.next();
+            }
+        }
+    }
+    if fields.is_empty() {
+        None
+    } else {
+        Some(fields.join(","))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_duplicate_key_fields() {
+        let message = r#"E11000 duplicate key error collection: db.User index: name_1 dup key: { name: "akari" }"#;
+        assert_eq!(Some(String::from("name")), duplicate_key_fields(message));
+    }
+
+    #[test]
+    fn test_duplicate_key_fields_with_compound_index() {
+        let message = r#"E11000 duplicate key error collection: db.Unit index: name_1_cu_1 dup key: { name: "a, \"b\": c", cu: 1 }"#;
+        assert_eq!(Some(String::from("name,cu")), duplicate_key_fields(message));
+    }
+
+    #[test]
+    fn test_duplicate_key_fields_without_dup_key() {
+        assert_eq!(None, duplicate_key_fields("E11000 duplicate key error"));
+    }
+}
@@ -1,6 +1,27 @@
+pub mod aggregate;
+pub mod audit;
+pub mod bulk;
 pub mod context;
+pub mod dump;
+pub mod encrypted;
+pub mod extjson;
+pub mod gridfs;
+pub mod id;
+pub mod index;
+pub mod listquery;
+pub mod metadata;
+pub mod migration;
+pub mod model;
+pub mod query;
+pub mod reference;
+pub mod revision;
+pub mod schema;
+pub mod search;
 pub mod service;
+pub mod softdelete;
 pub mod test_util;
+pub mod view;
+pub mod watch;
 pub mod withid;
 pub use mongodb;
 pub use mongodm;
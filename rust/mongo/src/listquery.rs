@@ -0,0 +1,398 @@
+use mongodm::{
+    bson::{Bson, Regex},
+    doc,
+};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{
+    query::{Field, Filter, Query, QueryValue},
+    service::{FindManyArgument, FindPageArgument, PageRequest, PageSort},
+    utils::result::{Result, StdResult},
+};
+
+#[derive(Debug, Error)]
+pub enum ListQueryError {
+    #[error("invalid filter: {0}")]
+    InvalidFilter(String),
+    #[error("unknown field: {0}")]
+    UnknownField(String),
+    #[error("unknown operator: {0}")]
+    UnknownOperator(String),
+    #[error("invalid value for {0}")]
+    InvalidValue(String),
+    #[error("field is not sortable: {0}")]
+    NotSortable(String),
+    #[error("cannot sort pages by multiple fields")]
+    MultipleSort,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilterOp {
+    Eq,
+    Ne,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+    In,
+    Exists,
+    Prefix,
+    Contains,
+}
+
+impl FilterOp {
+    pub fn parse(op: &str) -> Option<Self> {
+        Some(match op {
+            "eq" => Self::Eq,
+            "ne" => Self::Ne,
+            "gt" => Self::Gt,
+            "gte" => Self::Gte,
+            "lt" => Self::Lt,
+            "lte" => Self::Lte,
+            "in" => Self::In,
+            "exists" => Self::Exists,
+            "prefix" => Self::Prefix,
+            "contains" => Self::Contains,
+            _ => return None,
+        })
+    }
+
+    fn apply<M, T>(self, field: Field<M, T>, raw: &str) -> Option<Filter<M>>
+    where
+        T: QueryValue,
+    {
+        let value = &unescape(raw);
+        match self {
+            Self::Eq => T::parse(value).map(|v| field.eq(v)),
+            Self::Ne => T::parse(value).map(|v| field.ne(v)),
+            Self::Gt => T::parse(value).map(|v| field.gt(v)),
+            Self::Gte => T::parse(value).map(|v| field.gte(v)),
+            Self::Lt => T::parse(value).map(|v| field.lt(v)),
+            Self::Lte => T::parse(value).map(|v| field.lte(v)),
+            Self::In => split_escaped(raw, '|')
+                .iter()
+                .map(|v| T::parse(&unescape(v)))
+                .collect::<Option<Vec<_>>>()
+                .map(|v| field.is_in(v)),
+            Self::Exists => value.parse().ok().map(|v| field.exists(v)),
+            Self::Prefix if T::is_text() => Some(regex(field, format!("^{}", escape(value)))),
+            Self::Contains if T::is_text() => Some(regex(field, escape(value))),
+            Self::Prefix | Self::Contains => None,
+        }
+    }
+}
+
+fn regex<M, T>(field: Field<M, T>, pattern: String) -> Filter<M> {
+    Filter::from_document(doc! {
+        field.name(): Bson::RegularExpression(Regex {
+            pattern,
+            options: String::new(),
+        })
+    })
+}
+
+// A `\` in a filter value takes the next character literally, so values can
+// hold the `,` between terms and the `|` between `in` values.
+fn split_escaped(input: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    for (i, c) in input.char_indices() {
+        match (escaped, c) {
+            (false, '\\') => escaped = true,
+            (false, c) if c == separator => {
+                parts.push(&input[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => escaped = false,
+        }
+    }
+    parts.push(&input[start..]);
+    parts
+}
+
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unescaped.extend(chars.next()),
+            _ => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+pub(crate) fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if "\\^$.|?*+()[]{}".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+pub struct FilterableField<M> {
+    name: &'static str,
+    ops: &'static [FilterOp],
+    #[allow(clippy::type_complexity)]
+    build: Box<dyn Fn(FilterOp, &str) -> Option<Filter<M>>>,
+}
+
+impl<M: 'static> FilterableField<M> {
+    pub fn new<T>(field: Field<M, T>, ops: &'static [FilterOp]) -> Self
+    where
+        T: QueryValue + 'static,
+    {
+        Self {
+            name: field.name(),
+            ops,
+            build: Box::new(move |op, value| op.apply(field, value)),
+        }
+    }
+}
+
+pub trait ListConfig: Sized {
+    fn filterable() -> Vec<FilterableField<Self>>;
+
+    fn sortable() -> Vec<&'static str>;
+}
+
+// `filter` is `<field>:<op>:<value>` terms joined by `,`, e.g.
+// `name:prefix:aka,member:in:1|2`, and `sort` field names joined by `,`, each
+// optionally prefixed by `-` for descending order.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ListRequest {
+    #[serde(default)]
+    pub filter: Option<String>,
+    #[serde(default)]
+    pub sort: Option<String>,
+}
+
+impl ListRequest {
+    pub fn new(filter: Option<&str>, sort: Option<&str>) -> Self {
+        Self {
+            filter: filter.map(String::from),
+            sort: sort.map(String::from),
+        }
+    }
+
+    pub fn parse<M: ListConfig>(&self) -> Result<ListQuery<M>> {
+        Ok(ListQuery {
+            filter: match &self.filter {
+                Some(filter) => parse_filter(filter)?,
+                None => Filter::all(),
+            },
+            sort: match &self.sort {
+                Some(sort) => parse_sort::<M>(sort)?,
+                None => Vec::new(),
+            },
+        })
+    }
+}
+
+fn parse_filter<M: ListConfig>(filter: &str) -> Result<Filter<M>> {
+    let fields = M::filterable();
+    let mut filters = Vec::new();
+    for term in split_escaped(filter, ',')
+        .into_iter()
+        .filter(|term| !term.is_empty())
+    {
+        let mut parts = term.splitn(3, ':');
+        let (name, op, value) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(name), Some(op), Some(value)) => (name, op, value),
+            _ => return Err(ListQueryError::InvalidFilter(String::from(term)).into()),
+        };
+        let field = fields
+            .iter()
+            .find(|f| f.name == name)
+            .ok_or_else(|| ListQueryError::UnknownField(String::from(name)))?;
+        let op = FilterOp::parse(op)
+            .filter(|op| field.ops.contains(op))
+            .ok_or_else(|| ListQueryError::UnknownOperator(String::from(op)))?;
+        filters.push(
+            (field.build)(op, value)
+                .ok_or_else(|| ListQueryError::InvalidValue(String::from(name)))?,
+        );
+    }
+    Ok(Filter::all_of(filters))
+}
+
+fn parse_sort<M: ListConfig>(sort: &str) -> Result<Vec<(&'static str, bool)>> {
+    let sortable = M::sortable();
+    Ok(sort
+        .split(',')
+        .filter(|key| !key.is_empty())
+        .map(|key| {
+            let (name, ascending) = match key.strip_prefix('-') {
+                Some(name) => (name, false),
+                None => (key.strip_prefix('+').unwrap_or(key), true),
+            };
+            sortable
+                .iter()
+                .find(|s| **s == name)
+                .map(|s| (*s, ascending))
+                .ok_or_else(|| ListQueryError::NotSortable(String::from(name)))
+        })
+        .collect::<StdResult<Vec<_>, _>>()?)
+}
+
+pub struct ListQuery<M> {
+    pub filter: Filter<M>,
+    pub sort: Vec<(&'static str, bool)>,
+}
+
+impl<M> From<ListQuery<M>> for Query<M> {
+    fn from(list: ListQuery<M>) -> Self {
+        list.sort
+            .into_iter()
+            .fold(Query::filter(list.filter), |query, (name, ascending)| {
+                let field = Field::<M, ()>::new(name);
+                query.sort(if ascending { field.asc() } else { field.desc() })
+            })
+    }
+}
+
+impl<M> From<ListQuery<M>> for FindManyArgument {
+    #[inline]
+    fn from(list: ListQuery<M>) -> Self {
+        Query::from(list).into()
+    }
+}
+
+impl FindManyArgument {
+    // Pages are keyed on a single field, so a list query sorting on more than
+    // one is rejected here.
+    pub fn into_page_argument(self, page: PageRequest) -> Result<FindPageArgument> {
+        let FindManyArgument(query, option) = self;
+        let sort = option.and_then(|option| option.sort).unwrap_or_default();
+        let sort = match sort.iter().collect::<Vec<_>>().as_slice() {
+            [] => PageSort::default(),
+            [(name, Bson::Int32(1))] => PageSort::asc(name),
+            [(name, Bson::Int32(-1))] => PageSort::desc(name),
+            _ => return Err(ListQueryError::MultipleSort.into()),
+        };
+        Ok(FindPageArgument(query, sort, page))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use mongodm::bson::Document;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{model_fields, query::Fields};
+
+    #[allow(dead_code)]
+    struct Unit {
+        name: String,
+        member: i32,
+    }
+
+    model_fields! {
+        UnitFields for Unit {
+            name: String,
+            member: i32,
+        }
+    }
+
+    impl ListConfig for Unit {
+        fn filterable() -> Vec<FilterableField<Self>> {
+            let f = Self::fields();
+            vec![
+                FilterableField::new(f.name, &[FilterOp::Eq, FilterOp::Prefix]),
+                FilterableField::new(f.member, &[FilterOp::Gte, FilterOp::In]),
+            ]
+        }
+
+        fn sortable() -> Vec<&'static str> {
+            vec![Self::fields().name.name()]
+        }
+    }
+
+    fn filter(filter: &str) -> Result<Document> {
+        Ok(ListRequest::new(Some(filter), None)
+            .parse::<Unit>()?
+            .filter
+            .into_document())
+    }
+
+    fn err(filter: &str) -> String {
+        format!("{}", self::filter(filter).unwrap_err())
+    }
+
+    #[test]
+    fn test_parse_filter() {
+        assert_eq!(
+            doc! { "$and": [
+                { "name": Bson::RegularExpression(Regex {
+                    pattern: String::from("^a\\.k"),
+                    options: String::new(),
+                }) },
+                { "member": { "$in": [1, 2] } },
+            ] },
+            filter("name:prefix:a.k,member:in:1|2").unwrap()
+        );
+        assert_eq!(doc! { "name": "a:b" }, filter("name:eq:a:b").unwrap());
+    }
+
+    #[test]
+    fn test_parse_filter_with_escapes() {
+        assert_eq!(
+            doc! { "$and": [{ "name": "a,b" }, { "member": { "$gte": 1 } }] },
+            filter("name:eq:a\\,b,member:gte:1").unwrap()
+        );
+        assert_eq!(doc! { "name": "a\\b" }, filter("name:eq:a\\\\b").unwrap());
+        assert_eq!(vec![r"a\|b", "c"], split_escaped(r"a\|b|c", '|'));
+    }
+
+    #[test]
+    fn test_parse_filter_errors() {
+        assert_eq!("unknown field: pa", err("pa:eq:ako"));
+        assert_eq!("unknown operator: ne", err("name:ne:akari"));
+        assert_eq!("unknown operator: prefix", err("member:prefix:1"));
+        assert_eq!("invalid value for member", err("member:gte:many"));
+        assert_eq!("invalid filter: name", err("name"));
+    }
+
+    #[test]
+    fn test_parse_sort() {
+        let list = ListRequest::new(None, Some("-name"))
+            .parse::<Unit>()
+            .unwrap();
+        assert_eq!(vec![("name", false)], list.sort);
+        let err = ListRequest::new(None, Some("member"))
+            .parse::<Unit>()
+            .err()
+            .unwrap();
+        assert_eq!("field is not sortable: member", format!("{}", err));
+    }
+
+    #[test]
+    fn test_into_page_argument() {
+        let list = ListRequest::new(Some("name:eq:akari"), Some("-name"))
+            .parse::<Unit>()
+            .unwrap();
+        let FindPageArgument(doc, sort, _) = FindManyArgument::from(list)
+            .into_page_argument(PageRequest::default())
+            .unwrap();
+        assert_eq!(doc! { "name": "akari" }, doc);
+        assert_eq!(PageSort::desc("name"), sort);
+    }
+
+    #[test]
+    fn test_into_page_argument_with_multiple_sort() {
+        let f = Unit::fields();
+        let list = Query::filter(Filter::all())
+            .sort(f.name.asc().and(f.member.desc()))
+            .into();
+        let err = FindManyArgument::into_page_argument(list, PageRequest::default())
+            .err()
+            .unwrap();
+        assert_eq!("cannot sort pages by multiple fields", format!("{}", err));
+    }
+}
@@ -0,0 +1,103 @@
+use chrono::Utc;
+use mongodm::{
+    bson::{Bson, DateTime, Document},
+    doc,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{query::Field, withid::Id};
+
+pub const CREATED_AT: &str = "created_at";
+pub const UPDATED_AT: &str = "updated_at";
+pub const CREATED_BY: &str = "created_by";
+pub const UPDATED_BY: &str = "updated_by";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Metadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<DateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_by: Option<Id>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated_by: Option<Id>,
+}
+
+#[inline]
+pub fn created_at_field<M>() -> Field<M, chrono::DateTime<Utc>> {
+    Field::new(CREATED_AT)
+}
+
+#[inline]
+pub fn updated_at_field<M>() -> Field<M, chrono::DateTime<Utc>> {
+    Field::new(UPDATED_AT)
+}
+
+pub trait Timestamped {
+    fn metadata(&self) -> &Metadata;
+
+    #[inline]
+    fn created_at(&self) -> Option<&DateTime> {
+        self.metadata().created_at.as_ref()
+    }
+
+    #[inline]
+    fn updated_at(&self) -> Option<&DateTime> {
+        self.metadata().updated_at.as_ref()
+    }
+
+    #[inline]
+    fn created_by(&self) -> Option<&Id> {
+        self.metadata().created_by.as_ref()
+    }
+
+    #[inline]
+    fn updated_by(&self) -> Option<&Id> {
+        self.metadata().updated_by.as_ref()
+    }
+}
+
+impl Timestamped for Metadata {
+    #[inline]
+    fn metadata(&self) -> &Metadata {
+        self
+    }
+}
+
+fn actor_bson(actor: Option<Id>) -> Bson {
+    match actor {
+        Some(id) => Bson::ObjectId(id),
+        None => Bson::Null,
+    }
+}
+
+pub(crate) fn stamp_on_create(doc: &mut Document, actor: Option<Id>) {
+    let now = Bson::DateTime(Utc::now());
+    doc.insert(CREATED_AT, now.clone());
+    doc.insert(UPDATED_AT, now);
+    doc.insert(CREATED_BY, actor_bson(actor.clone()));
+    doc.insert(UPDATED_BY, actor_bson(actor));
+}
+
+// The creation fields never change once inserted, so they are dropped here
+// and carried over from the stored document by the update.
+pub(crate) fn stamp_on_update(doc: &mut Document, actor: Option<Id>) {
+    doc.remove(CREATED_AT);
+    doc.remove(CREATED_BY);
+    doc.insert(UPDATED_AT, Bson::DateTime(Utc::now()));
+    doc.insert(UPDATED_BY, actor_bson(actor));
+}
+
+// An update pipeline replacing the stored document with `doc` but keeping its
+// `_id` and creation fields, so a field missing from `doc` is removed just as
+// with a plain replace. `$literal` keeps strings starting with `$` from being
+// read as field paths.
+pub(crate) fn replace_on_update(doc: Document) -> Vec<Document> {
+    let kept = doc! {
+        "_id": "$_id",
+        CREATED_AT: format!("${}", CREATED_AT),
+        CREATED_BY: format!("${}", CREATED_BY),
+    };
+    vec![doc! { "$replaceWith": { "$mergeObjects": [kept, { "$literal": doc }] } }]
+}
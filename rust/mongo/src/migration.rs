@@ -0,0 +1,222 @@
+use std::marker::PhantomData;
+
+use async_trait::async_trait;
+use chrono::Utc;
+use futures::TryStreamExt;
+use mongodm::{
+    bson::{Bson, Document},
+    doc,
+    mongo::Database,
+    CollectionConfig,
+};
+
+use crate::{
+    schema::Schema,
+    utils::{result::Result, simple_error},
+};
+
+pub const MIGRATIONS_COLLECTION: &str = "_migrations";
+pub const SCHEMA_VERSION: &str = "_v";
+
+pub type Upcaster = fn(Document) -> Result<Document>;
+
+pub fn schema_version<M: Schema>() -> i32 {
+    M::upcasters().last().map_or(0, |(v, _)| *v)
+}
+
+fn document_version(doc: &Document) -> i32 {
+    match doc.get(SCHEMA_VERSION) {
+        Some(Bson::Int32(v)) => *v,
+        Some(Bson::Int64(v)) => *v as i32,
+        _ => 0,
+    }
+}
+
+pub(crate) fn upcast<M: Schema>(mut doc: Document) -> Result<Document> {
+    let current = document_version(&doc);
+    for (version, upcaster) in M::upcasters().into_iter().filter(|(v, _)| *v > current) {
+        doc = upcaster(doc)?;
+        doc.insert(SCHEMA_VERSION, version);
+    }
+    Ok(doc)
+}
+
+pub(crate) fn stamp_version<M: Schema>(doc: &mut Document) {
+    doc.insert(SCHEMA_VERSION, schema_version::<M>());
+}
+
+#[async_trait(?Send)]
+pub trait Migration {
+    fn name(&self) -> &str;
+
+    async fn apply(&self, db: &Database, dry_run: bool) -> Result<i64>;
+}
+
+pub struct DocumentMigration {
+    name: &'static str,
+    collection: &'static str,
+    filter: Document,
+    transform: Upcaster,
+}
+
+impl DocumentMigration {
+    pub fn new(
+        name: &'static str,
+        collection: &'static str,
+        filter: Document,
+        transform: Upcaster,
+    ) -> Self {
+        Self {
+            name,
+            collection,
+            filter,
+            transform,
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Migration for DocumentMigration {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    async fn apply(&self, db: &Database, dry_run: bool) -> Result<i64> {
+        let coll = db.collection(self.collection);
+        let mut cursor = coll.find(self.filter.clone(), None).await?;
+        let mut affected = 0;
+        while let Some(doc) = cursor.try_next().await? {
+            let id = doc
+                .get("_id")
+                .cloned()
+                .ok_or_else(|| simple_error!("document doesn't have _id"))?;
+            let migrated = (self.transform)(doc.clone())?;
+            if migrated != doc {
+                affected += 1;
+                if !dry_run {
+                    coll.replace_one(doc! { "_id": id }, migrated, None).await?;
+                }
+            }
+        }
+        Ok(affected)
+    }
+}
+
+pub struct UpcastMigration<M: Schema> {
+    name: &'static str,
+    p: PhantomData<fn() -> M>,
+}
+
+impl<M: Schema> UpcastMigration<M> {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            p: PhantomData,
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl<M: Schema> Migration for UpcastMigration<M> {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    async fn apply(&self, db: &Database, dry_run: bool) -> Result<i64> {
+        let filter = doc! {
+            "$or": [
+                { SCHEMA_VERSION: { "$exists": false } },
+                { SCHEMA_VERSION: { "$lt": schema_version::<M>() } },
+            ]
+        };
+        DocumentMigration::new(
+            self.name,
+            M::CollConf::collection_name(),
+            filter,
+            upcast::<M>,
+        )
+        .apply(db, dry_run)
+        .await
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct MigrationReport {
+    pub name: String,
+    pub affected: i64,
+    pub applied: bool,
+}
+
+#[derive(Default)]
+pub struct Migrator {
+    migrations: Vec<Box<dyn Migration>>,
+}
+
+impl Migrator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, migration: impl Migration + 'static) -> Self {
+        self.migrations.push(Box::new(migration));
+        self
+    }
+
+    pub async fn pending(&self, db: &Database) -> Result<Vec<&str>> {
+        let applied = applied_migrations(db).await?;
+        Ok(self
+            .migrations
+            .iter()
+            .map(|m| m.name())
+            .filter(|name| !applied.iter().any(|a| a == name))
+            .collect())
+    }
+
+    pub async fn run(&self, db: &Database, dry_run: bool) -> Result<Vec<MigrationReport>> {
+        let mut names = self.migrations.iter().map(|m| m.name()).collect::<Vec<_>>();
+        names.sort_unstable();
+        if names.windows(2).any(|w| w[0] == w[1]) {
+            return Err(simple_error!("duplicated migration name"));
+        }
+        let applied = applied_migrations(db).await?;
+        let coll = db.collection(MIGRATIONS_COLLECTION);
+        let mut reports = Vec::new();
+        for migration in self.migrations.iter() {
+            let name = migration.name();
+            if applied.iter().any(|a| a == name) {
+                continue;
+            }
+            let affected = migration.apply(db, dry_run).await?;
+            if !dry_run {
+                coll.insert_one(
+                    doc! {
+                        "_id": name,
+                        "applied_at": Bson::DateTime(Utc::now()),
+                        "affected": affected,
+                    },
+                    None,
+                )
+                .await?;
+            }
+            reports.push(MigrationReport {
+                name: String::from(name),
+                affected,
+                applied: !dry_run,
+            });
+        }
+        Ok(reports)
+    }
+}
+
+async fn applied_migrations(db: &Database) -> Result<Vec<String>> {
+    let docs = db
+        .collection(MIGRATIONS_COLLECTION)
+        .find(None, None)
+        .await?
+        .try_collect::<Vec<_>>()
+        .await?;
+    Ok(docs
+        .iter()
+        .filter_map(|doc| doc.get_str("_id").ok().map(String::from))
+        .collect())
+}
@@ -0,0 +1,314 @@
+// `define_model!` writes out the boilerplate of a collection from a single
+// definition:
+//
+//     define_model! {
+//         #[derive(Debug, Clone)]
+//         pub struct Idol {
+//             #[validate(length(max = 10))]
+//             pub name: String [unique, input, output],
+//             pub age: i32 [input, output],
+//             pub admin: bool [],
+//             #[serde(flatten, default)]
+//             pub meta: Metadata,
+//         }
+//         collection: "Idol",
+//         context: Context,
+//         id: GenerateSlug,
+//         config: IdolCfg,
+//         fields: IdolFields,
+//         validator: IdolValidator,
+//         repository: IdolRepository,
+//         input: IdolInput,
+//         output: IdolOutput via IdolView,
+//         indexes: [Index::new("age")],
+//     }
+//
+// The flags after a field pick what it takes part in: `unique` adds a unique
+// index and a uniqueness check to the validator, `input` a field of the input
+// struct and `output` a field of the output struct and of the view it is read
+// through. Every field with a flag list, even an empty one, gets a query field;
+// fields without one are only stored. The fields the input does not carry are
+// filled with `Default::default()` on conversion.
+//
+// Besides the model this generates the `CollectionConfig`, the `Model` impl,
+// the query fields, a `Validator`, a `ValidatedRepositoryWithId` alias, the
+// input struct converting into the model and the output struct converting from
+// both the model and the view. `id` names the `IdGenerator` of the repository
+// and the output's id type, `GenerateObjectId` if left out. `indexes` is
+// optional. The calling crate needs
+// `serde` and `validator`, which the derives refer to.
+
+#[doc(hidden)]
+pub mod __private {
+    pub use async_trait::async_trait;
+    pub use serde;
+
+    pub use crate::{
+        fcomps::{
+            convert::Convertible,
+            service::{FromHookResult, HookResult},
+        },
+        utils::result::Result,
+    };
+}
+
+#[macro_export]
+macro_rules! define_model {
+    (
+        $(#[$attr:meta])*
+        $vis:vis struct $name:ident {
+            $(
+                $(#[$fattr:meta])*
+                $fvis:vis $field:ident : $ty:ty $([$($flag:ident),* $(,)?])?
+            ),* $(,)?
+        }
+        collection: $collection:literal,
+        context: $ctx:ty,
+        $(id: $gen:ty,)?
+        config: $cfg:ident,
+        fields: $fields:ident,
+        validator: $validator:ident,
+        repository: $repo:ident,
+        input: $input:ident,
+        output: $output:ident via $view:ident,
+        $(indexes: [$($index:expr),* $(,)?],)?
+    ) => {
+        $(#[$attr])*
+        #[derive(
+            $crate::model::__private::serde::Serialize,
+            $crate::model::__private::serde::Deserialize,
+            $crate::validator::Validate,
+        )]
+        $vis struct $name {
+            $(
+                $(#[$fattr])*
+                $fvis $field: $ty,
+            )*
+        }
+
+        $crate::__define_model! {
+            @split
+            [
+                $vis $name $collection $ctx, $cfg $fields $validator $repo $input $output $view
+                [$($gen)?] [$($($index),*)?]
+            ]
+            [] [] [] [] [] ;
+            $($field : $ty $([$($flag)*])? ;)*
+        }
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __define_model {
+    // The id generator, `GenerateObjectId` unless given.
+    (@id) => { $crate::id::GenerateObjectId };
+    (@id $gen:ty) => { $gen };
+    // Sorts the fields into the query fields, unique fields, input fields,
+    // output fields and fields the input leaves to `Default::default()`.
+    (@split $h:tt $f:tt $u:tt $i:tt $o:tt $r:tt ;) => {
+        $crate::__define_model! { @emit $h $f $u $i $o $r }
+    };
+    (@split $h:tt $f:tt $u:tt $i:tt $o:tt $r:tt ;
+        $field:ident : $ty:ty [$($flag:ident)*] ; $($tail:tt)*
+    ) => {
+        $crate::__define_model! {
+            @flags $h $f $u $i $o $r ($field : $ty) no [$($flag)*] ; $($tail)*
+        }
+    };
+    (@split $h:tt $f:tt $u:tt $i:tt $o:tt [$($r:tt)*] ;
+        $field:ident : $ty:ty ; $($tail:tt)*
+    ) => {
+        $crate::__define_model! { @split $h $f $u $i $o [$($r)* $field] ; $($tail)* }
+    };
+    (@flags $h:tt [$($f:tt)*] $u:tt $i:tt $o:tt $r:tt
+        ($field:ident : $ty:ty) yes [] ; $($tail:tt)*
+    ) => {
+        $crate::__define_model! { @split $h [$($f)* ($field : $ty)] $u $i $o $r ; $($tail)* }
+    };
+    (@flags $h:tt [$($f:tt)*] $u:tt $i:tt $o:tt [$($r:tt)*]
+        ($field:ident : $ty:ty) no [] ; $($tail:tt)*
+    ) => {
+        $crate::__define_model! {
+            @split $h [$($f)* ($field : $ty)] $u $i $o [$($r)* $field] ; $($tail)*
+        }
+    };
+    (@flags $h:tt $f:tt [$($u:tt)*] $i:tt $o:tt $r:tt
+        ($field:ident : $ty:ty) $input:ident [unique $($flag:ident)*] ; $($tail:tt)*
+    ) => {
+        $crate::__define_model! {
+            @flags $h $f [$($u)* $field] $i $o $r ($field : $ty) $input [$($flag)*] ; $($tail)*
+        }
+    };
+    (@flags $h:tt $f:tt $u:tt [$($i:tt)*] $o:tt $r:tt
+        ($field:ident : $ty:ty) $input:ident [input $($flag:ident)*] ; $($tail:tt)*
+    ) => {
+        $crate::__define_model! {
+            @flags $h $f $u [$($i)* ($field : $ty)] $o $r ($field : $ty) yes [$($flag)*] ;
+            $($tail)*
+        }
+    };
+    (@flags $h:tt $f:tt $u:tt $i:tt [$($o:tt)*] $r:tt
+        ($field:ident : $ty:ty) $input:ident [output $($flag:ident)*] ; $($tail:tt)*
+    ) => {
+        $crate::__define_model! {
+            @flags $h $f $u $i [$($o)* ($field : $ty)] $r ($field : $ty) $input [$($flag)*] ;
+            $($tail)*
+        }
+    };
+    (@emit
+        [
+            $vis:vis $name:ident $collection:literal $ctx:ty, $cfg:ident $fields:ident
+            $validator:ident $repo:ident $input:ident $output:ident $view:ident
+            [$($gen:ty)?] [$($index:expr),*]
+        ]
+        [$(($field:ident : $fty:ty))*]
+        [$($unique:ident)*]
+        [$(($in:ident : $inty:ty))*]
+        [$(($out:ident : $outty:ty))*]
+        [$($rest:ident)*]
+    ) => {
+        $vis struct $cfg {}
+
+        impl $crate::mongodm::CollectionConfig for $cfg {
+            fn collection_name() -> &'static str {
+                $collection
+            }
+
+            fn indexes() -> $crate::mongodm::Indexes {
+                $crate::mongodm::Indexes::new()
+                    $(.with(
+                        $crate::mongodm::Index::new(stringify!($unique))
+                            .with_option($crate::mongodm::IndexOption::Unique)
+                    ))*
+                    $(.with($index))*
+            }
+        }
+
+        impl $crate::mongodm::Model for $name {
+            type CollConf = $cfg;
+        }
+
+        impl $crate::schema::Schema for $name {}
+
+        $crate::model_fields! {
+            $vis $fields for $name {
+                $($field: $fty),*
+            }
+        }
+
+        $vis struct $validator {}
+
+        #[$crate::model::__private::async_trait(?Send)]
+        impl $crate::withid::Validator for $validator {
+            type Model = $name;
+            type Ctx = $ctx;
+
+            #[allow(unused_variables)]
+            async fn validate(
+                cu: $crate::withid::CreateOrUpdate,
+                model: &'_ $name,
+                ctx: &'_ $ctx,
+            ) -> $crate::model::__private::Result<()> {
+                $(
+                    $crate::withid::_validate_uniqueness(
+                        stringify!($unique),
+                        $crate::mongodm::doc! { stringify!($unique): &model.$unique },
+                        |id| {
+                            $crate::mongodm::doc! {
+                                "_id": { "$ne": id },
+                                stringify!($unique): &model.$unique,
+                            }
+                        },
+                        None,
+                        &$crate::context::MongodmContext::repo::<$name>(ctx),
+                        cu.clone(),
+                    )
+                    .await?;
+                )*
+                Ok(())
+            }
+        }
+
+        $vis type $repo = $crate::withid::ValidatedRepositoryWithId<
+            $name,
+            $ctx,
+            $validator,
+            $crate::softdelete::HardDelete,
+            $crate::__define_model!(@id $($gen)?),
+        >;
+
+        #[derive($crate::model::__private::serde::Deserialize, Debug)]
+        $vis struct $input {
+            $(pub $in: $inty,)*
+        }
+
+        impl $crate::model::__private::Convertible<$name> for $input {
+            fn convert(self) -> $crate::model::__private::Result<$name> {
+                Ok($name {
+                    $($in: self.$in,)*
+                    $($rest: Default::default(),)*
+                })
+            }
+        }
+
+        impl<H> $crate::model::__private::FromHookResult<H, $input> for $name
+        where
+            H: $crate::model::__private::HookResult,
+        {
+            #[inline]
+            fn from_hook_result(_: H, input: $input) -> $crate::model::__private::Result<$name> {
+                $crate::model::__private::Convertible::convert(input)
+            }
+        }
+
+        #[derive($crate::model::__private::serde::Deserialize)]
+        $vis struct $view {
+            $(pub $out: $outty,)*
+        }
+
+        impl $crate::view::View for $view {
+            type Model = $name;
+
+            fn projection() -> Option<$crate::query::Projection<$name>> {
+                #[allow(unused_variables)]
+                let f = <$name as $crate::query::Fields>::fields();
+                Some($crate::query::Projection::new()$(.include(f.$out))*)
+            }
+        }
+
+        #[derive($crate::model::__private::serde::Serialize, Debug)]
+        $vis struct $output {
+            pub id: <$crate::__define_model!(@id $($gen)?) as $crate::id::IdGenerator>::Id,
+            $(pub $out: $outty,)*
+        }
+
+        impl $crate::model::__private::Convertible<$output>
+            for $crate::withid::WithId<
+                $view,
+                <$crate::__define_model!(@id $($gen)?) as $crate::id::IdGenerator>::Id,
+            >
+        {
+            fn convert(self) -> $crate::model::__private::Result<$output> {
+                Ok($output {
+                    id: self.0,
+                    $($out: self.1.$out,)*
+                })
+            }
+        }
+
+        impl $crate::model::__private::Convertible<$output>
+            for $crate::withid::WithId<
+                $name,
+                <$crate::__define_model!(@id $($gen)?) as $crate::id::IdGenerator>::Id,
+            >
+        {
+            fn convert(self) -> $crate::model::__private::Result<$output> {
+                Ok($output {
+                    id: self.0,
+                    $($out: self.1.$out,)*
+                })
+            }
+        }
+    };
+}
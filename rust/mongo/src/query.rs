@@ -0,0 +1,629 @@
+use std::marker::PhantomData;
+use std::ops::{Bound, RangeBounds};
+
+use chrono::{DateTime, Utc};
+use mongodm::{
+    bson::{oid::ObjectId, Bson, Document, Regex},
+    doc,
+    mongo::options::FindOptions,
+};
+
+use crate::service::{FindManyArgument, FindOneArgument};
+
+pub struct Field<M, T> {
+    name: &'static str,
+    p: PhantomData<fn() -> (M, T)>,
+}
+
+impl<M, T> Clone for Field<M, T> {
+    #[inline]
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<M, T> Copy for Field<M, T> {}
+
+impl<M, T> Field<M, T> {
+    #[inline]
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            p: PhantomData,
+        }
+    }
+
+    #[inline]
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    #[inline]
+    pub fn exists(&self, exists: bool) -> Filter<M> {
+        self.op("$exists", Bson::Boolean(exists))
+    }
+
+    #[inline]
+    pub fn asc(&self) -> Sort<M> {
+        Sort::new().then(self.name, 1)
+    }
+
+    #[inline]
+    pub fn desc(&self) -> Sort<M> {
+        Sort::new().then(self.name, -1)
+    }
+
+    fn op(&self, op: &str, value: Bson) -> Filter<M> {
+        Filter::from_document(doc! { self.name: { op: value } })
+    }
+}
+
+impl<M, T> Field<M, T>
+where
+    T: Into<Bson>,
+{
+    #[inline]
+    pub fn eq(&self, value: impl Into<T>) -> Filter<M> {
+        Filter::from_document(doc! { self.name: value.into().into() })
+    }
+
+    #[inline]
+    pub fn ne(&self, value: impl Into<T>) -> Filter<M> {
+        self.op("$ne", value.into().into())
+    }
+
+    #[inline]
+    pub fn gt(&self, value: impl Into<T>) -> Filter<M> {
+        self.op("$gt", value.into().into())
+    }
+
+    #[inline]
+    pub fn gte(&self, value: impl Into<T>) -> Filter<M> {
+        self.op("$gte", value.into().into())
+    }
+
+    #[inline]
+    pub fn lt(&self, value: impl Into<T>) -> Filter<M> {
+        self.op("$lt", value.into().into())
+    }
+
+    #[inline]
+    pub fn lte(&self, value: impl Into<T>) -> Filter<M> {
+        self.op("$lte", value.into().into())
+    }
+
+    pub fn is_in<V: Into<T>>(&self, values: impl IntoIterator<Item = V>) -> Filter<M> {
+        self.op("$in", values_to_bson(values))
+    }
+
+    pub fn not_in<V: Into<T>>(&self, values: impl IntoIterator<Item = V>) -> Filter<M> {
+        self.op("$nin", values_to_bson(values))
+    }
+
+    pub fn range(&self, range: impl RangeBounds<T>) -> Filter<M>
+    where
+        T: Clone,
+    {
+        let mut cond = Document::new();
+        match range.start_bound() {
+            Bound::Included(v) => cond.insert("$gte", v.clone().into()),
+            Bound::Excluded(v) => cond.insert("$gt", v.clone().into()),
+            Bound::Unbounded => None,
+        };
+        match range.end_bound() {
+            Bound::Included(v) => cond.insert("$lte", v.clone().into()),
+            Bound::Excluded(v) => cond.insert("$lt", v.clone().into()),
+            Bound::Unbounded => None,
+        };
+        if cond.is_empty() {
+            Filter::all()
+        } else {
+            Filter::from_document(doc! { self.name: cond })
+        }
+    }
+}
+
+impl<M> Field<M, String> {
+    pub fn regex(&self, pattern: impl AsRef<str>, options: impl AsRef<str>) -> Filter<M> {
+        Filter::from_document(doc! {
+            self.name: Bson::RegularExpression(Regex {
+                pattern: String::from(pattern.as_ref()),
+                options: String::from(options.as_ref()),
+            })
+        })
+    }
+}
+
+fn values_to_bson<T, V>(values: impl IntoIterator<Item = V>) -> Bson
+where
+    T: Into<Bson>,
+    V: Into<T>,
+{
+    Bson::Array(values.into_iter().map(|v| v.into().into()).collect())
+}
+
+pub struct Filter<M> {
+    doc: Document,
+    p: PhantomData<fn() -> M>,
+}
+
+impl<M> Clone for Filter<M> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self::from_document(self.doc.clone())
+    }
+}
+
+impl<M> Filter<M> {
+    #[inline]
+    pub(crate) fn from_document(doc: Document) -> Self {
+        Self {
+            doc,
+            p: PhantomData,
+        }
+    }
+
+    #[inline]
+    pub fn all() -> Self {
+        Self::from_document(Document::new())
+    }
+
+    #[inline]
+    pub fn and(self, other: Filter<M>) -> Self {
+        Self::all_of(vec![self, other])
+    }
+
+    #[inline]
+    pub fn or(self, other: Filter<M>) -> Self {
+        Self::any_of(vec![self, other])
+    }
+
+    pub fn all_of(filters: Vec<Filter<M>>) -> Self {
+        let docs = filters
+            .into_iter()
+            .map(|f| f.doc)
+            .filter(|doc| !doc.is_empty())
+            .collect::<Vec<_>>();
+        match docs.len() {
+            0 => Self::all(),
+            1 => Self::from_document(docs.into_iter().next().unwrap()),
+            _ => Self::from_document(doc! { "$and": docs }),
+        }
+    }
+
+    pub fn any_of(filters: Vec<Filter<M>>) -> Self {
+        let docs = filters.into_iter().map(|f| f.doc).collect::<Vec<_>>();
+        if docs.iter().any(|doc| doc.is_empty()) {
+            return Self::all();
+        }
+        match docs.len() {
+            0 => Self::all(),
+            1 => Self::from_document(docs.into_iter().next().unwrap()),
+            _ => Self::from_document(doc! { "$or": docs }),
+        }
+    }
+
+    #[inline]
+    pub fn into_document(self) -> Document {
+        self.doc
+    }
+}
+
+pub struct Sort<M> {
+    doc: Document,
+    p: PhantomData<fn() -> M>,
+}
+
+impl<M> Sort<M> {
+    #[inline]
+    fn new() -> Self {
+        Self {
+            doc: Document::new(),
+            p: PhantomData,
+        }
+    }
+
+    fn then(mut self, name: &str, direction: i32) -> Self {
+        self.doc.insert(name, direction);
+        self
+    }
+
+    #[inline]
+    pub fn and(mut self, other: Sort<M>) -> Self {
+        for (key, value) in other.doc {
+            self.doc.insert(key, value);
+        }
+        self
+    }
+
+    #[inline]
+    pub fn into_document(self) -> Document {
+        self.doc
+    }
+}
+
+pub struct Projection<M> {
+    doc: Document,
+    p: PhantomData<fn() -> M>,
+}
+
+impl<M> Default for Projection<M> {
+    #[inline]
+    fn default() -> Self {
+        Self {
+            doc: Document::new(),
+            p: PhantomData,
+        }
+    }
+}
+
+impl<M> Projection<M> {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[inline]
+    pub fn include<T>(mut self, field: Field<M, T>) -> Self {
+        self.doc.insert(field.name, 1);
+        self
+    }
+
+    #[inline]
+    pub fn exclude<T>(mut self, field: Field<M, T>) -> Self {
+        self.doc.insert(field.name, 0);
+        self
+    }
+
+    #[inline]
+    pub fn into_document(self) -> Document {
+        self.doc
+    }
+}
+
+pub struct Query<M> {
+    filter: Filter<M>,
+    sort: Option<Sort<M>>,
+    projection: Option<Projection<M>>,
+    skip: Option<i64>,
+    limit: Option<i64>,
+}
+
+impl<M> Default for Query<M> {
+    #[inline]
+    fn default() -> Self {
+        Self::filter(Filter::all())
+    }
+}
+
+impl<M> Query<M> {
+    #[inline]
+    pub fn filter(filter: Filter<M>) -> Self {
+        Self {
+            filter,
+            sort: None,
+            projection: None,
+            skip: None,
+            limit: None,
+        }
+    }
+
+    #[inline]
+    pub fn sort(mut self, sort: Sort<M>) -> Self {
+        self.sort = Some(match self.sort {
+            Some(current) => current.and(sort),
+            None => sort,
+        });
+        self
+    }
+
+    #[inline]
+    pub fn projection(mut self, projection: Projection<M>) -> Self {
+        self.projection = Some(projection);
+        self
+    }
+
+    #[inline]
+    pub fn skip(mut self, skip: i64) -> Self {
+        self.skip = Some(skip);
+        self
+    }
+
+    #[inline]
+    pub fn limit(mut self, limit: i64) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn build(self) -> (Document, Option<FindOptions>) {
+        let option = if self.sort.is_none()
+            && self.projection.is_none()
+            && self.skip.is_none()
+            && self.limit.is_none()
+        {
+            None
+        } else {
+            let mut option = FindOptions::default();
+            option.sort = self.sort.map(Sort::into_document);
+            option.projection = self.projection.map(Projection::into_document);
+            option.skip = self.skip;
+            option.limit = self.limit;
+            Some(option)
+        };
+        (self.filter.into_document(), option)
+    }
+}
+
+impl<M> From<Filter<M>> for Query<M> {
+    #[inline]
+    fn from(filter: Filter<M>) -> Self {
+        Self::filter(filter)
+    }
+}
+
+impl<M> From<Query<M>> for FindOneArgument {
+    #[inline]
+    fn from(query: Query<M>) -> Self {
+        let (doc, option) = query.build();
+        FindOneArgument(doc, option)
+    }
+}
+
+impl<M> From<Filter<M>> for FindOneArgument {
+    #[inline]
+    fn from(filter: Filter<M>) -> Self {
+        Query::from(filter).into()
+    }
+}
+
+impl<M> From<Query<M>> for FindManyArgument {
+    #[inline]
+    fn from(query: Query<M>) -> Self {
+        let (doc, option) = query.build();
+        FindManyArgument(doc, option)
+    }
+}
+
+impl<M> From<Filter<M>> for FindManyArgument {
+    #[inline]
+    fn from(filter: Filter<M>) -> Self {
+        Query::from(filter).into()
+    }
+}
+
+pub trait QueryValue: Into<Bson> + Sized {
+    fn parse(value: &str) -> Option<Self>;
+
+    #[inline]
+    fn is_text() -> bool {
+        false
+    }
+}
+
+impl QueryValue for String {
+    #[inline]
+    fn parse(value: &str) -> Option<Self> {
+        Some(String::from(value))
+    }
+
+    #[inline]
+    fn is_text() -> bool {
+        true
+    }
+}
+
+impl QueryValue for bool {
+    #[inline]
+    fn parse(value: &str) -> Option<Self> {
+        value.parse().ok()
+    }
+}
+
+impl QueryValue for i32 {
+    #[inline]
+    fn parse(value: &str) -> Option<Self> {
+        value.parse().ok()
+    }
+}
+
+impl QueryValue for i64 {
+    #[inline]
+    fn parse(value: &str) -> Option<Self> {
+        value.parse().ok()
+    }
+}
+
+impl QueryValue for f64 {
+    #[inline]
+    fn parse(value: &str) -> Option<Self> {
+        value.parse().ok()
+    }
+}
+
+impl QueryValue for ObjectId {
+    #[inline]
+    fn parse(value: &str) -> Option<Self> {
+        ObjectId::with_string(value).ok()
+    }
+}
+
+impl QueryValue for DateTime<Utc> {
+    #[inline]
+    fn parse(value: &str) -> Option<Self> {
+        DateTime::parse_from_rfc3339(value)
+            .ok()
+            .map(|d| d.with_timezone(&Utc))
+    }
+}
+
+pub trait Fields {
+    type Fields;
+
+    fn fields() -> Self::Fields;
+}
+
+#[inline]
+pub fn id_field<M, I>() -> Field<M, I> {
+    Field::new("_id")
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __field_key {
+    ($field:ident) => {
+        stringify!($field)
+    };
+    ($field:ident $key:literal) => {
+        $key
+    };
+}
+
+#[macro_export]
+macro_rules! model_fields {
+    ($vis:vis $name:ident for $model:ty {
+        $($field:ident $(as $key:literal)? : $ty:ty),* $(,)?
+    }) => {
+        $crate::model_fields! {
+            $vis $name for $model, id: $crate::withid::Id {
+                $($field $(as $key)? : $ty),*
+            }
+        }
+    };
+    ($vis:vis $name:ident for $model:ty, id: $id:ty {
+        $($field:ident $(as $key:literal)? : $ty:ty),* $(,)?
+    }) => {
+        #[derive(Clone, Copy)]
+        $vis struct $name {
+            pub id: $crate::query::Field<$model, $id>,
+            $(pub $field: $crate::query::Field<$model, $ty>,)*
+        }
+
+        impl $crate::query::Fields for $model {
+            type Fields = $name;
+
+            #[inline]
+            fn fields() -> $name {
+                $name {
+                    id: $crate::query::id_field(),
+                    $($field: $crate::query::Field::new($crate::__field_key!($field $($key)?)),)*
+                }
+            }
+        }
+
+        impl $name {
+            #[allow(dead_code)]
+            fn __check_fields(model: &$model) {
+                $(let _: &$ty = &model.$field;)*
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[allow(dead_code)]
+    struct Unit {
+        name: String,
+        member: i32,
+        leader: Option<String>,
+    }
+
+    model_fields! {
+        UnitFields for Unit {
+            name: String,
+            member as "member_count": i32,
+            leader: Option<String>,
+        }
+    }
+
+    #[test]
+    fn test_eq() {
+        let f = Unit::fields();
+        assert_eq!(doc! { "name": "nw" }, f.name.eq("nw").into_document());
+        assert_eq!(
+            doc! { "member_count": { "$ne": 3 } },
+            f.member.ne(3).into_document()
+        );
+    }
+
+    #[test]
+    fn test_in() {
+        let f = Unit::fields();
+        assert_eq!(
+            doc! { "name": { "$in": ["nw", "ng"] } },
+            f.name.is_in(vec!["nw", "ng"]).into_document()
+        );
+    }
+
+    #[test]
+    fn test_range() {
+        let f = Unit::fields();
+        assert_eq!(
+            doc! { "member_count": { "$gte": 2, "$lt": 5 } },
+            f.member.range(2..5).into_document()
+        );
+        assert_eq!(
+            doc! { "member_count": { "$lte": 5 } },
+            f.member.range(..=5).into_document()
+        );
+        assert_eq!(doc! {}, f.member.range(..).into_document());
+    }
+
+    #[test]
+    fn test_regex_and_exists() {
+        let f = Unit::fields();
+        assert_eq!(
+            doc! { "name": Bson::RegularExpression(Regex {
+                pattern: String::from("^n"),
+                options: String::from("i"),
+            }) },
+            f.name.regex("^n", "i").into_document()
+        );
+        assert_eq!(
+            doc! { "leader": { "$exists": true } },
+            f.leader.exists(true).into_document()
+        );
+    }
+
+    #[test]
+    fn test_and_or() {
+        let f = Unit::fields();
+        let filter = f
+            .name
+            .eq("nw")
+            .and(f.member.gt(2).or(f.leader.eq(None::<String>)));
+        assert_eq!(
+            doc! { "$and": [
+                { "name": "nw" },
+                { "$or": [{ "member_count": { "$gt": 2 } }, { "leader": Bson::Null }] },
+            ] },
+            filter.into_document()
+        );
+        assert_eq!(
+            doc! { "name": "nw" },
+            Filter::all().and(f.name.eq("nw")).into_document()
+        );
+        assert_eq!(doc! {}, Filter::all().or(f.name.eq("nw")).into_document());
+    }
+
+    #[test]
+    fn test_query_build() {
+        let f = Unit::fields();
+        let (doc, option) = Query::filter(f.name.eq("nw"))
+            .sort(f.member.desc())
+            .sort(f.id.asc())
+            .projection(Projection::new().include(f.name))
+            .limit(10)
+            .build();
+        let option = option.unwrap();
+        assert_eq!(doc! { "name": "nw" }, doc);
+        assert_eq!(Some(doc! { "member_count": -1, "_id": 1 }), option.sort);
+        assert_eq!(Some(doc! { "name": 1 }), option.projection);
+        assert_eq!(Some(10), option.limit);
+        assert!(Query::<Unit>::default().build().1.is_none());
+    }
+}
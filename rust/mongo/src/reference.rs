@@ -0,0 +1,285 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+
+use futures::{Stream, TryStreamExt};
+use mongodm::{bson::Bson, doc, CollectionConfig, Model};
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error;
+
+use crate::{
+    context::MongodmContext,
+    id::IdType,
+    query::QueryValue,
+    service::Page,
+    softdelete::not_deleted,
+    utils::result::Result,
+    withid::{Id, RepositoryWithId, WithId},
+};
+
+#[derive(Debug, Error)]
+#[error("referenced {collection} does not exist: {id}")]
+pub struct MissingRef {
+    pub collection: &'static str,
+    pub id: Bson,
+}
+
+pub struct Ref<M, I = Id> {
+    id: I,
+    p: PhantomData<fn() -> M>,
+}
+
+impl<M, I> Ref<M, I> {
+    #[inline]
+    pub fn new(id: I) -> Self {
+        Self { id, p: PhantomData }
+    }
+
+    #[inline]
+    pub fn id(&self) -> &I {
+        &self.id
+    }
+
+    #[inline]
+    pub fn into_id(self) -> I {
+        self.id
+    }
+}
+
+impl<M, I: Clone> Clone for Ref<M, I> {
+    #[inline]
+    fn clone(&self) -> Self {
+        Self::new(self.id.clone())
+    }
+}
+
+impl<M, I: fmt::Debug> fmt::Debug for Ref<M, I> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Ref").field(&self.id).finish()
+    }
+}
+
+impl<M, I: PartialEq> PartialEq for Ref<M, I> {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl<M, I: Eq> Eq for Ref<M, I> {}
+
+impl<M, I: Hash> Hash for Ref<M, I> {
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state)
+    }
+}
+
+impl<M, I: Clone> From<&WithId<M, I>> for Ref<M, I> {
+    #[inline]
+    fn from(item: &WithId<M, I>) -> Self {
+        Self::new(item.0.clone())
+    }
+}
+
+impl<M, I: IdType> From<Ref<M, I>> for Bson {
+    #[inline]
+    fn from(r: Ref<M, I>) -> Self {
+        r.id.to_bson()
+    }
+}
+
+impl<M, I> QueryValue for Ref<M, I>
+where
+    I: IdType + QueryValue,
+{
+    #[inline]
+    fn parse(value: &str) -> Option<Self> {
+        I::parse(value).map(Self::new)
+    }
+}
+
+// A ref is stored the same way as the `_id` it points to, so that joins and
+// queries on it match.
+impl<M, I: IdType> Serialize for Ref<M, I> {
+    #[inline]
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        self.id.to_bson().serialize(serializer)
+    }
+}
+
+impl<'de, M, I: IdType> Deserialize<'de> for Ref<M, I> {
+    #[inline]
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let bson = Bson::deserialize(deserializer)?;
+        Ok(Self::new(I::from_bson(bson).map_err(D::Error::custom)?))
+    }
+}
+
+pub trait Refs<M, I = Id> {
+    fn refs(&self) -> Vec<&Ref<M, I>>;
+}
+
+impl<M, I> Refs<M, I> for Ref<M, I> {
+    #[inline]
+    fn refs(&self) -> Vec<&Ref<M, I>> {
+        vec![self]
+    }
+}
+
+impl<M, I, J, T> Refs<M, I> for WithId<T, J>
+where
+    T: Refs<M, I>,
+{
+    #[inline]
+    fn refs(&self) -> Vec<&Ref<M, I>> {
+        self.1.refs()
+    }
+}
+
+pub struct Loader<Repo>
+where
+    Repo: RepositoryWithId,
+{
+    repo: Repo,
+    loaded: HashMap<Repo::Id, WithId<Repo::Model, Repo::Id>>,
+}
+
+impl<Repo> Loader<Repo>
+where
+    Repo: RepositoryWithId,
+{
+    pub async fn new(ctx: &Repo::Ctx) -> Self {
+        Self {
+            repo: Repo::new(ctx).await,
+            loaded: HashMap::new(),
+        }
+    }
+
+    pub async fn load<'a, I>(&mut self, refs: I) -> Result<()>
+    where
+        I: IntoIterator<Item = &'a Ref<Repo::Model, Repo::Id>>,
+        Repo::Model: 'a,
+    {
+        let ids = refs
+            .into_iter()
+            .map(|r| r.id.clone())
+            .filter(|id| !self.loaded.contains_key(id))
+            .collect::<HashSet<_>>();
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let ids = ids.iter().map(IdType::to_bson).collect::<Vec<_>>();
+        let mut cursor = self
+            .repo
+            .find_many(doc! { "_id": { "$in": ids } }, None)
+            .await?;
+        while let Some(item) = cursor.try_next().await? {
+            self.loaded.insert(item.0.clone(), item);
+        }
+        Ok(())
+    }
+
+    pub async fn load_items<T>(&mut self, items: &[T]) -> Result<()>
+    where
+        T: Refs<Repo::Model, Repo::Id>,
+    {
+        self.load(items.iter().flat_map(Refs::refs)).await
+    }
+
+    #[inline]
+    pub async fn load_page<T>(&mut self, page: &Page<T>) -> Result<()>
+    where
+        T: Refs<Repo::Model, Repo::Id>,
+    {
+        self.load_items(&page.items).await
+    }
+
+    pub async fn load_stream<T, St>(&mut self, stream: St) -> Result<Vec<T>>
+    where
+        T: Refs<Repo::Model, Repo::Id>,
+        St: Stream<Item = Result<T>>,
+    {
+        let items = stream.try_collect::<Vec<_>>().await?;
+        self.load_items(&items).await?;
+        Ok(items)
+    }
+
+    #[inline]
+    pub fn get(&self, r: &Ref<Repo::Model, Repo::Id>) -> Option<&WithId<Repo::Model, Repo::Id>> {
+        self.loaded.get(&r.id)
+    }
+}
+
+pub async fn ensure_refs<'a, M, I, Ctx, R>(refs: R, ctx: &Ctx) -> Result<()>
+where
+    M: Model + 'a,
+    I: IdType,
+    Ctx: MongodmContext,
+    R: IntoIterator<Item = &'a Ref<M, I>>,
+{
+    let ids = refs.into_iter().map(|r| r.id.to_bson()).collect::<Vec<_>>();
+    if ids.is_empty() {
+        return Ok(());
+    }
+    let found = ctx
+        .repo::<M>()
+        .get_underlying()
+        .distinct(
+            "_id",
+            not_deleted(doc! { "_id": { "$in": ids.clone() } }),
+            None,
+        )
+        .await?;
+    match ids.into_iter().find(|id| !found.contains(id)) {
+        Some(id) => Err(MissingRef {
+            collection: M::CollConf::collection_name(),
+            id,
+        }
+        .into()),
+        None => Ok(()),
+    }
+}
+
+#[macro_export]
+macro_rules! validate_refs {
+    (<$modeltype:ty>, $refs:expr, $ctx:expr) => {
+        $crate::reference::ensure_refs::<$modeltype, _, _, _>($refs, $ctx).await?
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use mongodm::bson::{from_bson, oid::ObjectId, to_bson};
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    struct Unit {}
+
+    #[derive(Serialize, Deserialize)]
+    struct Card {
+        owner: Ref<Unit>,
+    }
+
+    #[test]
+    fn test_ref_serde() {
+        let id = ObjectId::new();
+        let card = Card {
+            owner: Ref::new(id.clone()),
+        };
+        let bson = to_bson(&card).unwrap();
+        assert_eq!(Bson::Document(doc! { "owner": id.clone() }), bson);
+        let card = from_bson::<Card>(bson).unwrap();
+        assert_eq!(&id, card.owner.id());
+    }
+
+    #[test]
+    fn test_ref_query_value() {
+        let id = ObjectId::new();
+        let r = Ref::<Unit>::parse(&id.to_hex()).unwrap();
+        assert_eq!(Bson::ObjectId(id), Bson::from(r));
+        assert!(Ref::<Unit>::parse("card").is_none());
+    }
+}
@@ -0,0 +1,229 @@
+use std::{collections::HashMap, marker::PhantomData, sync::RwLock};
+
+use mongodm::{
+    bson::{from_bson, Bson, DateTime, Document},
+    CollectionConfig, Index, Indexes, Model,
+};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    migration::upcast, schema::Schema, search::SEARCH_FIELD, utils::result::Result, withid::Id,
+};
+
+pub const REVISION_SUFFIX: &str = "_revisions";
+
+static REVISION_COLLECTIONS: Lazy<RwLock<HashMap<&'static str, &'static str>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+pub trait RevisionConfig {
+    fn track_revisions() -> bool;
+}
+
+pub struct NoRevisions {}
+
+impl RevisionConfig for NoRevisions {
+    #[inline]
+    fn track_revisions() -> bool {
+        false
+    }
+}
+
+pub struct TrackRevisions {}
+
+impl RevisionConfig for TrackRevisions {
+    #[inline]
+    fn track_revisions() -> bool {
+        true
+    }
+}
+
+// `CollectionConfig` wants a `&'static str`, so each name is built once and
+// kept for the life of the process.
+pub fn revision_collection_name(collection: &'static str) -> &'static str {
+    if let Some(name) = REVISION_COLLECTIONS.read().unwrap().get(collection) {
+        return name;
+    }
+    REVISION_COLLECTIONS
+        .write()
+        .unwrap()
+        .entry(collection)
+        .or_insert_with(|| {
+            let name = format!("{}{}", collection, REVISION_SUFFIX);
+            Box::leak(name.into_boxed_str())
+        })
+}
+
+// A previous version of a document of `M`, taken just before it was
+// overwritten. `document` is stored as it was, schema version included, and is
+// upcast when read back.
+#[derive(Serialize, Deserialize)]
+pub struct Revision<M> {
+    pub target: Bson,
+    pub document: Document,
+    pub actor: Option<Id>,
+    pub at: DateTime,
+    #[serde(skip)]
+    p: PhantomData<fn() -> M>,
+}
+
+impl<M: Schema> Revision<M> {
+    pub(crate) fn new(target: Bson, mut document: Document, actor: Option<Id>) -> Self {
+        document.remove("_id");
+        document.remove(SEARCH_FIELD);
+        Self {
+            target,
+            document,
+            actor,
+            at: chrono::Utc::now().into(),
+            p: PhantomData,
+        }
+    }
+
+    pub fn model(&self) -> Result<M> {
+        Ok(from_bson(Bson::Document(upcast::<M>(
+            self.document.clone(),
+        )?))?)
+    }
+}
+
+pub struct RevisionCfg<M> {
+    p: PhantomData<fn() -> M>,
+}
+
+impl<M: Model> CollectionConfig for RevisionCfg<M> {
+    fn collection_name() -> &'static str {
+        revision_collection_name(M::CollConf::collection_name())
+    }
+
+    fn indexes() -> Indexes {
+        Indexes::new().with(Index::new("target").with_key("at"))
+    }
+}
+
+impl<M: Model> Model for Revision<M> {
+    type CollConf = RevisionCfg<M>;
+}
+
+impl<M: Model> Schema for Revision<M> {}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Change {
+    pub path: String,
+    pub before: Option<Bson>,
+    pub after: Option<Bson>,
+}
+
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        String::from(key)
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+fn diff_value(path: String, before: Option<&Bson>, after: Option<&Bson>, out: &mut Vec<Change>) {
+    match (before, after) {
+        (Some(Bson::Document(before)), Some(Bson::Document(after))) => {
+            diff_document(&path, before, after, out)
+        }
+        (Some(Bson::Array(before)), Some(Bson::Array(after))) => {
+            for i in 0..before.len().max(after.len()) {
+                diff_value(
+                    join(&path, &i.to_string()),
+                    before.get(i),
+                    after.get(i),
+                    out,
+                );
+            }
+        }
+        (before, after) if before != after => out.push(Change {
+            path,
+            before: before.cloned(),
+            after: after.cloned(),
+        }),
+        _ => {}
+    }
+}
+
+fn diff_document(path: &str, before: &Document, after: &Document, out: &mut Vec<Change>) {
+    for (key, value) in before {
+        diff_value(join(path, key), Some(value), after.get(key), out);
+    }
+    for (key, value) in after {
+        if !before.contains_key(key) {
+            diff_value(join(path, key), None, Some(value), out);
+        }
+    }
+}
+
+// Field-level changes between two versions of a document, addressed by dotted
+// paths with array indexes, e.g. `layers.2.color`. Internal `_` fields at the
+// top level are left out.
+pub fn diff(before: &Document, after: &Document) -> Vec<Change> {
+    let strip = |doc: &Document| {
+        doc.iter()
+            .filter(|(key, _)| !key.starts_with('_'))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect::<Document>()
+    };
+    let mut changes = Vec::new();
+    diff_document("", &strip(before), &strip(after), &mut changes);
+    changes
+}
+
+#[cfg(test)]
+mod test {
+    use mongodm::doc;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn change(path: &str, before: Option<Bson>, after: Option<Bson>) -> Change {
+        Change {
+            path: String::from(path),
+            before,
+            after,
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        let before = doc! {
+            "_v": 1,
+            "name": "akari",
+            "layers": [{ "color": "red" }, { "color": "blue" }],
+            "size": { "w": 10, "h": 20 },
+        };
+        let after = doc! {
+            "_v": 2,
+            "name": "akari",
+            "layers": [{ "color": "green" }],
+            "size": { "w": 10 },
+            "title": "unibo",
+        };
+        assert_eq!(
+            vec![
+                change(
+                    "layers.0.color",
+                    Some(Bson::from("red")),
+                    Some(Bson::from("green"))
+                ),
+                change("layers.1", Some(Bson::from(doc! { "color": "blue" })), None),
+                change("size.h", Some(Bson::Int32(20)), None),
+                change("title", None, Some(Bson::from("unibo"))),
+            ],
+            diff(&before, &after)
+        );
+        assert!(diff(&after, &after).is_empty());
+    }
+
+    #[test]
+    fn test_revision_collection_name() {
+        assert_eq!("Card_revisions", revision_collection_name("Card"));
+        assert!(std::ptr::eq(
+            revision_collection_name("Card"),
+            revision_collection_name("Card")
+        ));
+    }
+}
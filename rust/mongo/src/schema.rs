@@ -0,0 +1,64 @@
+use mongodm::{
+    bson::{from_bson, to_document, Bson, Document},
+    Model,
+};
+
+use crate::{
+    metadata::{stamp_on_create, stamp_on_update},
+    migration::{stamp_version, upcast, Upcaster},
+    utils::result::Result,
+    view::View,
+    withid::Id,
+};
+
+// What the repositories need to know about a model beyond its collection.
+// Every stored model implements it; the defaults fit a model whose documents
+// never changed shape.
+pub trait Schema: Model {
+    // The string fields, dotted paths allowed, that `search` matches against.
+    // Documents of a model without any carry no search field.
+    const SEARCH_FIELDS: &'static [&'static str] = &[];
+
+    // The steps that bring a document of an older schema version up to date,
+    // as `(version, upcaster)` in ascending order of version. The last version
+    // is the one new documents are stamped with.
+    #[inline]
+    fn upcasters() -> Vec<(i32, Upcaster)> {
+        Vec::new()
+    }
+}
+
+// The rest is for stores of these models other than the repositories here,
+// so that both read and write the same documents.
+
+// The document a new model is stored as.
+pub fn new_document<M: Schema>(id: Bson, model: &M, actor: Option<Id>) -> Result<Document> {
+    let mut doc = to_document(model)?;
+    doc.insert("_id", id);
+    stamp_version::<M>(&mut doc);
+    stamp_on_create(&mut doc, actor);
+    Ok(doc)
+}
+
+// Stamps a document about to replace a stored one, whose creation fields it
+// has to keep.
+pub fn touch_document<M: Schema>(doc: &mut Document, actor: Option<Id>) {
+    stamp_version::<M>(doc);
+    stamp_on_update(doc, actor);
+}
+
+// A stored document brought up to date with `patch` applied, and the model it
+// now reads as.
+pub fn patch_document<M: Schema>(doc: Document, patch: &Document) -> Result<(M, Document)> {
+    let mut doc = upcast::<M>(doc)?;
+    for (key, value) in patch {
+        doc.insert(key.clone(), value.clone());
+    }
+    let model = from_bson::<M>(Bson::Document(doc.clone()))?;
+    Ok((model, doc))
+}
+
+// A stored document, brought up to date, as `V`.
+pub fn read_document<V: View>(doc: Document) -> Result<V> {
+    Ok(from_bson(Bson::Document(upcast::<V::Model>(doc)?))?)
+}
@@ -0,0 +1,190 @@
+use std::collections::BTreeSet;
+
+use mongodm::{
+    bson::{Bson, Document},
+    doc, Index,
+};
+
+use crate::{schema::Schema, utils::result::Result};
+
+pub const SEARCH_FIELD: &str = "_search";
+pub const SCORE_FIELD: &str = "_score";
+
+const FULLWIDTH_OFFSET: u32 = 0xFEE0;
+const KATAKANA_OFFSET: u32 = 0x60;
+// U+FF61..=U+FF9D in order
+const HALFWIDTH_KANA: &str = "。「」、・ヲァィゥェォャュョッーアイウエオカキクケコサシスセソタチツテトナニヌネノハヒフヘホマミムメモヤユヨラリルレロワン";
+const VOICEABLE: &str = "かきくけこさしすせそたちつてとはひふへほ";
+const SEMI_VOICEABLE: &str = "はひふへほ";
+
+#[inline]
+pub fn search_index() -> Index {
+    Index::new(SEARCH_FIELD)
+}
+
+fn widen(c: char) -> char {
+    match c as u32 {
+        0x3000 => ' ',
+        code @ 0xFF01..=0xFF5E => std::char::from_u32(code - FULLWIDTH_OFFSET).unwrap_or(c),
+        code @ 0xFF61..=0xFF9D => HALFWIDTH_KANA
+            .chars()
+            .nth((code - 0xFF61) as usize)
+            .unwrap_or(c),
+        _ => c,
+    }
+}
+
+fn to_hiragana(c: char) -> char {
+    match c as u32 {
+        code @ 0x30A1..=0x30F6 => std::char::from_u32(code - KATAKANA_OFFSET).unwrap_or(c),
+        _ => c,
+    }
+}
+
+fn compose(prev: char, mark: char) -> Option<char> {
+    let voiced = matches!(mark, '\u{FF9E}' | '\u{3099}' | '\u{309B}');
+    let semi_voiced = matches!(mark, '\u{FF9F}' | '\u{309A}' | '\u{309C}');
+    if voiced && prev == 'う' {
+        Some('ゔ')
+    } else if voiced && VOICEABLE.contains(prev) {
+        std::char::from_u32(prev as u32 + 1)
+    } else if semi_voiced && SEMI_VOICEABLE.contains(prev) {
+        std::char::from_u32(prev as u32 + 2)
+    } else {
+        None
+    }
+}
+
+// Folds full-width ASCII and half-width katakana to their usual forms, katakana
+// to hiragana and letters to lower case, so `ｱｶﾘ`, `アカリ` and `あかり` match.
+pub fn normalize(text: &str) -> String {
+    let mut out: Vec<char> = Vec::with_capacity(text.len());
+    for c in text.chars() {
+        let c = to_hiragana(widen(c));
+        if let Some(composed) = out.last().and_then(|prev| compose(*prev, c)) {
+            out.pop();
+            out.push(composed);
+            continue;
+        }
+        out.extend(c.to_lowercase());
+    }
+    out.into_iter().collect()
+}
+
+fn grams_of(token: &[char], unigrams: bool, out: &mut BTreeSet<String>) {
+    if unigrams || token.len() == 1 {
+        out.extend(token.iter().map(|c| c.to_string()));
+    }
+    out.extend(token.windows(2).map(|w| w.iter().collect::<String>()));
+}
+
+fn tokens(text: &str) -> Vec<Vec<char>> {
+    normalize(text)
+        .split(|c: char| c.is_whitespace() || c.is_ascii_punctuation())
+        .filter(|token| !token.is_empty())
+        .map(|token| token.chars().collect())
+        .collect()
+}
+
+// Documents are indexed by unigrams and bigrams; a query uses bigrams only,
+// unless it is a single character.
+pub fn ngrams(text: &str) -> Vec<String> {
+    let mut grams = BTreeSet::new();
+    for token in tokens(text) {
+        grams_of(&token, true, &mut grams);
+    }
+    grams.into_iter().collect()
+}
+
+pub fn query_ngrams(text: &str) -> Vec<String> {
+    let mut grams = BTreeSet::new();
+    for token in tokens(text) {
+        grams_of(&token, false, &mut grams);
+    }
+    grams.into_iter().collect()
+}
+
+fn field_text<'a>(doc: &'a Document, path: &str, out: &mut Vec<&'a str>) {
+    let mut parts = path.splitn(2, '.');
+    let value = match parts.next().and_then(|key| doc.get(key)) {
+        Some(value) => value,
+        None => return,
+    };
+    match (value, parts.next()) {
+        (Bson::String(s), None) => out.push(s),
+        (Bson::Array(values), None) => out.extend(values.iter().filter_map(Bson::as_str)),
+        (Bson::Document(doc), Some(rest)) => field_text(doc, rest, out),
+        _ => {}
+    }
+}
+
+pub(crate) fn stamp_search<M: Schema>(doc: &mut Document) {
+    if M::SEARCH_FIELDS.is_empty() {
+        return;
+    }
+    let mut texts = Vec::new();
+    for field in M::SEARCH_FIELDS {
+        field_text(doc, field, &mut texts);
+    }
+    let grams = ngrams(&texts.join(" "));
+    doc.insert(SEARCH_FIELD, grams);
+}
+
+// an `Upcaster` for backfilling the search field of existing documents
+pub fn index_document<M: Schema>(mut doc: Document) -> Result<Document> {
+    stamp_search::<M>(&mut doc);
+    Ok(doc)
+}
+
+// Documents sharing at least half of the query bigrams are hits, ranked by the
+// number they share.
+pub(crate) fn search_stages(query: Document, grams: &[String]) -> Vec<Document> {
+    let min_score = ((grams.len() + 1) / 2) as i32;
+    vec![
+        doc! { "$match": { "$and": [query, { SEARCH_FIELD: { "$in": grams.to_vec() } }] } },
+        doc! { "$addFields": {
+            SCORE_FIELD: {
+                "$size": { "$setIntersection": [format!("${}", SEARCH_FIELD), grams.to_vec()] },
+            },
+        } },
+        doc! { "$match": { SCORE_FIELD: { "$gte": min_score } } },
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!("あかり", normalize("アカリ"));
+        assert_eq!("あかり", normalize("ｱｶﾘ"));
+        assert_eq!("がっこう", normalize("ｶﾞｯｺｳ"));
+        assert_eq!("ぱんだ", normalize("ﾊﾟﾝﾀﾞ"));
+        assert_eq!("ゔぃ", normalize("ｳﾞｨ"));
+        assert_eq!("abc 123", normalize("ＡＢＣ　１２３"));
+        assert_eq!("riamu", normalize("Riamu"));
+        assert_eq!("が", normalize("か\u{3099}"));
+    }
+
+    #[test]
+    fn test_ngrams() {
+        assert_eq!(vec!["あ", "あか", "か", "かり", "り"], ngrams("アカリ"));
+        assert_eq!(vec!["あか", "かり"], query_ngrams("ｱｶﾘ"));
+        assert_eq!(vec!["あ"], query_ngrams("あ"));
+        assert_eq!(vec!["ak", "ki", "ya"], query_ngrams("aki, ya"));
+        assert!(query_ngrams("  ").is_empty());
+    }
+
+    #[test]
+    fn test_field_text() {
+        let doc = doc! { "name": "akari", "tags": ["a", "b"], "profile": { "kana": "あかり" } };
+        let mut texts = Vec::new();
+        for field in &["name", "tags", "profile.kana", "missing"] {
+            field_text(&doc, field, &mut texts);
+        }
+        assert_eq!(vec!["akari", "a", "b", "あかり"], texts);
+    }
+}
@@ -0,0 +1,651 @@
+use std::marker::PhantomData;
+
+use async_trait::async_trait;
+use bson::Document;
+use mongodm::mongo::options::FindOptions;
+use serde::de::DeserializeOwned;
+use uuid::Uuid;
+
+mod page;
+
+pub use page::*;
+
+use crate::{
+    aggregate::{AggregateCursor, Pipeline},
+    bulk::BulkResult,
+    fcomps::{
+        behavior::{lift::Lift, Behave, BehaveDef, Behavior},
+        convert::{Convert, Convertible, Identity},
+        service::*,
+        SeqB,
+    },
+    utils::result::Result,
+    view::View,
+    watch::{ChangeStream, ResumeToken},
+    withid::{
+        ConvertModelWithIdCursor, ConvertibleStream, Id, ModelWithIdCursor, MongoRepositoryWithId,
+        RepositoryWithId, WithId,
+    },
+};
+
+pub struct WithIdCreateBehaviorDef<Repo>
+where
+    Repo: RepositoryWithId,
+{
+    p: PhantomData<fn() -> Repo>,
+}
+
+#[async_trait(?Send)]
+impl<Repo> BehaveDef for WithIdCreateBehaviorDef<Repo>
+where
+    Repo: RepositoryWithId,
+{
+    type In = Repo::Model;
+    type Out = Repo::Id;
+    type Ctx = Repo::Ctx;
+
+    #[inline]
+    async fn def(input: Self::In, ctx: &Self::Ctx) -> Result<Self::Out> {
+        <Repo as RepositoryWithId>::new(ctx)
+            .await
+            .create(&input)
+            .await
+    }
+}
+
+pub struct WithIdUpdateBehaviorDef<Repo>
+where
+    Repo: RepositoryWithId,
+{
+    p: PhantomData<fn() -> Repo>,
+}
+
+#[async_trait(?Send)]
+impl<Repo> BehaveDef for WithIdUpdateBehaviorDef<Repo>
+where
+    Repo: RepositoryWithId,
+{
+    type In = WithId<Repo::Model, Repo::Id>;
+    type Out = ();
+    type Ctx = Repo::Ctx;
+
+    #[inline]
+    async fn def(input: Self::In, ctx: &Self::Ctx) -> Result<Self::Out> {
+        <Repo as RepositoryWithId>::new(ctx)
+            .await
+            .update(&input)
+            .await
+    }
+}
+
+pub struct DeleteId<I = Id>(pub I);
+
+pub struct WithIdDeleteBehaviorDef<Repo>
+where
+    Repo: RepositoryWithId,
+{
+    p: PhantomData<fn() -> Repo>,
+}
+
+#[async_trait(?Send)]
+impl<Repo> BehaveDef for WithIdDeleteBehaviorDef<Repo>
+where
+    Repo: RepositoryWithId,
+{
+    type In = DeleteId<Repo::Id>;
+    type Out = ();
+    type Ctx = Repo::Ctx;
+
+    #[inline]
+    async fn def(input: Self::In, ctx: &Self::Ctx) -> Result<Self::Out> {
+        <Repo as RepositoryWithId>::new(ctx)
+            .await
+            .delete(&input.0)
+            .await
+    }
+}
+
+pub struct RestoreId<I = Id>(pub I);
+
+pub struct WithIdRestoreBehaviorDef<Repo>
+where
+    Repo: RepositoryWithId,
+{
+    p: PhantomData<fn() -> Repo>,
+}
+
+#[async_trait(?Send)]
+impl<Repo> BehaveDef for WithIdRestoreBehaviorDef<Repo>
+where
+    Repo: RepositoryWithId,
+{
+    type In = RestoreId<Repo::Id>;
+    type Out = ();
+    type Ctx = Repo::Ctx;
+
+    #[inline]
+    async fn def(input: Self::In, ctx: &Self::Ctx) -> Result<Self::Out> {
+        <Repo as RepositoryWithId>::new(ctx)
+            .await
+            .restore(&input.0)
+            .await
+    }
+}
+
+pub struct FindOneArgument(pub Document, pub Option<FindOptions>);
+
+pub struct WithIdFindOneBehaviorDef<Repo, V = <Repo as RepositoryWithId>::Model>
+where
+    Repo: RepositoryWithId,
+    V: View<Model = Repo::Model>,
+{
+    p: PhantomData<fn() -> (Repo, V)>,
+}
+
+#[async_trait(?Send)]
+impl<Repo, V> BehaveDef for WithIdFindOneBehaviorDef<Repo, V>
+where
+    Repo: RepositoryWithId,
+    V: View<Model = Repo::Model>,
+{
+    type In = FindOneArgument;
+    type Out = Option<WithId<V, Repo::Id>>;
+    type Ctx = Repo::Ctx;
+
+    #[inline]
+    async fn def(input: Self::In, ctx: &Self::Ctx) -> Result<Self::Out> {
+        <Repo as RepositoryWithId>::new(ctx)
+            .await
+            .find_one_view(input.0, input.1)
+            .await
+    }
+}
+
+pub struct FindManyArgument(pub Document, pub Option<FindOptions>);
+
+pub struct WithIdFindManyBehaviorDef<Repo, V = <Repo as RepositoryWithId>::Model>
+where
+    Repo: RepositoryWithId,
+    V: View<Model = Repo::Model>,
+{
+    p: PhantomData<fn() -> (Repo, V)>,
+}
+
+#[async_trait(?Send)]
+impl<Repo, V> BehaveDef for WithIdFindManyBehaviorDef<Repo, V>
+where
+    Repo: RepositoryWithId,
+    V: View<Model = Repo::Model>,
+{
+    type In = FindManyArgument;
+    type Out = ModelWithIdCursor<V, Repo::Id>;
+    type Ctx = Repo::Ctx;
+
+    #[inline]
+    async fn def(input: Self::In, ctx: &Self::Ctx) -> Result<Self::Out> {
+        <Repo as RepositoryWithId>::new(ctx)
+            .await
+            .find_many_view(input.0, input.1)
+            .await
+    }
+}
+
+pub struct WithIdFindPageBehaviorDef<Repo, V = <Repo as RepositoryWithId>::Model>
+where
+    Repo: RepositoryWithId,
+    V: View<Model = Repo::Model>,
+{
+    p: PhantomData<fn() -> (Repo, V)>,
+}
+
+#[async_trait(?Send)]
+impl<Repo, V> BehaveDef for WithIdFindPageBehaviorDef<Repo, V>
+where
+    Repo: RepositoryWithId,
+    V: View<Model = Repo::Model>,
+{
+    type In = FindPageArgument;
+    type Out = Page<WithId<V, Repo::Id>>;
+    type Ctx = Repo::Ctx;
+
+    #[inline]
+    async fn def(input: Self::In, ctx: &Self::Ctx) -> Result<Self::Out> {
+        <Repo as RepositoryWithId>::new(ctx)
+            .await
+            .find_page_view(input.0, &input.1, &input.2)
+            .await
+    }
+}
+
+pub struct SearchArgument(pub Document, pub String, pub PageRequest);
+
+pub struct WithIdSearchBehaviorDef<Repo, V = <Repo as RepositoryWithId>::Model>
+where
+    Repo: MongoRepositoryWithId,
+    V: View<Model = Repo::Model>,
+{
+    p: PhantomData<fn() -> (Repo, V)>,
+}
+
+#[async_trait(?Send)]
+impl<Repo, V> BehaveDef for WithIdSearchBehaviorDef<Repo, V>
+where
+    Repo: MongoRepositoryWithId,
+    V: View<Model = Repo::Model>,
+{
+    type In = SearchArgument;
+    type Out = Page<WithId<V, Repo::Id>>;
+    type Ctx = Repo::Ctx;
+
+    #[inline]
+    async fn def(input: Self::In, ctx: &Self::Ctx) -> Result<Self::Out> {
+        <Repo as RepositoryWithId>::new(ctx)
+            .await
+            .search_view(input.0, &input.1, &input.2)
+            .await
+    }
+}
+
+pub struct WithIdAggregateBehaviorDef<Repo, Out>
+where
+    Repo: MongoRepositoryWithId,
+    Out: DeserializeOwned,
+{
+    p: PhantomData<fn() -> (Repo, Out)>,
+}
+
+#[async_trait(?Send)]
+impl<Repo, Out> BehaveDef for WithIdAggregateBehaviorDef<Repo, Out>
+where
+    Repo: MongoRepositoryWithId,
+    Out: DeserializeOwned,
+{
+    type In = Pipeline<Repo::Model>;
+    type Out = AggregateCursor<Out>;
+    type Ctx = Repo::Ctx;
+
+    #[inline]
+    async fn def(input: Self::In, ctx: &Self::Ctx) -> Result<Self::Out> {
+        <Repo as RepositoryWithId>::new(ctx)
+            .await
+            .aggregate(input)
+            .await
+    }
+}
+
+pub struct CreateManyArgument<M>(pub Vec<M>);
+
+pub struct WithIdCreateManyBehaviorDef<Repo>
+where
+    Repo: RepositoryWithId,
+{
+    p: PhantomData<fn() -> Repo>,
+}
+
+#[async_trait(?Send)]
+impl<Repo> BehaveDef for WithIdCreateManyBehaviorDef<Repo>
+where
+    Repo: RepositoryWithId,
+{
+    type In = CreateManyArgument<Repo::Model>;
+    type Out = BulkResult<usize, Repo::Id>;
+    type Ctx = Repo::Ctx;
+
+    #[inline]
+    async fn def(input: Self::In, ctx: &Self::Ctx) -> Result<Self::Out> {
+        <Repo as RepositoryWithId>::new(ctx)
+            .await
+            .create_many(&input.0)
+            .await
+    }
+}
+
+pub struct UpdateManyArgument(pub Document, pub Document);
+
+pub struct WithIdUpdateManyBehaviorDef<Repo>
+where
+    Repo: RepositoryWithId,
+{
+    p: PhantomData<fn() -> Repo>,
+}
+
+#[async_trait(?Send)]
+impl<Repo> BehaveDef for WithIdUpdateManyBehaviorDef<Repo>
+where
+    Repo: RepositoryWithId,
+{
+    type In = UpdateManyArgument;
+    type Out = BulkResult<Repo::Id>;
+    type Ctx = Repo::Ctx;
+
+    #[inline]
+    async fn def(input: Self::In, ctx: &Self::Ctx) -> Result<Self::Out> {
+        <Repo as RepositoryWithId>::new(ctx)
+            .await
+            .update_many(input.0, input.1)
+            .await
+    }
+}
+
+pub struct DeleteManyArgument(pub Document);
+
+pub struct WithIdDeleteManyBehaviorDef<Repo>
+where
+    Repo: RepositoryWithId,
+{
+    p: PhantomData<fn() -> Repo>,
+}
+
+#[async_trait(?Send)]
+impl<Repo> BehaveDef for WithIdDeleteManyBehaviorDef<Repo>
+where
+    Repo: RepositoryWithId,
+{
+    type In = DeleteManyArgument;
+    type Out = i64;
+    type Ctx = Repo::Ctx;
+
+    #[inline]
+    async fn def(input: Self::In, ctx: &Self::Ctx) -> Result<Self::Out> {
+        <Repo as RepositoryWithId>::new(ctx)
+            .await
+            .delete_many(input.0)
+            .await
+    }
+}
+
+pub struct WatchArgument(pub Document, pub Option<ResumeToken>);
+
+pub struct WithIdWatchBehaviorDef<Repo>
+where
+    Repo: MongoRepositoryWithId,
+{
+    p: PhantomData<fn() -> Repo>,
+}
+
+#[async_trait(?Send)]
+impl<Repo> BehaveDef for WithIdWatchBehaviorDef<Repo>
+where
+    Repo: MongoRepositoryWithId,
+    Repo::Model: 'static,
+{
+    type In = WatchArgument;
+    type Out = ChangeStream<Repo::Model, Repo::Id>;
+    type Ctx = Repo::Ctx;
+
+    #[inline]
+    async fn def(input: Self::In, ctx: &Self::Ctx) -> Result<Self::Out> {
+        <Repo as RepositoryWithId>::new(ctx)
+            .await
+            .watch(input.0, input.1)
+            .await
+    }
+}
+
+pub type ConvertCursur<T, F> = ConvertibleStream<T, ModelWithIdCursor<F>>;
+
+pub type WithIdCreateBehavior<Repo> = Behave<WithIdCreateBehaviorDef<Repo>>;
+pub type WithIdUpdateBehavior<Repo> = Behave<WithIdUpdateBehaviorDef<Repo>>;
+pub type WithIdDeleteBehavior<Repo> = Behave<WithIdDeleteBehaviorDef<Repo>>;
+pub type WithIdRestoreBehavior<Repo> = Behave<WithIdRestoreBehaviorDef<Repo>>;
+pub type WithIdFindOneBehavior<Repo, V = <Repo as RepositoryWithId>::Model> =
+    Behave<WithIdFindOneBehaviorDef<Repo, V>>;
+pub type WithIdFindManyBehavior<Repo, V = <Repo as RepositoryWithId>::Model> =
+    Behave<WithIdFindManyBehaviorDef<Repo, V>>;
+pub type WithIdFindPageBehavior<Repo, V = <Repo as RepositoryWithId>::Model> =
+    Behave<WithIdFindPageBehaviorDef<Repo, V>>;
+pub type WithIdSearchBehavior<Repo, V = <Repo as RepositoryWithId>::Model> =
+    Behave<WithIdSearchBehaviorDef<Repo, V>>;
+pub type WithIdAggregateBehavior<Repo, Out> = Behave<WithIdAggregateBehaviorDef<Repo, Out>>;
+pub type WithIdCreateManyBehavior<Repo> = Behave<WithIdCreateManyBehaviorDef<Repo>>;
+pub type WithIdUpdateManyBehavior<Repo> = Behave<WithIdUpdateManyBehaviorDef<Repo>>;
+pub type WithIdDeleteManyBehavior<Repo> = Behave<WithIdDeleteManyBehaviorDef<Repo>>;
+pub type WithIdWatchBehavior<Repo> = Behave<WithIdWatchBehaviorDef<Repo>>;
+
+pub struct WithIdBehaviors<Repo, In, Out, OnFindOneIn, OnFindManyIn>
+where
+    Repo: RepositoryWithId,
+{
+    #[allow(clippy::type_complexity)]
+    p: PhantomData<fn() -> (Repo, In, Out, OnFindOneIn, OnFindManyIn)>,
+}
+
+impl<Repo, In, Out, OnFindOneIn, OnFindManyIn> CRUDBehaviors
+    for WithIdBehaviors<Repo, In, Out, OnFindOneIn, OnFindManyIn>
+where
+    Repo: RepositoryWithId,
+    WithId<Repo::Model, Repo::Id>: Convertible<Out>,
+{
+    type Ctx = Repo::Ctx;
+    type CreateIn = In;
+    type UpdateIn = WithId<In, Repo::Id>;
+    type DeleteIn = Repo::Id;
+    type FindOneIn = OnFindOneIn;
+    type FindManyIn = OnFindManyIn;
+    type FindOneOut = Option<Out>;
+    type FindManyOut = ConvertModelWithIdCursor<Out, Repo::Model, Repo::Id>;
+    type Create = WithIdCreateBehavior<Repo>;
+    type Update = WithIdUpdateBehavior<Repo>;
+    type Delete = WithIdDeleteBehavior<Repo>;
+    type FindOne = WithIdFindOneBehavior<Repo>;
+    type FindMany = WithIdFindManyBehavior<Repo>;
+}
+
+pub type WithIdCRUDService<
+    Repo,
+    In,
+    Out,
+    OnFindOneIn,
+    OnFindManyIn,
+    BeforeFilter = EmptyHook<<Repo as RepositoryWithId>::Ctx>,
+> = CRUDSevice<
+    SimpleCRUDServiceDef<WithIdBehaviors<Repo, In, Out, OnFindOneIn, OnFindManyIn>, BeforeFilter>,
+>;
+
+pub struct WithIdPageBehaviors<Repo, In, Out, OnFindOneIn, OnFindManyIn>
+where
+    Repo: RepositoryWithId,
+{
+    #[allow(clippy::type_complexity)]
+    p: PhantomData<fn() -> (Repo, In, Out, OnFindOneIn, OnFindManyIn)>,
+}
+
+impl<Repo, In, Out, OnFindOneIn, OnFindManyIn> CRUDBehaviors
+    for WithIdPageBehaviors<Repo, In, Out, OnFindOneIn, OnFindManyIn>
+where
+    Repo: RepositoryWithId,
+    WithId<Repo::Model, Repo::Id>: Convertible<Out>,
+{
+    type Ctx = Repo::Ctx;
+    type CreateIn = In;
+    type UpdateIn = WithId<In, Repo::Id>;
+    type DeleteIn = Repo::Id;
+    type FindOneIn = OnFindOneIn;
+    type FindManyIn = OnFindManyIn;
+    type FindOneOut = Option<Out>;
+    type FindManyOut = Page<Out>;
+    type Create = WithIdCreateBehavior<Repo>;
+    type Update = WithIdUpdateBehavior<Repo>;
+    type Delete = WithIdDeleteBehavior<Repo>;
+    type FindOne = WithIdFindOneBehavior<Repo>;
+    type FindMany = WithIdFindPageBehavior<Repo>;
+}
+
+pub type WithIdPageCRUDService<
+    Repo,
+    In,
+    Out,
+    OnFindOneIn,
+    OnFindManyIn,
+    BeforeFilter = EmptyHook<<Repo as RepositoryWithId>::Ctx>,
+> = CRUDSevice<
+    SimpleCRUDServiceDef<
+        WithIdPageBehaviors<Repo, In, Out, OnFindOneIn, OnFindManyIn>,
+        BeforeFilter,
+    >,
+>;
+
+pub type WithIdHookedServiceDef<Repo, In, BeforeFilter, OnHook, ServiceBehavior> = ServiceBaseBuild<
+    In,
+    SeqB!(
+        <BeforeFilter as CRUDHook>::Hook,
+        Lift<OnHook, <Repo as RepositoryWithId>::Ctx>
+    ),
+    Convert<
+        WithHookResult<<BeforeFilter as CRUDHook>::HookOut, In>,
+        <ServiceBehavior as Behavior>::In,
+    >,
+    ServiceBehavior,
+    Identity<<ServiceBehavior as Behavior>::Out>,
+    <ServiceBehavior as Behavior>::Out,
+>;
+
+pub type WithIdAggregateServiceDef<Repo, In, Out, BeforeFilter> = WithIdHookedServiceDef<
+    Repo,
+    In,
+    BeforeFilter,
+    <BeforeFilter as CRUDHook>::OnFindMany,
+    WithIdAggregateBehavior<Repo, Out>,
+>;
+
+pub type WithIdAggregateService<
+    Repo,
+    In,
+    Out,
+    BeforeFilter = EmptyHook<<Repo as RepositoryWithId>::Ctx>,
+> = ServiceBase<WithIdAggregateServiceDef<Repo, In, Out, BeforeFilter>>;
+
+pub type WithIdCreateManyService<
+    Repo,
+    In,
+    BeforeFilter = EmptyHook<<Repo as RepositoryWithId>::Ctx>,
+> = ServiceBase<
+    WithIdHookedServiceDef<
+        Repo,
+        In,
+        BeforeFilter,
+        <BeforeFilter as CRUDHook>::OnCreate,
+        WithIdCreateManyBehavior<Repo>,
+    >,
+>;
+
+pub type WithIdUpdateManyService<
+    Repo,
+    In,
+    BeforeFilter = EmptyHook<<Repo as RepositoryWithId>::Ctx>,
+> = ServiceBase<
+    WithIdHookedServiceDef<
+        Repo,
+        In,
+        BeforeFilter,
+        <BeforeFilter as CRUDHook>::OnUpdate,
+        WithIdUpdateManyBehavior<Repo>,
+    >,
+>;
+
+pub type WithIdDeleteManyService<
+    Repo,
+    In,
+    BeforeFilter = EmptyHook<<Repo as RepositoryWithId>::Ctx>,
+> = ServiceBase<
+    WithIdHookedServiceDef<
+        Repo,
+        In,
+        BeforeFilter,
+        <BeforeFilter as CRUDHook>::OnDelete,
+        WithIdDeleteManyBehavior<Repo>,
+    >,
+>;
+
+pub type WithIdWatchService<Repo, In, BeforeFilter = EmptyHook<<Repo as RepositoryWithId>::Ctx>> =
+    ServiceBase<
+        WithIdHookedServiceDef<
+            Repo,
+            In,
+            BeforeFilter,
+            <BeforeFilter as CRUDHook>::OnFindMany,
+            WithIdWatchBehavior<Repo>,
+        >,
+    >;
+
+pub type WithIdFindPageService<
+    Repo,
+    In,
+    V = <Repo as RepositoryWithId>::Model,
+    BeforeFilter = EmptyHook<<Repo as RepositoryWithId>::Ctx>,
+> = ServiceBase<
+    WithIdHookedServiceDef<
+        Repo,
+        In,
+        BeforeFilter,
+        <BeforeFilter as CRUDHook>::OnFindMany,
+        WithIdFindPageBehavior<Repo, V>,
+    >,
+>;
+
+pub type WithIdSearchService<
+    Repo,
+    In,
+    V = <Repo as RepositoryWithId>::Model,
+    BeforeFilter = EmptyHook<<Repo as RepositoryWithId>::Ctx>,
+> = ServiceBase<
+    WithIdHookedServiceDef<
+        Repo,
+        In,
+        BeforeFilter,
+        <BeforeFilter as CRUDHook>::OnFindMany,
+        WithIdSearchBehavior<Repo, V>,
+    >,
+>;
+
+// Lets an argument be passed to its service as it is.
+macro_rules! impl_identity {
+    (<$param:ident> $arg:ty) => {
+        impl<$param> Convertible<$arg> for $arg {
+            #[inline]
+            fn convert(self) -> Result<$arg> {
+                Ok(self)
+            }
+        }
+    };
+    ($($arg:ty),*) => {
+        $(
+            impl Convertible<$arg> for $arg {
+                #[inline]
+                fn convert(self) -> Result<$arg> {
+                    Ok(self)
+                }
+            }
+        )*
+    };
+}
+
+impl_identity!(<M> CreateManyArgument<M>);
+impl_identity!(
+    UpdateManyArgument,
+    DeleteManyArgument,
+    WatchArgument,
+    FindPageArgument,
+    SearchArgument
+);
+
+// Lets a bare id be passed to `delete` of a service without a hook. The
+// impl is on the id itself and reaches the service through the forwarding
+// `WithHookResult<(), _>` impl of fcomps, so a crate with its own `IdType`
+// can invoke this for it too. A blanket impl over `IdType` would overlap
+// with that forwarding impl.
+#[macro_export]
+macro_rules! impl_delete_id {
+    ($($id:ty),*) => {
+        $(
+            impl $crate::model::__private::Convertible<$crate::service::DeleteId<$id>> for $id {
+                #[inline]
+                fn convert(self) -> $crate::model::__private::Result<$crate::service::DeleteId<$id>> {
+                    Ok($crate::service::DeleteId(self))
+                }
+            }
+        )*
+    };
+}
+
+impl_delete_id!(Id, String, Uuid);
@@ -0,0 +1,353 @@
+use hmac::{Hmac, Mac, NewMac};
+use mongodm::{
+    bson::{Bson, Document},
+    doc,
+};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use thiserror::Error;
+
+use crate::{
+    fcomps::convert::Convertible,
+    id::IdError,
+    utils::{result::Result, simple_error},
+};
+
+pub const DEFAULT_PAGE_LIMIT: i64 = 20;
+pub const MAX_PAGE_LIMIT: i64 = 100;
+pub const TEST_CURSOR_KEY: &[u8] = b"ringoro-test-cursor-key";
+const CURSOR_KEY_LABEL: &[u8] = b"ringoro-cursor";
+
+#[derive(Debug, Error)]
+#[error("invalid cursor")]
+pub struct InvalidCursor {}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct PageRequest {
+    #[serde(default)]
+    pub cursor: Option<String>,
+    #[serde(default)]
+    pub limit: Option<i64>,
+}
+
+impl PageRequest {
+    pub fn new(cursor: Option<String>, limit: Option<i64>) -> Self {
+        Self { cursor, limit }
+    }
+
+    pub fn limit(&self) -> i64 {
+        self.limit
+            .unwrap_or(DEFAULT_PAGE_LIMIT)
+            .max(1)
+            .min(MAX_PAGE_LIMIT)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PageSort {
+    pub field: String,
+    pub ascending: bool,
+}
+
+impl PageSort {
+    pub fn asc(field: impl AsRef<str>) -> Self {
+        Self {
+            field: String::from(field.as_ref()),
+            ascending: true,
+        }
+    }
+
+    pub fn desc(field: impl AsRef<str>) -> Self {
+        Self {
+            field: String::from(field.as_ref()),
+            ascending: false,
+        }
+    }
+
+    fn direction(&self) -> i32 {
+        if self.ascending {
+            1
+        } else {
+            -1
+        }
+    }
+
+    fn is_id(&self) -> bool {
+        self.field == "_id"
+    }
+
+    pub fn sort_document(&self) -> Document {
+        if self.is_id() {
+            doc! { "_id": self.direction() }
+        } else {
+            doc! { &self.field: self.direction(), "_id": self.direction() }
+        }
+    }
+
+    // Comparisons only match values of the same type, so the documents without
+    // the key, which sort as null before any other value, get a branch of
+    // their own.
+    pub(crate) fn after(&self, position: &Position) -> Document {
+        let op = if self.ascending { "$gt" } else { "$lt" };
+        if self.is_id() {
+            return doc! { "_id": { op: position.id.clone() } };
+        }
+        let same_key =
+            doc! { &self.field: position.key.clone(), "_id": { op: position.id.clone() } };
+        let branches = match (&position.key, self.ascending) {
+            (Bson::Null, true) => vec![
+                same_key,
+                doc! { &self.field: { "$exists": true, "$ne": Bson::Null } },
+            ],
+            (Bson::Null, false) => vec![same_key],
+            (key, true) => vec![doc! { &self.field: { op: key.clone() } }, same_key],
+            (key, false) => vec![
+                doc! { &self.field: { op: key.clone() } },
+                same_key,
+                doc! { &self.field: Bson::Null },
+            ],
+        };
+        doc! { "$or": branches }
+    }
+
+    pub(crate) fn position_of(&self, doc: &Document) -> Result<Position> {
+        let id = doc.get("_id").cloned().ok_or(IdError::MissingId)?;
+        let key = key_at(doc, &self.field).unwrap_or(Bson::Null);
+        Ok(Position { key, id })
+    }
+}
+
+// the value at a dotted path, as the sort reads it
+fn key_at(doc: &Document, path: &str) -> Option<Bson> {
+    let mut parts = path.splitn(2, '.');
+    match (doc.get(parts.next()?)?, parts.next()) {
+        (value, None) => Some(value.clone()),
+        (Bson::Document(doc), Some(rest)) => key_at(doc, rest),
+        _ => None,
+    }
+}
+
+impl Default for PageSort {
+    fn default() -> Self {
+        Self::asc("_id")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Position {
+    pub key: Bson,
+    pub id: Bson,
+}
+
+impl Position {
+    pub(crate) fn encode(&self, sort: &PageSort, key: &[u8]) -> Result<String> {
+        let payload = doc! {
+            "f": &sort.field,
+            "d": sort.direction(),
+            "k": self.key.clone(),
+            "i": self.id.clone(),
+        };
+        let mut bytes = Vec::new();
+        payload.to_writer(&mut bytes)?;
+        let signature = sign(&bytes, key)?;
+        Ok(format!(
+            "{}.{}",
+            base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD),
+            base64::encode_config(&signature, base64::URL_SAFE_NO_PAD)
+        ))
+    }
+
+    pub(crate) fn decode(token: &str, sort: &PageSort, key: &[u8]) -> Result<Self> {
+        let payload = verify(token, key).ok_or(InvalidCursor {})?;
+        let doc = Document::from_reader(&mut payload.as_slice()).map_err(|_| InvalidCursor {})?;
+        if doc.get_str("f").ok() != Some(sort.field.as_str())
+            || doc.get_i32("d").ok() != Some(sort.direction())
+        {
+            return Err(InvalidCursor {}.into());
+        }
+        Ok(Self {
+            key: doc.get("k").cloned().unwrap_or(Bson::Null),
+            id: doc.get("i").cloned().ok_or(InvalidCursor {})?,
+        })
+    }
+}
+
+fn verify(token: &str, key: &[u8]) -> Option<Vec<u8>> {
+    let mut parts = token.splitn(2, '.');
+    let payload = base64::decode_config(parts.next()?, base64::URL_SAFE_NO_PAD).ok()?;
+    let signature = base64::decode_config(parts.next()?, base64::URL_SAFE_NO_PAD).ok()?;
+    let mut mac = Hmac::<Sha256>::new_varkey(key).ok()?;
+    mac.update(&payload);
+    mac.verify(&signature).ok()?;
+    Some(payload)
+}
+
+fn sign(payload: &[u8], key: &[u8]) -> Result<Vec<u8>> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).map_err(|_| simple_error!("invalid key"))?;
+    mac.update(payload);
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+// Cursors are signed with a key of their own, derived from the session key, so
+// neither signature can be passed off as the other.
+pub fn derive_cursor_key(session_key: &[u8]) -> Result<Vec<u8>> {
+    sign(CURSOR_KEY_LABEL, session_key)
+}
+
+#[derive(Serialize, Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub next_cursor: Option<String>,
+    pub has_next: bool,
+}
+
+impl<T, U> Convertible<Page<T>> for Page<U>
+where
+    U: Convertible<T>,
+{
+    fn convert(self) -> Result<Page<T>> {
+        Ok(Page {
+            items: self
+                .items
+                .into_iter()
+                .map(Convertible::convert)
+                .collect::<Result<Vec<_>>>()?,
+            next_cursor: self.next_cursor,
+            has_next: self.has_next,
+        })
+    }
+}
+
+pub struct FindPageArgument(pub Document, pub PageSort, pub PageRequest);
+
+// The query for the page `request` asks for, and how many documents to fetch
+// with it: one more than the page holds, to tell whether another follows.
+pub fn page_window(
+    query: Document,
+    sort: &PageSort,
+    request: &PageRequest,
+    key: &[u8],
+) -> Result<(Document, i64)> {
+    let query = match &request.cursor {
+        Some(cursor) => {
+            let position = Position::decode(cursor, sort, key)?;
+            doc! { "$and": [query, sort.after(&position)] }
+        }
+        None => query,
+    };
+    Ok((query, request.limit() + 1))
+}
+
+// The page made of the documents fetched with `page_window`, in `sort` order.
+pub fn collect_page<T>(
+    mut docs: Vec<Document>,
+    sort: &PageSort,
+    request: &PageRequest,
+    key: &[u8],
+    convert: impl FnMut(Document) -> Result<T>,
+) -> Result<Page<T>> {
+    let limit = request.limit();
+    let has_next = docs.len() as i64 > limit;
+    docs.truncate(limit as usize);
+    let next_cursor = match docs.last() {
+        Some(doc) if has_next => Some(sort.position_of(doc)?.encode(sort, key)?),
+        _ => None,
+    };
+    Ok(Page {
+        items: docs.into_iter().map(convert).collect::<Result<Vec<_>>>()?,
+        next_cursor,
+        has_next,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use mongodm::bson::oid::ObjectId;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    const KEY: &[u8] = b"secret";
+
+    fn position() -> Position {
+        Position {
+            key: Bson::String(String::from("akari")),
+            id: Bson::ObjectId(ObjectId::new()),
+        }
+    }
+
+    #[test]
+    fn test_cursor_roundtrip() {
+        let sort = PageSort::asc("name");
+        let position = position();
+        let token = position.encode(&sort, KEY).unwrap();
+        assert_eq!(position, Position::decode(&token, &sort, KEY).unwrap());
+    }
+
+    #[test]
+    fn test_derive_cursor_key() {
+        let key = derive_cursor_key(KEY).unwrap();
+        assert_eq!(key, derive_cursor_key(KEY).unwrap());
+        assert_ne!(KEY, key.as_slice());
+        assert_ne!(key, derive_cursor_key(b"other").unwrap());
+    }
+
+    #[test]
+    fn test_cursor_with_other_key() {
+        let sort = PageSort::asc("name");
+        let token = position().encode(&sort, KEY).unwrap();
+        assert!(Position::decode(&token, &sort, b"other").is_err());
+    }
+
+    #[test]
+    fn test_cursor_tampered() {
+        let sort = PageSort::asc("name");
+        let token = position().encode(&sort, KEY).unwrap();
+        let other = position().encode(&sort, KEY).unwrap();
+        let tampered = format!(
+            "{}.{}",
+            token.split('.').next().unwrap(),
+            other.split('.').nth(1).unwrap()
+        );
+        assert!(Position::decode(&tampered, &sort, KEY).is_err());
+    }
+
+    #[test]
+    fn test_cursor_with_other_sort() {
+        let token = position().encode(&PageSort::asc("name"), KEY).unwrap();
+        assert!(Position::decode(&token, &PageSort::desc("name"), KEY).is_err());
+    }
+
+    #[test]
+    fn test_position_of() {
+        let id = Bson::ObjectId(ObjectId::new());
+        let doc = doc! { "_id": id.clone(), "profile": { "rank": 2 } };
+        assert_eq!(
+            Bson::Int32(2),
+            PageSort::asc("profile.rank").position_of(&doc).unwrap().key
+        );
+        assert_eq!(
+            Bson::Null,
+            PageSort::asc("profile.name").position_of(&doc).unwrap().key
+        );
+        assert_eq!(
+            doc! {
+                "$or": [
+                    { "rank": Bson::Null, "_id": { "$gt": id.clone() } },
+                    { "rank": { "$exists": true, "$ne": Bson::Null } },
+                ]
+            },
+            PageSort::asc("rank").after(&Position {
+                key: Bson::Null,
+                id
+            })
+        );
+    }
+
+    #[test]
+    fn test_page_limit() {
+        assert_eq!(DEFAULT_PAGE_LIMIT, PageRequest::default().limit());
+        assert_eq!(MAX_PAGE_LIMIT, PageRequest::new(None, Some(1000)).limit());
+        assert_eq!(1, PageRequest::new(None, Some(0)).limit());
+    }
+}
@@ -0,0 +1,63 @@
+use chrono::{Duration, Utc};
+use mongodm::{
+    bson::{Bson, Document},
+    doc,
+};
+
+pub const DELETED_AT: &str = "deleted_at";
+
+pub trait DeleteConfig {
+    fn soft_delete() -> bool;
+
+    #[inline]
+    fn purge_after_days() -> Option<i64> {
+        None
+    }
+}
+
+pub struct HardDelete {}
+
+impl DeleteConfig for HardDelete {
+    #[inline]
+    fn soft_delete() -> bool {
+        false
+    }
+}
+
+pub struct SoftDelete {}
+
+impl DeleteConfig for SoftDelete {
+    #[inline]
+    fn soft_delete() -> bool {
+        true
+    }
+}
+
+pub(crate) fn exclude_deleted<D: DeleteConfig>(query: Document) -> Document {
+    if D::soft_delete() {
+        not_deleted(query)
+    } else {
+        query
+    }
+}
+
+pub(crate) fn not_deleted(query: Document) -> Document {
+    doc! { "$and": [query, { DELETED_AT: Bson::Null }] }
+}
+
+pub(crate) fn only_deleted(query: Document) -> Document {
+    doc! { "$and": [query, { DELETED_AT: { "$type": "date" } }] }
+}
+
+pub(crate) fn mark_deleted() -> Document {
+    doc! { "$set": { DELETED_AT: Bson::DateTime(Utc::now()) } }
+}
+
+pub(crate) fn unmark_deleted() -> Document {
+    doc! { "$unset": { DELETED_AT: "" } }
+}
+
+pub(crate) fn deleted_before(days: i64) -> Document {
+    let limit = Utc::now() - Duration::days(days);
+    doc! { DELETED_AT: { "$lt": Bson::DateTime(limit) } }
+}
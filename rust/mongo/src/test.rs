@@ -2,31 +2,80 @@ use std::marker::PhantomData;
 
 use async_trait::async_trait;
 use mongodm::{
-    doc, f, mongo::options::FindOptions, /*operator::*, */ CollectionConfig, Indexes, Model,
+    bson::Bson, doc, f, mongo::options::FindOptions, /*operator::*, */ CollectionConfig,
+    Index, IndexOption, Indexes, Model,
 };
 use pretty_assertions::assert_eq;
 use serde::{Deserialize, Serialize};
 use validator::Validate;
 
 use crate::{
+    aggregate::Pipeline,
+    audit::{AuditEntry, AuditRepository, AuditStatus, Audited, DeleteAuditor, UpdateAuditor},
     context::{Context, MongodmContext},
+    define_model,
+    dump::{
+        check_collection, export_collection, import_documents, read_documents, IdMap, ModelRegistry,
+    },
+    encrypted::{Deterministic, Encrypted},
     fcomps::{
-        behavior::{Behave, BehaveDef},
+        behavior::{Behave, BehaveDef, Behavior},
         convert::Convertible,
         service::{CRUDHook, FromHookResult, HookResult},
         validate,
     },
-    service::{DeleteId, FindManyArgument, FindOneArgument, WithIdCRUDService},
-    test_util::with_mongo,
+    id::{AssignedId, GenerateObjectId, GenerateSlug, GenerateUuid, IdError, IdType},
+    index::{case_insensitive, NotUnique},
+    migration::Upcaster,
+    model_fields,
+    query::{Field, Fields, Filter, Projection, Query},
+    reference::{Loader, MissingRef, Ref, Refs},
+    revision::{Change, TrackRevisions},
+    schema::Schema,
+    search::search_index,
+    service::{
+        CreateManyArgument, DeleteId, FindManyArgument, FindOneArgument, PageRequest,
+        WatchArgument, WithIdAggregateService, WithIdCRUDService, WithIdCreateManyService,
+        WithIdDeleteBehavior, WithIdUpdateBehavior, WithIdWatchService,
+    },
+    softdelete::{mark_deleted, HardDelete},
+    test_util::{with_mongo, with_replica_set},
     utils::{result::Result, simple_error},
-    validate_uniqueness,
+    validate_refs, validate_uniqueness,
+    view::View,
+    watch::ChangeEvent,
     withid::{
-        CreateOrUpdate, Id, RepositoryWithId, RepositoryWithIdBase, ValidatedRepositoryWithId,
-        Validator, WithId,
+        CreateOrUpdate, DefaultValidate, Id, MongoRepositoryWithId, PatchError, RepositoryWithId,
+        RepositoryWithIdBase, ValidatedRepositoryWithId, Validator, WithId,
     },
 };
 
-#[derive(Serialize, Deserialize, Validate)]
+// Collection config and `Model` of a fixture model, stored in the collection
+// named after it.
+macro_rules! fixture_model {
+    ($model:ident, $cfg:ident) => {
+        fixture_model!($model, $cfg, Indexes::new());
+    };
+    ($model:ident, $cfg:ident, $indexes:expr) => {
+        struct $cfg {}
+
+        impl CollectionConfig for $cfg {
+            fn collection_name() -> &'static str {
+                stringify!($model)
+            }
+
+            fn indexes() -> Indexes {
+                $indexes
+            }
+        }
+
+        impl Model for $model {
+            type CollConf = $cfg;
+        }
+    };
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
 struct TricoUnit {
     #[validate(length(max = 10))]
     name: String,
@@ -67,6 +116,17 @@ impl Model for TricoUnit {
     type CollConf = TricoUnitCfg;
 }
 
+impl Schema for TricoUnit {}
+
+model_fields! {
+    TricoUnitFields for TricoUnit {
+        name: String,
+        cu: String,
+        co: String,
+        pa: String,
+    }
+}
+
 #[derive(Serialize, Deserialize, Validate)]
 pub struct User {
     name: String,
@@ -82,7 +142,11 @@ impl CollectionConfig for UserCfg {
     }
 
     fn indexes() -> Indexes {
-        Indexes::new()
+        Indexes::new().with(
+            Index::new("name")
+                .with_option(IndexOption::Unique)
+                .with_option(case_insensitive()),
+        )
     }
 }
 
@@ -90,6 +154,8 @@ impl Model for User {
     type CollConf = UserCfg;
 }
 
+impl Schema for User {}
+
 pub struct My<T>(T);
 
 struct TricoUnitInput {
@@ -605,3 +671,2091 @@ async fn test_find_many_fail_on_hook() {
     .await
     .unwrap()
 }
+
+#[tokio::test]
+async fn test_create_stamps_metadata() {
+    use crate::metadata::{CREATED_AT, CREATED_BY, UPDATED_AT};
+
+    with_mongo(|ctx| async move {
+        let repo = Repo::new(ctx.as_ref()).await;
+        let id = repo
+            .create(&TricoUnit::new("unibo", "akari", "akira", "riamu"))
+            .await?;
+        let doc = repo.coll.find_one(doc! { "_id": id }, None).await?.unwrap();
+        assert_eq!(doc.get_datetime(CREATED_AT)?, doc.get_datetime(UPDATED_AT)?);
+        assert_eq!(Some(&Bson::Null), doc.get(CREATED_BY));
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_update_keeps_created_at() {
+    use crate::metadata::{CREATED_AT, UPDATED_AT};
+
+    with_mongo(|ctx| async move {
+        let repo = Repo::new(ctx.as_ref()).await;
+        let id = repo.create(&TricoUnit::new("unibo", "", "", "")).await?;
+        let before = repo
+            .coll
+            .find_one(doc! { "_id": id.clone() }, None)
+            .await?
+            .unwrap();
+        repo.update(&WithId(
+            id.clone(),
+            TricoUnit::new("unibo", "akari", "akira", "riamu"),
+        ))
+        .await?;
+        let after = repo
+            .coll
+            .find_one(doc! { "_id": id.clone() }, None)
+            .await?
+            .unwrap();
+        assert_eq!(
+            before.get_datetime(CREATED_AT)?,
+            after.get_datetime(CREATED_AT)?
+        );
+        assert!(before.get_datetime(UPDATED_AT)? <= after.get_datetime(UPDATED_AT)?);
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+struct Agency {
+    name: String,
+    office: Option<String>,
+}
+
+fixture_model!(Agency, AgencyCfg);
+
+impl Schema for Agency {}
+
+// `update` replaces the document, so fields the model no longer has or
+// serializes as null do not keep their old values
+#[tokio::test]
+async fn test_update_replaces_document() {
+    use crate::metadata::CREATED_AT;
+
+    with_mongo(|ctx| async move {
+        let repo = RepositoryWithIdBase::<Agency, Context>::new(ctx.as_ref()).await;
+        let id = repo
+            .create(&Agency {
+                name: String::from("takeuchi"),
+                office: Some(String::from("346")),
+            })
+            .await?;
+        repo.coll
+            .update_one(
+                doc! { "_id": id.clone() },
+                doc! { "$set": { "legacy": 1 } },
+                None,
+            )
+            .await?;
+        let mut agency = repo.find_one_by_id(&id).await?.unwrap().1;
+        assert_eq!(Some(String::from("346")), agency.office);
+        agency.office = None;
+        agency.name = String::from("$name");
+        repo.update(&WithId(id.clone(), agency)).await?;
+
+        let doc = repo
+            .coll
+            .find_one(doc! { "_id": id.clone() }, None)
+            .await?
+            .unwrap();
+        assert_eq!(Some(&Bson::Null), doc.get("office"));
+        assert_eq!(None, doc.get("legacy"));
+        assert_eq!("$name", doc.get_str("name")?);
+        assert!(doc.get_datetime(CREATED_AT).is_ok());
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_find_many_sort_by_created_at() {
+    use crate::metadata::CREATED_AT;
+    use futures::TryStreamExt;
+
+    with_mongo(|ctx| async move {
+        let repo = Repo::new(ctx.as_ref()).await;
+        let first = repo
+            .create(&TricoUnit::new("nw", "sakura", "izumi", "ako"))
+            .await?;
+        let second = repo
+            .create(&TricoUnit::new("ng", "uzuki", "rin", "mio"))
+            .await?;
+        let option = FindOptions::builder()
+            .sort(doc! { CREATED_AT: -1, "_id": -1 })
+            .build();
+        let result = repo
+            .find_many(doc! {}, Some(option))
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        assert_eq!(2, result.len());
+        assert_eq!(second, result[0].0);
+        assert_eq!(first, result[1].0);
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_find_page() {
+    use crate::service::{PageRequest, PageSort};
+
+    with_mongo(|ctx| async move {
+        let repo = Repo::new(ctx.as_ref()).await;
+        for name in &["unibo", "nw", "ng", "tp"] {
+            repo.create(&TricoUnit::new(name, "cu", "co", "pa")).await?;
+        }
+        let sort = PageSort::desc("name");
+        let first = repo
+            .find_page(doc! {}, &sort, &PageRequest::new(None, Some(3)))
+            .await?;
+        assert_eq!(
+            vec!["unibo", "tp", "nw"],
+            first
+                .items
+                .iter()
+                .map(|u| &u.1.name[..])
+                .collect::<Vec<_>>()
+        );
+        assert!(first.has_next);
+        let second = repo
+            .find_page(
+                doc! {},
+                &sort,
+                &PageRequest::new(first.next_cursor, Some(3)),
+            )
+            .await?;
+        assert_eq!(
+            vec!["ng"],
+            second
+                .items
+                .iter()
+                .map(|u| &u.1.name[..])
+                .collect::<Vec<_>>()
+        );
+        assert!(!second.has_next);
+        assert_eq!(None, second.next_cursor);
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_find_page_with_same_sort_key() {
+    use crate::service::{PageRequest, PageSort};
+
+    with_mongo(|ctx| async move {
+        let repo = RepositoryWithIdBase::<TricoUnit, Context>::new(ctx.as_ref()).await;
+        let mut ids = Vec::new();
+        for _ in 0..3 {
+            ids.push(repo.create(&TricoUnit::new("nw", "cu", "co", "pa")).await?);
+        }
+        let sort = PageSort::asc("name");
+        let mut cursor = None;
+        let mut result = Vec::new();
+        loop {
+            let page = repo
+                .find_page(doc! {}, &sort, &PageRequest::new(cursor, Some(1)))
+                .await?;
+            result.extend(page.items.into_iter().map(|u| u.0));
+            if !page.has_next {
+                break;
+            }
+            cursor = page.next_cursor;
+        }
+        assert_eq!(ids, result);
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[derive(Debug, Serialize, Deserialize, Validate)]
+struct Ranked {
+    name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    profile: Option<Rank>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Rank {
+    rank: i32,
+}
+
+fixture_model!(Ranked, RankedCfg);
+
+impl Schema for Ranked {}
+
+async fn page_names(
+    repo: &RepositoryWithIdBase<Ranked, Context>,
+    sort: &crate::service::PageSort,
+) -> Result<Vec<String>> {
+    use crate::service::PageRequest;
+
+    let mut cursor = None;
+    let mut names = Vec::new();
+    loop {
+        let page = repo
+            .find_page(doc! {}, sort, &PageRequest::new(cursor, Some(1)))
+            .await?;
+        names.extend(page.items.into_iter().map(|item| item.1.name));
+        if !page.has_next {
+            return Ok(names);
+        }
+        cursor = page.next_cursor;
+    }
+}
+
+#[tokio::test]
+async fn test_find_page_with_missing_sort_key() {
+    use crate::service::PageSort;
+
+    with_mongo(|ctx| async move {
+        let repo = RepositoryWithIdBase::<Ranked, Context>::new(ctx.as_ref()).await;
+        for (name, rank) in &[
+            ("a", Some(2)),
+            ("b", None),
+            ("c", Some(1)),
+            ("d", None),
+            ("e", Some(3)),
+        ] {
+            repo.create(&Ranked {
+                name: String::from(*name),
+                profile: rank.map(|rank| Rank { rank }),
+            })
+            .await?;
+        }
+        assert_eq!(
+            vec!["b", "d", "c", "a", "e"],
+            page_names(&repo, &PageSort::asc("profile.rank")).await?
+        );
+        assert_eq!(
+            vec!["e", "a", "c", "d", "b"],
+            page_names(&repo, &PageSort::desc("profile.rank")).await?
+        );
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_find_page_with_invalid_cursor() {
+    use crate::service::{InvalidCursor, PageRequest, PageSort};
+
+    with_mongo(|ctx| async move {
+        let repo = Repo::new(ctx.as_ref()).await;
+        let request = PageRequest::new(Some(String::from("invalid")), None);
+        let err = repo
+            .find_page(doc! {}, &PageSort::default(), &request)
+            .await
+            .unwrap_err();
+        assert!(err.is::<InvalidCursor>());
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_find_many_with_query() {
+    use futures::TryStreamExt;
+
+    with_mongo(|ctx| async move {
+        let repo = Repo::new(ctx.as_ref()).await;
+        for (name, cu) in &[("nw", "sakura"), ("ng", "uzuki"), ("unibo", "akari")] {
+            repo.create(&TricoUnit::new(name, cu, "co", "pa")).await?;
+        }
+        let f = TricoUnit::fields();
+        let filter = Filter::any_of(vec![f.name.regex("^n", ""), f.cu.is_in(vec!["akari"])])
+            .and(f.name.ne("ng"));
+        let (doc, option) = Query::filter(filter).sort(f.name.desc()).build();
+        let result = repo
+            .find_many(doc, option)
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        assert_eq!(
+            vec!["unibo", "nw"],
+            result.iter().map(|u| &u.1.name[..]).collect::<Vec<_>>()
+        );
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_find_one_with_option() {
+    with_mongo(|ctx| async move {
+        let repo = Repo::new(ctx.as_ref()).await;
+        for name in &["nw", "unibo", "ng"] {
+            repo.create(&TricoUnit::new(name, "cu", "co", "pa")).await?;
+        }
+        let f = TricoUnit::fields();
+        let FindOneArgument(doc, option) = Query::filter(f.cu.eq("cu")).sort(f.name.desc()).into();
+        let value = repo.find_one(doc, option).await?.unwrap();
+        assert_eq!("unibo", value.1.name);
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[derive(Deserialize)]
+struct TricoUnitName {
+    name: String,
+    cu: Option<String>,
+}
+
+impl View for TricoUnitName {
+    type Model = TricoUnit;
+
+    fn projection() -> Option<Projection<TricoUnit>> {
+        Some(Projection::new().include(TricoUnit::fields().name))
+    }
+}
+
+#[tokio::test]
+async fn test_find_with_view() {
+    use futures::TryStreamExt;
+
+    with_mongo(|ctx| async move {
+        let repo = Repo::new(ctx.as_ref()).await;
+        let id = repo
+            .create(&TricoUnit::new("unibo", "akari", "akira", "riamu"))
+            .await?;
+        let value = repo
+            .find_one_view::<TricoUnitName>(doc! { "_id": id.clone() }, None)
+            .await?
+            .unwrap();
+        assert_eq!(id, value.0);
+        assert_eq!("unibo", value.1.name);
+        assert_eq!(None, value.1.cu);
+        let values = repo
+            .find_many_view::<TricoUnitName>(doc! {}, None)
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        assert_eq!(1, values.len());
+        assert_eq!(None, values[0].1.cu);
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[derive(Deserialize, Debug, PartialEq)]
+struct CuCount {
+    #[serde(rename = "_id")]
+    cu: String,
+    count: i32,
+}
+
+struct CountByCuInput {}
+
+impl FromHookResult<My<Option<User>>, CountByCuInput> for Pipeline<TricoUnit> {
+    fn from_hook_result(_: My<Option<User>>, _: CountByCuInput) -> Result<Self> {
+        Ok(count_by_cu())
+    }
+}
+
+fn count_by_cu() -> Pipeline<TricoUnit> {
+    Pipeline::new()
+        .group_by(TricoUnit::fields().cu, doc! { "count": { "$sum": 1 } })
+        .sort(Field::<TricoUnit, String>::new("_id").asc())
+}
+
+type CountByCuService = WithIdAggregateService<Repo, CountByCuInput, CuCount, BeforeHook>;
+
+async fn create_units(ctx: &Context) -> Result<()> {
+    let repo = Repo::new(ctx).await;
+    repo.create(&TricoUnit::new("nw", "sakura", "izumi", "ako"))
+        .await?;
+    repo.create(&TricoUnit::new("unibo", "akari", "akira", "riamu"))
+        .await?;
+    repo.create(&TricoUnit::new("bw", "akari", "tsukasa", "akira"))
+        .await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_aggregate() {
+    use futures::TryStreamExt;
+
+    with_mongo(|ctx| async move {
+        create_units(ctx.as_ref()).await?;
+        let result = CountByCuService::apply(CountByCuInput {}, ctx.as_ref())
+            .await
+            .result()?
+            .try_collect::<Vec<_>>()
+            .await?;
+        assert_eq!(
+            vec![
+                CuCount {
+                    cu: String::from("akari"),
+                    count: 2
+                },
+                CuCount {
+                    cu: String::from("sakura"),
+                    count: 1
+                },
+            ],
+            result
+        );
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_aggregate_fail_on_hook() {
+    with_mongo(|ctx| async move {
+        add_user("find-many", ctx.as_ref()).await;
+        create_units(ctx.as_ref()).await?;
+        assert!(CountByCuService::apply(CountByCuInput {}, ctx.as_ref())
+            .await
+            .result()
+            .is_err());
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+type SoftRepo = ValidatedRepositoryWithId<
+    TricoUnit,
+    Context,
+    TricoUnitValidator,
+    crate::softdelete::SoftDelete,
+>;
+
+#[tokio::test]
+async fn test_soft_delete_hides_document() {
+    with_mongo(|ctx| async move {
+        let repo = SoftRepo::new(ctx.as_ref()).await;
+        let id = repo
+            .create(&TricoUnit::new("unibo", "akari", "akira", "riamu"))
+            .await?;
+        repo.delete(&id).await?;
+        assert!(repo.find_one_by_id(&id).await?.is_none());
+        assert!(repo.delete(&id).await.is_err());
+        let c = repo.repo.count_documents(None, None).await?;
+        assert_eq!(1, c);
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_aggregate_hides_soft_deleted() {
+    use futures::TryStreamExt;
+
+    with_mongo(|ctx| async move {
+        let repo = SoftRepo::new(ctx.as_ref()).await;
+        repo.create(&TricoUnit::new("unibo", "akari", "akira", "riamu"))
+            .await?;
+        let id = repo
+            .create(&TricoUnit::new("bw", "akari", "tsukasa", "akira"))
+            .await?;
+        repo.delete(&id).await?;
+        let result = repo
+            .aggregate::<CuCount>(count_by_cu())
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        assert_eq!(1, result.len());
+        assert_eq!(1, result[0].count);
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_restore() {
+    use futures::TryStreamExt;
+
+    with_mongo(|ctx| async move {
+        let repo = SoftRepo::new(ctx.as_ref()).await;
+        let id = repo
+            .create(&TricoUnit::new("unibo", "akari", "akira", "riamu"))
+            .await?;
+        repo.delete(&id).await?;
+        let deleted = repo
+            .find_deleted(doc! {}, None)
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        assert_eq!(1, deleted.len());
+        repo.restore(&id).await?;
+        let value = repo.find_one_by_id(&id).await?.unwrap();
+        assert_eq!("unibo", value.1.name);
+        assert!(repo.restore(&id).await.is_err());
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_restore_with_hard_delete() {
+    with_mongo(|ctx| async move {
+        let repo = Repo::new(ctx.as_ref()).await;
+        let id = repo
+            .create(&TricoUnit::new("unibo", "akari", "akira", "riamu"))
+            .await?;
+        repo.delete(&id).await?;
+        assert!(repo.restore(&id).await.is_err());
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+struct PurgeImmediately {}
+
+impl crate::softdelete::DeleteConfig for PurgeImmediately {
+    fn soft_delete() -> bool {
+        true
+    }
+
+    fn purge_after_days() -> Option<i64> {
+        Some(0)
+    }
+}
+
+type PurgeRepo = RepositoryWithIdBase<TricoUnit, Context, TricoUnitValidator, PurgeImmediately>;
+
+#[tokio::test]
+async fn test_purge() {
+    with_mongo(|ctx| async move {
+        let repo = PurgeRepo::new(ctx.as_ref()).await;
+        let id = repo
+            .create(&TricoUnit::new("unibo", "akari", "akira", "riamu"))
+            .await?;
+        let _ = repo
+            .create(&TricoUnit::new("nw", "sakura", "izumi", "ako"))
+            .await?;
+        repo.delete(&id).await?;
+        assert_eq!(1, repo.purge().await?);
+        let c = repo.repo.count_documents(None, None).await?;
+        assert_eq!(1, c);
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_unique_index_violation() {
+    with_mongo(|ctx| async move {
+        ctx.sync_indexes::<User>().await?;
+        let repo = UserRepo::new(ctx.as_ref()).await;
+        let _ = repo
+            .create(&User {
+                name: String::from("akari"),
+            })
+            .await?;
+        let err = repo
+            .create(&User {
+                name: String::from("AKARI"),
+            })
+            .await
+            .unwrap_err();
+        assert_eq!("name", err.downcast_ref::<NotUnique>().unwrap().field);
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_validate_uniqueness_error() {
+    with_mongo(|ctx| async move {
+        let repo = Repo::new(ctx.as_ref()).await;
+        let _ = repo.create(&TricoUnit::new("unibo", "", "", "")).await?;
+        let err = repo
+            .create(&TricoUnit::new("unibo", "akari", "akira", "riamu"))
+            .await
+            .unwrap_err();
+        assert_eq!("name", err.downcast_ref::<NotUnique>().unwrap().field);
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[derive(Serialize, Deserialize)]
+struct Idol {
+    name: String,
+    unit: String,
+}
+
+fixture_model!(Idol, IdolCfg);
+
+impl Schema for Idol {
+    fn upcasters() -> Vec<(i32, Upcaster)> {
+        vec![(1, rename_unit_name)]
+    }
+}
+
+fn rename_unit_name(mut doc: mongodm::bson::Document) -> Result<mongodm::bson::Document> {
+    if let Some(unit) = doc.remove("unit_name") {
+        doc.insert("unit", unit);
+    }
+    Ok(doc)
+}
+
+#[tokio::test]
+async fn test_lazy_upcast() {
+    with_mongo(|ctx| async move {
+        let coll = ctx.database().collection("Idol");
+        let _ = coll
+            .insert_one(doc! { "name": "akari", "unit_name": "unibo" }, None)
+            .await?;
+        let repo = RepositoryWithIdBase::<Idol, Context>::new(ctx.as_ref()).await;
+        let value = repo
+            .find_one(doc! { "name": "akari" }, None)
+            .await?
+            .unwrap();
+        assert_eq!("unibo", value.1.unit);
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_migrator() {
+    use crate::migration::{MigrationReport, Migrator, UpcastMigration, MIGRATIONS_COLLECTION};
+
+    with_mongo(|ctx| async move {
+        let db = ctx.database();
+        let _ = db
+            .collection("Idol")
+            .insert_one(doc! { "name": "akari", "unit_name": "unibo" }, None)
+            .await?;
+        let migrator = Migrator::new().with(UpcastMigration::<Idol>::new("idol_unit"));
+
+        let reports = migrator.run(&db, true).await?;
+        assert_eq!(
+            vec![MigrationReport {
+                name: String::from("idol_unit"),
+                affected: 1,
+                applied: false,
+            }],
+            reports
+        );
+        let raw = db.collection("Idol").find_one(None, None).await?.unwrap();
+        assert!(raw.get("unit_name").is_some());
+        assert_eq!(vec!["idol_unit"], migrator.pending(&db).await?);
+
+        let reports = migrator.run(&db, false).await?;
+        assert_eq!(1, reports[0].affected);
+        let raw = db.collection("Idol").find_one(None, None).await?.unwrap();
+        assert_eq!("unibo", raw.get_str("unit")?);
+        let c = db
+            .collection(MIGRATIONS_COLLECTION)
+            .count_documents(None, None)
+            .await?;
+        assert_eq!(1, c);
+
+        assert!(migrator.run(&db, false).await?.is_empty());
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[derive(Deserialize)]
+struct UnitWithIdols {
+    name: String,
+    idols: Vec<Idol>,
+}
+
+#[tokio::test]
+async fn test_lookup_skips_deleted_and_upcasts() {
+    use futures::TryStreamExt;
+
+    with_mongo(|ctx| async move {
+        Repo::new(ctx.as_ref())
+            .await
+            .create(&TricoUnit::new("unibo", "akari", "akira", "riamu"))
+            .await?;
+        let _ = ctx
+            .database()
+            .collection("Idol")
+            .insert_many(
+                vec![
+                    doc! { "name": "akari", "unit_name": "unibo" },
+                    doc! { "name": "akari", "unit": "bw", "deleted_at": Bson::DateTime(chrono::Utc::now()) },
+                ],
+                None,
+            )
+            .await?;
+        let pipeline = Pipeline::<TricoUnit>::new().lookup(
+            TricoUnit::fields().cu,
+            Field::<Idol, String>::new("name"),
+            "idols",
+        );
+        let result = Repo::new(ctx.as_ref())
+            .await
+            .aggregate::<UnitWithIdols>(pipeline)
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        assert_eq!("unibo", result[0].name);
+        assert_eq!(
+            vec!["unibo"],
+            result[0]
+                .idols
+                .iter()
+                .map(|idol| idol.unit.as_str())
+                .collect::<Vec<_>>()
+        );
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[derive(Serialize, Deserialize, Validate)]
+struct Card {
+    name: String,
+    owner: Ref<User>,
+}
+
+fixture_model!(Card, CardCfg);
+
+impl Schema for Card {}
+
+impl Refs<User> for Card {
+    fn refs(&self) -> Vec<&Ref<User>> {
+        vec![&self.owner]
+    }
+}
+
+struct CardValidator {}
+
+#[async_trait(?Send)]
+impl Validator for CardValidator {
+    type Model = Card;
+    type Ctx = Context;
+
+    async fn validate(_: CreateOrUpdate, _: &'_ Card, _: &'_ Context) -> Result<()> {
+        Ok(())
+    }
+
+    async fn validate_refs(model: &'_ Card, ctx: &'_ Context) -> Result<()> {
+        validate_refs!(<User>, Some(&model.owner), ctx);
+        Ok(())
+    }
+}
+
+type CardRepo = ValidatedRepositoryWithId<Card, Context, CardValidator>;
+
+#[tokio::test]
+async fn test_create_with_missing_ref() {
+    with_mongo(|ctx| async move {
+        let repo = CardRepo::new(ctx.as_ref()).await;
+        let owner = Ref::new(Id::new());
+        let err = repo
+            .create(&Card {
+                name: String::from("akari"),
+                owner: owner.clone(),
+            })
+            .await
+            .err()
+            .unwrap();
+        let err = err.downcast_ref::<MissingRef>().unwrap();
+        assert_eq!("User", err.collection);
+        assert_eq!(owner.id().to_bson(), err.id);
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_create_with_deleted_ref() {
+    with_mongo(|ctx| async move {
+        let users = UserRepo::new(ctx.as_ref()).await;
+        let owner = users
+            .create(&User {
+                name: String::from("p"),
+            })
+            .await?;
+        users
+            .coll
+            .update_one(doc! { "_id": owner.clone() }, mark_deleted(), None)
+            .await?;
+        let err = CardRepo::new(ctx.as_ref())
+            .await
+            .create(&Card {
+                name: String::from("akari"),
+                owner: Ref::new(owner),
+            })
+            .await
+            .err()
+            .unwrap();
+        assert!(err.is::<MissingRef>());
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_update_with_missing_ref() {
+    with_mongo(|ctx| async move {
+        let owner = UserRepo::new(ctx.as_ref())
+            .await
+            .create(&User {
+                name: String::from("p"),
+            })
+            .await?;
+        let repo = CardRepo::new(ctx.as_ref()).await;
+        let id = repo
+            .create(&Card {
+                name: String::from("akari"),
+                owner: Ref::new(owner),
+            })
+            .await?;
+        let card = Card {
+            name: String::from("akari"),
+            owner: Ref::new(Id::new()),
+        };
+        let err = repo.update(&WithId(id, card)).await.err().unwrap();
+        assert!(err.is::<MissingRef>());
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_populate() {
+    with_mongo(|ctx| async move {
+        let users = UserRepo::new(ctx.as_ref()).await;
+        let akari = users
+            .create(&User {
+                name: String::from("akari"),
+            })
+            .await?;
+        let riamu = users
+            .create(&User {
+                name: String::from("riamu"),
+            })
+            .await?;
+        let repo = CardRepo::new(ctx.as_ref()).await;
+        for (name, owner) in &[("smile", &akari), ("yumekawa", &riamu), ("ganbari", &akari)] {
+            repo.create(&Card {
+                name: String::from(*name),
+                owner: Ref::new((*owner).clone()),
+            })
+            .await?;
+        }
+        let mut loader = Loader::<UserRepo>::new(ctx.as_ref()).await;
+        let cards = loader
+            .load_stream(repo.find_many(doc! {}, None).await?)
+            .await?;
+        assert_eq!(3, cards.len());
+        for card in &cards {
+            let owner = loader.get(&card.1.owner).unwrap();
+            assert_eq!(card.1.owner.id(), &owner.0);
+        }
+        let names = cards
+            .iter()
+            .map(|card| loader.get(&card.1.owner).unwrap().1.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["akari", "riamu", "akari"], names);
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[derive(Serialize, Deserialize, Validate, Debug, PartialEq)]
+struct ShareLink {
+    target: String,
+}
+
+fixture_model!(ShareLink, ShareLinkCfg);
+
+impl Schema for ShareLink {}
+
+model_fields! {
+    ShareLinkFields for ShareLink, id: String {
+        target: String,
+    }
+}
+
+type SlugRepo = RepositoryWithIdBase<
+    ShareLink,
+    Context,
+    DefaultValidate<ShareLink, Context>,
+    HardDelete,
+    GenerateSlug,
+>;
+
+#[tokio::test]
+async fn test_slug_id() {
+    with_mongo(|ctx| async move {
+        let repo = SlugRepo::new(ctx.as_ref()).await;
+        let id = repo
+            .create(&ShareLink {
+                target: String::from("akari"),
+            })
+            .await?;
+        let link = repo.find_one_by_id(&id).await?.unwrap();
+        assert_eq!(id, link.0);
+        assert_eq!("akari", link.1.target);
+        let link = repo
+            .find_one(ShareLink::fields().id.eq(id.clone()).into_document(), None)
+            .await?
+            .unwrap();
+        assert_eq!(id, link.0);
+        repo.update(&WithId(
+            id.clone(),
+            ShareLink {
+                target: String::from("riamu"),
+            },
+        ))
+        .await?;
+        assert_eq!("riamu", repo.find_one_by_id(&id).await?.unwrap().1.target);
+        repo.delete(&id).await?;
+        assert!(repo.find_one_by_id(&id).await?.is_none());
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+type UuidRepo = RepositoryWithIdBase<
+    ShareLink,
+    Context,
+    DefaultValidate<ShareLink, Context>,
+    HardDelete,
+    GenerateUuid,
+>;
+
+#[tokio::test]
+async fn test_uuid_id() {
+    use futures::TryStreamExt;
+
+    with_mongo(|ctx| async move {
+        let repo = UuidRepo::new(ctx.as_ref()).await;
+        let id = repo
+            .create(&ShareLink {
+                target: String::from("akari"),
+            })
+            .await?;
+        let raw = ctx
+            .repo::<ShareLink>()
+            .get_underlying()
+            .find_one(None, None)
+            .await?
+            .unwrap();
+        assert_eq!(Some(&id.to_bson()), raw.get("_id"));
+        assert!(matches!(raw.get("_id"), Some(Bson::Binary(_))));
+        assert!(repo.find_one_by_id(&id).await?.is_some());
+        let links = repo
+            .find_many(doc! {}, None)
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        assert_eq!(1, links.len());
+        assert_eq!(id, links[0].0);
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[derive(Serialize, Deserialize, Validate)]
+struct ShareVisit {
+    link: Ref<ShareLink, uuid::Uuid>,
+}
+
+fixture_model!(ShareVisit, ShareVisitCfg);
+
+impl Schema for ShareVisit {}
+
+#[derive(Deserialize)]
+struct VisitWithLinks {
+    links: Vec<ShareLink>,
+}
+
+#[tokio::test]
+async fn test_lookup_uuid_ref() {
+    use futures::TryStreamExt;
+
+    with_mongo(|ctx| async move {
+        let id = UuidRepo::new(ctx.as_ref())
+            .await
+            .create(&ShareLink {
+                target: String::from("akari"),
+            })
+            .await?;
+        let repo = RepositoryWithIdBase::<ShareVisit, Context>::new(ctx.as_ref()).await;
+        let visit = repo
+            .create(&ShareVisit {
+                link: Ref::new(id.clone()),
+            })
+            .await?;
+        let raw = ctx
+            .repo::<ShareVisit>()
+            .get_underlying()
+            .find_one(None, None)
+            .await?
+            .unwrap();
+        assert_eq!(Some(&id.to_bson()), raw.get("link"));
+        assert_eq!(&id, repo.find_one_by_id(&visit).await?.unwrap().1.link.id());
+
+        type LinkRef = Ref<ShareLink, uuid::Uuid>;
+        let pipeline = Pipeline::<ShareVisit>::new().lookup(
+            Field::<ShareVisit, LinkRef>::new("link"),
+            Field::<ShareLink, LinkRef>::new("_id"),
+            "links",
+        );
+        let result = repo
+            .aggregate::<VisitWithLinks>(pipeline)
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        assert_eq!(
+            vec![ShareLink {
+                target: String::from("akari"),
+            }],
+            result[0].links
+        );
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+type AssignedRepo = RepositoryWithIdBase<
+    ShareLink,
+    Context,
+    DefaultValidate<ShareLink, Context>,
+    HardDelete,
+    AssignedId<String>,
+>;
+
+#[tokio::test]
+async fn test_assigned_id() {
+    with_mongo(|ctx| async move {
+        let repo = AssignedRepo::new(ctx.as_ref()).await;
+        let link = ShareLink {
+            target: String::from("akari"),
+        };
+        let err = repo.create(&link).await.err().unwrap();
+        assert!(matches!(
+            err.downcast_ref::<IdError>(),
+            Some(IdError::NotAssigned)
+        ));
+        let id = String::from("nw-2020");
+        repo.create_with_id(&id, &link).await?;
+        assert_eq!(link, repo.find_one_by_id(&id).await?.unwrap().1);
+        assert!(repo.create_with_id(&id, &link).await.is_err());
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_find_with_mismatched_id_type() {
+    with_mongo(|ctx| async move {
+        let repo = AssignedRepo::new(ctx.as_ref()).await;
+        repo.create_with_id(
+            &String::from("nw-2020"),
+            &ShareLink {
+                target: String::from("akari"),
+            },
+        )
+        .await?;
+        let err = UuidRepo::new(ctx.as_ref())
+            .await
+            .find_one(doc! {}, None)
+            .await
+            .err()
+            .unwrap();
+        assert!(err.is::<IdError>());
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_create_many() {
+    with_mongo(|ctx| async move {
+        let repo = Repo::new(ctx.as_ref()).await;
+        repo.create(&TricoUnit::new("nw", "sakura", "izumi", "ako"))
+            .await?;
+        let result = repo
+            .create_many(&[
+                TricoUnit::new("unibo", "akari", "akira", "riamu"),
+                TricoUnit::new("nw", "sakura", "izumi", "ako"),
+                TricoUnit::new("too long unit name", "", "", ""),
+                TricoUnit::new("bw", "akari", "tsukasa", "akira"),
+            ])
+            .await?;
+        assert!(!result.is_complete());
+        assert_eq!(
+            vec![0, 3],
+            result.succeeded().map(|(i, _)| *i).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![1, 2],
+            result.failed().map(|(i, _)| *i).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            "name",
+            result.items[1]
+                .1
+                .as_ref()
+                .unwrap_err()
+                .downcast_ref::<NotUnique>()
+                .unwrap()
+                .field
+        );
+        for (_, id) in result.succeeded() {
+            assert!(repo.find_one_by_id(id).await?.is_some());
+        }
+        assert_eq!(3, repo.repo.count_documents(None, None).await?);
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_update_many() {
+    use futures::TryStreamExt;
+
+    with_mongo(|ctx| async move {
+        create_units(ctx.as_ref()).await?;
+        let repo = Repo::new(ctx.as_ref()).await;
+        let result = repo
+            .update_many(doc! { "cu": "akari" }, doc! { "pa": "ako" })
+            .await?;
+        assert!(result.is_complete());
+        assert_eq!(2, result.items.len());
+        let units = repo
+            .find_many(doc! { "pa": "ako" }, None)
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        assert_eq!(3, units.len());
+
+        let result = repo
+            .update_many(doc! { "cu": "akari" }, doc! { "name": "bw" })
+            .await?;
+        assert_eq!(1, result.succeeded().count());
+        let (id, err) = result.failed().next().unwrap();
+        assert_eq!("name", err.downcast_ref::<NotUnique>().unwrap().field);
+        let value = repo.find_one_by_id(id).await?.unwrap();
+        assert_eq!("unibo", value.1.name);
+
+        // a unique value set on the whole batch is taken by the first one
+        let result = repo
+            .update_many(doc! { "cu": "akari" }, doc! { "name": "ng" })
+            .await?;
+        assert_eq!(1, result.succeeded().count());
+        let (_, err) = result.failed().next().unwrap();
+        assert_eq!("name", err.downcast_ref::<NotUnique>().unwrap().field);
+
+        for patch in vec![
+            doc! { "$set": { "pa": "ako" } },
+            doc! { "profile.pa": "ako" },
+            doc! { "_id": 1 },
+        ] {
+            let err = repo
+                .update_many(doc! { "cu": "akari" }, patch)
+                .await
+                .unwrap_err();
+            assert!(err.is::<PatchError>());
+        }
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_update_many_upcasts() {
+    with_mongo(|ctx| async move {
+        let coll = ctx.database().collection("Idol");
+        coll.insert_one(doc! { "name": "akari", "unit_name": "unibo" }, None)
+            .await?;
+        let repo = RepositoryWithIdBase::<Idol, Context>::new(ctx.as_ref()).await;
+        let result = repo
+            .update_many(doc! { "name": "akari" }, doc! { "name": "akira" })
+            .await?;
+        assert!(result.is_complete());
+        let raw = coll.find_one(None, None).await?.unwrap();
+        assert_eq!(
+            (Some("akira"), Some("unibo"), Some(&Bson::Int32(1))),
+            (
+                raw.get_str("name").ok(),
+                raw.get_str("unit").ok(),
+                raw.get("_v")
+            )
+        );
+        assert!(!raw.contains_key("unit_name"));
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_delete_many() {
+    use futures::TryStreamExt;
+
+    with_mongo(|ctx| async move {
+        create_units(ctx.as_ref()).await?;
+        let repo = Repo::new(ctx.as_ref()).await;
+        assert_eq!(2, repo.delete_many(doc! { "cu": "akari" }).await?);
+        assert_eq!(1, repo.repo.count_documents(None, None).await?);
+
+        let repo = SoftRepo::new(ctx.as_ref()).await;
+        repo.create(&TricoUnit::new("unibo", "akari", "akira", "riamu"))
+            .await?;
+        assert_eq!(1, repo.delete_many(doc! { "cu": "akari" }).await?);
+        assert_eq!(0, repo.delete_many(doc! { "cu": "akari" }).await?);
+        let deleted = repo
+            .find_deleted(doc! {}, None)
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        assert_eq!(1, deleted.len());
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+impl FromHookResult<My<Option<User>>, Vec<TricoUnitInput>> for CreateManyArgument<TricoUnit> {
+    fn from_hook_result(_: My<Option<User>>, input: Vec<TricoUnitInput>) -> Result<Self> {
+        Ok(Self(
+            input
+                .into_iter()
+                .map(|i| TricoUnit::new(i.name, i.cu, i.co, i.pa))
+                .collect(),
+        ))
+    }
+}
+
+type CreateUnitsService = WithIdCreateManyService<Repo, Vec<TricoUnitInput>, BeforeHook>;
+
+#[tokio::test]
+async fn test_create_many_service() {
+    with_mongo(|ctx| async move {
+        let result = CreateUnitsService::apply(
+            vec![
+                TricoUnitInput::new("unibo", "akari", "akira", "riamu"),
+                TricoUnitInput::new("bw", "akari", "tsukasa", "akira"),
+            ],
+            ctx.as_ref(),
+        )
+        .await
+        .result()?;
+        assert!(result.is_complete());
+        assert_eq!(2, result.items.len());
+
+        add_user("create", ctx.as_ref()).await;
+        assert!(CreateUnitsService::apply(
+            vec![TricoUnitInput::new("nw", "sakura", "izumi", "ako")],
+            ctx.as_ref(),
+        )
+        .await
+        .result()
+        .is_err());
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+impl FromHookResult<My<Option<User>>, FindManyInput> for WatchArgument {
+    fn from_hook_result(h: My<Option<User>>, input: FindManyInput) -> Result<Self> {
+        Ok(Self(FindManyArgument::from_hook_result(h, input)?.0, None))
+    }
+}
+
+type WatchUnitsService = WithIdWatchService<Repo, FindManyInput, BeforeHook>;
+
+async fn check_watch(ctx: &Context) -> Result<()> {
+    use futures::StreamExt;
+
+    let repo = Repo::new(ctx).await;
+    let other = repo
+        .create(&TricoUnit::new("nw", "sakura", "izumi", "ako"))
+        .await?;
+    let mut changes = WatchUnitsService::apply(
+        FindManyInput {
+            cu: String::from("akari"),
+            order: Order::Id,
+        },
+        ctx,
+    )
+    .await
+    .result()?;
+    // neither is delivered, as the document never matched
+    repo.update(&WithId(
+        other.clone(),
+        TricoUnit::new("nw", "sakura", "izumi", "riamu"),
+    ))
+    .await?;
+    repo.delete(&other).await?;
+    let id = repo
+        .create(&TricoUnit::new("unibo", "akari", "akira", "riamu"))
+        .await?;
+    match changes.next().await.unwrap()?.event {
+        ChangeEvent::Inserted(unit) => assert_eq!("unibo", unit.1.name),
+        event => panic!("unexpected event: {:?}", event),
+    }
+
+    repo.update(&WithId(
+        id.clone(),
+        TricoUnit::new("unibo", "akari", "akira", "ako"),
+    ))
+    .await?;
+    match changes.next().await.unwrap()?.event {
+        ChangeEvent::Updated(unit) => assert_eq!("ako", unit.1.pa),
+        event => panic!("unexpected event: {:?}", event),
+    }
+
+    // stops matching the filter
+    repo.update(&WithId(
+        id.clone(),
+        TricoUnit::new("unibo", "sakura", "akira", "ako"),
+    ))
+    .await?;
+    match changes.next().await.unwrap()?.event {
+        ChangeEvent::Deleted(deleted) => assert_eq!(id, deleted),
+        event => panic!("unexpected event: {:?}", event),
+    }
+
+    repo.update(&WithId(
+        id.clone(),
+        TricoUnit::new("unibo", "akari", "akira", "ako"),
+    ))
+    .await?;
+    match changes.next().await.unwrap()?.event {
+        ChangeEvent::Inserted(unit) => assert_eq!(id, unit.0),
+        event => panic!("unexpected event: {:?}", event),
+    }
+
+    repo.delete(&id).await?;
+    match changes.next().await.unwrap()?.event {
+        ChangeEvent::Deleted(deleted) => assert_eq!(id, deleted),
+        event => panic!("unexpected event: {:?}", event),
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_watch() {
+    with_mongo(|ctx| async move { check_watch(ctx.as_ref()).await })
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_watch_with_change_stream() {
+    with_replica_set(|ctx| async move { check_watch(ctx.as_ref()).await })
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_watch_fail_on_hook() {
+    with_mongo(|ctx| async move {
+        add_user("find-many", ctx.as_ref()).await;
+        assert!(WatchUnitsService::apply(
+            FindManyInput {
+                cu: String::from("akari"),
+                order: Order::Id,
+            },
+            ctx.as_ref(),
+        )
+        .await
+        .result()
+        .is_err());
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_watch_resume() {
+    use futures::StreamExt;
+
+    with_mongo(|ctx| async move {
+        let repo = Repo::new(ctx.as_ref()).await;
+        let mut changes = repo.watch(doc! {}, None).await?;
+        repo.create(&TricoUnit::new("unibo", "akari", "akira", "riamu"))
+            .await?;
+        let token = changes.next().await.unwrap()?.token;
+        drop(changes);
+
+        repo.create(&TricoUnit::new("bw", "akari", "tsukasa", "akira"))
+            .await?;
+        let mut changes = repo.watch(doc! {}, Some(token)).await?;
+        let mut names = Vec::new();
+        for _ in 0..2 {
+            match changes.next().await.unwrap()?.event {
+                ChangeEvent::Inserted(unit) => names.push(unit.1.name),
+                event => panic!("unexpected event: {:?}", event),
+            }
+        }
+        assert_eq!(vec!["unibo", "bw"], names);
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+// Changes from before the token were never delivered, whatever matches now.
+#[tokio::test]
+async fn test_watch_resume_with_change_stream() {
+    use futures::StreamExt;
+
+    with_replica_set(|ctx| async move {
+        let repo = Repo::new(ctx.as_ref()).await;
+        let mut changes = repo.watch(doc! {}, None).await?;
+        let id = repo
+            .create(&TricoUnit::new("unibo", "akari", "akira", "riamu"))
+            .await?;
+        let token = changes.next().await.unwrap()?.token;
+        drop(changes);
+
+        repo.create(&TricoUnit::new("bw", "akari", "tsukasa", "akira"))
+            .await?;
+        repo.update(&WithId(
+            id.clone(),
+            TricoUnit::new("unibo", "akari", "akira", "ako"),
+        ))
+        .await?;
+        repo.delete(&id).await?;
+        let mut changes = repo.watch(doc! {}, Some(token)).await?;
+        match changes.next().await.unwrap()?.event {
+            ChangeEvent::Inserted(unit) => assert_eq!("bw", unit.1.name),
+            event => panic!("unexpected event: {:?}", event),
+        }
+        // already gone when the update is looked up
+        match changes.next().await.unwrap()?.event {
+            ChangeEvent::Deleted(deleted) => assert_eq!(id, deleted),
+            event => panic!("unexpected event: {:?}", event),
+        }
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SearchIdol {
+    name: String,
+    kana: String,
+}
+
+impl SearchIdol {
+    fn new(name: impl AsRef<str>, kana: impl AsRef<str>) -> Self {
+        Self {
+            name: String::from(name.as_ref()),
+            kana: String::from(kana.as_ref()),
+        }
+    }
+}
+
+fixture_model!(
+    SearchIdol,
+    SearchIdolCfg,
+    Indexes::new().with(search_index())
+);
+
+impl Schema for SearchIdol {
+    const SEARCH_FIELDS: &'static [&'static str] = &["name", "kana"];
+}
+
+type SearchRepo = RepositoryWithIdBase<SearchIdol, Context>;
+
+async fn search_names(repo: &SearchRepo, text: &str) -> Result<Vec<String>> {
+    Ok(repo
+        .search(doc! {}, text, &PageRequest::default())
+        .await?
+        .items
+        .into_iter()
+        .map(|idol| idol.1.name)
+        .collect())
+}
+
+#[tokio::test]
+async fn test_search() {
+    with_mongo(|ctx| async move {
+        ctx.sync_indexes::<SearchIdol>().await?;
+        let repo = SearchRepo::new(ctx.as_ref()).await;
+        for (name, kana) in &[
+            ("Akari", "アカリ"),
+            ("Akane", "アカネ"),
+            ("Riamu", "リアム"),
+        ] {
+            repo.create(&SearchIdol::new(name, kana)).await?;
+        }
+
+        assert_eq!(vec!["Akari", "Akane"], search_names(&repo, "あかり").await?);
+        assert_eq!(vec!["Akari", "Akane"], search_names(&repo, "ｱｶﾘ").await?);
+        assert_eq!(vec!["Riamu"], search_names(&repo, "ＲＩＡＭ").await?);
+        assert!(search_names(&repo, "  ").await?.is_empty());
+
+        let riamu = repo
+            .find_one(doc! { "name": "Riamu" }, None)
+            .await?
+            .unwrap();
+        repo.update(&WithId(riamu.0, SearchIdol::new("Riamu", "ユメミ")))
+            .await?;
+        assert_eq!(vec!["Riamu"], search_names(&repo, "ゆめみ").await?);
+        repo.update_many(doc! { "name": "Riamu" }, doc! { "kana": "リアム" })
+            .await?;
+        assert!(search_names(&repo, "ゆめみ").await?.is_empty());
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_search_page() {
+    with_mongo(|ctx| async move {
+        let repo = SearchRepo::new(ctx.as_ref()).await;
+        for name in &["akari", "akane", "akagi", "sakaki"] {
+            repo.create(&SearchIdol::new(name, "")).await?;
+        }
+        let first = repo
+            .search(doc! {}, "aka", &PageRequest::new(None, Some(2)))
+            .await?;
+        assert!(first.has_next);
+        let second = repo
+            .search(
+                doc! {},
+                "aka",
+                &PageRequest::new(first.next_cursor, Some(2)),
+            )
+            .await?;
+        assert!(!second.has_next);
+        let mut names = first
+            .items
+            .into_iter()
+            .chain(second.items)
+            .map(|idol| idol.1.name)
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(vec!["akagi", "akane", "akari", "sakaki"], names);
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+async fn audit_entries(ctx: &Context, target: &Id) -> Result<Vec<WithId<AuditEntry>>> {
+    use futures::TryStreamExt;
+
+    let entries = AuditRepository::<Context>::new(ctx)
+        .await
+        .find_many(
+            doc! { "collection": "TricoUnit", "target": target.to_hex() },
+            Some(
+                FindOptions::builder()
+                    .sort(doc! { "at": 1, "_id": 1 })
+                    .build(),
+            ),
+        )
+        .await?
+        .try_collect()
+        .await?;
+    Ok(entries)
+}
+
+#[tokio::test]
+async fn test_audited_update_and_delete() {
+    type Update = Audited<WithIdUpdateBehavior<Repo>, UpdateAuditor<Repo>>;
+    type Delete = Audited<WithIdDeleteBehavior<Repo>, DeleteAuditor<Repo>>;
+
+    with_mongo(|ctx| async move {
+        let repo = Repo::new(ctx.as_ref()).await;
+        let id = repo
+            .create(&TricoUnit::new("unibo", "akari", "", ""))
+            .await?;
+        Update::apply(
+            WithId(id.clone(), TricoUnit::new("unibo", "akari", "akira", "")),
+            ctx.as_ref(),
+        )
+        .await
+        .result()?;
+        Delete::apply(DeleteId(id.clone()), ctx.as_ref())
+            .await
+            .result()?;
+        let entries = audit_entries(ctx.as_ref(), &id).await?;
+        assert_eq!(
+            vec![
+                ("update", AuditStatus::Pending),
+                ("update", AuditStatus::Done),
+                ("delete", AuditStatus::Pending),
+                ("delete", AuditStatus::Done),
+            ],
+            entries
+                .iter()
+                .map(|entry| (entry.1.operation.as_str(), entry.1.status))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(Some(entries[0].0.clone()), entries[1].1.outcome_of);
+        assert_eq!(Some(entries[2].0.clone()), entries[3].1.outcome_of);
+        assert_eq!(
+            doc! { "co": { "before": "", "after": "akira" } },
+            entries[1].1.diff
+        );
+        assert_eq!(None, entries[1].1.actor);
+        assert_eq!(
+            Some(&doc! { "before": "unibo", "after": Bson::Null }),
+            entries[3].1.diff.get_document("name").ok()
+        );
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_audited_records_failed_behavior() {
+    type Update = Audited<WithIdUpdateBehavior<Repo>, UpdateAuditor<Repo>>;
+
+    with_mongo(|ctx| async move {
+        let repo = Repo::new(ctx.as_ref()).await;
+        let id = repo.create(&TricoUnit::new("unibo", "", "", "")).await?;
+        Update::apply(
+            WithId(
+                id.clone(),
+                TricoUnit::new("theunitnameistoolog", "", "", ""),
+            ),
+            ctx.as_ref(),
+        )
+        .await
+        .result()
+        .unwrap_err();
+        let entries = audit_entries(ctx.as_ref(), &id).await?;
+        assert_eq!(
+            vec![AuditStatus::Pending, AuditStatus::Failed],
+            entries
+                .iter()
+                .map(|entry| entry.1.status)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(Some(entries[0].0.clone()), entries[1].1.outcome_of);
+        assert!(entries[1].1.error.is_some());
+        assert!(entries[1].1.diff.is_empty());
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+type RevisionRepo = ValidatedRepositoryWithId<
+    TricoUnit,
+    Context,
+    TricoUnitValidator,
+    HardDelete,
+    GenerateObjectId,
+    TrackRevisions,
+>;
+
+#[tokio::test]
+async fn test_revisions() {
+    with_mongo(|ctx| async move {
+        let repo = RevisionRepo::new(ctx.as_ref()).await;
+        let id = repo
+            .create(&TricoUnit::new("unibo", "akari", "", ""))
+            .await?;
+        repo.update(&WithId(
+            id.clone(),
+            TricoUnit::new("unibo", "akari", "akira", ""),
+        ))
+        .await?;
+        repo.update_many(doc! { "name": "unibo" }, doc! { "pa": "riamu" })
+            .await?;
+        let revisions = repo.list_revisions(&id).await?;
+        assert_eq!(
+            vec![("akira", ""), ("", "")],
+            revisions
+                .iter()
+                .map(|revision| revision.1.model())
+                .collect::<Result<Vec<TricoUnit>>>()?
+                .iter()
+                .map(|unit| (unit.co.as_str(), unit.pa.as_str()))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![Change {
+                path: String::from("co"),
+                before: Some(Bson::from("")),
+                after: Some(Bson::from("akira")),
+            }],
+            repo.diff_revisions(&revisions[1].0, &revisions[0].0)
+                .await?
+        );
+
+        repo.restore_revision(&revisions[1].0).await?;
+        let unit = repo.find_one_by_id(&id).await?.unwrap();
+        assert_eq!(
+            ("akari", "", ""),
+            (&unit.1.cu[..], &unit.1.co[..], &unit.1.pa[..])
+        );
+        assert_eq!(3, repo.list_revisions(&id).await?.len());
+
+        repo.delete(&id).await?;
+        assert!(repo.list_revisions(&id).await?.is_empty());
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_restore_revision_is_validated() {
+    with_mongo(|ctx| async move {
+        let repo = RevisionRepo::new(ctx.as_ref()).await;
+        let id = repo.create(&TricoUnit::new("nw", "sakura", "", "")).await?;
+        repo.update(&WithId(id.clone(), TricoUnit::new("nw2", "sakura", "", "")))
+            .await?;
+        let _ = repo.create(&TricoUnit::new("nw", "izumi", "", "")).await?;
+        let revisions = repo.list_revisions(&id).await?;
+        repo.restore_revision(&revisions[0].0).await.unwrap_err();
+        assert_eq!("nw2", repo.find_one_by_id(&id).await?.unwrap().1.name);
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Secret {
+    name: String,
+    token: Encrypted<String>,
+    email: Encrypted<String, Deterministic>,
+}
+
+fixture_model!(Secret, SecretCfg);
+
+impl Schema for Secret {}
+
+type SecretRepo = RepositoryWithIdBase<
+    Secret,
+    Context,
+    DefaultValidate<Secret, Context>,
+    HardDelete,
+    GenerateObjectId,
+    TrackRevisions,
+>;
+
+fn secret(name: &str, token: &str, email: &str) -> Secret {
+    Secret {
+        name: String::from(name),
+        token: Encrypted::new(String::from(token)),
+        email: Encrypted::new(String::from(email)),
+    }
+}
+
+#[tokio::test]
+async fn test_encrypted_fields() {
+    with_mongo(|ctx| async move {
+        let repo = SecretRepo::new(ctx.as_ref()).await;
+        let id = repo
+            .create(&secret("akari", "t0", "akari@example.com"))
+            .await?;
+        let other = repo
+            .create(&secret("akira", "t1", "akira@example.com"))
+            .await?;
+        let unit = repo.find_one_by_id(&id).await?.unwrap();
+        assert_eq!(
+            ("t0", "akari@example.com"),
+            (&unit.1.token[..], &unit.1.email[..])
+        );
+
+        let coll = ctx.database().collection("Secret");
+        let raw = coll
+            .find_one(doc! { "_id": id.clone() }, None)
+            .await?
+            .unwrap();
+        assert!(matches!(raw.get("token"), Some(Bson::Binary(_))));
+
+        let email = String::from("akari@example.com");
+        let query =
+            Encrypted::<String, Deterministic>::query::<Secret, _>("email", &email, ctx.as_ref())?;
+        let found = repo.find_one(doc! { "email": query }, None).await?.unwrap();
+        assert_eq!(id, found.0);
+
+        // a sealed value does not open under another document
+        coll.update_one(
+            doc! { "_id": other.clone() },
+            doc! { "$set": { "token": raw.get("token").unwrap().clone() } },
+            None,
+        )
+        .await?;
+        let _ = repo.find_one_by_id(&other).await.unwrap_err();
+
+        repo.update(&WithId(
+            id.clone(),
+            secret("akari", "t2", "akari@example.com"),
+        ))
+        .await?;
+        let revisions = repo.list_revisions(&id).await?;
+        assert_eq!("t0", &revisions[0].1.model()?.token[..]);
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_remap_encrypted() {
+    with_mongo(|ctx| async move {
+        let repo = SecretRepo::new(ctx.as_ref()).await;
+        let id = repo
+            .create(&secret("akari", "t0", "akari@example.com"))
+            .await?;
+        repo.update(&WithId(
+            id.clone(),
+            secret("akari", "t1", "akari@example.com"),
+        ))
+        .await?;
+
+        let db = ctx.database();
+        let registry = ModelRegistry::new().with::<Secret>();
+        let mut ids = IdMap::new();
+        let mut dumps = Vec::new();
+        for name in &["Secret", "Secret_revisions"] {
+            let mut out = Vec::new();
+            export_collection(&db, name, &mut out, |doc| ids.assign(doc)).await?;
+            dumps.push((*name, read_documents(out.as_slice())?));
+        }
+        for (name, docs) in dumps {
+            let docs = docs
+                .into_iter()
+                .map(|doc| ids.remap_document(&registry, ctx.keyring(), name, doc))
+                .collect::<Result<Vec<_>>>()?;
+            import_documents(&db, name, docs).await?;
+        }
+        let copy = ids.get(&id).unwrap().clone();
+        let found = repo.find_one_by_id(&copy).await?.unwrap();
+        assert_eq!(
+            ("t1", "akari@example.com"),
+            (&found.1.token[..], &found.1.email[..])
+        );
+        let revisions = repo.list_revisions(&copy).await?;
+        assert_eq!("t0", &revisions[0].1.model()?.token[..]);
+
+        // the sealed values of an unknown model cannot be copied
+        let raw = db
+            .collection("Secret")
+            .find_one(doc! { "_id": id.clone() }, None)
+            .await?
+            .unwrap();
+        let _ = ids
+            .remap_document(&ModelRegistry::new(), ctx.keyring(), "Secret", raw)
+            .unwrap_err();
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+define_model! {
+    #[derive(Debug, Clone)]
+    pub struct Producer {
+        #[validate(length(max = 10))]
+        pub name: String [unique, input, output],
+        pub idols: i32 [input, output],
+        pub office: Option<String> [input],
+        pub retired: bool [],
+        #[serde(flatten, default)]
+        pub meta: crate::metadata::Metadata,
+    }
+    collection: "Producer",
+    context: Context,
+    config: ProducerCfg,
+    fields: ProducerFields,
+    validator: ProducerValidator,
+    repository: ProducerRepository,
+    input: ProducerInput,
+    output: ProducerOutput via ProducerView,
+    indexes: [Index::new("idols")],
+}
+
+fn producer_input(name: &str, idols: i32) -> ProducerInput {
+    ProducerInput {
+        name: String::from(name),
+        idols,
+        office: Some(String::from("346")),
+    }
+}
+
+#[test]
+fn test_define_model() {
+    assert_eq!("Producer", ProducerCfg::collection_name());
+    assert_eq!(
+        doc! { "retired": false },
+        Producer::fields().retired.eq(false).into_document()
+    );
+    assert_eq!(
+        Some(doc! { "name": 1, "idols": 1 }),
+        ProducerView::projection().map(Projection::into_document)
+    );
+    let producer =
+        Producer::from_hook_result(My(None::<User>), producer_input("takeuchi", 14)).unwrap();
+    assert_eq!(Some(String::from("346")), producer.office);
+    assert!(!producer.retired);
+    let output: ProducerOutput = WithId(Id::new(), producer).convert().unwrap();
+    assert_eq!(("takeuchi", 14), (output.name.as_str(), output.idols));
+}
+
+#[tokio::test]
+async fn test_define_model_repository() {
+    with_mongo(|ctx| async move {
+        ctx.sync_indexes::<Producer>().await?;
+        let repo = ProducerRepository::new(ctx.as_ref()).await;
+        let id = repo
+            .create(&producer_input("takeuchi", 14).convert()?)
+            .await?;
+        let err = repo
+            .create(&producer_input("takeuchi", 3).convert()?)
+            .await
+            .unwrap_err();
+        assert_eq!("name", err.downcast_ref::<NotUnique>().unwrap().field);
+        let _ = repo
+            .create(&producer_input("akagi-miria-p", 1).convert()?)
+            .await
+            .unwrap_err();
+
+        let view = repo
+            .find_one_view::<ProducerView>(doc! { "_id": id.clone() }, None)
+            .await?
+            .unwrap();
+        let output: ProducerOutput = view.convert()?;
+        assert_eq!(id, output.id);
+        assert_eq!(("takeuchi", 14), (output.name.as_str(), output.idols));
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+define_model! {
+    #[derive(Debug, Clone)]
+    pub struct Office {
+        pub name: String [input, output],
+    }
+    collection: "Office",
+    context: Context,
+    id: GenerateSlug,
+    config: OfficeCfg,
+    fields: OfficeFields,
+    validator: OfficeValidator,
+    repository: OfficeRepository,
+    input: OfficeInput,
+    output: OfficeOutput via OfficeView,
+}
+
+#[tokio::test]
+async fn test_define_model_id() {
+    with_mongo(|ctx| async move {
+        let repo = OfficeRepository::new(ctx.as_ref()).await;
+        let id: String = repo
+            .create(
+                &OfficeInput {
+                    name: String::from("346"),
+                }
+                .convert()?,
+            )
+            .await?;
+        let output: OfficeOutput = repo
+            .find_one_view::<OfficeView>(doc! { "_id": &id }, None)
+            .await?
+            .unwrap()
+            .convert()?;
+        assert_eq!(
+            (id.as_str(), "346"),
+            (output.id.as_str(), output.name.as_str())
+        );
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
+
+#[tokio::test]
+async fn test_dump() {
+    with_mongo(|ctx| async move {
+        let repo = Repo::new(ctx.as_ref()).await;
+        let id = repo
+            .create(&TricoUnit::new("unibo", "akari", "akira", "riamu"))
+            .await?;
+        let db = ctx.database();
+        let name = TricoUnitCfg::collection_name();
+
+        let mut out = Vec::new();
+        let mut seen = Vec::new();
+        let count = export_collection(&db, name, &mut out, |doc| seen.push(doc.clone())).await?;
+        assert_eq!(1, count);
+        let docs = read_documents(out.as_slice())?;
+        assert_eq!(seen, docs);
+
+        db.collection(name).drop(None).await?;
+        assert_eq!(1, import_documents(&db, name, docs.clone()).await?);
+        assert_eq!(1, import_documents(&db, name, docs.clone()).await?);
+        assert_eq!(1, db.collection(name).count_documents(None, None).await?);
+        assert_eq!("akari", repo.find_one_by_id(&id).await?.unwrap().1.cu);
+
+        let mut ids = IdMap::new();
+        ids.assign(&docs[0]);
+        let remapped = docs
+            .into_iter()
+            .map(|doc| ids.rewrite_document(doc))
+            .collect();
+        import_documents(&db, name, remapped).await?;
+        let copy = repo.find_one_by_id(ids.get(&id).unwrap()).await?.unwrap();
+        assert_eq!("unibo", copy.1.name);
+
+        db.collection(name)
+            .insert_one(doc! { "name": 1 }, None)
+            .await?;
+        let registry = ModelRegistry::new().with::<TricoUnit>();
+        let failures = check_collection(&db, &registry, name).await?;
+        assert_eq!(1, failures.len());
+        assert_eq!(name, failures[0].collection);
+        let _ = check_collection(&db, &registry, "Unknown")
+            .await
+            .unwrap_err();
+        Ok(())
+    })
+    .await
+    .unwrap()
+}
@@ -1,22 +1,186 @@
-use std::{env::var, sync::Arc};
+use std::{
+    env::var,
+    fs,
+    panic::AssertUnwindSafe,
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
-use crate::context::Context;
-use crate::utils::result::Result;
+use async_trait::async_trait;
+use futures::FutureExt;
+use log::warn;
+use mongodm::{
+    bson::{oid::ObjectId, Bson, Document},
+    CollectionConfig, Model,
+};
+use serde_json::Value;
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::{
+    context::Context,
+    utils::{result::Result, simple_error},
+    withid::{RepositoryWithId, WithId},
+};
+
+static SEQUENCE: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Error)]
+pub enum FixtureError {
+    #[error("unknown fixture format: {0}")]
+    UnknownFormat(String),
+    #[error("fixture must be a list of documents")]
+    NotDocuments,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FixtureFormat {
+    Json,
+    Yaml,
+}
+
+impl FixtureFormat {
+    fn from_path(path: &Path) -> Result<Self> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => Ok(FixtureFormat::Json),
+            Some("yaml") | Some("yml") => Ok(FixtureFormat::Yaml),
+            _ => Err(FixtureError::UnknownFormat(path.display().to_string()).into()),
+        }
+    }
+
+    fn parse(self, text: &str) -> Result<Value> {
+        Ok(match self {
+            FixtureFormat::Json => serde_json::from_str(text)?,
+            FixtureFormat::Yaml => serde_yaml::from_str(text)?,
+        })
+    }
+}
+
+async fn build_context(uri: &str) -> Result<Context> {
+    let database = format!(
+        "{}-{}",
+        var("MONGO_TEST_DATABASE")?,
+        Uuid::new_v4().to_simple()
+    );
+    Context::build_in_test(uri, &database).await
+}
+
+async fn test_context() -> Result<Arc<Context>> {
+    Ok(Arc::new(build_context(&var("MONGO_TEST_URI")?).await?))
+}
 
 pub async fn with_mongo<Fut>(f: impl FnOnce(Arc<Context>) -> Fut) -> Result<()>
 where
     Fut: std::future::Future<Output = Result<()>>,
 {
-    let ctx = {
-        let uri = var("MONGO_TEST_URI")?;
-        let database = var("MONGO_TEST_DATABASE")?;
-        let m = Context::build_in_test(&uri, &database).await?;
-        Arc::new(m)
+    run_in(test_context().await?, f).await
+}
+
+// Runs `f` against the replica set at `MONGO_TEST_REPLSET_URI`, watching
+// through change streams, and does nothing when it is not given.
+pub async fn with_replica_set<Fut>(f: impl FnOnce(Arc<Context>) -> Fut) -> Result<()>
+where
+    Fut: std::future::Future<Output = Result<()>>,
+{
+    let uri = match var("MONGO_TEST_REPLSET_URI") {
+        Ok(uri) => uri,
+        Err(_) => return Ok(()),
     };
-    let _ = ctx.database().drop(None).await?;
-    let _ = f(Arc::clone(&ctx)).await;
-    let _ = ctx.database().drop(None).await?;
-    Ok(())
+    let ctx = build_context(&uri).await?.with_watch_poll_interval(None);
+    run_in(Arc::new(ctx), f).await
+}
+
+async fn run_in<Fut>(ctx: Arc<Context>, f: impl FnOnce(Arc<Context>) -> Fut) -> Result<()>
+where
+    Fut: std::future::Future<Output = Result<()>>,
+{
+    let result = AssertUnwindSafe(f(Arc::clone(&ctx))).catch_unwind().await;
+    let dropped = ctx.database().drop(None).await;
+    match result {
+        Ok(Ok(())) => Ok(dropped?),
+        // a failure to drop must not hide the one of `f`
+        Ok(Err(err)) => {
+            if let Err(drop_err) = dropped {
+                warn!("cannot drop {}: {}", ctx.database().name(), drop_err);
+            }
+            Err(err)
+        }
+        Err(panic) => std::panic::resume_unwind(panic),
+    }
+}
+
+#[inline]
+pub fn sequence() -> usize {
+    SEQUENCE.fetch_add(1, Ordering::SeqCst)
+}
+
+pub async fn load_fixture<M>(ctx: &Context, path: impl AsRef<Path>) -> Result<Vec<Bson>>
+where
+    M: Model,
+{
+    let path = path.as_ref();
+    let format = FixtureFormat::from_path(path)?;
+    load_fixture_str::<M>(ctx, format, &fs::read_to_string(path)?).await
+}
+
+// Documents are inserted as written, so Extended JSON (e.g. `$oid`, `$date`)
+// can be used for ids and timestamps.
+pub async fn load_fixture_str<M>(
+    ctx: &Context,
+    format: FixtureFormat,
+    text: &str,
+) -> Result<Vec<Bson>>
+where
+    M: Model,
+{
+    let docs = match Bson::from(format.parse(text)?) {
+        Bson::Array(items) => items
+            .into_iter()
+            .map(|item| match item {
+                Bson::Document(mut doc) => {
+                    if !doc.contains_key("_id") {
+                        doc.insert("_id", ObjectId::new());
+                    }
+                    Ok(doc)
+                }
+                _ => Err(FixtureError::NotDocuments.into()),
+            })
+            .collect::<Result<Vec<Document>>>()?,
+        _ => return Err(FixtureError::NotDocuments.into()),
+    };
+    let ids = docs
+        .iter()
+        .filter_map(|doc| doc.get("_id").cloned())
+        .collect::<Vec<_>>();
+    if !docs.is_empty() {
+        ctx.database()
+            .collection(M::CollConf::collection_name())
+            .insert_many(docs, None)
+            .await?;
+    }
+    Ok(ids)
+}
+
+#[async_trait(?Send)]
+pub trait Factory: Sized {
+    type Repo: RepositoryWithId;
+
+    fn build(self) -> <Self::Repo as RepositoryWithId>::Model;
+
+    async fn create(
+        self,
+        ctx: &<Self::Repo as RepositoryWithId>::Ctx,
+    ) -> Result<WithId<<Self::Repo as RepositoryWithId>::Model, <Self::Repo as RepositoryWithId>::Id>>
+    {
+        let repo = Self::Repo::new(ctx).await;
+        let id = repo.create(&self.build()).await?;
+        repo.find_one_by_id(&id)
+            .await?
+            .ok_or_else(|| simple_error!("created model is not found"))
+    }
 }
 
 #[tokio::test]
@@ -24,31 +188,106 @@ async fn test_with_mongo() -> Result<()> {
     use mongodb::bson::doc;
     use pretty_assertions::assert_eq;
 
-    with_mongo(|ctx| async move {
-        assert_eq!(ctx.database().name(), var("MONGO_TEST_DATABASE")?);
-        let coll = ctx.database().collection("hoge");
-        let docs = vec![
-            doc! { "title": "1984", "author": "George Orwell" },
-            doc! { "title": "Animal Farm", "author": "George Orwell" },
-            doc! { "title": "The Great Gatsby", "author": "F. Scott Fitzgerald" },
-        ];
-        coll.insert_many(docs, None).await?;
-        let count = coll.count_documents(None, None).await?;
-        assert_eq!(3, count);
-        Ok(())
+    let mut name = String::new();
+    with_mongo(|ctx| {
+        name = String::from(ctx.database().name());
+        async move {
+            assert!(ctx
+                .database()
+                .name()
+                .starts_with(&var("MONGO_TEST_DATABASE")?));
+            let coll = ctx.database().collection("hoge");
+            let docs = vec![
+                doc! { "title": "1984", "author": "George Orwell" },
+                doc! { "title": "Animal Farm", "author": "George Orwell" },
+                doc! { "title": "The Great Gatsby", "author": "F. Scott Fitzgerald" },
+            ];
+            coll.insert_many(docs, None).await?;
+            let count = coll.count_documents(None, None).await?;
+            assert_eq!(3, count);
+            Ok(())
+        }
     })
     .await?;
-    let ctx = {
-        let uri = var("MONGO_TEST_URI")?;
-        let database = var("MONGO_TEST_DATABASE")?;
-        let m = Context::build_in_test(&uri, &database).await?;
-        Arc::new(m)
-    };
-    let count = ctx
-        .database()
-        .collection("hoge")
-        .count_documents(None, None)
-        .await?;
-    assert_eq!(0, count);
+    let ctx = test_context().await?;
+    assert_ne!(name, ctx.database().name());
+    let names = ctx.client().list_database_names(None, None).await?;
+    assert!(!names.contains(&name));
     Ok(())
 }
+
+#[tokio::test]
+async fn test_with_mongo_propagates_error() {
+    let err = with_mongo(|_| async move { Err(simple_error!("akari")) })
+        .await
+        .unwrap_err();
+    assert_eq!("akari", err.to_string());
+}
+
+#[tokio::test]
+#[should_panic(expected = "akira")]
+async fn test_with_mongo_propagates_panic() {
+    let _ = with_mongo(|_| async move {
+        assert_eq!("akari", "akira");
+        Ok(())
+    })
+    .await;
+}
+
+#[cfg(test)]
+struct UnitCfg {}
+
+#[cfg(test)]
+impl CollectionConfig for UnitCfg {
+    fn collection_name() -> &'static str {
+        "Unit"
+    }
+
+    fn indexes() -> mongodm::Indexes {
+        mongodm::Indexes::new()
+    }
+}
+
+#[cfg(test)]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Unit {}
+
+#[cfg(test)]
+impl Model for Unit {
+    type CollConf = UnitCfg;
+}
+
+#[tokio::test]
+async fn test_load_fixture() -> Result<()> {
+    use mongodb::bson::doc;
+    use pretty_assertions::assert_eq;
+
+    with_mongo(|ctx| async move {
+        let yaml = "
+- _id: { $oid: '5f9b7a8c1c9d440000a1b2c3' }
+  name: unibo
+- name: bw
+";
+        let ids = load_fixture_str::<Unit>(ctx.as_ref(), FixtureFormat::Yaml, yaml).await?;
+        assert_eq!(2, ids.len());
+        assert_eq!(
+            Bson::ObjectId(ObjectId::with_string("5f9b7a8c1c9d440000a1b2c3")?),
+            ids[0]
+        );
+        let json = r#"[{ "name": "nw" }]"#;
+        load_fixture_str::<Unit>(ctx.as_ref(), FixtureFormat::Json, json).await?;
+        let coll = ctx.database().collection("Unit");
+        assert_eq!(3, coll.count_documents(None, None).await?);
+        assert_eq!(
+            1,
+            coll.count_documents(doc! { "name": "unibo" }, None).await?
+        );
+        assert!(
+            load_fixture_str::<Unit>(ctx.as_ref(), FixtureFormat::Json, r#"{ "name": "nw" }"#)
+                .await
+                .is_err()
+        );
+        Ok(())
+    })
+    .await
+}
@@ -0,0 +1,136 @@
+use mongodm::{
+    bson::{Bson, Document},
+    mongo::options::FindOptions,
+};
+use serde::de::DeserializeOwned;
+
+use crate::{migration::SCHEMA_VERSION, query::Projection, schema::Schema};
+
+pub trait View: DeserializeOwned {
+    type Model: Schema;
+
+    fn projection() -> Option<Projection<Self::Model>>;
+}
+
+impl<M> View for M
+where
+    M: Schema,
+{
+    type Model = M;
+
+    #[inline]
+    fn projection() -> Option<Projection<M>> {
+        None
+    }
+}
+
+fn is_inclusion(projection: &Document) -> bool {
+    projection
+        .iter()
+        .any(|(key, value)| key != "_id" && matches!(value, Bson::Int32(1)))
+}
+
+pub(crate) fn view_projection<V>(keep: Option<&str>) -> Option<Document>
+where
+    V: View,
+{
+    let mut projection = V::projection()?.into_document();
+    if is_inclusion(&projection) {
+        projection.insert(SCHEMA_VERSION, 1);
+        if let Some(key) = keep {
+            projection.insert(key, 1);
+        }
+    } else if let Some(key) = keep {
+        projection.remove(key);
+    }
+    Some(projection)
+}
+
+pub(crate) fn view_option<V>(option: Option<FindOptions>, keep: Option<&str>) -> Option<FindOptions>
+where
+    V: View,
+{
+    let projection = match view_projection::<V>(keep) {
+        Some(projection) => projection,
+        None => return option,
+    };
+    let mut option = option.unwrap_or_default();
+    option.projection = Some(projection);
+    Some(option)
+}
+
+#[cfg(test)]
+mod test {
+    use mongodm::{doc, CollectionConfig, Indexes, Model};
+    use pretty_assertions::assert_eq;
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::query::Field;
+
+    #[derive(Serialize, Deserialize)]
+    struct Unit {
+        name: String,
+        image: Vec<u8>,
+    }
+
+    struct UnitCfg {}
+
+    impl CollectionConfig for UnitCfg {
+        fn collection_name() -> &'static str {
+            "Unit"
+        }
+
+        fn indexes() -> Indexes {
+            Indexes::new()
+        }
+    }
+
+    impl Model for Unit {
+        type CollConf = UnitCfg;
+    }
+
+    impl Schema for Unit {}
+
+    #[derive(Deserialize)]
+    struct UnitName {}
+
+    impl View for UnitName {
+        type Model = Unit;
+
+        fn projection() -> Option<Projection<Unit>> {
+            Some(Projection::new().include(Field::<Unit, String>::new("name")))
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct UnitWithoutImage {}
+
+    impl View for UnitWithoutImage {
+        type Model = Unit;
+
+        fn projection() -> Option<Projection<Unit>> {
+            Some(Projection::new().exclude(Field::<Unit, Vec<u8>>::new("image")))
+        }
+    }
+
+    #[test]
+    fn test_inclusion_view_option() {
+        let option = view_option::<UnitName>(None, Some("created_at")).unwrap();
+        assert_eq!(
+            Some(doc! { "name": 1, SCHEMA_VERSION: 1, "created_at": 1 }),
+            option.projection
+        );
+    }
+
+    #[test]
+    fn test_exclusion_view_option() {
+        let option = view_option::<UnitWithoutImage>(None, Some("image")).unwrap();
+        assert_eq!(Some(doc! {}), option.projection);
+    }
+
+    #[test]
+    fn test_model_view_option() {
+        assert!(view_option::<Unit>(None, Some("name")).is_none());
+    }
+}
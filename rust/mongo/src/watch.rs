@@ -0,0 +1,471 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use chrono::{DateTime, TimeZone, Utc};
+use futures::{
+    stream::{self, LocalBoxStream},
+    StreamExt, TryStreamExt,
+};
+use mongodb::{
+    options::{AggregateOptions, FindOptions},
+    Collection,
+};
+use mongodm::{
+    bson::{from_bson, Bson, Document},
+    doc,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    encrypted::Sealer,
+    id::{IdError, IdType},
+    metadata::{CREATED_AT, UPDATED_AT},
+    migration::upcast,
+    schema::Schema,
+    softdelete::{exclude_deleted, DeleteConfig},
+    utils::result::Result,
+    withid::{Id, WithId},
+};
+
+const POLLED_AT: &str = "polledAt";
+const MAX_AWAIT_TIME: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Error)]
+pub enum WatchError {
+    #[error("invalid resume token")]
+    InvalidResumeToken,
+    #[error("unknown change event: {0}")]
+    UnknownEvent(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResumeToken(pub Document);
+
+#[derive(Debug)]
+pub enum ChangeEvent<M, I = Id> {
+    Inserted(WithId<M, I>),
+    Updated(WithId<M, I>),
+    Deleted(I),
+}
+
+#[derive(Debug)]
+pub struct Change<M, I = Id> {
+    pub token: ResumeToken,
+    pub event: ChangeEvent<M, I>,
+}
+
+pub type ChangeStream<M, I = Id> = LocalBoxStream<'static, Result<Change<M, I>>>;
+
+fn to_model<M: Schema>(mut doc: Document, sealer: &Sealer) -> Result<M> {
+    sealer.open_with_id(&mut doc);
+    Ok(from_bson(Bson::Document(upcast::<M>(doc)?))?)
+}
+
+fn id_of<I: IdType>(doc: &Document) -> Result<I> {
+    I::from_bson(doc.get("_id").cloned().ok_or(IdError::MissingId)?)
+}
+
+// A document is reported once it matches `query` and until it stops matching,
+// as the poller does. An event only says which document changed, so its
+// current state is looked up again to tell whether it still matches, and
+// deletes of documents never delivered are dropped. What was delivered before
+// a resume token is not known, so the first event of each document after it
+// is taken by its operation type instead.
+pub(crate) async fn change_stream<M, I, D>(
+    coll: &Collection,
+    sealer: Sealer,
+    query: Document,
+    resume: Option<ResumeToken>,
+) -> Result<ChangeStream<M, I>>
+where
+    M: Schema + 'static,
+    I: IdType,
+    D: DeleteConfig,
+{
+    let mut options = Document::new();
+    let resumed = resume.is_some();
+    if let Some(token) = resume {
+        if token.0.contains_key(POLLED_AT) {
+            return Err(WatchError::InvalidResumeToken.into());
+        }
+        options.insert("resumeAfter", token.0);
+    }
+    let pipeline = vec![
+        doc! { "$changeStream": options },
+        doc! { "$match": {
+            "operationType": { "$in": ["insert", "update", "replace", "delete"] },
+        } },
+    ];
+    let option = AggregateOptions::builder()
+        .max_await_time(MAX_AWAIT_TIME)
+        .build();
+    let events = coll.aggregate(pipeline, option).await?.boxed_local();
+    let query = exclude_deleted::<D>(query);
+    let (delivered, seen) = if resumed {
+        (HashSet::new(), Some(HashSet::new()))
+    } else {
+        // taken after the stream is opened, so no change falls in between
+        let option = FindOptions::builder().projection(doc! { "_id": 1 }).build();
+        let delivered = coll
+            .find(query.clone(), option)
+            .await?
+            .map(|doc| id_of::<I>(&doc?))
+            .try_collect::<HashSet<_>>()
+            .await?;
+        (delivered, None)
+    };
+    let watcher = Watcher::<M, I> {
+        coll: coll.clone(),
+        sealer,
+        query,
+        events,
+        delivered,
+        seen,
+        p: PhantomData,
+    };
+    Ok(stream::try_unfold(watcher, Watcher::next).boxed_local())
+}
+
+struct Watcher<M, I> {
+    coll: Collection,
+    sealer: Sealer,
+    query: Document,
+    events: LocalBoxStream<'static, mongodb::error::Result<Document>>,
+    delivered: HashSet<I>,
+    // documents with an event since the resume token
+    seen: Option<HashSet<I>>,
+    p: PhantomData<fn() -> M>,
+}
+
+impl<M, I> Watcher<M, I>
+where
+    M: Schema,
+    I: IdType,
+{
+    async fn next(mut self) -> Result<Option<(Change<M, I>, Self)>> {
+        while let Some(event) = self.events.next().await {
+            if let Some(change) = self.decode(event?).await? {
+                return Ok(Some((change, self)));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn decode(&mut self, event: Document) -> Result<Option<Change<M, I>>> {
+        let token = ResumeToken(event.get_document("_id")?.clone());
+        let id = id_of::<I>(event.get_document("documentKey")?)?;
+        let operation = event.get_str("operationType")?;
+        let doc = match operation {
+            "delete" => None,
+            "insert" | "update" | "replace" => {
+                let query = doc! { "$and": [{ "_id": id.to_bson() }, self.query.clone()] };
+                self.coll.find_one(query, None).await?
+            }
+            operation => return Err(WatchError::UnknownEvent(String::from(operation)).into()),
+        };
+        let first = match &mut self.seen {
+            Some(seen) => seen.insert(id.clone()),
+            None => false,
+        };
+        let event = if first {
+            Some(resumed_transition(
+                id,
+                operation,
+                doc,
+                &self.sealer,
+                &mut self.delivered,
+            )?)
+        } else {
+            transition(id, doc, &self.sealer, &mut self.delivered)?
+        };
+        Ok(event.map(|event| Change { token, event }))
+    }
+}
+
+// The event for the current state of a document, `None` when it does not
+// match, given the documents reported so far.
+fn transition<M, I>(
+    id: I,
+    doc: Option<Document>,
+    sealer: &Sealer,
+    delivered: &mut HashSet<I>,
+) -> Result<Option<ChangeEvent<M, I>>>
+where
+    M: Schema,
+    I: IdType,
+{
+    Ok(match (doc, delivered.contains(&id)) {
+        (Some(doc), true) => Some(ChangeEvent::Updated(WithId(
+            id,
+            to_model::<M>(doc, sealer)?,
+        ))),
+        (Some(doc), false) => {
+            delivered.insert(id.clone());
+            Some(ChangeEvent::Inserted(WithId(
+                id,
+                to_model::<M>(doc, sealer)?,
+            )))
+        }
+        (None, true) => {
+            delivered.remove(&id);
+            Some(ChangeEvent::Deleted(id))
+        }
+        (None, false) => None,
+    })
+}
+
+// The event for the first change of a document after a resume token, which
+// may report a delete the client never had the document for.
+fn resumed_transition<M, I>(
+    id: I,
+    operation: &str,
+    doc: Option<Document>,
+    sealer: &Sealer,
+    delivered: &mut HashSet<I>,
+) -> Result<ChangeEvent<M, I>>
+where
+    M: Schema,
+    I: IdType,
+{
+    Ok(match doc {
+        Some(doc) => {
+            delivered.insert(id.clone());
+            let model = WithId(id, to_model::<M>(doc, sealer)?);
+            match operation {
+                "insert" => ChangeEvent::Inserted(model),
+                _ => ChangeEvent::Updated(model),
+            }
+        }
+        None => ChangeEvent::Deleted(id),
+    })
+}
+
+fn now() -> DateTime<Utc> {
+    Utc.timestamp_millis(Utc::now().timestamp_millis())
+}
+
+struct Poller<M, I> {
+    coll: Collection,
+    sealer: Sealer,
+    query: Document,
+    interval: Duration,
+    since: DateTime<Utc>,
+    known: HashMap<I, Bson>,
+    pending: VecDeque<Change<M, I>>,
+}
+
+impl<M, I> Poller<M, I>
+where
+    M: Schema,
+    I: IdType,
+{
+    async fn start(
+        coll: Collection,
+        sealer: Sealer,
+        query: Document,
+        resume: Option<ResumeToken>,
+        interval: Duration,
+    ) -> Result<Self> {
+        let resumed_at = match resume {
+            Some(token) => Some(
+                *token
+                    .0
+                    .get_datetime(POLLED_AT)
+                    .map_err(|_| WatchError::InvalidResumeToken)?,
+            ),
+            None => None,
+        };
+        let mut poller = Self {
+            coll,
+            sealer,
+            query,
+            interval,
+            since: resumed_at.unwrap_or_else(now),
+            known: HashMap::new(),
+            pending: VecDeque::new(),
+        };
+        let docs = poller.fetch().await?;
+        for doc in &docs {
+            let stamp = doc.get(UPDATED_AT).cloned().unwrap_or(Bson::Null);
+            let known = match (resumed_at, doc.get_datetime(CREATED_AT), &stamp) {
+                (Some(at), Ok(created), _) if *created >= at => None,
+                (Some(at), _, Bson::DateTime(updated)) if *updated >= at => Some(Bson::Null),
+                _ => Some(stamp.clone()),
+            };
+            if let Some(stamp) = known {
+                poller.known.insert(id_of::<I>(doc)?, stamp);
+            }
+        }
+        if resumed_at.is_some() {
+            poller.diff(docs)?;
+        }
+        Ok(poller)
+    }
+
+    async fn fetch(&self) -> Result<Vec<Document>> {
+        Ok(self
+            .coll
+            .find(self.query.clone(), None)
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?)
+    }
+
+    fn diff(&mut self, docs: Vec<Document>) -> Result<()> {
+        let token = ResumeToken(doc! { POLLED_AT: Bson::DateTime(self.since) });
+        let mut current = HashMap::new();
+        for doc in docs {
+            let id = id_of::<I>(&doc)?;
+            let stamp = doc.get(UPDATED_AT).cloned().unwrap_or(Bson::Null);
+            let event = match self.known.remove(&id) {
+                None => Some(ChangeEvent::Inserted(WithId(
+                    id.clone(),
+                    to_model::<M>(doc, &self.sealer)?,
+                ))),
+                Some(known) if known != stamp => Some(ChangeEvent::Updated(WithId(
+                    id.clone(),
+                    to_model::<M>(doc, &self.sealer)?,
+                ))),
+                Some(_) => None,
+            };
+            if let Some(event) = event {
+                self.pending.push_back(Change {
+                    token: token.clone(),
+                    event,
+                });
+            }
+            current.insert(id, stamp);
+        }
+        for (id, _) in self.known.drain() {
+            self.pending.push_back(Change {
+                token: token.clone(),
+                event: ChangeEvent::Deleted(id),
+            });
+        }
+        self.known = current;
+        Ok(())
+    }
+
+    async fn next(mut self) -> Result<Option<(Change<M, I>, Self)>> {
+        loop {
+            if let Some(change) = self.pending.pop_front() {
+                return Ok(Some((change, self)));
+            }
+            tokio::time::delay_for(self.interval).await;
+            let polled_at = now();
+            let docs = self.fetch().await?;
+            self.diff(docs)?;
+            self.since = polled_at;
+        }
+    }
+}
+
+// Polls the collection and diffs snapshots, for deployments without change
+// streams (e.g. a standalone mongod). Events may be redelivered on resume, and
+// documents hard deleted while disconnected are not reported.
+pub(crate) async fn poll_stream<M, I, D>(
+    coll: Collection,
+    sealer: Sealer,
+    query: Document,
+    resume: Option<ResumeToken>,
+    interval: Duration,
+) -> Result<ChangeStream<M, I>>
+where
+    M: Schema + 'static,
+    I: IdType,
+    D: DeleteConfig,
+{
+    let query = exclude_deleted::<D>(query);
+    let poller = Poller::<M, I>::start(coll, sealer, query, resume, interval).await?;
+    Ok(stream::try_unfold(poller, Poller::next).boxed_local())
+}
+
+#[cfg(test)]
+mod test {
+    use mongodm::{CollectionConfig, Indexes, Model};
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    struct Unit {
+        name: String,
+    }
+
+    struct UnitCfg {}
+
+    impl CollectionConfig for UnitCfg {
+        fn collection_name() -> &'static str {
+            "Unit"
+        }
+
+        fn indexes() -> Indexes {
+            Indexes::new()
+        }
+    }
+
+    impl Model for Unit {
+        type CollConf = UnitCfg;
+    }
+
+    impl Schema for Unit {}
+
+    fn describe(event: Option<ChangeEvent<Unit, String>>) -> Option<String> {
+        event.map(|event| match event {
+            ChangeEvent::Inserted(unit) => format!("inserted {}", unit.1.name),
+            ChangeEvent::Updated(unit) => format!("updated {}", unit.1.name),
+            ChangeEvent::Deleted(id) => format!("deleted {}", id),
+        })
+    }
+
+    #[test]
+    fn test_transition() {
+        let sealer = Sealer::new(None, UnitCfg::collection_name());
+        let mut delivered = HashSet::new();
+        let mut step = |id: &str, name: Option<&str>| {
+            let doc = name.map(|name| doc! { "name": name });
+            describe(transition(String::from(id), doc, &sealer, &mut delivered).unwrap())
+        };
+        assert_eq!(
+            Some(String::from("inserted unibo")),
+            step("1", Some("unibo"))
+        );
+        assert_eq!(Some(String::from("updated bw")), step("1", Some("bw")));
+        // stops matching
+        assert_eq!(Some(String::from("deleted 1")), step("1", None));
+        // never delivered
+        assert_eq!(None, step("2", None));
+        assert_eq!(None, step("1", None));
+        // matches again
+        assert_eq!(Some(String::from("inserted nw")), step("1", Some("nw")));
+    }
+
+    #[test]
+    fn test_resumed_transition() {
+        let sealer = Sealer::new(None, UnitCfg::collection_name());
+        let mut delivered = HashSet::new();
+        let mut step = |id: &str, operation: &str, name: Option<&str>| {
+            let doc = name.map(|name| doc! { "name": name });
+            let id = String::from(id);
+            describe(Some(
+                resumed_transition(id, operation, doc, &sealer, &mut delivered).unwrap(),
+            ))
+        };
+        assert_eq!(
+            Some(String::from("inserted unibo")),
+            step("1", "insert", Some("unibo"))
+        );
+        assert_eq!(
+            Some(String::from("updated bw")),
+            step("2", "update", Some("bw"))
+        );
+        // may have matched before the token
+        assert_eq!(Some(String::from("deleted 3")), step("3", "update", None));
+        assert_eq!(Some(String::from("deleted 4")), step("4", "delete", None));
+        let mut ids = delivered.into_iter().collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(vec!["1", "2"], ids);
+    }
+}
@@ -4,54 +4,80 @@ use std::pin::Pin;
 
 use async_trait::async_trait;
 use futures::{
-    stream,
+    stream::{self, BoxStream},
     task::{Context, Poll},
-    Stream, StreamExt,
+    Stream, StreamExt, TryStreamExt,
+};
+use mongodb::{
+    error::{BulkWriteFailure, ErrorKind},
+    options::{
+        Collation, FindOneAndUpdateOptions, FindOneOptions, FindOptions, InsertManyOptions,
+        ReturnDocument, UpdateModifications,
+    },
+    Collection,
 };
-use mongodb::{options::FindOptions, Collection, Cursor};
 use mongodm::{
-    bson::{from_bson, oid::ObjectId, Bson, Document},
+    bson::{oid::ObjectId, to_document, Bson, Document},
     doc,
     prelude::MongoError,
-    Model, Repository,
+    CollectionConfig, Model, Repository,
 };
+use serde::de::DeserializeOwned;
+use thiserror::Error;
 use validator::Validate;
 
 use crate::{
+    aggregate::{upcast_joined, AggregateCursor, AggregateDocument, AggregateStream, Pipeline},
+    bulk::BulkResult,
     context::MongodmContext,
+    encrypted::Sealer,
     fcomps::{
         convert::Convertible,
         service::{FromHookResult, HookResult},
     },
+    id::{GenerateObjectId, IdError, IdGenerator, IdType},
+    index::{translate_bulk_write_error, translate_write_error, NotUnique},
+    metadata::{replace_on_update, stamp_on_create, stamp_on_update},
+    migration::stamp_version,
+    revision::{self, Change, NoRevisions, Revision, RevisionConfig},
+    schema::{patch_document, read_document, Schema},
+    search::{query_ngrams, search_stages, stamp_search, SCORE_FIELD},
+    service::{collect_page, page_window, Page, PageRequest, PageSort, Position},
+    softdelete::{
+        deleted_before, exclude_deleted, mark_deleted, not_deleted, only_deleted, unmark_deleted,
+        DeleteConfig, HardDelete,
+    },
     utils::{
-        result::{Result, StdResult},
+        result::{Error, Result, StdResult},
         simple_error,
     },
+    view::{view_option, view_projection, View},
+    watch::{change_stream, poll_stream, ChangeStream, ResumeToken},
 };
 
 pub type Id = ObjectId;
 
 #[derive(Debug, Clone)]
-pub struct WithId<M>(pub Id, pub M);
+pub struct WithId<M, I = Id>(pub I, pub M);
 
-impl<M> HookResult for WithId<M> {}
+impl<M, I> HookResult for WithId<M, I> {}
 
-impl<T, U> Convertible<WithId<T>> for WithId<U>
+impl<T, U, I> Convertible<WithId<T, I>> for WithId<U, I>
 where
     U: Convertible<T>,
 {
     #[inline]
-    fn convert(self) -> Result<WithId<T>> {
+    fn convert(self) -> Result<WithId<T, I>> {
         Ok(WithId(self.0, self.1.convert()?))
     }
 }
 
-impl<T, U, H> FromHookResult<H, WithId<U>> for WithId<T>
+impl<T, U, I, H> FromHookResult<H, WithId<U, I>> for WithId<T, I>
 where
     T: FromHookResult<H, U>,
 {
     #[inline]
-    fn from_hook_result(h: H, input: WithId<U>) -> Result<Self> {
+    fn from_hook_result(h: H, input: WithId<U, I>) -> Result<Self> {
         Ok(Self(input.0, T::from_hook_result(h, input.1)?))
     }
 }
@@ -101,15 +127,16 @@ where
     }
 }
 
-impl<M> Convertible<WithId<M>> for StdResult<Document, MongoError>
+impl<V, I> Convertible<WithId<V, I>> for StdResult<Document, MongoError>
 where
-    M: Model,
+    V: View,
+    I: IdType,
 {
-    fn convert(self) -> Result<WithId<M>> {
+    fn convert(self) -> Result<WithId<V, I>> {
         match self {
             Ok(doc) => {
-                let id = get_id_from_doc(&doc);
-                let doc = from_bson(Bson::Document(doc));
+                let id = get_id_from_doc::<I>(&doc);
+                let doc = read_document(doc);
                 match (id, doc) {
                     (Ok(id), Ok(doc)) => Ok(WithId(id, doc)),
                     _ => Err(simple_error!("find conv error")),
@@ -120,7 +147,9 @@ where
     }
 }
 
-pub type ModelWithIdCursor<M> = ConvertibleStream<WithId<M>, Cursor>;
+pub type DocumentStream = BoxStream<'static, StdResult<Document, MongoError>>;
+
+pub type ModelWithIdCursor<M, I = Id> = ConvertibleStream<WithId<M, I>, DocumentStream>;
 
 #[derive(Debug)]
 pub struct ConvertibleTryStream<T, F, St>
@@ -182,11 +211,14 @@ where
     }
 }
 
-pub type ConvertModelWithIdCursor<T, M> = ConvertibleTryStream<T, WithId<M>, ModelWithIdCursor<M>>;
+pub type ConvertModelWithIdCursor<T, M, I = Id> =
+    ConvertibleTryStream<T, WithId<M, I>, ModelWithIdCursor<M, I>>;
 
+#[derive(Clone, Debug)]
 pub enum CreateOrUpdate {
     Create,
-    Update(Id),
+    // the id as stored, whatever its type
+    Update(Bson),
 }
 
 #[async_trait(?Send)]
@@ -195,6 +227,11 @@ pub trait Validator {
     type Ctx;
     async fn validate(cu: CreateOrUpdate, model: &'_ Self::Model, ctx: &'_ Self::Ctx)
         -> Result<()>;
+
+    #[inline]
+    async fn validate_refs(_model: &'_ Self::Model, _ctx: &'_ Self::Ctx) -> Result<()> {
+        Ok(())
+    }
 }
 
 pub struct DefaultValidate<Model, Ctx> {
@@ -244,55 +281,169 @@ where
         model.validate()?;
         V::validate(cu, model, ctx).await
     }
+
+    #[inline]
+    async fn validate_refs(model: &Model, ctx: &Ctx) -> Result<()>
+    where
+        V: 'async_trait,
+    {
+        V::validate_refs(model, ctx).await
+    }
 }
 
 #[async_trait(?Send)]
 pub trait RepositoryWithId {
-    type Model: mongodm::Model;
+    type Model: Schema;
     type Ctx;
+    type Id: IdType;
 
     async fn new(ctx: &Self::Ctx) -> Self;
 
-    async fn create(&self, model: &Self::Model) -> Result<Id>;
+    async fn create(&self, model: &Self::Model) -> Result<Self::Id>;
+
+    async fn create_with_id(&self, id: &Self::Id, model: &Self::Model) -> Result<()>;
 
-    async fn update(&self, model: &WithId<Self::Model>) -> Result<()>;
+    async fn update(&self, model: &WithId<Self::Model, Self::Id>) -> Result<()>;
+
+    async fn delete(&self, id: &Self::Id) -> Result<()>;
+
+    async fn restore(&self, id: &Self::Id) -> Result<()>;
+
+    async fn create_many(&self, models: &[Self::Model]) -> Result<BulkResult<usize, Self::Id>>;
+
+    async fn update_many(&self, query: Document, patch: Document) -> Result<BulkResult<Self::Id>>;
+
+    async fn delete_many(&self, query: Document) -> Result<i64>;
+
+    async fn find_one_view<V>(
+        &self,
+        query: Document,
+        option: Option<FindOptions>,
+    ) -> Result<Option<WithId<V, Self::Id>>>
+    where
+        V: View<Model = Self::Model>;
+
+    async fn find_many_view<V>(
+        &self,
+        query: Document,
+        option: Option<FindOptions>,
+    ) -> Result<ModelWithIdCursor<V, Self::Id>>
+    where
+        V: View<Model = Self::Model>;
 
-    async fn delete(&self, id: &Id) -> Result<()>;
+    async fn find_page_view<V>(
+        &self,
+        query: Document,
+        sort: &PageSort,
+        request: &PageRequest,
+    ) -> Result<Page<WithId<V, Self::Id>>>
+    where
+        V: View<Model = Self::Model>;
 
-    async fn find_one(&self, query: Document) -> Result<Option<WithId<Self::Model>>>;
+    #[inline]
+    async fn find_one(
+        &self,
+        query: Document,
+        option: Option<FindOptions>,
+    ) -> Result<Option<WithId<Self::Model, Self::Id>>> {
+        self.find_one_view(query, option).await
+    }
 
-    async fn find_one_by_id(&self, id: &Id) -> Result<Option<WithId<Self::Model>>>;
+    #[inline]
+    async fn find_one_by_id(&self, id: &Self::Id) -> Result<Option<WithId<Self::Model, Self::Id>>> {
+        self.find_one(doc! {"_id": id.to_bson()}, None).await
+    }
 
+    #[inline]
     async fn find_many(
         &self,
         query: Document,
         option: Option<FindOptions>,
-    ) -> Result<ModelWithIdCursor<Self::Model>>
+    ) -> Result<ModelWithIdCursor<Self::Model, Self::Id>> {
+        self.find_many_view(query, option).await
+    }
+
+    #[inline]
+    async fn find_page(
+        &self,
+        query: Document,
+        sort: &PageSort,
+        request: &PageRequest,
+    ) -> Result<Page<WithId<Self::Model, Self::Id>>> {
+        self.find_page_view(query, sort, request).await
+    }
+}
+
+// Search, aggregation and change streams, which only MongoDB has. Other
+// stores implement `RepositoryWithId` alone, so these cannot be called on them.
+#[async_trait(?Send)]
+pub trait MongoRepositoryWithId: RepositoryWithId {
+    async fn search_view<V>(
+        &self,
+        query: Document,
+        text: &str,
+        request: &PageRequest,
+    ) -> Result<Page<WithId<V, Self::Id>>>
+    where
+        V: View<Model = Self::Model>;
+
+    async fn aggregate<Out>(&self, pipeline: Pipeline<Self::Model>) -> Result<AggregateCursor<Out>>
     where
-        Self::Model: Model;
+        Out: DeserializeOwned;
+
+    async fn watch(
+        &self,
+        query: Document,
+        resume: Option<ResumeToken>,
+    ) -> Result<ChangeStream<Self::Model, Self::Id>>
+    where
+        Self::Model: 'static;
+
+    #[inline]
+    async fn search(
+        &self,
+        query: Document,
+        text: &str,
+        request: &PageRequest,
+    ) -> Result<Page<WithId<Self::Model, Self::Id>>> {
+        self.search_view(query, text, request).await
+    }
 }
 
-pub struct RepositoryWithIdBase<M, Ctx, V = DefaultValidate<M, Ctx>>
-where
+pub struct RepositoryWithIdBase<
+    M,
+    Ctx,
+    V = DefaultValidate<M, Ctx>,
+    D = HardDelete,
+    G = GenerateObjectId,
+    R = NoRevisions,
+> where
     Ctx: MongodmContext + Clone,
-    M: Model,
+    M: Schema,
     V: Validator<Model = M, Ctx = Ctx>,
+    D: DeleteConfig,
+    G: IdGenerator,
+    R: RevisionConfig,
 {
     pub(crate) ctx: Ctx,
     pub(crate) repo: Repository<M>,
     pub(crate) coll: Collection,
-    p: PhantomData<V>,
+    p: PhantomData<fn() -> (V, D, G, R)>,
 }
 
 #[async_trait(?Send)]
-impl<M, Ctx, V> RepositoryWithId for RepositoryWithIdBase<M, Ctx, V>
+impl<M, Ctx, V, D, G, R> RepositoryWithId for RepositoryWithIdBase<M, Ctx, V, D, G, R>
 where
     Ctx: MongodmContext,
-    M: Model,
+    M: Schema,
     V: Validator<Model = M, Ctx = Ctx>,
+    D: DeleteConfig,
+    G: IdGenerator,
+    R: RevisionConfig,
 {
     type Model = M;
     type Ctx = Ctx;
+    type Id = G::Id;
 
     async fn new(ctx: &Ctx) -> Self {
         let repo = ctx.repo::<M>();
@@ -304,124 +455,660 @@ where
         }
     }
 
-    async fn create(&self, model: &M) -> Result<Id> {
+    async fn create(&self, model: &M) -> Result<G::Id> {
+        let (id, doc) = self.prepare_create(model).await?;
+        self.insert_doc(doc).await?;
+        Ok(id)
+    }
+
+    async fn create_with_id(&self, id: &G::Id, model: &M) -> Result<()> {
         V::validate(CreateOrUpdate::Create, model, &self.ctx).await?;
-        oid(self.repo.insert_one(model, None).await?.inserted_id)
-    }
-
-    async fn update(&self, model: &WithId<M>) -> Result<()> {
-        V::validate(CreateOrUpdate::Update(model.0.clone()), &model.1, &self.ctx).await?;
-        let result = self
-            .repo
-            .replace_one(
-                doc! {"_id": Bson::ObjectId(model.0.clone()) },
-                &model.1,
-                None,
-            )
-            .await?;
-        match (result.modified_count, result.matched_count) {
+        V::validate_refs(model, &self.ctx).await?;
+        self.insert_doc(self.model_doc(id, model)?).await
+    }
+
+    async fn update(&self, model: &WithId<M, G::Id>) -> Result<()> {
+        V::validate(
+            CreateOrUpdate::Update(model.0.to_bson()),
+            &model.1,
+            &self.ctx,
+        )
+        .await?;
+        V::validate_refs(&model.1, &self.ctx).await?;
+        let mut doc = to_document(&model.1)?;
+        stamp_version::<M>(&mut doc);
+        stamp_search::<M>(&mut doc);
+        stamp_on_update(&mut doc, self.ctx.actor_id());
+        self.sealer().seal(&mut doc, &model.0.to_bson())?;
+        let query = exclude_deleted::<D>(doc! {"_id": model.0.to_bson() });
+        if R::track_revisions() {
+            return match self.replace_doc(query, doc).await? {
+                Some(previous) => self.record_revision(previous).await,
+                None => Err(simple_error!("not found!")),
+            };
+        }
+        match self
+            .update_doc(query, replace_on_update(doc), false)
+            .await?
+        {
             (1, 1) => Ok(()),
             (0, 0) => Err(simple_error!("not found!")), // TODO: 404
             _ => Err(simple_error!("cannot update!")),
         }
     }
 
-    async fn delete(&self, id: &Id) -> Result<()> {
-        let result = self
-            .repo
-            .delete_one(doc! {"_id": Bson::ObjectId(id.clone()) }, None)
-            .await?;
-        if result.deleted_count == 1 {
+    async fn delete(&self, id: &G::Id) -> Result<()> {
+        let query = doc! {"_id": id.to_bson() };
+        let deleted_count = if D::soft_delete() {
+            self.update_doc(exclude_deleted::<D>(query), mark_deleted(), false)
+                .await?
+                .1
+        } else {
+            self.delete_tracked(query, false).await?
+        };
+        if deleted_count == 1 {
             Ok(())
         } else {
             Err(simple_error!("cannot update!"))
         }
     }
 
-    async fn find_one(&self, query: Document) -> Result<Option<WithId<M>>> {
-        let doc_opt = self.coll.find_one(query, None).await?;
+    async fn restore(&self, id: &G::Id) -> Result<()> {
+        if !D::soft_delete() {
+            return Err(simple_error!("soft delete is disabled"));
+        }
+        let query = only_deleted(doc! {"_id": id.to_bson() });
+        if self.update_doc(query, unmark_deleted(), false).await?.1 == 1 {
+            Ok(())
+        } else {
+            Err(simple_error!("not found!"))
+        }
+    }
+
+    async fn create_many(&self, models: &[M]) -> Result<BulkResult<usize, G::Id>> {
+        let mut result = BulkResult::default();
+        let mut pending = Vec::new();
+        let mut docs = Vec::new();
+        for (index, model) in models.iter().enumerate() {
+            match self.prepare_create(model).await {
+                Ok((id, doc)) => {
+                    pending.push((index, id));
+                    docs.push(doc);
+                }
+                Err(err) => result.push(index, Err(err)),
+            }
+        }
+        let errors = self.insert_docs(docs).await?;
+        for ((index, id), error) in pending.into_iter().zip(errors) {
+            result.push(
+                index,
+                match error {
+                    Some(err) => Err(err),
+                    None => Ok(id),
+                },
+            );
+        }
+        result.items.sort_by_key(|(index, _)| *index);
+        Ok(result)
+    }
+
+    async fn update_many(&self, query: Document, patch: Document) -> Result<BulkResult<G::Id>> {
+        check_patch(&patch)?;
+        let docs = self
+            .find_docs(exclude_deleted::<D>(query), None)
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        // ids are read before anything is written, so a malformed document
+        // fails the whole call rather than a part of it
+        let docs = docs
+            .into_iter()
+            .map(|doc| Ok((get_id_from_doc::<G::Id>(&doc)?, doc)))
+            .collect::<Result<Vec<_>>>()?;
+        let mut result = BulkResult::default();
+        // one document at a time, so that each validation sees the documents
+        // patched before it, e.g. a unique field set on the whole batch
+        for (id, doc) in docs {
+            let outcome = self.patch_doc(&id, doc, &patch).await;
+            result.push(id, outcome);
+        }
+        Ok(result)
+    }
+
+    async fn delete_many(&self, query: Document) -> Result<i64> {
+        if D::soft_delete() {
+            Ok(self
+                .update_doc(exclude_deleted::<D>(query), mark_deleted(), true)
+                .await?
+                .1)
+        } else {
+            self.delete_tracked(query, true).await
+        }
+    }
+
+    async fn find_one_view<W>(
+        &self,
+        query: Document,
+        option: Option<FindOptions>,
+    ) -> Result<Option<WithId<W, G::Id>>>
+    where
+        W: View<Model = M>,
+    {
+        let option = view_option::<W>(option, None);
+        let doc_opt = self
+            .find_one_doc(exclude_deleted::<D>(query), option)
+            .await?;
         if let Some(doc) = doc_opt {
-            let id = get_id_from_doc(&doc)?;
-            let item = h_doc_to_model(doc)?;
+            let id = get_id_from_doc::<G::Id>(&doc)?;
+            let item = read_document::<W>(doc)?;
             Ok(Some(WithId(id, item)))
         } else {
             Ok(None)
         }
     }
 
-    async fn find_one_by_id(&self, id: &Id) -> Result<Option<WithId<M>>> {
-        self.find_one(doc! {"_id": Bson::ObjectId(id.clone())})
-            .await
-    }
-
-    async fn find_many(
+    async fn find_many_view<W>(
         &self,
         query: Document,
         option: Option<FindOptions>,
-    ) -> Result<ModelWithIdCursor<M>> {
+    ) -> Result<ModelWithIdCursor<W, G::Id>>
+    where
+        W: View<Model = M>,
+    {
+        let option = view_option::<W>(option, None);
         Ok(ModelWithIdCursor::from(
-            self.coll.find(query, option).await?,
+            self.find_docs(exclude_deleted::<D>(query), option).await?,
         ))
     }
+
+    async fn find_page_view<W>(
+        &self,
+        query: Document,
+        sort: &PageSort,
+        request: &PageRequest,
+    ) -> Result<Page<WithId<W, G::Id>>>
+    where
+        W: View<Model = M>,
+    {
+        let key = self.ctx.cursor_key();
+        let (query, limit) = page_window(query, sort, request, key)?;
+        let option = FindOptions::builder()
+            .sort(sort.sort_document())
+            .limit(limit)
+            .build();
+        let option = view_option::<W>(Some(option), Some(&sort.field));
+        let docs = self
+            .find_docs(exclude_deleted::<D>(query), option)
+            .await?
+            .try_collect::<Vec<_>>()
+            .await?;
+        collect_page(docs, sort, request, key, |doc| {
+            Ok(WithId(
+                get_id_from_doc::<G::Id>(&doc)?,
+                read_document::<W>(doc)?,
+            ))
+        })
+    }
 }
 
-fn oid(bson: Bson) -> Result<Id> {
-    match bson {
-        Bson::ObjectId(oid) => Ok(oid),
-        _ => Err(simple_error!("value is not ObjectId")),
+#[async_trait(?Send)]
+impl<M, Ctx, V, D, G, R> MongoRepositoryWithId for RepositoryWithIdBase<M, Ctx, V, D, G, R>
+where
+    Ctx: MongodmContext,
+    M: Schema,
+    V: Validator<Model = M, Ctx = Ctx>,
+    D: DeleteConfig,
+    G: IdGenerator,
+    R: RevisionConfig,
+{
+    async fn search_view<W>(
+        &self,
+        query: Document,
+        text: &str,
+        request: &PageRequest,
+    ) -> Result<Page<WithId<W, G::Id>>>
+    where
+        W: View<Model = M>,
+    {
+        let grams = query_ngrams(text);
+        if grams.is_empty() {
+            return Ok(Page {
+                items: vec![],
+                next_cursor: None,
+                has_next: false,
+            });
+        }
+        let sort = PageSort::desc(SCORE_FIELD);
+        let key = self.ctx.cursor_key();
+        let position = match &request.cursor {
+            Some(cursor) => Some(Position::decode(cursor, &sort, key)?),
+            None => None,
+        };
+        let limit = request.limit();
+        let mut stages = search_stages(exclude_deleted::<D>(query), &grams);
+        if let Some(position) = &position {
+            stages.push(doc! { "$match": sort.after(position) });
+        }
+        stages.push(doc! { "$sort": sort.sort_document() });
+        stages.push(doc! { "$limit": limit + 1 });
+        if let Some(projection) = view_projection::<W>(Some(SCORE_FIELD)) {
+            stages.push(doc! { "$project": projection });
+        }
+        let docs = self
+            .aggregate_docs(stages)
+            .await?
+            .map(|doc| doc.0)
+            .try_collect::<Vec<_>>()
+            .await?;
+        let sealer = self.sealer();
+        collect_page(docs, &sort, request, key, |mut doc| {
+            sealer.open_with_id(&mut doc);
+            Ok(WithId(
+                get_id_from_doc::<G::Id>(&doc)?,
+                read_document::<W>(doc)?,
+            ))
+        })
+    }
+
+    // `Encrypted` fields come out of the pipeline sealed.
+    async fn aggregate<Out>(&self, pipeline: Pipeline<M>) -> Result<AggregateCursor<Out>>
+    where
+        Out: DeserializeOwned,
+    {
+        let (mut stages, joined) = pipeline.into_parts();
+        if D::soft_delete() {
+            stages.insert(0, doc! { "$match": exclude_deleted::<D>(doc! {}) });
+        }
+        let docs = self
+            .aggregate_docs(stages)
+            .await?
+            .map(move |doc| AggregateDocument(doc.0.and_then(|doc| upcast_joined(doc, &joined))))
+            .boxed();
+        Ok(AggregateCursor::from(docs))
+    }
+
+    async fn watch(
+        &self,
+        query: Document,
+        resume: Option<ResumeToken>,
+    ) -> Result<ChangeStream<M, G::Id>>
+    where
+        M: 'static,
+    {
+        match self.ctx.watch_poll_interval() {
+            Some(interval) => {
+                poll_stream::<M, G::Id, D>(
+                    self.coll.clone(),
+                    self.sealer(),
+                    query,
+                    resume,
+                    interval,
+                )
+                .await
+            }
+            None => change_stream::<M, G::Id, D>(&self.coll, self.sealer(), query, resume).await,
+        }
     }
 }
 
-fn get_id_from_doc(doc: &Document) -> Result<Id> {
-    if let Some(id) = doc.get("_id") {
-        oid(id.clone())
-    } else {
-        Err(simple_error!("document don'n have _id"))
+impl<M, Ctx, V, D, G, R> RepositoryWithIdBase<M, Ctx, V, D, G, R>
+where
+    Ctx: MongodmContext,
+    M: Schema,
+    V: Validator<Model = M, Ctx = Ctx>,
+    D: DeleteConfig,
+    G: IdGenerator,
+    R: RevisionConfig,
+{
+    pub async fn find_deleted(
+        &self,
+        query: Document,
+        option: Option<FindOptions>,
+    ) -> Result<ModelWithIdCursor<M, G::Id>> {
+        Ok(ModelWithIdCursor::from(
+            self.find_docs(only_deleted(query), option).await?,
+        ))
+    }
+
+    pub async fn purge(&self) -> Result<i64> {
+        match (D::soft_delete(), D::purge_after_days()) {
+            (true, Some(days)) => self.delete_tracked(deleted_before(days), true).await,
+            _ => Ok(0),
+        }
+    }
+
+    pub async fn list_revisions(&self, id: &G::Id) -> Result<Vec<WithId<Revision<M>>>> {
+        let option = FindOptions::builder()
+            .sort(doc! { "at": -1, "_id": -1 })
+            .build();
+        let sealer = self.sealer();
+        self.revisions()
+            .await
+            .find_many(doc! { "target": id.to_bson() }, Some(option))
+            .await?
+            .map_ok(|mut revision| {
+                sealer.open(&mut revision.1.document, &revision.1.target);
+                revision
+            })
+            .try_collect()
+            .await
+    }
+
+    pub async fn diff_revisions(&self, from: &Id, to: &Id) -> Result<Vec<Change>> {
+        let from = self.find_revision(from).await?;
+        let to = self.find_revision(to).await?;
+        if from.target != to.target {
+            return Err(simple_error!("revisions belong to different documents"));
+        }
+        Ok(revision::diff(&from.document, &to.document))
+    }
+
+    // Goes through `update`, so the revision is validated like any other
+    // write and the version it replaces becomes a revision in turn.
+    pub async fn restore_revision(&self, revision_id: &Id) -> Result<()> {
+        let revision = self.find_revision(revision_id).await?;
+        let id = G::Id::from_bson(revision.target.clone())?;
+        self.update(&WithId(id, revision.model()?)).await
+    }
+
+    async fn revisions(&self) -> RepositoryWithIdBase<Revision<M>, Ctx> {
+        RepositoryWithIdBase::new(&self.ctx).await
+    }
+
+    async fn find_revision(&self, id: &Id) -> Result<Revision<M>> {
+        match self.revisions().await.find_one_by_id(id).await? {
+            Some(WithId(_, mut revision)) => {
+                self.sealer().open(&mut revision.document, &revision.target);
+                Ok(revision)
+            }
+            None => Err(simple_error!("revision not found")),
+        }
+    }
+
+    async fn record_revision(&self, mut doc: Document) -> Result<()> {
+        let target = doc.get("_id").cloned().unwrap_or(Bson::Null);
+        self.sealer().seal(&mut doc, &target)?;
+        let revision = Revision::new(target, doc, self.ctx.actor_id());
+        self.revisions().await.create(&revision).await?;
+        Ok(())
+    }
+
+    fn model_doc(&self, id: &G::Id, model: &M) -> Result<Document> {
+        let mut doc = to_document(model)?;
+        doc.insert("_id", id.to_bson());
+        stamp_version::<M>(&mut doc);
+        stamp_search::<M>(&mut doc);
+        stamp_on_create(&mut doc, self.ctx.actor_id());
+        self.sealer().seal(&mut doc, &id.to_bson())?;
+        Ok(doc)
+    }
+
+    #[inline]
+    fn sealer(&self) -> Sealer {
+        Sealer::new(self.ctx.keyring(), M::CollConf::collection_name())
+    }
+
+    async fn prepare_create(&self, model: &M) -> Result<(G::Id, Document)> {
+        V::validate(CreateOrUpdate::Create, model, &self.ctx).await?;
+        V::validate_refs(model, &self.ctx).await?;
+        let id = G::generate().ok_or(IdError::NotAssigned)?;
+        let doc = self.model_doc(&id, model)?;
+        Ok((id, doc))
+    }
+
+    async fn validate_patch(
+        &self,
+        id: &G::Id,
+        doc: Document,
+        patch: &Document,
+    ) -> Result<Document> {
+        let (model, doc) = patch_document::<M>(doc, patch)?;
+        V::validate(CreateOrUpdate::Update(id.to_bson()), &model, &self.ctx).await?;
+        V::validate_refs(&model, &self.ctx).await?;
+        Ok(doc)
+    }
+
+    // The whole document is written back, as it may have been upcast on the
+    // way, with its search field stamped in the same write.
+    async fn patch_doc(&self, id: &G::Id, doc: Document, patch: &Document) -> Result<()> {
+        let mut doc = self.validate_patch(id, doc, patch).await?;
+        doc.remove("_id");
+        stamp_version::<M>(&mut doc);
+        stamp_search::<M>(&mut doc);
+        stamp_on_update(&mut doc, self.ctx.actor_id());
+        self.sealer().seal(&mut doc, &id.to_bson())?;
+        let query = exclude_deleted::<D>(doc! {"_id": id.to_bson() });
+        if R::track_revisions() {
+            return match self.replace_doc(query, doc).await? {
+                Some(previous) => self.record_revision(previous).await,
+                None => Err(simple_error!("not found!")),
+            };
+        }
+        match self
+            .update_doc(query, replace_on_update(doc), false)
+            .await?
+        {
+            (1, _) => Ok(()),
+            _ => Err(simple_error!("not found!")),
+        }
+    }
+
+    async fn insert_doc(&self, doc: Document) -> Result<()> {
+        self.coll
+            .insert_one(doc, None)
+            .await
+            .map_err(translate_write_error)?;
+        Ok(())
+    }
+
+    async fn insert_docs(&self, docs: Vec<Document>) -> Result<Vec<Option<Error>>> {
+        let mut errors = docs.iter().map(|_| None).collect::<Vec<_>>();
+        if docs.is_empty() {
+            return Ok(errors);
+        }
+        let option = InsertManyOptions::builder().ordered(false).build();
+        if let Err(err) = self.coll.insert_many(docs, option).await {
+            let failures = match err.kind.as_ref() {
+                ErrorKind::BulkWriteError(BulkWriteFailure {
+                    write_errors: Some(write_errors),
+                    write_concern_error: None,
+                    ..
+                }) => Some(
+                    write_errors
+                        .iter()
+                        .map(|e| (e.index, translate_bulk_write_error(e)))
+                        .collect::<Vec<_>>(),
+                ),
+                _ => None,
+            };
+            match failures {
+                Some(failures) => {
+                    for (index, failure) in failures {
+                        errors[index] = Some(failure);
+                    }
+                }
+                None => return Err(translate_write_error(err)),
+            }
+        }
+        Ok(errors)
+    }
+
+    async fn update_doc(
+        &self,
+        query: Document,
+        update: impl Into<UpdateModifications>,
+        multi: bool,
+    ) -> Result<(i64, i64)> {
+        let result = if multi {
+            self.coll.update_many(query, update, None).await
+        } else {
+            self.coll.update_one(query, update, None).await
+        };
+        let result = result.map_err(translate_write_error)?;
+        Ok((result.matched_count, result.modified_count))
+    }
+
+    // Replaces one document as `update_doc` does and returns it as it was
+    // before, read in the same write so that no other write comes in between.
+    async fn replace_doc(&self, query: Document, doc: Document) -> Result<Option<Document>> {
+        let option = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::Before)
+            .build();
+        self.coll
+            .find_one_and_update(query, replace_on_update(doc), option)
+            .await
+            .map_err(translate_write_error)
+    }
+
+    // Deletes for good, revisions included.
+    async fn delete_tracked(&self, query: Document, multi: bool) -> Result<i64> {
+        if !R::track_revisions() {
+            return self.delete_docs(query, multi).await;
+        }
+        let mut option = FindOptions::builder().projection(doc! { "_id": 1 }).build();
+        if !multi {
+            option.limit = Some(1);
+        }
+        let ids = self
+            .coll
+            .find(query.clone(), option)
+            .await?
+            .map_ok(|doc| doc.get("_id").cloned().unwrap_or(Bson::Null))
+            .try_collect::<Vec<_>>()
+            .await?;
+        // only the documents whose ids were read, so no revisions are left
+        // behind by one matching in between
+        let query = doc! { "$and": [query, { "_id": { "$in": ids.clone() } }] };
+        let deleted_count = self.delete_docs(query, true).await?;
+        self.revisions()
+            .await
+            .delete_many(doc! { "target": { "$in": ids } })
+            .await?;
+        Ok(deleted_count)
+    }
+
+    async fn delete_docs(&self, query: Document, multi: bool) -> Result<i64> {
+        if multi {
+            Ok(self.coll.delete_many(query, None).await?.deleted_count)
+        } else {
+            Ok(self.coll.delete_one(query, None).await?.deleted_count)
+        }
+    }
+
+    async fn find_one_doc(
+        &self,
+        query: Document,
+        option: Option<FindOptions>,
+    ) -> Result<Option<Document>> {
+        let mut option = option.unwrap_or_default();
+        option.limit = Some(1);
+        let mut cursor = self.coll.find(query, option).await?;
+        let mut doc = cursor.try_next().await?;
+        if let Some(doc) = &mut doc {
+            self.sealer().open_with_id(doc);
+        }
+        Ok(doc)
+    }
+
+    async fn find_docs(
+        &self,
+        query: Document,
+        option: Option<FindOptions>,
+    ) -> Result<DocumentStream> {
+        let sealer = self.sealer();
+        Ok(self
+            .coll
+            .find(query, option)
+            .await?
+            .map_ok(move |mut doc| {
+                sealer.open_with_id(&mut doc);
+                doc
+            })
+            .boxed())
+    }
+
+    async fn aggregate_docs(&self, stages: Vec<Document>) -> Result<AggregateStream> {
+        let cursor = self.coll.aggregate(stages, None).await?;
+        Ok(cursor
+            .map(|doc| AggregateDocument(doc.map_err(Error::from)))
+            .boxed())
     }
 }
 
-fn h_doc_to_model<M>(doc: Document) -> Result<M>
+fn get_id_from_doc<I>(doc: &Document) -> Result<I>
 where
-    M: Model,
+    I: IdType,
 {
-    Ok(from_bson(Bson::Document(doc))?)
+    match doc.get("_id") {
+        Some(id) => I::from_bson(id.clone()),
+        None => Err(IdError::MissingId.into()),
+    }
 }
 
-pub type ValidatedRepositoryWithId<M, Ctx, V = DefaultValidate<M, Ctx>> =
-    RepositoryWithIdBase<M, Ctx, FromValidate<M, Ctx, V>>;
+pub type ValidatedRepositoryWithId<
+    M,
+    Ctx,
+    V = DefaultValidate<M, Ctx>,
+    D = HardDelete,
+    G = GenerateObjectId,
+    R = NoRevisions,
+> = RepositoryWithIdBase<M, Ctx, FromValidate<M, Ctx, V>, D, G, R>;
 
-pub async fn _valiidate_uniqueness<M>(
+#[derive(Debug, Error)]
+pub enum PatchError {
+    #[error("patch cannot contain update operators or nested paths: {0}")]
+    InvalidKey(String),
+    #[error("patch cannot change _id")]
+    Id,
+}
+
+// A patch sets whole top-level fields of the model, so one that looks like an
+// update document is rejected before anything is read.
+pub fn check_patch(patch: &Document) -> Result<()> {
+    for key in patch.keys() {
+        if key == "_id" {
+            return Err(PatchError::Id.into());
+        }
+        if key.starts_with('$') || key.contains('.') {
+            return Err(PatchError::InvalidKey(key.clone()).into());
+        }
+    }
+    Ok(())
+}
+
+pub async fn _validate_uniqueness<M>(
+    field: &str,
     doc_on_create: Document,
-    doc_on_update: impl Fn(Id) -> Document,
+    doc_on_update: impl Fn(Bson) -> Document,
+    collation: Option<Collation>,
     repo: &Repository<M>,
     cu: CreateOrUpdate,
 ) -> Result<()>
 where
     M: Model,
 {
-    match cu {
-        CreateOrUpdate::Create => {
-            if repo.find_one(doc_on_create, None).await?.is_some() {
-                Err(simple_error!("not unique on create"))
-            } else {
-                Ok(())
-            }
-        }
-        CreateOrUpdate::Update(id) => {
-            if repo.find_one(doc_on_update(id), None).await?.is_some() {
-                Err(simple_error!("not unique on update"))
-            } else {
-                Ok(())
-            }
-        }
+    // soft-deleted documents do not hold on to their values
+    let query = match cu {
+        CreateOrUpdate::Create => not_deleted(doc_on_create),
+        CreateOrUpdate::Update(id) => not_deleted(doc_on_update(id)),
+    };
+    let option = collation.map(|collation| FindOneOptions::builder().collation(collation).build());
+    if repo.find_one(query, option).await?.is_some() {
+        Err(NotUnique::new(field).into())
+    } else {
+        Ok(())
     }
 }
 
 #[macro_export]
 macro_rules! validate_uniqueness {
     (<$modeltype:ty, $field:ident>, $cu:expr, $model:expr, $ctx:expr) => {
+        $crate::validate_uniqueness!(<$modeltype, $field>, $cu, $model, $ctx, None)
+    };
+    (<$modeltype:ty, $field:ident>, $cu:expr, $model:expr, $ctx:expr, $collation:expr) => {
         let repo = ($ctx).repo::<$modeltype>();
-        $crate::withid::_valiidate_uniqueness(
+        $crate::withid::_validate_uniqueness(
+            stringify!($field),
             mongodm::doc! {
                 stringify!($field): &$model.$field
             },
@@ -431,6 +1118,7 @@ macro_rules! validate_uniqueness {
                     stringify!($field): &$model.$field
                 }
             },
+            $collation,
             &repo,
             $cu,
         )
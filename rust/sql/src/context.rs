@@ -0,0 +1,75 @@
+use std::sync::Arc;
+
+use sqlx::any::{AnyPool, AnyPoolOptions};
+
+use crate::{
+    mongo::{
+        service::{derive_cursor_key, TEST_CURSOR_KEY},
+        withid::Id,
+    },
+    table::{create_table_statements, SqlTable},
+    utils::{config::Config, result::Result, simple_error},
+};
+
+pub trait SqlContext
+where
+    Self: Clone,
+{
+    fn pool(&self) -> &AnyPool;
+
+    fn cursor_key(&self) -> &[u8];
+
+    #[inline]
+    fn actor_id(&self) -> Option<Id> {
+        None
+    }
+}
+
+#[derive(Clone)]
+pub struct Context {
+    pool: AnyPool,
+    cursor_key: Arc<Vec<u8>>,
+}
+
+impl Context {
+    pub async fn new(config: &Config) -> Result<Self> {
+        if config.sql_uri.is_empty() {
+            return Err(simple_error!("sql_uri is not configured"));
+        }
+        Ok(Context {
+            pool: AnyPoolOptions::new().connect(&config.sql_uri).await?,
+            cursor_key: Arc::new(derive_cursor_key(&config.session_key_bin()?)?),
+        })
+    }
+
+    // every connection to `sqlite::memory:` opens a database of its own, so
+    // the pool is kept to a single one
+    pub async fn build_in_test() -> Result<Self> {
+        Ok(Context {
+            pool: AnyPoolOptions::new()
+                .max_connections(1)
+                .connect("sqlite::memory:")
+                .await?,
+            cursor_key: Arc::new(TEST_CURSOR_KEY.to_vec()),
+        })
+    }
+
+    pub async fn sync_table<M: SqlTable>(&self) -> Result<()> {
+        for statement in create_table_statements::<M>() {
+            sqlx::query(&statement).execute(&self.pool).await?;
+        }
+        Ok(())
+    }
+}
+
+impl SqlContext for Context {
+    #[inline]
+    fn pool(&self) -> &AnyPool {
+        &self.pool
+    }
+
+    #[inline]
+    fn cursor_key(&self) -> &[u8] {
+        &self.cursor_key
+    }
+}
@@ -0,0 +1,10 @@
+pub mod context;
+pub mod query;
+pub mod repository;
+pub mod table;
+use ringoro_fcomps as fcomps;
+use ringoro_mongo as mongo;
+use ringoro_utils as utils;
+
+#[cfg(test)]
+mod test;
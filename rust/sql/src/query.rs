@@ -0,0 +1,315 @@
+use thiserror::Error;
+
+use crate::{
+    mongo::mongodm::bson::{Bson, Document},
+    table::{quote, Column, ColumnType, ID_COLUMN},
+    utils::result::Result,
+};
+
+#[derive(Debug, Error)]
+pub enum QueryError {
+    #[error("field is not a column: {0}")]
+    UnknownField(String),
+    #[error("unsupported operator: {0}")]
+    UnsupportedOperator(String),
+    #[error("invalid value for {0}")]
+    InvalidValue(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SqlValue {
+    Null(ColumnType),
+    Text(String),
+    Integer(i64),
+    Real(f64),
+    Boolean(bool),
+}
+
+pub fn id_text(id: &Bson) -> Result<String> {
+    match id {
+        Bson::ObjectId(id) => Ok(id.to_hex()),
+        Bson::String(id) => Ok(id.clone()),
+        Bson::Binary(binary) => Ok(binary.bytes.iter().map(|b| format!("{:02x}", b)).collect()),
+        _ => Err(QueryError::InvalidValue(String::from("_id")).into()),
+    }
+}
+
+pub(crate) fn bson_to_value(field: &str, kind: ColumnType, value: &Bson) -> Result<SqlValue> {
+    Ok(match (kind, value) {
+        (kind, Bson::Null) => SqlValue::Null(kind),
+        (ColumnType::Text, Bson::String(v)) => SqlValue::Text(v.clone()),
+        (ColumnType::Text, Bson::ObjectId(v)) => SqlValue::Text(v.to_hex()),
+        (ColumnType::Integer, Bson::Int32(v)) => SqlValue::Integer(*v as i64),
+        (ColumnType::Integer, Bson::Int64(v)) => SqlValue::Integer(*v),
+        (ColumnType::Real, Bson::Double(v)) => SqlValue::Real(*v),
+        (ColumnType::Real, Bson::Int32(v)) => SqlValue::Real(*v as f64),
+        (ColumnType::Real, Bson::Int64(v)) => SqlValue::Real(*v as f64),
+        (ColumnType::Boolean, Bson::Boolean(v)) => SqlValue::Boolean(*v),
+        (ColumnType::DateTime, Bson::DateTime(v)) => SqlValue::Integer(v.timestamp_millis()),
+        _ => return Err(QueryError::InvalidValue(String::from(field)).into()),
+    })
+}
+
+enum Target<'a> {
+    Id,
+    Column(&'a Column),
+}
+
+impl<'a> Target<'a> {
+    fn name(&self) -> String {
+        match self {
+            Self::Id => String::from(ID_COLUMN),
+            Self::Column(column) => quote(&column.name()),
+        }
+    }
+
+    fn value(&self, value: &Bson) -> Result<SqlValue> {
+        match (self, value) {
+            (Self::Id, Bson::Null) => Ok(SqlValue::Null(ColumnType::Text)),
+            (Self::Id, id) => Ok(SqlValue::Text(id_text(id)?)),
+            (Self::Column(column), value) => bson_to_value(column.field, column.kind, value),
+        }
+    }
+}
+
+fn join(mut terms: Vec<String>, separator: &str, empty: &str) -> String {
+    match terms.len() {
+        0 => String::from(empty),
+        1 => terms.remove(0),
+        _ => format!("({})", terms.join(separator)),
+    }
+}
+
+// Translates the subset of query documents that maps onto the id and the
+// extracted columns: plain equality, comparison operators, `$in`, `$nin`,
+// `$exists` and the `$and`, `$or` and `$nor` combinators. Values are bound as
+// `$1`, `$2`, ... in the order they appear.
+pub struct Where<'a> {
+    columns: &'a [Column],
+    pub params: Vec<SqlValue>,
+}
+
+impl<'a> Where<'a> {
+    pub fn new(columns: &'a [Column]) -> Self {
+        Self {
+            columns,
+            params: Vec::new(),
+        }
+    }
+
+    pub fn bind(&mut self, value: SqlValue) -> String {
+        self.params.push(value);
+        format!("${}", self.params.len())
+    }
+
+    fn target(&self, field: &str) -> Result<Target<'a>> {
+        if field == "_id" {
+            return Ok(Target::Id);
+        }
+        let columns: &'a [Column] = self.columns;
+        columns
+            .iter()
+            .find(|column| column.field == field)
+            .map(Target::Column)
+            .ok_or_else(|| QueryError::UnknownField(String::from(field)).into())
+    }
+
+    pub fn translate(&mut self, query: &Document) -> Result<String> {
+        let mut terms = Vec::new();
+        for (key, value) in query {
+            terms.push(match key.as_str() {
+                "$and" => self.logical(key, value, " AND ", "1 = 1")?,
+                "$or" => self.logical(key, value, " OR ", "1 = 0")?,
+                "$nor" => format!("NOT ({})", self.logical(key, value, " OR ", "1 = 0")?),
+                op if op.starts_with('$') => {
+                    return Err(QueryError::UnsupportedOperator(String::from(op)).into())
+                }
+                field => self.field(field, value)?,
+            });
+        }
+        Ok(join(terms, " AND ", "1 = 1"))
+    }
+
+    fn logical(&mut self, op: &str, value: &Bson, separator: &str, empty: &str) -> Result<String> {
+        let queries = match value {
+            Bson::Array(queries) => queries,
+            _ => return Err(QueryError::InvalidValue(String::from(op)).into()),
+        };
+        let mut terms = Vec::new();
+        for query in queries {
+            match query {
+                Bson::Document(query) => terms.push(self.translate(query)?),
+                _ => return Err(QueryError::InvalidValue(String::from(op)).into()),
+            }
+        }
+        Ok(join(terms, separator, empty))
+    }
+
+    fn field(&mut self, field: &str, value: &Bson) -> Result<String> {
+        let target = self.target(field)?;
+        match value {
+            Bson::Document(ops) if ops.keys().any(|key| key.starts_with('$')) => {
+                let mut terms = Vec::new();
+                for (op, value) in ops {
+                    terms.push(self.operator(&target, op, value)?);
+                }
+                Ok(join(terms, " AND ", "1 = 1"))
+            }
+            value => self.operator(&target, "$eq", value),
+        }
+    }
+
+    fn operator(&mut self, target: &Target, op: &str, value: &Bson) -> Result<String> {
+        let name = target.name();
+        let comparison = match op {
+            "$eq" => "=",
+            "$ne" => "<>",
+            "$gt" => ">",
+            "$gte" => ">=",
+            "$lt" => "<",
+            "$lte" => "<=",
+            "$in" => return self.within(target, value, false),
+            "$nin" => return self.within(target, value, true),
+            "$exists" => {
+                return match value.as_bool() {
+                    Some(true) => Ok(format!("{} IS NOT NULL", name)),
+                    Some(false) => Ok(format!("{} IS NULL", name)),
+                    None => Err(QueryError::InvalidValue(String::from(op)).into()),
+                }
+            }
+            _ => return Err(QueryError::UnsupportedOperator(String::from(op)).into()),
+        };
+        Ok(match (op, value) {
+            ("$eq", Bson::Null) => format!("{} IS NULL", name),
+            ("$ne", Bson::Null) => format!("{} IS NOT NULL", name),
+            // a missing value is "not equal" in MongoDB
+            ("$ne", value) => {
+                let param = self.bind(target.value(value)?);
+                format!("({0} <> {1} OR {0} IS NULL)", name, param)
+            }
+            (_, value) => {
+                let param = self.bind(target.value(value)?);
+                format!("{} {} {}", name, comparison, param)
+            }
+        })
+    }
+
+    fn within(&mut self, target: &Target, value: &Bson, negate: bool) -> Result<String> {
+        let values = match value {
+            Bson::Array(values) => values,
+            _ => return Err(QueryError::InvalidValue(String::from("$in")).into()),
+        };
+        if values.is_empty() {
+            return Ok(String::from(if negate { "1 = 1" } else { "1 = 0" }));
+        }
+        let mut params = Vec::new();
+        for value in values {
+            params.push(self.bind(target.value(value)?));
+        }
+        Ok(format!(
+            "{} {}IN ({})",
+            target.name(),
+            if negate { "NOT " } else { "" },
+            params.join(", ")
+        ))
+    }
+
+    pub fn order_by(&self, sort: &Document) -> Result<String> {
+        let mut keys = Vec::new();
+        for (field, direction) in sort {
+            let direction = match direction {
+                Bson::Int32(-1) | Bson::Int64(-1) => "DESC",
+                _ => "ASC",
+            };
+            keys.push(format!("{} {}", self.target(field)?.name(), direction));
+        }
+        Ok(keys.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::mongo::mongodm::{bson::oid::ObjectId, doc};
+
+    fn columns() -> Vec<Column> {
+        vec![
+            Column::new("name", ColumnType::Text).unique(),
+            Column::new("age", ColumnType::Integer),
+            Column::new("profile.height", ColumnType::Real),
+        ]
+    }
+
+    fn translate(query: Document) -> Result<(String, Vec<SqlValue>)> {
+        let columns = columns();
+        let mut clause = Where::new(&columns);
+        let sql = clause.translate(&query)?;
+        Ok((sql, clause.params))
+    }
+
+    #[test]
+    fn test_translate() {
+        assert_eq!(
+            (
+                String::from(r#"("f_name" = $1 AND ("f_age" >= $2 AND "f_age" < $3))"#),
+                vec![
+                    SqlValue::Text(String::from("akari")),
+                    SqlValue::Integer(14),
+                    SqlValue::Integer(20)
+                ]
+            ),
+            translate(doc! { "name": "akari", "age": { "$gte": 14, "$lt": 20 } }).unwrap()
+        );
+        assert_eq!(
+            (
+                String::from(
+                    r#"(("f_name" IN ($1, $2) OR "f_profile__height" IS NULL) AND ("f_age" <> $3 OR "f_age" IS NULL))"#
+                ),
+                vec![
+                    SqlValue::Text(String::from("akari")),
+                    SqlValue::Text(String::from("akira")),
+                    SqlValue::Integer(15),
+                ]
+            ),
+            translate(doc! {
+                "$or": [
+                    { "name": { "$in": ["akari", "akira"] } },
+                    { "profile.height": Bson::Null },
+                ],
+                "age": { "$ne": 15 },
+            })
+            .unwrap()
+        );
+        let id = ObjectId::new();
+        assert_eq!(
+            (String::from("id = $1"), vec![SqlValue::Text(id.to_hex())]),
+            translate(doc! { "_id": id.clone() }).unwrap()
+        );
+        assert_eq!((String::from("1 = 1"), vec![]), translate(doc! {}).unwrap());
+    }
+
+    #[test]
+    fn test_translate_unsupported() {
+        for query in vec![
+            doc! { "unit": "unibo" },
+            doc! { "name": { "$regex": "^a" } },
+            doc! { "$text": { "$search": "akari" } },
+            doc! { "age": "fourteen" },
+        ] {
+            assert!(translate(query).unwrap_err().is::<QueryError>());
+        }
+    }
+
+    #[test]
+    fn test_order_by() {
+        let columns = columns();
+        assert_eq!(
+            r#""f_age" DESC, id ASC"#,
+            Where::new(&columns)
+                .order_by(&doc! { "age": -1, "_id": 1 })
+                .unwrap()
+        );
+    }
+}
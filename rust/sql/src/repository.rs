@@ -0,0 +1,442 @@
+use std::marker::PhantomData;
+
+use async_trait::async_trait;
+use futures::{stream, StreamExt};
+use sqlx::{
+    any::{Any, AnyArguments},
+    query::Query,
+    Done, Row,
+};
+
+use crate::{
+    context::SqlContext,
+    mongo::{
+        bulk::BulkResult,
+        extjson::{decode_document, encode_document},
+        id::{GenerateObjectId, IdError, IdGenerator, IdType},
+        index::NotUnique,
+        metadata::{CREATED_AT, CREATED_BY},
+        mongodb::options::FindOptions,
+        mongodm::{
+            bson::{to_document, Document},
+            doc,
+        },
+        schema::{new_document, patch_document, read_document, touch_document},
+        service::{collect_page, page_window, Page, PageRequest, PageSort},
+        view::View,
+        withid::{
+            check_patch, CreateOrUpdate, DefaultValidate, ModelWithIdCursor, RepositoryWithId,
+            Validator, WithId,
+        },
+    },
+    query::{id_text, SqlValue, Where},
+    table::{quote, table_name, ColumnType, SqlTable, DOC_COLUMN, ID_COLUMN},
+    utils::{
+        result::{Error, Result, StdResult},
+        simple_error,
+    },
+};
+
+type SqlQuery<'q> = Query<'q, Any, AnyArguments<'q>>;
+
+fn bind(query: SqlQuery<'_>, value: SqlValue) -> SqlQuery<'_> {
+    match value {
+        SqlValue::Null(ColumnType::Text) => query.bind(None::<String>),
+        SqlValue::Null(ColumnType::Integer) | SqlValue::Null(ColumnType::DateTime) => {
+            query.bind(None::<i64>)
+        }
+        SqlValue::Null(ColumnType::Real) => query.bind(None::<f64>),
+        SqlValue::Null(ColumnType::Boolean) => query.bind(None::<bool>),
+        SqlValue::Text(v) => query.bind(v),
+        SqlValue::Integer(v) => query.bind(v),
+        SqlValue::Real(v) => query.bind(v),
+        SqlValue::Boolean(v) => query.bind(v),
+    }
+}
+
+fn placeholders(from: usize, count: usize) -> Vec<String> {
+    (from..from + count).map(|i| format!("${}", i)).collect()
+}
+
+// Both databases name the violated column or index in the message: SQLite
+// says `UNIQUE constraint failed: User.f_name`, PostgreSQL names the index,
+// e.g. `User_f_name` or `User_pkey`.
+fn translate_error<M: SqlTable>(err: sqlx::Error) -> Error {
+    if let sqlx::Error::Database(db) = &err {
+        let message = db.message();
+        let column = M::columns()
+            .into_iter()
+            .find(|column| column.unique && message.contains(&column.name()));
+        if let Some(column) = column {
+            return NotUnique::new(column.field).into();
+        }
+        let primary = format!("{}.{}", table_name::<M>(), ID_COLUMN);
+        if message.contains(&primary) || message.contains("_pkey") {
+            return NotUnique::new("_id").into();
+        }
+    }
+    err.into()
+}
+
+// The creation fields of the stored document, which an update never changes.
+fn keep_creation(current: &Document, doc: &mut Document) {
+    for key in &[CREATED_AT, CREATED_BY] {
+        if let Some(value) = current.get(*key) {
+            doc.insert(*key, value.clone());
+        }
+    }
+}
+
+// Keeps each document as canonical Extended JSON in a text column and copies
+// the fields listed by `SqlTable::columns` next to it, which is what queries,
+// sorts and unique constraints work on. Views are deserialized from the whole
+// document, so their projections are not applied. Soft delete needs MongoDB,
+// as do search, aggregation and change streams, which are not implemented.
+pub struct SqlRepositoryWithId<M, Ctx, V = DefaultValidate<M, Ctx>, G = GenerateObjectId>
+where
+    Ctx: SqlContext,
+    M: SqlTable,
+    V: Validator<Model = M, Ctx = Ctx>,
+    G: IdGenerator,
+{
+    ctx: Ctx,
+    p: PhantomData<fn() -> (M, V, G)>,
+}
+
+#[async_trait(?Send)]
+impl<M, Ctx, V, G> RepositoryWithId for SqlRepositoryWithId<M, Ctx, V, G>
+where
+    Ctx: SqlContext,
+    M: SqlTable,
+    V: Validator<Model = M, Ctx = Ctx>,
+    G: IdGenerator,
+{
+    type Model = M;
+    type Ctx = Ctx;
+    type Id = G::Id;
+
+    async fn new(ctx: &Ctx) -> Self {
+        Self {
+            ctx: ctx.clone(),
+            p: PhantomData,
+        }
+    }
+
+    async fn create(&self, model: &M) -> Result<G::Id> {
+        let (id, doc) = self.prepare_create(model).await?;
+        self.insert_doc(&doc).await?;
+        Ok(id)
+    }
+
+    async fn create_with_id(&self, id: &G::Id, model: &M) -> Result<()> {
+        V::validate(CreateOrUpdate::Create, model, &self.ctx).await?;
+        V::validate_refs(model, &self.ctx).await?;
+        self.insert_doc(&self.model_doc(id, model)?).await
+    }
+
+    async fn update(&self, model: &WithId<M, G::Id>) -> Result<()> {
+        V::validate(
+            CreateOrUpdate::Update(model.0.to_bson()),
+            &model.1,
+            &self.ctx,
+        )
+        .await?;
+        V::validate_refs(&model.1, &self.ctx).await?;
+        let mut doc = to_document(&model.1)?;
+        touch_document::<M>(&mut doc, self.ctx.actor_id());
+        let (row, current) = match self.find_row(doc! {"_id": model.0.to_bson() }).await? {
+            Some(found) => found,
+            None => return Err(simple_error!("not found!")),
+        };
+        doc.insert("_id", model.0.to_bson());
+        keep_creation(&current, &mut doc);
+        match self.update_doc(&doc, &row).await? {
+            1 => Ok(()),
+            _ => Err(simple_error!("cannot update!")),
+        }
+    }
+
+    async fn delete(&self, id: &G::Id) -> Result<()> {
+        let sql = format!(
+            "DELETE FROM {} WHERE {} = $1",
+            quote(table_name::<M>()),
+            ID_COLUMN
+        );
+        let id = SqlValue::Text(id_text(&id.to_bson())?);
+        if self.execute(&sql, vec![id]).await? == 1 {
+            Ok(())
+        } else {
+            Err(simple_error!("cannot update!"))
+        }
+    }
+
+    async fn restore(&self, _id: &G::Id) -> Result<()> {
+        Err(simple_error!("soft delete is disabled"))
+    }
+
+    async fn create_many(&self, models: &[M]) -> Result<BulkResult<usize, G::Id>> {
+        let mut result = BulkResult::default();
+        for (index, model) in models.iter().enumerate() {
+            result.push(index, self.create(model).await);
+        }
+        Ok(result)
+    }
+
+    async fn update_many(&self, query: Document, patch: Document) -> Result<BulkResult<G::Id>> {
+        check_patch(&patch)?;
+        let rows = self
+            .select_rows(&query, None, None, None)
+            .await?
+            .into_iter()
+            .map(|row| {
+                let doc = decode_document(&row)?;
+                match doc.get("_id") {
+                    Some(id) => Ok((G::Id::from_bson(id.clone())?, row, doc)),
+                    None => Err(IdError::MissingId.into()),
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut result = BulkResult::default();
+        for (id, row, doc) in rows {
+            let outcome = self.apply_patch(&id, &row, doc, &patch).await;
+            result.push(id, outcome);
+        }
+        Ok(result)
+    }
+
+    async fn delete_many(&self, query: Document) -> Result<i64> {
+        let columns = M::columns();
+        let mut clause = Where::new(&columns);
+        let sql = format!(
+            "DELETE FROM {} WHERE {}",
+            quote(table_name::<M>()),
+            clause.translate(&query)?
+        );
+        Ok(self.execute(&sql, clause.params).await? as i64)
+    }
+
+    async fn find_one_view<W>(
+        &self,
+        query: Document,
+        option: Option<FindOptions>,
+    ) -> Result<Option<WithId<W, G::Id>>>
+    where
+        W: View<Model = M>,
+    {
+        let option = option.unwrap_or_default();
+        let docs = self
+            .select(&query, option.sort.as_ref(), Some(1), option.skip)
+            .await?;
+        match docs.into_iter().next() {
+            Some(doc) => Ok(Some(Self::to_model(doc)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn find_many_view<W>(
+        &self,
+        query: Document,
+        option: Option<FindOptions>,
+    ) -> Result<ModelWithIdCursor<W, G::Id>>
+    where
+        W: View<Model = M>,
+    {
+        let option = option.unwrap_or_default();
+        let docs = self
+            .select(&query, option.sort.as_ref(), option.limit, option.skip)
+            .await?;
+        Ok(ModelWithIdCursor::from(
+            stream::iter(docs.into_iter().map(Ok)).boxed(),
+        ))
+    }
+
+    async fn find_page_view<W>(
+        &self,
+        query: Document,
+        sort: &PageSort,
+        request: &PageRequest,
+    ) -> Result<Page<WithId<W, G::Id>>>
+    where
+        W: View<Model = M>,
+    {
+        let key = self.ctx.cursor_key();
+        let (query, limit) = page_window(query, sort, request, key)?;
+        let docs = self
+            .select(&query, Some(&sort.sort_document()), Some(limit), None)
+            .await?;
+        collect_page(docs, sort, request, key, Self::to_model)
+    }
+}
+
+impl<M, Ctx, V, G> SqlRepositoryWithId<M, Ctx, V, G>
+where
+    Ctx: SqlContext,
+    M: SqlTable,
+    V: Validator<Model = M, Ctx = Ctx>,
+    G: IdGenerator,
+{
+    fn to_model<W>(doc: Document) -> Result<WithId<W, G::Id>>
+    where
+        W: View<Model = M>,
+    {
+        let id = match doc.get("_id") {
+            Some(id) => G::Id::from_bson(id.clone())?,
+            None => return Err(IdError::MissingId.into()),
+        };
+        Ok(WithId(id, read_document(doc)?))
+    }
+
+    #[inline]
+    fn model_doc(&self, id: &G::Id, model: &M) -> Result<Document> {
+        new_document(id.to_bson(), model, self.ctx.actor_id())
+    }
+
+    async fn prepare_create(&self, model: &M) -> Result<(G::Id, Document)> {
+        V::validate(CreateOrUpdate::Create, model, &self.ctx).await?;
+        V::validate_refs(model, &self.ctx).await?;
+        let id = G::generate().ok_or(IdError::NotAssigned)?;
+        let doc = self.model_doc(&id, model)?;
+        Ok((id, doc))
+    }
+
+    async fn apply_patch(
+        &self,
+        id: &G::Id,
+        row: &str,
+        current: Document,
+        patch: &Document,
+    ) -> Result<()> {
+        let (model, mut doc) = patch_document::<M>(current.clone(), patch)?;
+        V::validate(CreateOrUpdate::Update(id.to_bson()), &model, &self.ctx).await?;
+        V::validate_refs(&model, &self.ctx).await?;
+        touch_document::<M>(&mut doc, self.ctx.actor_id());
+        keep_creation(&current, &mut doc);
+        match self.update_doc(&doc, row).await? {
+            1 => Ok(()),
+            _ => Err(simple_error!("cannot update!")),
+        }
+    }
+
+    fn row_values(doc: &Document) -> Result<Vec<SqlValue>> {
+        M::columns()
+            .iter()
+            .map(|column| column.extract(doc))
+            .collect()
+    }
+
+    async fn insert_doc(&self, doc: &Document) -> Result<()> {
+        let id = doc.get("_id").ok_or(IdError::MissingId)?;
+        let mut names = vec![String::from(ID_COLUMN), String::from(DOC_COLUMN)];
+        names.extend(M::columns().iter().map(|column| quote(&column.name())));
+        let mut params = vec![
+            SqlValue::Text(id_text(id)?),
+            SqlValue::Text(encode_document(doc)?),
+        ];
+        params.extend(Self::row_values(doc)?);
+        let sql = format!(
+            "INSERT INTO {} ({}) VALUES ({})",
+            quote(table_name::<M>()),
+            names.join(", "),
+            placeholders(1, params.len()).join(", ")
+        );
+        self.execute(&sql, params).await?;
+        Ok(())
+    }
+
+    // Only while the stored row is still `row`, so that a read, modify and
+    // write never overwrites a write made in between.
+    async fn update_doc(&self, doc: &Document, row: &str) -> Result<u64> {
+        let id = doc.get("_id").ok_or(IdError::MissingId)?;
+        let mut names = vec![String::from(DOC_COLUMN)];
+        names.extend(M::columns().iter().map(|column| quote(&column.name())));
+        let mut params = vec![SqlValue::Text(encode_document(doc)?)];
+        params.extend(Self::row_values(doc)?);
+        let assignments = names
+            .iter()
+            .zip(placeholders(1, params.len()))
+            .map(|(name, param)| format!("{} = {}", name, param))
+            .collect::<Vec<_>>();
+        params.push(SqlValue::Text(id_text(id)?));
+        params.push(SqlValue::Text(String::from(row)));
+        let sql = format!(
+            "UPDATE {} SET {} WHERE {} = ${} AND {} = ${}",
+            quote(table_name::<M>()),
+            assignments.join(", "),
+            ID_COLUMN,
+            params.len() - 1,
+            DOC_COLUMN,
+            params.len()
+        );
+        self.execute(&sql, params).await
+    }
+
+    async fn execute(&self, sql: &str, params: Vec<SqlValue>) -> Result<u64> {
+        let query = params.into_iter().fold(sqlx::query(sql), bind);
+        let done = query
+            .execute(self.ctx.pool())
+            .await
+            .map_err(translate_error::<M>)?;
+        Ok(done.rows_affected())
+    }
+
+    // the stored row and its document
+    async fn find_row(&self, query: Document) -> Result<Option<(String, Document)>> {
+        match self.select_rows(&query, None, Some(1), None).await?.pop() {
+            Some(row) => {
+                let doc = decode_document(&row)?;
+                Ok(Some((row, doc)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn select(
+        &self,
+        query: &Document,
+        sort: Option<&Document>,
+        limit: Option<i64>,
+        skip: Option<i64>,
+    ) -> Result<Vec<Document>> {
+        self.select_rows(query, sort, limit, skip)
+            .await?
+            .iter()
+            .map(|row| decode_document(row))
+            .collect()
+    }
+
+    // `limit` follows MongoDB: zero means no limit and a negative one is
+    // taken as its absolute value.
+    async fn select_rows(
+        &self,
+        query: &Document,
+        sort: Option<&Document>,
+        limit: Option<i64>,
+        skip: Option<i64>,
+    ) -> Result<Vec<String>> {
+        let columns = M::columns();
+        let mut clause = Where::new(&columns);
+        let mut sql = format!(
+            "SELECT {} FROM {} WHERE {}",
+            DOC_COLUMN,
+            quote(table_name::<M>()),
+            clause.translate(query)?
+        );
+        if let Some(sort) = sort.filter(|sort| !sort.is_empty()) {
+            sql.push_str(&format!(" ORDER BY {}", clause.order_by(sort)?));
+        }
+        let limit = limit.map(i64::abs).filter(|limit| *limit > 0);
+        if limit.is_some() || skip.is_some() {
+            sql.push_str(&format!(
+                " LIMIT {} OFFSET {}",
+                limit.unwrap_or(i64::MAX),
+                skip.unwrap_or(0).max(0)
+            ));
+        }
+        let query = clause.params.into_iter().fold(sqlx::query(&sql), bind);
+        let rows = query.fetch_all(self.ctx.pool()).await?;
+        Ok(rows
+            .iter()
+            .map(|row| row.try_get::<String, _>(DOC_COLUMN))
+            .collect::<StdResult<Vec<_>, _>>()?)
+    }
+}
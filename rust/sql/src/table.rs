@@ -0,0 +1,116 @@
+use crate::{
+    mongo::mongodm::{
+        bson::{Bson, Document},
+        CollectionConfig, Model,
+    },
+    mongo::schema::Schema,
+    query::{bson_to_value, SqlValue},
+    utils::result::Result,
+};
+
+pub const ID_COLUMN: &str = "id";
+pub const DOC_COLUMN: &str = "doc";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColumnType {
+    Text,
+    Integer,
+    Real,
+    Boolean,
+    // milliseconds since the epoch
+    DateTime,
+}
+
+impl ColumnType {
+    fn sql_type(self) -> &'static str {
+        match self {
+            Self::Text => "TEXT",
+            Self::Integer | Self::DateTime => "BIGINT",
+            Self::Real => "DOUBLE PRECISION",
+            Self::Boolean => "BOOLEAN",
+        }
+    }
+}
+
+// A field copied out of the stored document into its own indexed column, so it
+// can be filtered and sorted on.
+#[derive(Debug, Clone)]
+pub struct Column {
+    pub field: &'static str,
+    pub kind: ColumnType,
+    pub unique: bool,
+}
+
+impl Column {
+    pub fn new(field: &'static str, kind: ColumnType) -> Self {
+        Self {
+            field,
+            kind,
+            unique: false,
+        }
+    }
+
+    pub fn unique(mut self) -> Self {
+        self.unique = true;
+        self
+    }
+
+    pub fn name(&self) -> String {
+        format!("f_{}", self.field.replace('.', "__"))
+    }
+
+    pub(crate) fn extract(&self, doc: &Document) -> Result<SqlValue> {
+        let mut value = Some(Bson::Document(doc.clone()));
+        for key in self.field.split('.') {
+            value = match value {
+                Some(Bson::Document(mut doc)) => doc.remove(key),
+                _ => None,
+            };
+        }
+        bson_to_value(self.field, self.kind, &value.unwrap_or(Bson::Null))
+    }
+}
+
+pub trait SqlTable: Schema {
+    fn columns() -> Vec<Column>;
+}
+
+#[inline]
+pub fn table_name<M: Model>() -> &'static str {
+    M::CollConf::collection_name()
+}
+
+#[inline]
+pub(crate) fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+// Both SQLite and PostgreSQL accept these statements as they are.
+pub fn create_table_statements<M: SqlTable>() -> Vec<String> {
+    let table = table_name::<M>();
+    let columns = M::columns();
+    let mut definitions = vec![
+        format!("{} TEXT PRIMARY KEY", ID_COLUMN),
+        format!("{} TEXT NOT NULL", DOC_COLUMN),
+    ];
+    definitions.extend(
+        columns
+            .iter()
+            .map(|column| format!("{} {}", quote(&column.name()), column.kind.sql_type())),
+    );
+    let mut statements = vec![format!(
+        "CREATE TABLE IF NOT EXISTS {} ({})",
+        quote(table),
+        definitions.join(", ")
+    )];
+    statements.extend(columns.iter().map(|column| {
+        format!(
+            "CREATE {}INDEX IF NOT EXISTS {} ON {} ({})",
+            if column.unique { "UNIQUE " } else { "" },
+            quote(&format!("{}_{}", table, column.name())),
+            quote(table),
+            quote(&column.name())
+        )
+    }));
+    statements
+}
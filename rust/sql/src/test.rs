@@ -0,0 +1,417 @@
+use futures::TryStreamExt;
+use pretty_assertions::assert_eq;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    context::Context,
+    fcomps::convert::Convertible,
+    mongo::{
+        id::{GenerateSlug, IdType},
+        index::NotUnique,
+        metadata::Metadata,
+        mongodb::options::FindOptions,
+        mongodm::{
+            bson::{oid::ObjectId, Bson},
+            doc, CollectionConfig, Indexes, Model,
+        },
+        schema::Schema,
+        service::{FindManyArgument, FindOneArgument, PageRequest, PageSort, WithIdCRUDService},
+        withid::{DefaultValidate, Id, PatchError, RepositoryWithId, WithId},
+    },
+    repository::SqlRepositoryWithId,
+    table::{Column, ColumnType, SqlTable},
+    utils::result::Result,
+};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Idol {
+    name: String,
+    age: i32,
+    unit: Option<String>,
+    #[serde(flatten)]
+    metadata: Metadata,
+}
+
+impl Idol {
+    fn new(name: &str, age: i32, unit: Option<&str>) -> Self {
+        Self {
+            name: String::from(name),
+            age,
+            unit: unit.map(String::from),
+            metadata: Metadata::default(),
+        }
+    }
+}
+
+struct IdolCfg {}
+
+impl CollectionConfig for IdolCfg {
+    fn collection_name() -> &'static str {
+        "Idol"
+    }
+
+    fn indexes() -> Indexes {
+        Indexes::new()
+    }
+}
+
+impl Model for Idol {
+    type CollConf = IdolCfg;
+}
+
+impl Schema for Idol {}
+
+impl SqlTable for Idol {
+    fn columns() -> Vec<Column> {
+        vec![
+            Column::new("name", ColumnType::Text).unique(),
+            Column::new("age", ColumnType::Integer),
+            Column::new("unit", ColumnType::Text),
+            Column::new("created_at", ColumnType::DateTime),
+        ]
+    }
+}
+
+type Repo = SqlRepositoryWithId<Idol, Context>;
+
+async fn context() -> Context {
+    let ctx = Context::build_in_test().await.unwrap();
+    ctx.sync_table::<Idol>().await.unwrap();
+    ctx
+}
+
+async fn create_idols(repo: &Repo) -> Vec<Id> {
+    let mut ids = Vec::new();
+    for (name, age, unit) in vec![
+        ("akari", 14, Some("unibo")),
+        ("akira", 15, Some("unibo")),
+        ("riamu", 19, Some("unibo")),
+        ("kaede", 25, None),
+        ("mayu", 16, None),
+    ] {
+        ids.push(repo.create(&Idol::new(name, age, unit)).await.unwrap());
+    }
+    ids
+}
+
+fn names(items: &[WithId<Idol>]) -> Vec<&str> {
+    items.iter().map(|item| item.1.name.as_str()).collect()
+}
+
+#[tokio::test]
+async fn test_create_update_delete() {
+    let ctx = context().await;
+    let repo = Repo::new(&ctx).await;
+    let id = repo.create(&Idol::new("akari", 14, None)).await.unwrap();
+    let created = repo.find_one_by_id(&id).await.unwrap().unwrap();
+    assert_eq!("akari", created.1.name);
+    assert_eq!(None, created.1.unit);
+    assert!(created.1.metadata.created_at.is_some());
+
+    repo.update(&WithId(id.clone(), Idol::new("akari", 15, Some("unibo"))))
+        .await
+        .unwrap();
+    let updated = repo.find_one_by_id(&id).await.unwrap().unwrap();
+    assert_eq!(15, updated.1.age);
+    assert_eq!(Some(String::from("unibo")), updated.1.unit);
+    assert_eq!(created.1.metadata.created_at, updated.1.metadata.created_at);
+
+    let missing = WithId(ObjectId::new(), Idol::new("akira", 15, None));
+    let _ = repo.update(&missing).await.unwrap_err();
+
+    repo.delete(&id).await.unwrap();
+    assert!(repo.find_one_by_id(&id).await.unwrap().is_none());
+    let _ = repo.delete(&id).await.unwrap_err();
+}
+
+#[tokio::test]
+async fn test_create_with_id() {
+    let ctx = context().await;
+    let repo =
+        SqlRepositoryWithId::<Idol, Context, DefaultValidate<Idol, Context>, GenerateSlug>::new(
+            &ctx,
+        )
+        .await;
+    let id = repo.create(&Idol::new("akari", 14, None)).await.unwrap();
+    assert_eq!(
+        "akari",
+        repo.find_one_by_id(&id).await.unwrap().unwrap().1.name
+    );
+    let assigned = String::from("riamu");
+    repo.create_with_id(&assigned, &Idol::new("riamu", 19, None))
+        .await
+        .unwrap();
+    let err = repo
+        .create_with_id(&assigned, &Idol::new("yume", 16, None))
+        .await
+        .unwrap_err();
+    assert_eq!("_id", err.downcast_ref::<NotUnique>().unwrap().field);
+}
+
+#[tokio::test]
+async fn test_find() {
+    let ctx = context().await;
+    let repo = Repo::new(&ctx).await;
+    let ids = create_idols(&repo).await;
+
+    let option = FindOptions::builder()
+        .sort(doc! { "age": -1 })
+        .limit(2)
+        .skip(1)
+        .build();
+    let items = repo
+        .find_many(doc! { "age": { "$gte": 15 } }, Some(option))
+        .await
+        .unwrap()
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+    assert_eq!(vec!["riamu", "mayu"], names(&items));
+
+    let items = repo
+        .find_many(
+            doc! { "$or": [{ "unit": Bson::Null }, { "name": "akari" }] },
+            Some(FindOptions::builder().sort(doc! { "name": 1 }).build()),
+        )
+        .await
+        .unwrap()
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+    assert_eq!(vec!["akari", "kaede", "mayu"], names(&items));
+
+    let item = repo
+        .find_one(doc! { "_id": { "$in": [ids[1].to_bson()] } }, None)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(ids[1], item.0);
+    assert_eq!("akira", item.1.name);
+
+    let _ = repo
+        .find_many(doc! { "name": { "$regex": "^a" } }, None)
+        .await
+        .unwrap_err();
+}
+
+#[tokio::test]
+async fn test_find_page() {
+    let ctx = context().await;
+    let repo = Repo::new(&ctx).await;
+    create_idols(&repo).await;
+
+    let sort = PageSort::desc("age");
+    let mut request = PageRequest::new(None, Some(2));
+    let mut pages = Vec::new();
+    loop {
+        let page = repo.find_page(doc! {}, &sort, &request).await.unwrap();
+        pages.push(names(&page.items).join(","));
+        match page.next_cursor {
+            Some(cursor) => request.cursor = Some(cursor),
+            None => break,
+        }
+    }
+    assert_eq!(vec!["kaede,riamu", "mayu,akira", "akari"], pages);
+}
+
+#[tokio::test]
+async fn test_unique() {
+    let ctx = context().await;
+    let repo = Repo::new(&ctx).await;
+    let id = repo.create(&Idol::new("akari", 14, None)).await.unwrap();
+    let err = repo
+        .create(&Idol::new("akari", 15, None))
+        .await
+        .unwrap_err();
+    assert_eq!("name", err.downcast_ref::<NotUnique>().unwrap().field);
+
+    let other = repo.create(&Idol::new("akira", 15, None)).await.unwrap();
+    let err = repo
+        .update(&WithId(other, Idol::new("akari", 15, None)))
+        .await
+        .unwrap_err();
+    assert!(err.is::<NotUnique>());
+
+    let result = repo
+        .create_many(&[Idol::new("riamu", 19, None), Idol::new("akari", 14, None)])
+        .await
+        .unwrap();
+    assert_eq!(
+        vec![0],
+        result
+            .succeeded()
+            .map(|(index, _)| *index)
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(
+        vec![1],
+        result.failed().map(|(index, _)| *index).collect::<Vec<_>>()
+    );
+    assert!(repo.find_one_by_id(&id).await.unwrap().is_some());
+}
+
+#[tokio::test]
+async fn test_update_many_and_delete_many() {
+    let ctx = context().await;
+    let repo = Repo::new(&ctx).await;
+    create_idols(&repo).await;
+
+    let result = repo
+        .update_many(doc! { "unit": "unibo" }, doc! { "unit": "unit" })
+        .await
+        .unwrap();
+    assert_eq!(3, result.succeeded().count());
+    let count = repo
+        .find_many(doc! { "unit": "unit" }, None)
+        .await
+        .unwrap()
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap()
+        .len();
+    assert_eq!(3, count);
+
+    // a patch that does not fit the model is reported per document
+    let result = repo
+        .update_many(doc! { "name": "kaede" }, doc! { "age": "twenty" })
+        .await
+        .unwrap();
+    assert_eq!(1, result.failed().count());
+    let err = repo
+        .update_many(doc! {}, doc! { "$set": { "age": 20 } })
+        .await
+        .unwrap_err();
+    assert!(err.is::<PatchError>());
+
+    assert_eq!(
+        2,
+        repo.delete_many(doc! { "age": { "$lt": 16 } })
+            .await
+            .unwrap()
+    );
+    assert_eq!(
+        vec!["mayu", "riamu", "kaede"],
+        names(
+            &repo
+                .find_many(
+                    doc! {},
+                    Some(FindOptions::builder().sort(doc! { "age": 1 }).build())
+                )
+                .await
+                .unwrap()
+                .try_collect::<Vec<_>>()
+                .await
+                .unwrap()
+        )
+    );
+}
+
+#[tokio::test]
+async fn test_unsupported() {
+    let ctx = context().await;
+    let repo = Repo::new(&ctx).await;
+    let id = repo.create(&Idol::new("akari", 14, None)).await.unwrap();
+    let _ = repo.restore(&id).await.unwrap_err();
+}
+
+struct IdolInput {
+    name: String,
+    age: i32,
+}
+
+impl Convertible<Idol> for IdolInput {
+    fn convert(self) -> Result<Idol> {
+        Ok(Idol::new(&self.name, self.age, None))
+    }
+}
+
+struct FindOneInput {
+    name: String,
+}
+
+impl Convertible<FindOneArgument> for FindOneInput {
+    fn convert(self) -> Result<FindOneArgument> {
+        Ok(FindOneArgument(doc! { "name": self.name }, None))
+    }
+}
+
+struct FindManyInput {
+    max_age: i32,
+}
+
+impl Convertible<FindManyArgument> for FindManyInput {
+    fn convert(self) -> Result<FindManyArgument> {
+        let option = FindOptions::builder().sort(doc! { "name": 1 }).build();
+        Ok(FindManyArgument(
+            doc! { "age": { "$lte": self.max_age } },
+            Some(option),
+        ))
+    }
+}
+
+#[derive(Debug)]
+struct IdolOutput {
+    id: Id,
+    name: String,
+}
+
+impl Convertible<IdolOutput> for WithId<Idol> {
+    fn convert(self) -> Result<IdolOutput> {
+        Ok(IdolOutput {
+            id: self.0,
+            name: self.1.name,
+        })
+    }
+}
+
+type Service = WithIdCRUDService<Repo, IdolInput, IdolOutput, FindOneInput, FindManyInput>;
+
+#[tokio::test]
+async fn test_service() {
+    let ctx = context().await;
+    let input = |name: &str, age| IdolInput {
+        name: String::from(name),
+        age,
+    };
+    let id = Service::create(input("akari", 14), &ctx).await.unwrap();
+    Service::create(input("akira", 15), &ctx).await.unwrap();
+    Service::update(WithId(id.clone(), input("akari", 16)), &ctx)
+        .await
+        .unwrap();
+
+    let found = Service::find_one(
+        FindOneInput {
+            name: String::from("akari"),
+        },
+        &ctx,
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(id, found.id);
+
+    let items = Service::find_many(FindManyInput { max_age: 16 }, &ctx)
+        .await
+        .unwrap()
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap();
+    assert_eq!(
+        vec!["akari", "akira"],
+        items
+            .iter()
+            .map(|item| item.name.as_str())
+            .collect::<Vec<_>>()
+    );
+
+    Service::delete(id, &ctx).await.unwrap();
+    assert!(Service::find_one(
+        FindOneInput {
+            name: String::from("akari"),
+        },
+        &ctx,
+    )
+    .await
+    .unwrap()
+    .is_none());
+}
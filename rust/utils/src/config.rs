@@ -14,12 +14,40 @@ pub struct Config {
     pub twitter_consumer_key: String,
     pub twitter_consumer_secret: String,
     pub twitter_redirect_url: String,
+    #[serde(default = "default_blob_backend")]
+    pub blob_backend: String,
+    #[serde(default = "default_blob_dir")]
+    pub blob_dir: String,
+    #[serde(default)]
+    pub s3_endpoint: String,
+    #[serde(default)]
+    pub s3_bucket: String,
+    #[serde(default)]
+    pub s3_region: String,
+    #[serde(default)]
+    pub s3_access_key: String,
+    #[serde(default)]
+    pub s3_secret_key: String,
+    // `<id>:<hex 32 bytes>` pairs separated by commas, the first one encrypts
+    #[serde(default)]
+    pub encryption_keys: String,
+    // e.g. `sqlite://ringoro.db` or `postgres://...`, for the sql backend
+    #[serde(default)]
+    pub sql_uri: String,
 }
 
 fn default_port() -> u16 {
     8080
 }
 
+fn default_blob_backend() -> String {
+    String::from("gridfs")
+}
+
+fn default_blob_dir() -> String {
+    String::from("blobs")
+}
+
 impl Config {
     pub fn from_env() -> Result<Config> {
         Ok(envy::prefixed("RINGORO_").from_env::<Config>()?)
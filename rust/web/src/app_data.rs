@@ -1,6 +1,9 @@
-use crate::{context::MongoContext, utils::config::Config};
+use std::sync::Arc;
+
+use crate::{blob::BlobStore, context::MongoContext, utils::config::Config};
 
 pub struct AppData {
     pub context: MongoContext,
     pub config: Config,
+    pub blob: Arc<dyn BlobStore + Send + Sync>,
 }
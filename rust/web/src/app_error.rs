@@ -3,7 +3,12 @@ use std::fmt;
 use actix_web::{error::ResponseError, http::StatusCode, HttpResponse};
 use log::error;
 
-use crate::utils::result::{self, StdResult};
+use crate::{
+    mongo::{
+        listquery::ListQueryError, reference::MissingRef, service::InvalidCursor, watch::WatchError,
+    },
+    utils::result::{self, StdResult},
+};
 
 #[derive(Debug)]
 pub struct AppError {
@@ -18,7 +23,18 @@ impl fmt::Display for AppError {
 
 impl ResponseError for AppError {
     fn status_code(&self) -> StatusCode {
-        StatusCode::INTERNAL_SERVER_ERROR
+        if self.error.is::<InvalidCursor>()
+            || self.error.is::<ListQueryError>()
+            || self.error.is::<MissingRef>()
+            || matches!(
+                self.error.downcast_ref::<WatchError>(),
+                Some(WatchError::InvalidResumeToken)
+            )
+        {
+            StatusCode::BAD_REQUEST
+        } else {
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
     }
 }
 
@@ -38,7 +38,8 @@ pub async fn check_login(
     let result = match session::get_username(session) {
         Ok(user_name) => {
             let repo = UserRepository::new(ctx).await;
-            repo.find_one(doc! {f!(name in User): &user_name}).await?
+            repo.find_one(doc! {f!(name in User): &user_name}, None)
+                .await?
         }
         _ => None,
     };
@@ -107,7 +108,7 @@ pub async fn test_create_user(
 async fn create_or_get_user(user_name: impl AsRef<str>, ctx: &Context) -> Result<WithId<User>> {
     let repo = UserRepository::new(ctx).await;
     let user = match repo
-        .find_one(doc! {f!(name in User): user_name.as_ref()})
+        .find_one(doc! {f!(name in User): user_name.as_ref()}, None)
         .await?
     {
         Some(u) => u,
@@ -133,7 +134,7 @@ async fn verify(cfg: &Config, session: &Session, ctx: &Context) -> Result<()> {
     if verified_user_name == saved_user_name {
         let repo = UserRepository::new(ctx).await;
         let _ = repo
-            .find_one(doc! {f!(name in User): saved_user_name})
+            .find_one(doc! {f!(name in User): saved_user_name}, None)
             .await?;
         session::set_last_verified_at(session)?;
         Ok(())
@@ -0,0 +1,30 @@
+use std::io::{Error, ErrorKind, Result};
+
+use ringoro_utils::config::Config;
+use ringoro_web::{blob, context::MongoContext, migrations};
+
+#[actix_web::main]
+async fn main() -> Result<()> {
+    let dry_run = std::env::args().any(|arg| arg == "--dry-run");
+    match run(dry_run).await {
+        Ok(()) => Ok(()),
+        Err(err) => Err(Error::new(ErrorKind::Other, err)),
+    }
+}
+
+async fn run(dry_run: bool) -> ringoro_utils::result::Result<()> {
+    let config = Config::from_env()?;
+    let context = MongoContext::new(&config).await?;
+    let reports = migrations::migrator(blob::from_config(&config, &context)?)
+        .run(&context.database(), dry_run)
+        .await?;
+    for report in reports {
+        println!(
+            "{}{}: {} documents",
+            if dry_run { "[dry-run] " } else { "" },
+            report.name,
+            report.affected
+        );
+    }
+    Ok(())
+}
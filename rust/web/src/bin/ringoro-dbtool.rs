@@ -0,0 +1,64 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    path::Path,
+};
+
+use ringoro_utils::{config::Config, simple_error};
+use ringoro_web::{blob, context::MongoContext, dbtool::DbTool};
+
+const USAGE: &str = "usage:
+    ringoro-dbtool export <dir> [collection...]
+    ringoro-dbtool import <dir> [--remap] [collection...]
+    ringoro-dbtool check [collection...]";
+
+#[actix_web::main]
+async fn main() -> Result<()> {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    match run(&args).await {
+        Ok(()) => Ok(()),
+        Err(err) => Err(Error::new(ErrorKind::Other, err)),
+    }
+}
+
+async fn run(args: &[String]) -> ringoro_utils::result::Result<()> {
+    let config = Config::from_env()?;
+    let context = MongoContext::new(&config).await?;
+    let tool = DbTool::new(&context, blob::from_config(&config, &context)?);
+    match args {
+        [command, dir, rest @ ..] if command == "export" => {
+            let report = tool.export(Path::new(dir), rest).await?;
+            for (name, count) in report.collections {
+                println!("{}: {} documents", name, count);
+            }
+            println!("blobs: {}", report.blobs);
+            for key in report.missing_blobs {
+                println!("missing blob: {}", key);
+            }
+        }
+        [command, dir, rest @ ..] if command == "import" => {
+            let remap = rest.iter().any(|arg| arg == "--remap");
+            let collections = rest
+                .iter()
+                .filter(|arg| *arg != "--remap")
+                .cloned()
+                .collect::<Vec<_>>();
+            let report = tool.import(Path::new(dir), &collections, remap).await?;
+            println!("blobs: {}", report.blobs);
+            for (name, count) in report.collections {
+                println!("{}: {} documents", name, count);
+            }
+        }
+        [command, rest @ ..] if command == "check" => {
+            let failures = tool.check(rest).await?;
+            for failure in failures.iter() {
+                println!("{} {}: {}", failure.collection, failure.id, failure.error);
+            }
+            if !failures.is_empty() {
+                let count = failures.len();
+                return Err(simple_error!("{} documents failed to deserialize", count));
+            }
+        }
+        _ => return Err(simple_error!("{}", USAGE)),
+    }
+    Ok(())
+}
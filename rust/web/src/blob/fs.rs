@@ -0,0 +1,260 @@
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use futures::{stream, StreamExt, TryStreamExt};
+use mongodm::bson::oid::ObjectId;
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::{self, File},
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::Mutex,
+};
+
+use super::{check_key, check_prefix, BlobBody, BlobMeta, BlobStore};
+use crate::utils::{
+    result::Result,
+    serde::{Deserialize, Serialize},
+};
+
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+const TMP_DIR: &str = "tmp";
+
+#[derive(Serialize, Deserialize)]
+struct Sidecar {
+    content_type: String,
+    size: i64,
+    // sidecars from before counting have a single holder
+    #[serde(default = "one")]
+    refs: i64,
+}
+
+#[inline]
+fn one() -> i64 {
+    1
+}
+
+// Blobs are laid out as `<root>/<key[..2]>/<key>` with a `<key>.json` sidecar
+// holding the content type and the number of holders. The count is only kept
+// consistent within one process, so a root must not be shared by servers.
+pub struct FsStore {
+    root: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl FsStore {
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    fn dir(&self, key: &str) -> PathBuf {
+        self.root.join(&key[..2])
+    }
+
+    fn data_path(&self, key: &str) -> PathBuf {
+        self.dir(key).join(key)
+    }
+
+    fn sidecar_path(&self, key: &str) -> PathBuf {
+        self.dir(key).join(format!("{}.json", key))
+    }
+
+    async fn write_tmp(&self, path: &Path, mut body: BlobBody) -> Result<(String, i64)> {
+        let mut file = File::create(path).await?;
+        let mut hasher = Sha256::new();
+        let mut size = 0;
+        while let Some(data) = body.try_next().await? {
+            hasher.update(&data);
+            size += data.len() as i64;
+            file.write_all(&data).await?;
+        }
+        file.sync_all().await?;
+        Ok((format!("{:x}", hasher.finalize()), size))
+    }
+
+    async fn read_sidecar(&self, key: &str) -> Result<Option<Sidecar>> {
+        match fs::read(self.sidecar_path(key)).await {
+            Ok(sidecar) => Ok(Some(serde_json::from_slice(&sidecar)?)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn write_sidecar(&self, key: &str, sidecar: &Sidecar) -> Result<()> {
+        Ok(fs::write(self.sidecar_path(key), serde_json::to_vec(sidecar)?).await?)
+    }
+}
+
+async fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path).await {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+async fn read_chunk(mut file: File) -> Result<Option<(Vec<u8>, File)>> {
+    let mut buf = vec![0; READ_CHUNK_SIZE];
+    let n = file.read(&mut buf).await?;
+    if n == 0 {
+        return Ok(None);
+    }
+    buf.truncate(n);
+    Ok(Some((buf, file)))
+}
+
+#[async_trait(?Send)]
+impl BlobStore for FsStore {
+    async fn put(&self, content_type: &str, body: BlobBody) -> Result<BlobMeta> {
+        let tmp_dir = self.root.join(TMP_DIR);
+        fs::create_dir_all(&tmp_dir).await?;
+        let tmp = tmp_dir.join(ObjectId::new().to_hex());
+        let (key, size) = match self.write_tmp(&tmp, body).await {
+            Ok(written) => written,
+            Err(err) => {
+                remove_if_exists(&tmp).await?;
+                return Err(err);
+            }
+        };
+        let _guard = self.lock.lock().await;
+        if let Some(mut sidecar) = self.read_sidecar(&key).await? {
+            if fs::metadata(self.data_path(&key)).await.is_ok() {
+                sidecar.refs += 1;
+                self.write_sidecar(&key, &sidecar).await?;
+                remove_if_exists(&tmp).await?;
+                return Ok(BlobMeta {
+                    key,
+                    content_type: sidecar.content_type,
+                    size: sidecar.size,
+                });
+            }
+        }
+        fs::create_dir_all(self.dir(&key)).await?;
+        let sidecar = Sidecar {
+            content_type: String::from(content_type),
+            size,
+            refs: 1,
+        };
+        self.write_sidecar(&key, &sidecar).await?;
+        fs::rename(&tmp, self.data_path(&key)).await?;
+        Ok(BlobMeta {
+            key,
+            content_type: sidecar.content_type,
+            size,
+        })
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<(BlobMeta, BlobBody)>> {
+        let meta = match self.head(key).await? {
+            Some(meta) => meta,
+            None => return Ok(None),
+        };
+        let file = File::open(self.data_path(key)).await?;
+        let body = stream::try_unfold(file, read_chunk).boxed_local();
+        Ok(Some((meta, body)))
+    }
+
+    async fn head(&self, key: &str) -> Result<Option<BlobMeta>> {
+        check_key(key)?;
+        let sidecar = match self.read_sidecar(key).await? {
+            Some(sidecar) => sidecar,
+            None => return Ok(None),
+        };
+        if fs::metadata(self.data_path(key)).await.is_err() {
+            return Ok(None);
+        }
+        Ok(Some(BlobMeta {
+            key: String::from(key),
+            content_type: sidecar.content_type,
+            size: sidecar.size,
+        }))
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        check_key(key)?;
+        let _guard = self.lock.lock().await;
+        match self.read_sidecar(key).await? {
+            Some(mut sidecar) if sidecar.refs > 1 => {
+                sidecar.refs -= 1;
+                self.write_sidecar(key, &sidecar).await
+            }
+            _ => {
+                remove_if_exists(&self.data_path(key)).await?;
+                remove_if_exists(&self.sidecar_path(key)).await
+            }
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        check_prefix(prefix)?;
+        let mut keys = Vec::new();
+        let mut dirs = match fs::read_dir(&self.root).await {
+            Ok(dirs) => dirs,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(keys),
+            Err(err) => return Err(err.into()),
+        };
+        while let Some(dir) = dirs.next_entry().await? {
+            let name = dir.file_name().to_string_lossy().into_owned();
+            if name.len() != 2 || !(name.starts_with(prefix) || prefix.starts_with(&name)) {
+                continue;
+            }
+            let mut entries = fs::read_dir(dir.path()).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let key = entry.file_name().to_string_lossy().into_owned();
+                if check_key(&key).is_ok() && key.starts_with(prefix) {
+                    keys.push(key);
+                }
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::blob::{body_from_bytes, content_key, read_body};
+
+    fn temp_root() -> PathBuf {
+        std::env::temp_dir().join(format!("ringoro-blob-{}", ObjectId::new().to_hex()))
+    }
+
+    #[tokio::test]
+    async fn test_fs_store() -> Result<()> {
+        let root = temp_root();
+        let store = FsStore::new(&root);
+        let body = stream::iter(vec![Ok(b"a".to_vec()), Ok(b"bc".to_vec())]).boxed_local();
+        let meta = store.put("text/plain", body).await?;
+        assert_eq!(content_key(b"abc"), meta.key);
+        assert_eq!(3, meta.size);
+        assert_eq!(Some(meta.clone()), store.head(&meta.key).await?);
+
+        let again = store
+            .put("text/plain", body_from_bytes(b"abc".to_vec()))
+            .await?;
+        assert_eq!(meta, again);
+        assert_eq!(vec![meta.key.clone()], store.list("").await?);
+        assert_eq!(vec![meta.key.clone()], store.list(&meta.key[..3]).await?);
+        assert!(store.list("0000").await?.is_empty());
+
+        let (_, body) = store.get(&meta.key).await?.unwrap();
+        assert_eq!(b"abc".to_vec(), read_body(body).await?);
+
+        // one delete for each put
+        store.delete(&meta.key).await?;
+        assert!(store.head(&meta.key).await?.is_some());
+        store.delete(&meta.key).await?;
+        assert!(store.head(&meta.key).await?.is_none());
+        assert!(store.get(&meta.key).await?.is_none());
+        store.delete(&meta.key).await?;
+        assert!(store.head("../../etc/passwd").await.is_err());
+        fs::remove_dir_all(&root).await?;
+        Ok(())
+    }
+}
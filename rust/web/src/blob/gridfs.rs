@@ -0,0 +1,121 @@
+use async_trait::async_trait;
+use futures::StreamExt;
+use mongodm::mongo::Database;
+
+use super::{check_key, check_prefix, BlobBody, BlobMeta, BlobStore};
+use crate::{
+    mongo::gridfs::{BlobRef, GridFs},
+    utils::result::Result,
+};
+
+pub struct GridFsStore {
+    fs: GridFs,
+}
+
+impl GridFsStore {
+    #[inline]
+    pub fn new(database: Database) -> Self {
+        Self {
+            fs: GridFs::new(database),
+        }
+    }
+}
+
+impl From<BlobRef> for BlobMeta {
+    #[inline]
+    fn from(blob: BlobRef) -> Self {
+        Self {
+            key: blob.sha256,
+            content_type: blob.content_type,
+            size: blob.size,
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl BlobStore for GridFsStore {
+    // The upload is kept only when the content is new, or when the existing
+    // file was released before it could be taken.
+    async fn put(&self, content_type: &str, body: BlobBody) -> Result<BlobMeta> {
+        let blob = self.fs.upload(content_type, body).await?;
+        if let Some(existing) = self.fs.find_by_sha256(&blob.sha256).await? {
+            if existing.id != blob.id && self.fs.retain(&existing.id).await? {
+                self.fs.delete(&blob.id).await?;
+                return Ok(existing.into());
+            }
+        }
+        Ok(blob.into())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<(BlobMeta, BlobBody)>> {
+        check_key(key)?;
+        match self.fs.find_by_sha256(key).await? {
+            Some(blob) => {
+                let body = self.fs.download(&blob.id).await?.boxed_local();
+                Ok(Some((blob.into(), body)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn head(&self, key: &str) -> Result<Option<BlobMeta>> {
+        check_key(key)?;
+        Ok(self.fs.find_by_sha256(key).await?.map(BlobMeta::from))
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        check_key(key)?;
+        self.fs.release_by_sha256(key).await
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        check_prefix(prefix)?;
+        self.fs.list_sha256(prefix).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        blob::{body_from_bytes, content_key, read_body},
+        mongo::test_util::with_mongo,
+    };
+
+    #[tokio::test]
+    async fn test_gridfs_store() {
+        with_mongo(|ctx| async move {
+            let store = GridFsStore::new(ctx.database());
+            let meta = store
+                .put("text/plain", body_from_bytes(b"abc".to_vec()))
+                .await?;
+            assert_eq!(content_key(b"abc"), meta.key);
+            let again = store
+                .put("text/plain", body_from_bytes(b"abc".to_vec()))
+                .await?;
+            assert_eq!(meta, again);
+            assert_eq!(
+                1,
+                ctx.database()
+                    .collection("fs.files")
+                    .count_documents(None, None)
+                    .await?
+            );
+            assert_eq!(vec![meta.key.clone()], store.list("ba").await?);
+
+            let (_, body) = store.get(&meta.key).await?.unwrap();
+            assert_eq!(b"abc".to_vec(), read_body(body).await?);
+
+            // one delete for each put
+            store.delete(&meta.key).await?;
+            assert!(store.head(&meta.key).await?.is_some());
+            store.delete(&meta.key).await?;
+            assert!(store.head(&meta.key).await?.is_none());
+            Ok(())
+        })
+        .await
+        .unwrap()
+    }
+}
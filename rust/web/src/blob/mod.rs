@@ -0,0 +1,139 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use futures::{
+    stream::{self, LocalBoxStream},
+    StreamExt, TryStreamExt,
+};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+mod fs;
+mod gridfs;
+mod s3;
+
+pub use fs::*;
+pub use gridfs::*;
+pub use s3::*;
+
+use crate::{
+    context::MongoContext,
+    utils::{
+        config::Config,
+        result::Result,
+        serde::{Deserialize, Serialize},
+        simple_error,
+    },
+};
+
+pub type BlobBody = LocalBoxStream<'static, Result<Vec<u8>>>;
+
+#[derive(Debug, Error)]
+pub enum BlobError {
+    #[error("invalid blob key: {0}")]
+    InvalidKey(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BlobMeta {
+    pub key: String,
+    pub content_type: String,
+    pub size: i64,
+}
+
+// Keys are shared by every upload of the same content, so each `put` takes a
+// reference on it and each `delete` drops one; the content goes with the last.
+#[async_trait(?Send)]
+pub trait BlobStore {
+    async fn put(&self, content_type: &str, body: BlobBody) -> Result<BlobMeta>;
+
+    async fn get(&self, key: &str) -> Result<Option<(BlobMeta, BlobBody)>>;
+
+    async fn head(&self, key: &str) -> Result<Option<BlobMeta>>;
+
+    async fn delete(&self, key: &str) -> Result<()>;
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>>;
+
+    // Stores that cannot count remove the content on any `delete`, and callers
+    // have to make sure nothing else refers to it first.
+    #[inline]
+    fn counts_references(&self) -> bool {
+        true
+    }
+}
+
+#[inline]
+pub fn body_from_bytes(data: Vec<u8>) -> BlobBody {
+    stream::once(async move { Ok(data) }).boxed_local()
+}
+
+pub async fn read_body(body: BlobBody) -> Result<Vec<u8>> {
+    Ok(body.try_collect::<Vec<_>>().await?.concat())
+}
+
+#[inline]
+pub fn content_key(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+// keys are sha256 digests of the content, so anything else is rejected before
+// it can reach a path or an URL
+pub(crate) fn check_key(key: &str) -> Result<()> {
+    if key.len() == 64 && key.bytes().all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f')) {
+        Ok(())
+    } else {
+        Err(BlobError::InvalidKey(String::from(key)).into())
+    }
+}
+
+pub(crate) fn check_prefix(prefix: &str) -> Result<()> {
+    if prefix.len() <= 64
+        && prefix
+            .bytes()
+            .all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f'))
+    {
+        Ok(())
+    } else {
+        Err(BlobError::InvalidKey(String::from(prefix)).into())
+    }
+}
+
+pub fn from_config(
+    config: &Config,
+    ctx: &MongoContext,
+) -> Result<Arc<dyn BlobStore + Send + Sync>> {
+    match config.blob_backend.as_str() {
+        "gridfs" => Ok(Arc::new(GridFsStore::new(ctx.database()))),
+        "fs" => Ok(Arc::new(FsStore::new(&config.blob_dir))),
+        "s3" => Ok(Arc::new(S3Store::new(S3Config {
+            endpoint: config.s3_endpoint.clone(),
+            bucket: config.s3_bucket.clone(),
+            region: config.s3_region.clone(),
+            access_key: config.s3_access_key.clone(),
+            secret_key: config.s3_secret_key.clone(),
+        }))),
+        backend => Err(simple_error!("unknown blob backend: {}", backend)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_content_key() {
+        let key = content_key(b"abc");
+        assert_eq!(
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            key
+        );
+        assert!(check_key(&key).is_ok());
+        assert!(check_key("../etc/passwd").is_err());
+        assert!(check_key(&key.to_uppercase()).is_err());
+        assert!(check_prefix("ba78").is_ok());
+        assert!(check_prefix("ba/").is_err());
+    }
+}
@@ -1,9 +1,13 @@
+use std::sync::Arc;
+use std::time::Duration;
+
 use mongodm::{Model, Repository};
 
 pub use crate::{
     mongo::{
         context::{Context as MongoContext, MongodmContext},
-        withid::WithId,
+        encrypted::Keyring,
+        withid::{Id, WithId},
     },
     stores::User,
 };
@@ -27,4 +31,20 @@ impl MongodmContext for Context {
     {
         self.mongo.repo()
     }
+
+    fn cursor_key(&self) -> &[u8] {
+        self.mongo.cursor_key()
+    }
+
+    fn actor_id(&self) -> Option<Id> {
+        self.user.as_ref().map(|user| user.0.clone())
+    }
+
+    fn watch_poll_interval(&self) -> Option<Duration> {
+        self.mongo.watch_poll_interval()
+    }
+
+    fn keyring(&self) -> Option<Arc<Keyring>> {
+        self.mongo.keyring()
+    }
 }
@@ -8,7 +8,6 @@ use actix_web::{
     http::header,
     post, web, App, HttpResponse,
 };
-use futures::TryStreamExt;
 use serde::Deserialize;
 
 use crate::{
@@ -32,25 +31,6 @@ macro_rules! entry {
     };
 }
 
-macro_rules! list_entry {
-    ([$path:tt] async fn $name:ident ($input:ty) -> [$output:ty] = $service:path) => {
-        #[post($path)]
-        #[allow(clippy::unit_arg)]
-        async fn $name(
-            session: Session,
-            query: web::Json<$input>,
-            st: web::Data<Arc<AppData>>,
-        ) -> Responce {
-            let ctx = ctx(session, st).await?;
-            let result = $service(query.into_inner(), &ctx)
-                .await?
-                .try_collect::<Vec<$output>>()
-                .await?;
-            Ok(HttpResponse::Ok().json(result))
-        }
-    };
-}
-
 pub fn route<V, T>(app: App<T, V>) -> App<T, V>
 where
     V: MessageBody,
@@ -68,6 +48,9 @@ where
         .service(get_user)
         .service(get_users)
         .service(delete_user)
+        .service(search_users_entry)
+        .service(audit_log_entry)
+        .service(promote_temp_img_entry)
 }
 
 #[get("/login")]
@@ -131,9 +114,9 @@ entry! {
     async fn get_user(UserFindOneInput) = UserService::find_one
 }
 
-list_entry! {
+entry! {
     ["/api/users"]
-    async fn get_users(()) -> [UserOutput] = UserService::find_many
+    async fn get_users(UserListInput) = UserService::find_many
 }
 
 entry! {
@@ -141,6 +124,21 @@ entry! {
     async fn delete_user(UserFindOneInput) = UserService::delete
 }
 
+entry! {
+    ["/api/search_users"]
+    async fn search_users_entry(UserSearchInput) = search_users
+}
+
+entry! {
+    ["/api/audit_log"]
+    async fn audit_log_entry(AuditLogInput) = audit_log
+}
+
+entry! {
+    ["/api/promote_temp_img"]
+    async fn promote_temp_img_entry(PromoteTempImgInput) = promote_temp_img
+}
+
 async fn ctx(session: Session, st: web::Data<Arc<AppData>>) -> Result<Context> {
     let dummy = Context::new(st.context.clone(), None);
     let user = auth::check_login(&st.config, &session, &dummy).await?;
@@ -0,0 +1,457 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use futures::TryStreamExt;
+use mongodm::{
+    bson::{from_bson, Bson, Document},
+    mongo::Database,
+};
+
+use crate::{
+    blob::{body_from_bytes, check_key, BlobMeta, BlobStore},
+    context::MongoContext,
+    mongo::{
+        audit::AuditEntry,
+        context::MongodmContext,
+        dump::{
+            check_collection, export_collection, find_conflicts, import_documents,
+            insert_documents, read_documents, CheckFailure, IdMap, ModelRegistry,
+        },
+        encrypted::Keyring,
+        migration::MIGRATIONS_COLLECTION,
+        revision::REVISION_SUFFIX,
+    },
+    stores::{RawImage, TempImg, User},
+    utils::{result::Result, simple_error},
+};
+
+const EXTENSION: &str = "ndjson";
+const BLOB_DIR: &str = "blobs";
+const BLOB_INDEX: &str = "index.ndjson";
+// the collections of the default GridFS bucket
+const GRIDFS_COLLECTIONS: [&str; 2] = ["fs.files", "fs.chunks"];
+
+pub fn registry() -> ModelRegistry {
+    ModelRegistry::new()
+        .with::<User>()
+        .with::<TempImg>()
+        .with::<RawImage>()
+        .with::<AuditEntry>()
+}
+
+fn as_blob_meta(doc: &Document) -> Option<BlobMeta> {
+    if !doc.contains_key("key") || !doc.contains_key("content_type") {
+        return None;
+    }
+    let meta = from_bson::<BlobMeta>(Bson::Document(doc.clone())).ok()?;
+    check_key(&meta.key).ok().map(|_| meta)
+}
+
+// Any embedded document shaped like a `BlobMeta` is taken as a blob reference.
+fn collect_blobs(doc: &Document, blobs: &mut Vec<BlobMeta>) {
+    match as_blob_meta(doc) {
+        Some(meta) => blobs.push(meta),
+        None => doc
+            .values()
+            .for_each(|value| collect_blob_values(value, blobs)),
+    }
+}
+
+fn collect_blob_values(value: &Bson, blobs: &mut Vec<BlobMeta>) {
+    match value {
+        Bson::Document(doc) => collect_blobs(doc, blobs),
+        Bson::Array(values) => values
+            .iter()
+            .for_each(|value| collect_blob_values(value, blobs)),
+        _ => (),
+    }
+}
+
+fn collection_path(dir: &Path, collection: &str) -> PathBuf {
+    dir.join(format!("{}.{}", collection, EXTENSION))
+}
+
+// Database state rather than data: not copied by an import with `remap`.
+fn is_state(collection: &str) -> bool {
+    collection == MIGRATIONS_COLLECTION || GRIDFS_COLLECTIONS.contains(&collection)
+}
+
+fn dumped_collections(dir: &Path) -> Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension().map_or(false, |ext| ext == EXTENSION) {
+            if let Some(name) = path.file_stem().and_then(|name| name.to_str()) {
+                names.push(String::from(name));
+            }
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ExportReport {
+    pub collections: Vec<(String, usize)>,
+    pub blobs: usize,
+    pub missing_blobs: Vec<String>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ImportReport {
+    pub collections: Vec<(String, usize)>,
+    pub blobs: usize,
+}
+
+// A dump directory holds a `<collection>.ndjson` file of canonical Extended
+// JSON per collection and the blobs they refer to under `blobs/`, listed in
+// `blobs/index.ndjson`. Next to the models it holds their revisions and, for a
+// whole database, the applied migrations and the GridFS bucket.
+pub struct DbTool {
+    db: Database,
+    store: Arc<dyn BlobStore + Send + Sync>,
+    registry: ModelRegistry,
+    keyring: Option<Arc<Keyring>>,
+}
+
+impl DbTool {
+    pub fn new(ctx: &MongoContext, store: Arc<dyn BlobStore + Send + Sync>) -> Self {
+        Self {
+            db: ctx.database(),
+            store,
+            registry: registry(),
+            keyring: ctx.keyring(),
+        }
+    }
+
+    // no collections means every registered one
+    fn selected(&self, collections: &[String]) -> Vec<String> {
+        if collections.is_empty() {
+            self.registry
+                .collections()
+                .into_iter()
+                .map(String::from)
+                .collect()
+        } else {
+            collections.to_vec()
+        }
+    }
+
+    // The existing collections that go along with the selected ones.
+    async fn companions(&self, selected: &[String], everything: bool) -> Result<Vec<String>> {
+        let existing = self.db.list_collection_names(None).await?;
+        let mut names = selected
+            .iter()
+            .map(|name| format!("{}{}", name, REVISION_SUFFIX))
+            .collect::<Vec<_>>();
+        if everything {
+            names.push(String::from(MIGRATIONS_COLLECTION));
+            names.extend(GRIDFS_COLLECTIONS.iter().map(|name| String::from(*name)));
+        }
+        Ok(names
+            .into_iter()
+            .filter(|name| existing.contains(name) && !selected.contains(name))
+            .collect())
+    }
+
+    async fn export_to(
+        &self,
+        dir: &Path,
+        name: &str,
+        visit: impl FnMut(&Document),
+    ) -> Result<usize> {
+        let mut out = BufWriter::new(File::create(collection_path(dir, name))?);
+        let count = export_collection(&self.db, name, &mut out, visit).await?;
+        out.flush()?;
+        Ok(count)
+    }
+
+    // Only the models are searched for blobs, the same documents an import
+    // takes references for.
+    pub async fn export(&self, dir: &Path, collections: &[String]) -> Result<ExportReport> {
+        let blob_dir = dir.join(BLOB_DIR);
+        fs::create_dir_all(&blob_dir)?;
+        let mut report = ExportReport::default();
+        let mut blobs = Vec::new();
+        let selected = self.selected(collections);
+        for name in &selected {
+            let count = self
+                .export_to(dir, name, |doc| collect_blobs(doc, &mut blobs))
+                .await?;
+            report.collections.push((name.clone(), count));
+        }
+        for name in self.companions(&selected, collections.is_empty()).await? {
+            let count = self.export_to(dir, &name, |_| ()).await?;
+            report.collections.push((name, count));
+        }
+
+        blobs.sort_by(|a, b| a.key.cmp(&b.key));
+        blobs.dedup_by(|a, b| a.key == b.key);
+        let mut index = BufWriter::new(File::create(blob_dir.join(BLOB_INDEX))?);
+        for blob in blobs {
+            match self.store.get(&blob.key).await? {
+                Some((meta, mut body)) => {
+                    let mut out = BufWriter::new(File::create(blob_dir.join(&meta.key))?);
+                    while let Some(chunk) = body.try_next().await? {
+                        out.write_all(&chunk)?;
+                    }
+                    out.flush()?;
+                    writeln!(index, "{}", serde_json::to_string(&meta)?)?;
+                    report.blobs += 1;
+                }
+                None => report.missing_blobs.push(blob.key),
+            }
+        }
+        index.flush()?;
+        Ok(report)
+    }
+
+    // With `remap` every `ObjectId` key gets a fresh id and the references to it
+    // across the imported collections follow. The copies are only added, so
+    // nothing is written if an id or unique value left as it is is already
+    // taken, and migrations and GridFS files are not copied. Otherwise ids are
+    // kept and existing documents replaced.
+    pub async fn import(
+        &self,
+        dir: &Path,
+        collections: &[String],
+        remap: bool,
+    ) -> Result<ImportReport> {
+        let names = if collections.is_empty() {
+            dumped_collections(dir)?
+        } else {
+            collections.to_vec()
+        };
+        let mut dumps = Vec::new();
+        for name in names {
+            if remap && is_state(&name) {
+                continue;
+            }
+            let docs = read_documents(BufReader::new(File::open(collection_path(dir, &name))?))?;
+            dumps.push((name, docs));
+        }
+        if remap {
+            let mut ids = IdMap::new();
+            dumps
+                .iter()
+                .flat_map(|(_, docs)| docs.iter())
+                .for_each(|doc| ids.assign(doc));
+            for (name, docs) in dumps.iter_mut() {
+                *docs = docs
+                    .drain(..)
+                    .map(|doc| ids.remap_document(&self.registry, self.keyring.clone(), name, doc))
+                    .collect::<Result<_>>()?;
+            }
+            for (name, docs) in &dumps {
+                let conflicts = find_conflicts(&self.db, name, docs).await?;
+                if !conflicts.is_empty() {
+                    return Err(simple_error!(
+                        "{} documents of {} conflict with existing ones: {:?}",
+                        conflicts.len(),
+                        name,
+                        conflicts
+                    ));
+                }
+            }
+        }
+
+        let mut refs = HashMap::new();
+        for doc in dumps.iter().flat_map(|(_, docs)| docs.iter()) {
+            let mut blobs = Vec::new();
+            collect_blobs(doc, &mut blobs);
+            for blob in blobs {
+                *refs.entry(blob.key).or_insert(0) += 1;
+            }
+        }
+        // A restored GridFS bucket brings its blobs along with their references,
+        // so it goes in before the blobs.
+        let (gridfs, dumps): (Vec<_>, Vec<_>) = dumps
+            .into_iter()
+            .partition(|(name, _)| GRIDFS_COLLECTIONS.contains(&name.as_str()));
+        let mut report = ImportReport::default();
+        for (name, docs) in gridfs {
+            let count = import_documents(&self.db, &name, docs).await?;
+            report.collections.push((name, count));
+        }
+        let restored = !report.collections.is_empty();
+        report.blobs = self
+            .import_blobs(&dir.join(BLOB_DIR), &refs, restored)
+            .await?;
+        for (name, docs) in dumps {
+            let count = if remap {
+                insert_documents(&self.db, &name, docs).await?
+            } else {
+                import_documents(&self.db, &name, docs).await?
+            };
+            report.collections.push((name, count));
+        }
+        Ok(report)
+    }
+
+    // Blobs are content addressed, so only ones the store doesn't have yet are
+    // counted. Each imported document referring to a blob takes a reference on
+    // it, like the upload it came from did, unless the blob came back with a
+    // `restored` GridFS bucket.
+    async fn import_blobs(
+        &self,
+        blob_dir: &Path,
+        refs: &HashMap<String, usize>,
+        restored: bool,
+    ) -> Result<usize> {
+        let index = blob_dir.join(BLOB_INDEX);
+        if !index.is_file() {
+            return Ok(0);
+        }
+        let mut count = 0;
+        for line in BufReader::new(File::open(index)?).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let meta = serde_json::from_str::<BlobMeta>(&line)?;
+            check_key(&meta.key)?;
+            let holders = refs.get(&meta.key).copied().unwrap_or(0);
+            let exists = self.store.head(&meta.key).await?.is_some();
+            let puts = match (self.store.counts_references(), exists) {
+                (_, true) if restored => 0,
+                (true, _) => holders,
+                (false, false) => holders.min(1),
+                (false, true) => 0,
+            };
+            if puts == 0 {
+                continue;
+            }
+            let data = fs::read(blob_dir.join(&meta.key))?;
+            for _ in 0..puts {
+                let stored = self
+                    .store
+                    .put(&meta.content_type, body_from_bytes(data.clone()))
+                    .await?;
+                if stored.key != meta.key {
+                    let key = meta.key;
+                    return Err(simple_error!("blob {} does not match its content", key));
+                }
+            }
+            if !exists {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    pub async fn check(&self, collections: &[String]) -> Result<Vec<CheckFailure>> {
+        let mut failures = Vec::new();
+        for name in self.selected(collections) {
+            failures.extend(check_collection(&self.db, &self.registry, &name).await?);
+        }
+        Ok(failures)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::TryStreamExt;
+    use mongodm::{doc, CollectionConfig};
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        blob::FsStore,
+        context::Context,
+        mongo::{
+            test_util::with_mongo,
+            withid::{Id, RepositoryWithId},
+        },
+        stores::{RawImageCfg, RawImageRepository},
+    };
+
+    #[test]
+    fn test_collect_blobs() {
+        let key = crate::blob::content_key(b"akari");
+        let doc = doc! {
+            "blob": { "key": key.as_str(), "content_type": "image/png", "size": 5 },
+            "images": [{ "orig": { "key": key.as_str(), "content_type": "image/png", "size": 5i64 } }],
+            "other": { "key": "../etc/passwd", "content_type": "text/plain", "size": 1 },
+        };
+        let mut blobs = Vec::new();
+        collect_blobs(&doc, &mut blobs);
+        assert_eq!(
+            vec![key.as_str(), key.as_str()],
+            blobs
+                .iter()
+                .map(|blob| blob.key.as_str())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_export_import() {
+        with_mongo(|ctx| async move {
+            let root = std::env::temp_dir().join(format!("ringoro-dbtool-{}", Id::new().to_hex()));
+            let store = Arc::new(FsStore::new(root.join("store")));
+            let dump = root.join("dump");
+            let tool = DbTool::new(&ctx, store.clone());
+            let repo = RawImageRepository::new(&Context::new((*ctx).clone(), None)).await;
+
+            let blob = store
+                .put("image/png", body_from_bytes(b"akari".to_vec()))
+                .await?;
+            let id = repo.create(&RawImage { blob: blob.clone() }).await?;
+            let collections = vec![String::from(RawImageCfg::collection_name())];
+            let report = tool.export(&dump, &collections).await?;
+            assert_eq!(vec![(collections[0].clone(), 1)], report.collections);
+            assert_eq!(1, report.blobs);
+
+            ctx.database()
+                .collection(RawImageCfg::collection_name())
+                .drop(None)
+                .await?;
+            store.delete(&blob.key).await?;
+            let report = tool.import(&dump, &[], false).await?;
+            assert_eq!(1, report.blobs);
+            assert_eq!(vec![(collections[0].clone(), 1)], report.collections);
+            assert_eq!(blob, repo.find_one_by_id(&id).await?.unwrap().1.blob);
+            assert!(store.head(&blob.key).await?.is_some());
+
+            let report = tool.import(&dump, &collections, true).await?;
+            assert_eq!(0, report.blobs);
+            let ids = repo
+                .find_many(doc! {}, None)
+                .await?
+                .try_collect::<Vec<_>>()
+                .await?
+                .into_iter()
+                .map(|image| image.0)
+                .collect::<Vec<_>>();
+            assert_eq!(2, ids.len());
+            assert!(ids.contains(&id));
+
+            assert_eq!(Vec::<CheckFailure>::new(), tool.check(&collections).await?);
+            ctx.database()
+                .collection(RawImageCfg::collection_name())
+                .insert_one(doc! { "blob": "broken" }, None)
+                .await?;
+            assert_eq!(1, tool.check(&collections).await?.len());
+
+            // a kept id that is taken stops the copy before anything is written
+            let slugs = vec![String::from("DbToolSlug")];
+            let coll = ctx.database().collection(&slugs[0]);
+            coll.insert_one(doc! { "_id": "akari", "owner": id.clone() }, None)
+                .await?;
+            tool.export(&dump, &slugs).await?;
+            let err = tool.import(&dump, &slugs, true).await.unwrap_err();
+            assert!(err.to_string().contains("DbToolSlug"));
+            assert_eq!(1, coll.count_documents(None, None).await?);
+            coll.drop(None).await?;
+            fs::remove_dir_all(&root)?;
+            Ok(())
+        })
+        .await
+        .unwrap()
+    }
+}
@@ -3,10 +3,13 @@ use std::io::Cursor;
 use svgtypes::ViewBox;
 use xmltree::Element;
 
-use crate::utils::{
-    result::Result,
-    serde::{Deserialize, Serialize},
-    simple_error,
+use crate::{
+    blob::{body_from_bytes, BlobMeta, BlobStore},
+    utils::{
+        result::Result,
+        serde::{Deserialize, Serialize},
+        simple_error,
+    },
 };
 
 const CAPACITY: usize = 100 * 1024;
@@ -71,6 +74,19 @@ pub enum ResizedImage {
     Text(TextImage),
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StoredImage {
+    pub orig: BlobMeta,
+    pub large: BlobMeta,
+    pub small: BlobMeta,
+}
+
+async fn put_bin<S: BlobStore + ?Sized>(store: &S, image: &BinImage) -> Result<BlobMeta> {
+    store
+        .put(&image.type_, body_from_bytes(image.data.clone()))
+        .await
+}
+
 impl ResizedImage {
     pub fn resize(
         type_: impl AsRef<str>,
@@ -90,6 +106,27 @@ impl ResizedImage {
         image.check_size(w, h, delta)?;
         image.resize(w, h, shink_rate)
     }
+
+    // svg is scaled by the client, so one blob serves every variant
+    pub async fn store<S: BlobStore + ?Sized>(&self, store: &S) -> Result<StoredImage> {
+        match self {
+            ResizedImage::Bin(bin) => Ok(StoredImage {
+                orig: put_bin(store, &bin.orig).await?,
+                large: put_bin(store, &bin.large).await?,
+                small: put_bin(store, &bin.small).await?,
+            }),
+            ResizedImage::Text(text) => {
+                let meta = store
+                    .put(&text.type_, body_from_bytes(text.data.clone().into_bytes()))
+                    .await?;
+                Ok(StoredImage {
+                    orig: meta.clone(),
+                    large: meta.clone(),
+                    small: meta,
+                })
+            }
+        }
+    }
 }
 
 enum BinImageType {
@@ -264,6 +301,8 @@ fn resize_svg(orig: &Element, w: f64, h: f64) -> Result<ResizedImage> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::blob::FsStore;
+    use mongodm::bson::oid::ObjectId;
     use std::path::PathBuf;
 
     fn load_image(filename: &str) -> Vec<u8> {
@@ -294,4 +333,30 @@ mod test {
         let _result =
             ResizedImage::resize("image/svg+xml", input, 721f64, 545f64, 0.5, 0.03).unwrap();
     }
+
+    #[tokio::test]
+    async fn test_store() -> Result<()> {
+        let root = std::env::temp_dir().join(format!("ringoro-image-{}", ObjectId::new().to_hex()));
+        let store = FsStore::new(&root);
+        let file = load_image("sample.png");
+        let resized = ResizedImage::resize(
+            "image/png",
+            ImageInput::Bin(file),
+            854f64,
+            560f64,
+            0.5,
+            0.03,
+        )?;
+        let stored = resized.store(&store).await?;
+        assert!(stored.small.size < stored.large.size);
+        assert!(store.head(&stored.orig.key).await?.is_some());
+
+        let file = load_image("sample.svg");
+        let input = ImageInput::Text(String::from_utf8(file)?);
+        let resized = ResizedImage::resize("image/svg+xml", input, 721f64, 545f64, 0.5, 0.03)?;
+        let stored = resized.store(&store).await?;
+        assert_eq!(stored.orig, stored.small);
+        tokio::fs::remove_dir_all(&root).await?;
+        Ok(())
+    }
 }
@@ -1,9 +1,12 @@
 pub mod app_data;
 pub mod app_error;
 pub mod auth;
+pub mod blob;
 pub mod context;
 pub mod controller;
+pub mod dbtool;
 pub mod image;
+pub mod migrations;
 pub mod server;
 pub mod services;
 pub mod stores;
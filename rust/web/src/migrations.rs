@@ -0,0 +1,202 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use chrono::{Duration, Utc};
+use futures::TryStreamExt;
+use mongodm::{
+    bson::{to_bson, Bson, Document},
+    doc,
+    mongo::Database,
+    CollectionConfig,
+};
+
+use crate::{
+    blob::{body_from_bytes, BlobStore},
+    mongo::{
+        metadata::{CREATED_AT, UPDATED_AT},
+        migration::{DocumentMigration, Migration, Migrator},
+        search::{index_document, SEARCH_FIELD},
+    },
+    stores::{RawImageCfg, TempImgCfg, User, UserCfg, TEMP_IMG_LIFETIME_HOURS},
+    utils::{result::Result, simple_error},
+};
+
+// `store` is where inline images are moved to, i.e. `blob::from_config`.
+pub fn migrator(store: Arc<dyn BlobStore + Send + Sync>) -> Migrator {
+    Migrator::new()
+        .with(DocumentMigration::new(
+            "20201227_backfill_user_created_at",
+            UserCfg::collection_name(),
+            doc! { CREATED_AT: { "$exists": false } },
+            backfill_created_at,
+        ))
+        .with(InlineImageToBlobStore::new(
+            "20210110_move_temp_img_to_blob_store",
+            TempImgCfg::collection_name(),
+            Arc::clone(&store),
+        ))
+        .with(InlineImageToBlobStore::new(
+            "20210110_move_raw_image_to_blob_store",
+            RawImageCfg::collection_name(),
+            store,
+        ))
+        .with(DocumentMigration::new(
+            "20210124_backfill_temp_img_expires_at",
+            TempImgCfg::collection_name(),
+            doc! { "expires_at": { "$exists": false } },
+            backfill_expires_at,
+        ))
+        .with(DocumentMigration::new(
+            "20210131_backfill_user_search",
+            UserCfg::collection_name(),
+            doc! { SEARCH_FIELD: { "$exists": false } },
+            index_document::<User>,
+        ))
+}
+
+struct InlineImageToBlobStore {
+    name: &'static str,
+    collection: &'static str,
+    store: Arc<dyn BlobStore + Send + Sync>,
+}
+
+impl InlineImageToBlobStore {
+    fn new(
+        name: &'static str,
+        collection: &'static str,
+        store: Arc<dyn BlobStore + Send + Sync>,
+    ) -> Self {
+        Self {
+            name,
+            collection,
+            store,
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl Migration for InlineImageToBlobStore {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    async fn apply(&self, db: &Database, dry_run: bool) -> Result<i64> {
+        let coll = db.collection(self.collection);
+        let mut cursor = coll
+            .find(doc! { "data": { "$exists": true } }, None)
+            .await?;
+        let mut affected = 0;
+        while let Some(doc) = cursor.try_next().await? {
+            affected += 1;
+            if dry_run {
+                continue;
+            }
+            let id = doc
+                .get("_id")
+                .cloned()
+                .ok_or_else(|| simple_error!("document doesn't have _id"))?;
+            let blob = self
+                .store
+                .put(doc.get_str("type")?, body_from_bytes(inline_bytes(&doc)?))
+                .await?;
+            coll.update_one(
+                doc! { "_id": id },
+                doc! {
+                    "$set": { "blob": to_bson(&blob)? },
+                    "$unset": { "data": "", "type": "" },
+                },
+                None,
+            )
+            .await?;
+        }
+        Ok(affected)
+    }
+}
+
+// `Vec<u8>` was serialized as an array of numbers, but a binary is read too.
+fn inline_bytes(doc: &Document) -> Result<Vec<u8>> {
+    match doc.get("data") {
+        Some(Bson::Binary(binary)) => Ok(binary.bytes.clone()),
+        Some(Bson::Array(items)) => items
+            .iter()
+            .map(|item| match item {
+                Bson::Int32(v) if (0..=255).contains(v) => Ok(*v as u8),
+                Bson::Int64(v) if (0..=255).contains(v) => Ok(*v as u8),
+                _ => Err(simple_error!("data is not a byte array")),
+            })
+            .collect(),
+        _ => Err(simple_error!("data is not a byte array")),
+    }
+}
+
+// uploads from before expiry get a full lifetime from now on
+fn backfill_expires_at(mut doc: Document) -> Result<Document> {
+    let expires_at = Utc::now() + Duration::hours(TEMP_IMG_LIFETIME_HOURS);
+    doc.insert("expires_at", Bson::DateTime(expires_at));
+    Ok(doc)
+}
+
+fn backfill_created_at(mut doc: Document) -> Result<Document> {
+    let created_at = doc
+        .get_object_id("_id")
+        .map_err(|_| simple_error!("document doesn't have _id"))?
+        .timestamp();
+    doc.insert(CREATED_AT, Bson::DateTime(created_at));
+    doc.insert(UPDATED_AT, Bson::DateTime(created_at));
+    Ok(doc)
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        blob::{read_body, BlobMeta, GridFsStore},
+        mongo::{
+            mongodm::bson::{from_bson, spec::BinarySubtype, Binary},
+            test_util::with_mongo,
+        },
+    };
+
+    #[tokio::test]
+    async fn test_inline_image_to_blob_store() {
+        with_mongo(|ctx| async move {
+            let db = ctx.database();
+            let coll = db.collection(TempImgCfg::collection_name());
+            let binary = Binary {
+                subtype: BinarySubtype::Generic,
+                bytes: b"bw".to_vec(),
+            };
+            coll.insert_many(
+                vec![
+                    doc! { "_id": 1, "data": [117, 110, 105, 98, 111], "type": "image/png" },
+                    doc! { "_id": 2, "data": Bson::Binary(binary), "type": "image/jpeg" },
+                ],
+                None,
+            )
+            .await?;
+            let store = Arc::new(GridFsStore::new(db.clone()));
+            let migration =
+                InlineImageToBlobStore::new("test", TempImgCfg::collection_name(), store.clone());
+            assert_eq!(2, migration.apply(&db, false).await?);
+
+            for (id, data, content_type) in vec![(1, "unibo", "image/png"), (2, "bw", "image/jpeg")]
+            {
+                let doc = coll.find_one(doc! { "_id": id }, None).await?.unwrap();
+                assert_eq!(
+                    vec!["_id", "blob"],
+                    doc.keys().map(String::as_str).collect::<Vec<_>>()
+                );
+                let blob = from_bson::<BlobMeta>(doc.get("blob").unwrap().clone())?;
+                assert_eq!(content_type, blob.content_type);
+                let (_, body) = store.get(&blob.key).await?.unwrap();
+                assert_eq!(data.as_bytes().to_vec(), read_body(body).await?);
+            }
+            assert_eq!(0, migration.apply(&db, false).await?);
+            Ok(())
+        })
+        .await
+        .unwrap()
+    }
+}
@@ -1,26 +1,44 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use actix_cors::Cors;
 use actix_redis::RedisSession;
-use actix_web::{middleware, App, HttpServer};
+use actix_web::{middleware, rt, App, HttpServer};
+use log::error;
 
 use crate::{
     app_data::AppData,
-    context::MongoContext,
-    controller,
+    blob::{self, BlobStore},
+    context::{Context, MongoContext},
+    controller, migrations,
+    mongo::withid::RepositoryWithId,
+    stores::{self, sweep_temp_imgs, UserRepository},
     utils::{config::Config, result::Result},
 };
 
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
 pub async fn run() -> Result<()> {
     std::env::set_var("RUST_LOG", "debug");
     env_logger::init();
 
     let config = Config::from_env()?;
     let context = MongoContext::new(&config).await?;
+    stores::sync_indexes(&context).await?;
+    let blob = blob::from_config(&config, &context)?;
+    migrations::migrator(Arc::clone(&blob))
+        .run(&context.database(), false)
+        .await?;
     let redis_address = config.redis_address.clone();
     let session_key = config.session_key_bin()?;
     let bind_name = config.bind_name();
-    let data = Arc::new(AppData { context, config });
+    rt::spawn(purge_job(context.clone()));
+    rt::spawn(sweep_job(context.clone(), Arc::clone(&blob)));
+    let data = Arc::new(AppData {
+        context,
+        config,
+        blob,
+    });
 
     Ok(HttpServer::new(move || {
         let app = App::new()
@@ -42,3 +60,25 @@ pub async fn run() -> Result<()> {
     .run()
     .await?)
 }
+
+async fn purge_job(context: MongoContext) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let ctx = Context::new(context.clone(), None);
+        if let Err(err) = UserRepository::new(&ctx).await.purge().await {
+            error!(target: "ringoro", "ERROR: purge: {}", err);
+        }
+    }
+}
+
+async fn sweep_job(context: MongoContext, store: Arc<dyn BlobStore + Send + Sync>) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        let ctx = Context::new(context.clone(), None);
+        if let Err(err) = sweep_temp_imgs(store.as_ref(), &ctx).await {
+            error!(target: "ringoro", "ERROR: sweep: {}", err);
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use chrono::{DateTime, Utc};
+use mongodm::bson::Bson;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    context::Context,
+    fcomps::{convert::Convertible, service::FromHookResult},
+    mongo::{
+        audit::{AuditEntry, AuditRepository, AuditStatus},
+        listquery::ListRequest,
+        service::{FindManyArgument, FindPageArgument, Page, PageRequest},
+        withid::{Id, WithId},
+    },
+    services::auth_hook::*,
+    utils::{result::Result, simple_error},
+};
+
+#[derive(Deserialize, Debug, Default)]
+pub struct AuditLogInput {
+    #[serde(flatten)]
+    pub list: ListRequest,
+    #[serde(flatten)]
+    pub page: PageRequest,
+}
+
+#[derive(Serialize, Debug)]
+pub struct AuditEntryOutput {
+    pub id: Id,
+    pub actor: Option<Id>,
+    pub operation: String,
+    pub collection: String,
+    pub target: String,
+    pub diff: serde_json::Value,
+    pub at: DateTime<Utc>,
+    pub status: AuditStatus,
+    pub outcome_of: Option<Id>,
+    pub error: Option<String>,
+}
+
+impl Convertible<AuditEntryOutput> for WithId<AuditEntry> {
+    fn convert(self) -> Result<AuditEntryOutput> {
+        Ok(AuditEntryOutput {
+            id: self.0,
+            actor: self.1.actor,
+            operation: self.1.operation,
+            collection: self.1.collection,
+            target: self.1.target,
+            diff: Bson::Document(self.1.diff).into_relaxed_extjson(),
+            at: self.1.at.0,
+            status: self.1.status,
+            outcome_of: self.1.outcome_of,
+            error: self.1.error,
+        })
+    }
+}
+
+impl FromHookResult<Wrap<AuthInfo>, AuditLogInput> for FindPageArgument {
+    fn from_hook_result(
+        Wrap { value: user }: Wrap<AuthInfo>,
+        mut input: AuditLogInput,
+    ) -> Result<FindPageArgument> {
+        match user {
+            Some(user) if user.1.is_admin() => {
+                // newest first unless asked otherwise
+                input.list.sort.get_or_insert_with(|| String::from("-at"));
+                FindManyArgument::from(input.list.parse::<AuditEntry>()?)
+                    .into_page_argument(input.page)
+            }
+            _ => Err(simple_error!("auth error")),
+        }
+    }
+}
+
+pub async fn audit_log(input: AuditLogInput, ctx: &Context) -> Result<Page<AuditEntryOutput>> {
+    let FindPageArgument(query, sort, request) =
+        FindPageArgument::from_hook_result(Wrap::new(ctx.user.clone()), input)?;
+    AuditRepository::new(ctx)
+        .await
+        .find_page(query, &sort, &request)
+        .await?
+        .convert()
+}
+
+#[cfg(test)]
+mod test {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        mongo::{context::Context as MongoContext, test_util, test_util::Factory},
+        services::{UserFindOneInput, UserService},
+        stores::{User, UserFactory},
+    };
+
+    async fn create_user(name: &str, admin: bool, ctx: &MongoContext) -> WithId<User> {
+        let factory = UserFactory::default().name(name);
+        let factory = if admin { factory.admin() } else { factory };
+        factory
+            .create(&Context::new(ctx.clone(), None))
+            .await
+            .unwrap()
+    }
+
+    fn filter(filter: &str) -> AuditLogInput {
+        AuditLogInput {
+            list: ListRequest::new(Some(filter), None),
+            page: PageRequest::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_audit_log() {
+        test_util::with_mongo(|ctx| async move {
+            let user = create_user("akari", false, ctx.as_ref()).await;
+            let other = create_user("riamu", false, ctx.as_ref()).await;
+            let admin = create_user("akira", true, ctx.as_ref()).await;
+            let admin_ctx = Context::new(ctx.as_ref().clone(), Some(admin.clone()));
+            UserService::delete(
+                UserFindOneInput { id: None },
+                &Context::new(ctx.as_ref().clone(), Some(user.clone())),
+            )
+            .await?;
+            UserService::delete(UserFindOneInput { id: Some(other.0) }, &admin_ctx).await?;
+
+            let result = audit_log(filter("status:eq:done"), &admin_ctx).await?;
+            assert_eq!(
+                vec![Some(admin.0.clone()), Some(user.0.clone())],
+                result
+                    .items
+                    .iter()
+                    .map(|entry| entry.actor.clone())
+                    .collect::<Vec<_>>()
+            );
+            assert_eq!("delete", result.items[1].operation);
+            assert_eq!("User", result.items[1].collection);
+            assert_eq!(user.0.to_hex(), result.items[1].target);
+            assert_eq!("akari", result.items[1].diff["name"]["before"]);
+
+            let query = format!("target:eq:{},status:eq:done", other.0.to_hex());
+            let result = audit_log(filter(&query), &admin_ctx).await?;
+            assert_eq!(1, result.items.len());
+            assert_eq!(Some(admin.0), result.items[0].actor);
+            Ok(())
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_audit_log_with_not_admin_user() {
+        test_util::with_mongo(|ctx| async move {
+            let user = create_user("akari", false, ctx.as_ref()).await;
+            let _ = audit_log(
+                AuditLogInput::default(),
+                &Context::new(ctx.as_ref().clone(), Some(user)),
+            )
+            .await
+            .unwrap_err();
+            let _ = audit_log(
+                AuditLogInput::default(),
+                &Context::new(ctx.as_ref().clone(), None),
+            )
+            .await
+            .unwrap_err();
+            Ok(())
+        })
+        .await
+        .unwrap()
+    }
+}
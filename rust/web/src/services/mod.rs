@@ -1,4 +1,8 @@
+mod audit;
 mod auth_hook;
+mod rawimage;
 mod user;
 
+pub use audit::*;
+pub use rawimage::*;
 pub use user::*;
@@ -0,0 +1,70 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    context::Context, fcomps::validate::ValidateRefDef, mongo::withid::Id, services::auth_hook::*,
+    stores, utils::result::Result,
+};
+
+#[derive(Deserialize, Debug)]
+pub struct PromoteTempImgInput {
+    pub id: Id,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RawImageOutput {
+    pub id: Id,
+}
+
+pub async fn promote_temp_img(input: PromoteTempImgInput, ctx: &Context) -> Result<RawImageOutput> {
+    OnlyLoggedInDef::def(&Wrap::new(ctx.user.clone()))?;
+    Ok(RawImageOutput {
+        id: stores::promote_temp_img(&input.id, ctx).await?,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{Duration, Utc};
+
+    use super::*;
+    use crate::{
+        blob::{content_key, BlobMeta},
+        mongo::{
+            test_util::{self, Factory},
+            withid::RepositoryWithId,
+        },
+        stores::{RawImageRepository, TempImg, TempImgRepository, UserFactory},
+    };
+
+    #[tokio::test]
+    async fn test_promote_temp_img() {
+        test_util::with_mongo(|ctx| async move {
+            let anonymous = Context::new(ctx.as_ref().clone(), None);
+            let temp = TempImgRepository::new(&anonymous)
+                .await
+                .create(&TempImg {
+                    blob: BlobMeta {
+                        key: content_key(b"akari"),
+                        content_type: String::from("image/png"),
+                        size: 5,
+                    },
+                    expires_at: (Utc::now() + Duration::hours(1)).into(),
+                })
+                .await?;
+            let input = || PromoteTempImgInput { id: temp.clone() };
+            let _ = promote_temp_img(input(), &anonymous).await.unwrap_err();
+
+            let user = UserFactory::default().create(&anonymous).await?;
+            let ctx = Context::new(ctx.as_ref().clone(), Some(user));
+            let output = promote_temp_img(input(), &ctx).await?;
+            assert!(RawImageRepository::new(&ctx)
+                .await
+                .find_one_by_id(&output.id)
+                .await?
+                .is_some());
+            Ok(())
+        })
+        .await
+        .unwrap()
+    }
+}
@@ -4,19 +4,22 @@ use serde::{Deserialize, Serialize};
 use crate::{
     context::Context,
     fcomps::{
-        behavior::PanicBehave,
+        behavior::{Behavior, PanicBehave},
         convert::Convertible,
         service::{CRUDBehaviors, CRUDSevice, FromHookResult, SimpleCRUDServiceDef},
     },
     mongo::{
+        audit::{Audited, DeleteAuditor},
+        listquery::ListRequest,
         service::{
-            DeleteId, FindManyArgument, FindOneArgument, WithIdDeleteBehavior,
-            WithIdFindManyBehavior, WithIdFindOneBehavior,
+            DeleteId, FindManyArgument, FindOneArgument, FindPageArgument, Page, PageRequest,
+            SearchArgument, WithIdDeleteBehavior, WithIdFindOneBehavior, WithIdFindPageBehavior,
+            WithIdSearchService,
         },
-        withid::{ConvertModelWithIdCursor, Id, WithId},
+        withid::{Id, WithId},
     },
     services::auth_hook::*,
-    stores::{User, UserRepository},
+    stores::{User, UserRepository, UserSummary},
     utils::{result::Result, simple_error},
 };
 
@@ -25,6 +28,21 @@ pub struct UserFindOneInput {
     pub id: Option<Id>,
 }
 
+#[derive(Deserialize, Debug, Default)]
+pub struct UserListInput {
+    #[serde(flatten)]
+    pub list: ListRequest,
+    #[serde(flatten)]
+    pub page: PageRequest,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UserSearchInput {
+    pub q: String,
+    #[serde(flatten)]
+    pub page: PageRequest,
+}
+
 #[derive(Serialize, Debug)]
 pub struct UserOutput {
     pub id: Id,
@@ -40,6 +58,15 @@ impl Convertible<UserOutput> for WithId<User> {
     }
 }
 
+impl Convertible<UserOutput> for WithId<UserSummary> {
+    fn convert(self) -> Result<UserOutput> {
+        Ok(UserOutput {
+            id: self.0,
+            name: self.1.name,
+        })
+    }
+}
+
 impl FromHookResult<Wrap<AuthInfo>, UserFindOneInput> for FindOneArgument {
     fn from_hook_result(
         Wrap { value: user }: Wrap<AuthInfo>,
@@ -88,17 +115,39 @@ impl FromHookResult<Wrap<AuthInfo>, UserFindOneInput> for DeleteId {
     }
 }
 
-impl FromHookResult<Wrap<AuthInfo>, ()> for FindManyArgument {
-    fn from_hook_result(Wrap { value: user }: Wrap<AuthInfo>, _: ()) -> Result<FindManyArgument> {
+impl FromHookResult<Wrap<AuthInfo>, ListRequest> for FindManyArgument {
+    fn from_hook_result(
+        Wrap { value: user }: Wrap<AuthInfo>,
+        input: ListRequest,
+    ) -> Result<FindManyArgument> {
         if let Some(user) = user {
             if user.1.is_admin() {
-                return Ok(FindManyArgument(doc! {}, None));
+                return Ok(input.parse::<User>()?.into());
             }
         }
         Err(simple_error!("unexpected"))
     }
 }
 
+impl FromHookResult<Wrap<AuthInfo>, UserListInput> for FindPageArgument {
+    fn from_hook_result(user: Wrap<AuthInfo>, input: UserListInput) -> Result<FindPageArgument> {
+        FindManyArgument::from_hook_result(user, input.list)?.into_page_argument(input.page)
+    }
+}
+
+impl FromHookResult<Wrap<AuthInfo>, UserSearchInput> for SearchArgument {
+    fn from_hook_result(
+        Wrap { value: user }: Wrap<AuthInfo>,
+        input: UserSearchInput,
+    ) -> Result<SearchArgument> {
+        if user.is_some() {
+            Ok(SearchArgument(doc! {}, input.q, input.page))
+        } else {
+            Err(simple_error!("unexpected"))
+        }
+    }
+}
+
 pub struct UserBehavior;
 impl CRUDBehaviors for UserBehavior {
     type Ctx = Context;
@@ -106,14 +155,14 @@ impl CRUDBehaviors for UserBehavior {
     type UpdateIn = ();
     type DeleteIn = UserFindOneInput;
     type FindOneIn = UserFindOneInput;
-    type FindManyIn = ();
+    type FindManyIn = UserListInput;
     type FindOneOut = Option<UserOutput>;
-    type FindManyOut = ConvertModelWithIdCursor<UserOutput, User>;
+    type FindManyOut = Page<UserOutput>;
     type Create = PanicBehave<(), WithId<User>, Context>;
     type Update = PanicBehave<(), WithId<User>, Context>;
-    type Delete = WithIdDeleteBehavior<UserRepository>;
+    type Delete = Audited<WithIdDeleteBehavior<UserRepository>, DeleteAuditor<UserRepository>>;
     type FindOne = WithIdFindOneBehavior<UserRepository>;
-    type FindMany = WithIdFindManyBehavior<UserRepository>;
+    type FindMany = WithIdFindPageBehavior<UserRepository, UserSummary>;
 }
 
 pub type UserService = CRUDSevice<
@@ -123,6 +172,20 @@ pub type UserService = CRUDSevice<
     >,
 >;
 
+pub type UserSearchService = WithIdSearchService<
+    UserRepository,
+    UserSearchInput,
+    UserSummary,
+    AuthHook<DenyAll, DenyAll, OnlyLoggedIn, OnlyLoggedIn, OnlyLoggedIn>,
+>;
+
+pub async fn search_users(input: UserSearchInput, ctx: &Context) -> Result<Page<UserOutput>> {
+    UserSearchService::apply(input, ctx)
+        .await
+        .result()?
+        .convert()
+}
+
 #[cfg(test)]
 mod test {
     use futures::TryStreamExt;
@@ -130,7 +193,15 @@ mod test {
     use pretty_assertions::assert_eq;
 
     use super::*;
-    use crate::mongo::{context::Context as MongoContext, test_util, withid::RepositoryWithId};
+    use crate::{
+        mongo::{
+            context::Context as MongoContext,
+            index::NotUnique,
+            test_util::{self, Factory},
+            withid::RepositoryWithId,
+        },
+        stores::UserFactory,
+    };
 
     fn dummy_ctx(ctx: &MongoContext) -> Context {
         Context::new(ctx.clone(), None)
@@ -140,18 +211,35 @@ mod test {
         UserRepository::new(&dummy_ctx(ctx)).await
     }
 
-    async fn save_user(user: &User, ctx: &MongoContext) -> WithId<User> {
-        let repo = repo(ctx).await;
-        let id = repo.create(&user).await.unwrap();
-        repo.find_one_by_id(&id).await.unwrap().unwrap()
-    }
-
     async fn create_user(name: impl AsRef<str>, ctx: &MongoContext) -> WithId<User> {
-        save_user(&User::new(String::from(name.as_ref())), ctx).await
+        UserFactory::default()
+            .name(name)
+            .create(&dummy_ctx(ctx))
+            .await
+            .unwrap()
     }
 
     async fn create_admin_user(name: impl AsRef<str>, ctx: &MongoContext) -> WithId<User> {
-        save_user(&User::new_admin_user(String::from(name.as_ref())), ctx).await
+        UserFactory::default()
+            .name(name)
+            .admin()
+            .create(&dummy_ctx(ctx))
+            .await
+            .unwrap()
+    }
+
+    fn page(cursor: Option<String>, limit: Option<i64>) -> UserListInput {
+        UserListInput {
+            list: ListRequest::default(),
+            page: PageRequest::new(cursor, limit),
+        }
+    }
+
+    fn filter(filter: &str, sort: Option<&str>) -> UserListInput {
+        UserListInput {
+            list: ListRequest::new(Some(filter), sort),
+            page: PageRequest::default(),
+        }
     }
 
     async fn list(doc: Document, ctx: &MongoContext) -> Vec<WithId<User>> {
@@ -204,6 +292,40 @@ mod test {
         .unwrap()
     }
 
+    #[tokio::test]
+    async fn test_recreate_deleted_user() {
+        test_util::with_mongo(|ctx| async move {
+            crate::stores::sync_indexes(ctx.as_ref()).await?;
+            let user = create_user("akari", ctx.as_ref()).await;
+            repo(ctx.as_ref()).await.delete(&user.0).await?;
+            let recreated = create_user("Akari", ctx.as_ref()).await;
+            let list = list(doc! {}, ctx.as_ref()).await;
+            assert_eq!(
+                vec![recreated.0],
+                list.into_iter().map(|user| user.0).collect::<Vec<_>>()
+            );
+            Ok(())
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_validate_name_ignoring_case() {
+        test_util::with_mongo(|ctx| async move {
+            let _ = create_user("akari", ctx.as_ref()).await;
+            let err = repo(ctx.as_ref())
+                .await
+                .create(&User::new(String::from("AKARI")))
+                .await
+                .unwrap_err();
+            assert_eq!("name", err.downcast_ref::<NotUnique>().unwrap().field);
+            Ok(())
+        })
+        .await
+        .unwrap()
+    }
+
     #[tokio::test]
     async fn test_delete_with_admin_user() {
         test_util::with_mongo(|ctx| async move {
@@ -320,14 +442,92 @@ mod test {
         test_util::with_mongo(|ctx| async move {
             let _ = create_user("akari", ctx.as_ref()).await;
             let admin = create_admin_user("akira", ctx.as_ref()).await;
-            let result =
-                UserService::find_many((), &Context::new(ctx.as_ref().clone(), Some(admin)))
-                    .await
-                    .unwrap()
-                    .try_collect::<Vec<UserOutput>>()
-                    .await
-                    .unwrap();
-            assert_eq!(2, result.len());
+            let result = UserService::find_many(
+                UserListInput::default(),
+                &Context::new(ctx.as_ref().clone(), Some(admin)),
+            )
+            .await
+            .unwrap();
+            assert_eq!(2, result.items.len());
+            assert!(!result.has_next);
+            Ok(())
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_find_many_with_cursor() {
+        test_util::with_mongo(|ctx| async move {
+            let _ = create_user("akari", ctx.as_ref()).await;
+            let _ = create_user("kyoko", ctx.as_ref()).await;
+            let admin = create_admin_user("akira", ctx.as_ref()).await;
+            let ctx = Context::new(ctx.as_ref().clone(), Some(admin));
+            let first = UserService::find_many(page(None, Some(2)), &ctx)
+                .await
+                .unwrap();
+            assert_eq!(
+                vec!["akari", "kyoko"],
+                first.items.iter().map(|u| &u.name[..]).collect::<Vec<_>>()
+            );
+            assert!(first.has_next);
+            let second = UserService::find_many(page(first.next_cursor, Some(2)), &ctx)
+                .await
+                .unwrap();
+            assert_eq!(
+                vec!["akira"],
+                second.items.iter().map(|u| &u.name[..]).collect::<Vec<_>>()
+            );
+            assert!(!second.has_next);
+            assert_eq!(None, second.next_cursor);
+            Ok(())
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_find_many_with_filter() {
+        test_util::with_mongo(|ctx| async move {
+            let _ = create_user("akari", ctx.as_ref()).await;
+            let _ = create_user("kyoko", ctx.as_ref()).await;
+            let admin = create_admin_user("akira", ctx.as_ref()).await;
+            let ctx = Context::new(ctx.as_ref().clone(), Some(admin));
+            let result = UserService::find_many(filter("name:prefix:aka", Some("-name")), &ctx)
+                .await
+                .unwrap();
+            assert_eq!(
+                vec!["akira", "akari"],
+                result.items.iter().map(|u| &u.name[..]).collect::<Vec<_>>()
+            );
+            let result = UserService::find_many(filter("admin:eq:true", None), &ctx)
+                .await
+                .unwrap();
+            assert_eq!(
+                vec!["akira"],
+                result.items.iter().map(|u| &u.name[..]).collect::<Vec<_>>()
+            );
+            Ok(())
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_find_many_with_unknown_filter() {
+        use crate::mongo::listquery::ListQueryError;
+
+        test_util::with_mongo(|ctx| async move {
+            let admin = create_admin_user("akira", ctx.as_ref()).await;
+            let ctx = Context::new(ctx.as_ref().clone(), Some(admin));
+            for input in vec![
+                filter("password:eq:x", None),
+                filter("name:regex:.*", None),
+                filter("name:eq:akira", Some("admin")),
+            ] {
+                let err = UserService::find_many(input, &ctx).await.unwrap_err();
+                assert!(err.is::<ListQueryError>());
+            }
             Ok(())
         })
         .await
@@ -339,9 +539,12 @@ mod test {
         test_util::with_mongo(|ctx| async move {
             let user = create_user("akari", ctx.as_ref()).await;
             let _ = create_admin_user("akira", ctx.as_ref()).await;
-            let _ = UserService::find_many((), &Context::new(ctx.as_ref().clone(), Some(user)))
-                .await
-                .unwrap_err();
+            let _ = UserService::find_many(
+                UserListInput::default(),
+                &Context::new(ctx.as_ref().clone(), Some(user)),
+            )
+            .await
+            .unwrap_err();
             Ok(())
         })
         .await
@@ -353,7 +556,45 @@ mod test {
         test_util::with_mongo(|ctx| async move {
             let _ = create_user("akari", ctx.as_ref()).await;
             let _ = create_admin_user("akira", ctx.as_ref()).await;
-            let _ = UserService::find_many((), &Context::new(ctx.as_ref().clone(), None))
+            let _ = UserService::find_many(
+                UserListInput::default(),
+                &Context::new(ctx.as_ref().clone(), None),
+            )
+            .await
+            .unwrap_err();
+            Ok(())
+        })
+        .await
+        .unwrap()
+    }
+
+    fn search(q: &str) -> UserSearchInput {
+        UserSearchInput {
+            q: String::from(q),
+            page: PageRequest::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_search_users() {
+        test_util::with_mongo(|ctx| async move {
+            let user = create_user("あかり", ctx.as_ref()).await;
+            let _ = create_user("アカネ", ctx.as_ref()).await;
+            let _ = create_user("riamu", ctx.as_ref()).await;
+            let result = search_users(
+                search("ｱｶﾘ"),
+                &Context::new(ctx.as_ref().clone(), Some(user)),
+            )
+            .await?;
+            assert_eq!(
+                vec!["あかり", "アカネ"],
+                result
+                    .items
+                    .iter()
+                    .map(|user| user.name.as_str())
+                    .collect::<Vec<_>>()
+            );
+            let _ = search_users(search("akari"), &Context::new(ctx.as_ref().clone(), None))
                 .await
                 .unwrap_err();
             Ok(())
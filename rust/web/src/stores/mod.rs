@@ -1,5 +1,21 @@
+mod rawimage;
 mod tempimg;
 mod user;
 
+pub use rawimage::*;
 pub use tempimg::*;
 pub use user::*;
+
+use crate::{
+    context::MongoContext,
+    mongo::{audit::AuditEntry, gridfs::GridFs},
+    utils::result::Result,
+};
+
+pub async fn sync_indexes(ctx: &MongoContext) -> Result<()> {
+    ctx.sync_indexes::<User>().await?;
+    ctx.sync_indexes::<TempImg>().await?;
+    ctx.sync_indexes::<AuditEntry>().await?;
+    GridFs::new(ctx.database()).ensure_indexes().await?;
+    Ok(())
+}
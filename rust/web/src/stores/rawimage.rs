@@ -1,12 +1,15 @@
 use mongodm::{CollectionConfig, Indexes, Model};
 
-use crate::utils::serde::{Deserialize, Serialize};
+use crate::{
+    blob::BlobMeta,
+    context::Context,
+    mongo::{schema::Schema, withid::RepositoryWithIdBase},
+    utils::serde::{Deserialize, Serialize},
+};
 
 #[derive(Serialize, Deserialize)]
 pub struct RawImage {
-    pub data: Vec<u8>,
-    #[serde(rename = "type")]
-    pub type_: String,
+    pub blob: BlobMeta,
 }
 
 pub struct RawImageCfg {}
@@ -22,5 +25,9 @@ impl CollectionConfig for RawImageCfg {
 }
 
 impl Model for RawImage {
-    type CollConf = TempImgCfg;
+    type CollConf = RawImageCfg;
 }
+
+impl Schema for RawImage {}
+
+pub type RawImageRepository = RepositoryWithIdBase<RawImage, Context>;
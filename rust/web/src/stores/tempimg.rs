@@ -1,12 +1,51 @@
-use mongodm::{CollectionConfig, Indexes, Model};
+use async_trait::async_trait;
+use chrono::{Duration, Utc};
+use log::error;
+use mongodm::{
+    bson::{from_bson, Bson, DateTime},
+    doc, CollectionConfig, Index, Indexes, Model,
+};
+use thiserror::Error;
 
-use crate::utils::serde::{Deserialize, Serialize};
+use crate::{
+    blob::{body_from_bytes, read_body, BlobMeta, BlobStore},
+    context::Context,
+    fcomps::behavior::Behavior,
+    mongo::{
+        context::MongodmContext,
+        index::ttl,
+        schema::Schema,
+        withid::{Id, RepositoryWithId, RepositoryWithIdBase},
+    },
+    stores::{RawImage, RawImageRepository},
+    utils::{
+        result::Result,
+        serde::{Deserialize, Serialize},
+        simple_error,
+    },
+};
+
+pub const TEMP_IMG_LIFETIME_HOURS: i64 = 24;
+
+// The sweeper has to see an expired document to release its blob, so the TTL
+// monitor only removes what the sweeper has missed for a whole day.
+const TTL_GRACE_SECONDS: i32 = 24 * 60 * 60;
+const SWEEP_CLAIM_SECONDS: i64 = 10 * 60;
+const SWEPT_AT: &str = "swept_at";
 
 #[derive(Serialize, Deserialize)]
 pub struct TempImg {
-    pub data: Vec<u8>,
-    #[serde(rename = "type")]
-    pub type_: String,
+    pub blob: BlobMeta,
+    pub expires_at: DateTime,
+}
+
+impl TempImg {
+    pub fn new(blob: BlobMeta) -> Self {
+        Self {
+            blob,
+            expires_at: (Utc::now() + Duration::hours(TEMP_IMG_LIFETIME_HOURS)).into(),
+        }
+    }
 }
 
 pub struct TempImgCfg {}
@@ -17,10 +56,334 @@ impl CollectionConfig for TempImgCfg {
     }
 
     fn indexes() -> Indexes {
-        Indexes::new()
+        Indexes::new().with(Index::new("expires_at").with_option(ttl(TTL_GRACE_SECONDS)))
     }
 }
 
 impl Model for TempImg {
     type CollConf = TempImgCfg;
 }
+
+impl Schema for TempImg {}
+
+pub type TempImgRepository = RepositoryWithIdBase<TempImg, Context>;
+
+#[derive(Debug, Error)]
+pub enum PromoteError {
+    #[error("temp image not found or expired")]
+    NotFound,
+}
+
+// Blob keys are content addressed, so the permanent asset takes over the blob
+// and its reference as is. The asset is created before deleting the unexpired
+// temp document claims it, so the blob is referenced all along; an asset whose
+// claim is lost to another promotion or to expiry is deleted again.
+pub struct PromoteTempImg {
+    result: Result<Id>,
+}
+
+impl PromoteTempImg {
+    async fn promote(id: Id, ctx: &Context) -> Result<Id> {
+        let coll = ctx.repo::<TempImg>().get_underlying();
+        let live = doc! {
+            "_id": id,
+            "expires_at": { "$gt": Bson::DateTime(Utc::now()) },
+        };
+        let doc = coll
+            .find_one(live.clone(), None)
+            .await?
+            .ok_or(PromoteError::NotFound)?;
+        let img = from_bson::<TempImg>(Bson::Document(doc))?;
+        let raw = RawImageRepository::new(ctx).await;
+        let raw_id = raw.create(&RawImage { blob: img.blob }).await?;
+        let claimed: Result<Id> = match coll.find_one_and_delete(live, None).await {
+            Ok(Some(_)) => return Ok(raw_id),
+            Ok(None) => Err(PromoteError::NotFound.into()),
+            Err(err) => Err(err.into()),
+        };
+        if let Err(err) = raw.delete(&raw_id).await {
+            error!("failed to delete unclaimed RawImage {}: {}", raw_id, err);
+        }
+        claimed
+    }
+}
+
+#[async_trait(?Send)]
+impl Behavior for PromoteTempImg {
+    type In = Id;
+    type Out = Id;
+    type Ctx = Context;
+
+    #[inline]
+    async fn apply(id: Id, ctx: &Context) -> Self {
+        Self {
+            result: Self::promote(id, ctx).await,
+        }
+    }
+
+    #[inline]
+    fn result(self) -> Result<Id> {
+        self.result
+    }
+}
+
+pub async fn promote_temp_img(id: &Id, ctx: &Context) -> Result<Id> {
+    PromoteTempImg::apply(id.clone(), ctx).await.result()
+}
+
+// for stores that cannot count references
+async fn is_referenced(key: &str, except: &Bson, ctx: &Context) -> Result<bool> {
+    let query = doc! { "blob.key": key };
+    let temp = doc! { "blob.key": key, "_id": { "$ne": except.clone() } };
+    Ok(TempImgRepository::new(ctx)
+        .await
+        .find_one(temp, None)
+        .await?
+        .is_some()
+        || RawImageRepository::new(ctx)
+            .await
+            .find_one(query, None)
+            .await?
+            .is_some())
+}
+
+// Stores that cannot count give no way to delete a key only while it is
+// unreferenced, so the content is read first and put back when a reference
+// has turned up while it was being deleted.
+async fn release_shared<S: BlobStore + ?Sized>(
+    store: &S,
+    key: &str,
+    except: &Bson,
+    ctx: &Context,
+) -> Result<()> {
+    if is_referenced(key, except, ctx).await? {
+        return Ok(());
+    }
+    let (meta, body) = match store.get(key).await? {
+        Some(blob) => blob,
+        None => return Ok(()),
+    };
+    let data = read_body(body).await?;
+    store.delete(key).await?;
+    if is_referenced(key, except, ctx).await? {
+        store.put(&meta.content_type, body_from_bytes(data)).await?;
+    }
+    Ok(())
+}
+
+// None of the blob stores expire objects, so expired uploads are removed here
+// together with their blobs. Each one is claimed first, so that concurrent
+// sweepers release a blob once; a claim left by a sweeper that died is taken
+// over after a while.
+pub async fn sweep_temp_imgs<S: BlobStore + ?Sized>(store: &S, ctx: &Context) -> Result<i64> {
+    let coll = ctx.repo::<TempImg>().get_underlying();
+    let mut swept = 0;
+    loop {
+        let now = Utc::now();
+        let stale = now - Duration::seconds(SWEEP_CLAIM_SECONDS);
+        let expired = doc! {
+            "expires_at": { "$lt": Bson::DateTime(now) },
+            "$or": [
+                { SWEPT_AT: { "$exists": false } },
+                { SWEPT_AT: { "$lt": Bson::DateTime(stale) } },
+            ],
+        };
+        let claim = doc! { "$set": { SWEPT_AT: Bson::DateTime(now) } };
+        let doc = match coll.find_one_and_update(expired, claim, None).await? {
+            Some(doc) => doc,
+            None => return Ok(swept),
+        };
+        let id = doc
+            .get("_id")
+            .cloned()
+            .ok_or_else(|| simple_error!("document doesn't have _id"))?;
+        let img = from_bson::<TempImg>(Bson::Document(doc))?;
+        if store.counts_references() {
+            store.delete(&img.blob.key).await?;
+        } else {
+            release_shared(store, &img.blob.key, &id, ctx).await?;
+        }
+        coll.delete_one(doc! { "_id": id }, None).await?;
+        swept += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures::TryStreamExt;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        blob::{BlobBody, FsStore},
+        mongo::test_util::with_mongo,
+    };
+
+    async fn put(store: &FsStore, data: &[u8]) -> Result<BlobMeta> {
+        store.put("image/png", body_from_bytes(data.to_vec())).await
+    }
+
+    async fn create_temp(blob: BlobMeta, hours: i64, ctx: &Context) -> Result<Id> {
+        TempImgRepository::new(ctx)
+            .await
+            .create(&TempImg {
+                blob,
+                expires_at: (Utc::now() + Duration::hours(hours)).into(),
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_sweep_temp_imgs() {
+        with_mongo(|ctx| async move {
+            let ctx = Context::new((*ctx).clone(), None);
+            let root = std::env::temp_dir().join(format!("ringoro-sweep-{}", Id::new().to_hex()));
+            let store = FsStore::new(&root);
+            let expired = put(&store, b"expired").await?;
+            let kept = put(&store, b"kept").await?;
+            let shared = put(&store, b"shared").await?;
+
+            create_temp(expired.clone(), -1, &ctx).await?;
+            let live = create_temp(kept.clone(), 1, &ctx).await?;
+            create_temp(shared.clone(), -1, &ctx).await?;
+            // being swept by someone else
+            let claimed = create_temp(put(&store, b"claimed").await?, -1, &ctx).await?;
+            ctx.repo::<TempImg>()
+                .get_underlying()
+                .update_one(
+                    doc! { "_id": claimed.clone() },
+                    doc! { "$set": { SWEPT_AT: Bson::DateTime(Utc::now()) } },
+                    None,
+                )
+                .await?;
+            // the raw image holds a reference of its own
+            RawImageRepository::new(&ctx)
+                .await
+                .create(&RawImage {
+                    blob: put(&store, b"shared").await?,
+                })
+                .await?;
+
+            assert_eq!(2, sweep_temp_imgs(&store, &ctx).await?);
+            assert!(store.head(&expired.key).await?.is_none());
+            assert!(store.head(&kept.key).await?.is_some());
+            assert!(store.head(&shared.key).await?.is_some());
+            let left = TempImgRepository::new(&ctx)
+                .await
+                .find_many(doc! {}, None)
+                .await?
+                .try_collect::<Vec<_>>()
+                .await?;
+            assert_eq!(
+                vec![live, claimed],
+                left.into_iter().map(|img| img.0).collect::<Vec<_>>()
+            );
+            tokio::fs::remove_dir_all(&root).await?;
+            Ok(())
+        })
+        .await
+        .unwrap()
+    }
+
+    // an upload of the same content lands while the blob is being deleted
+    struct RacingStore {
+        inner: FsStore,
+        ctx: Context,
+    }
+
+    #[async_trait(?Send)]
+    impl BlobStore for RacingStore {
+        async fn put(&self, content_type: &str, body: BlobBody) -> Result<BlobMeta> {
+            self.inner.put(content_type, body).await
+        }
+
+        async fn get(&self, key: &str) -> Result<Option<(BlobMeta, BlobBody)>> {
+            self.inner.get(key).await
+        }
+
+        async fn head(&self, key: &str) -> Result<Option<BlobMeta>> {
+            self.inner.head(key).await
+        }
+
+        async fn delete(&self, key: &str) -> Result<()> {
+            let blob = self.inner.head(key).await?.unwrap();
+            self.inner.delete(key).await?;
+            create_temp(blob, 1, &self.ctx).await?;
+            Ok(())
+        }
+
+        async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+            self.inner.list(prefix).await
+        }
+
+        fn counts_references(&self) -> bool {
+            false
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sweep_temp_imgs_racing_upload() {
+        with_mongo(|ctx| async move {
+            let ctx = Context::new((*ctx).clone(), None);
+            let root = std::env::temp_dir().join(format!("ringoro-race-{}", Id::new().to_hex()));
+            let store = RacingStore {
+                inner: FsStore::new(&root),
+                ctx: ctx.clone(),
+            };
+            let blob = put(&store.inner, b"racing").await?;
+            create_temp(blob.clone(), -1, &ctx).await?;
+
+            assert_eq!(1, sweep_temp_imgs(&store, &ctx).await?);
+            let (_, body) = store.get(&blob.key).await?.unwrap();
+            assert_eq!(b"racing".to_vec(), read_body(body).await?);
+            tokio::fs::remove_dir_all(&root).await?;
+            Ok(())
+        })
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_promote_temp_img() {
+        with_mongo(|ctx| async move {
+            let ctx = Context::new((*ctx).clone(), None);
+            let blob = BlobMeta {
+                key: crate::blob::content_key(b"akari"),
+                content_type: String::from("image/png"),
+                size: 5,
+            };
+            let live = create_temp(blob.clone(), 1, &ctx).await?;
+            let expired = create_temp(blob.clone(), -1, &ctx).await?;
+
+            let id = promote_temp_img(&live, &ctx).await?;
+            let raw = RawImageRepository::new(&ctx)
+                .await
+                .find_one_by_id(&id)
+                .await?
+                .unwrap();
+            assert_eq!(blob, raw.1.blob);
+            let temp = TempImgRepository::new(&ctx).await;
+            assert!(temp.find_one_by_id(&live).await?.is_none());
+
+            // neither twice nor after expiry
+            for id in vec![live, expired.clone()] {
+                let err = promote_temp_img(&id, &ctx).await.unwrap_err();
+                assert!(err.is::<PromoteError>());
+            }
+            assert!(temp.find_one_by_id(&expired).await?.is_some());
+            let raws = RawImageRepository::new(&ctx)
+                .await
+                .find_many(doc! {}, None)
+                .await?
+                .try_collect::<Vec<_>>()
+                .await?;
+            assert_eq!(
+                vec![id],
+                raws.into_iter().map(|img| img.0).collect::<Vec<_>>()
+            );
+            Ok(())
+        })
+        .await
+        .unwrap()
+    }
+}
@@ -1,12 +1,21 @@
 use async_trait::async_trait;
-use mongodm::{CollectionConfig, Indexes, Model};
+use mongodm::{CollectionConfig, Index, IndexOption, Indexes, Model};
 use validator::Validate;
 
 use crate::{
     context::Context,
     mongo::{
         context::MongodmContext,
+        index::{case_insensitive, case_insensitive_collation, not_deleted},
+        listquery::{FilterOp, FilterableField, ListConfig},
+        metadata::{created_at_field, Metadata, Timestamped},
+        model_fields,
+        query::{Fields, Projection},
+        schema::Schema,
+        search::search_index,
+        softdelete::DeleteConfig,
         validate_uniqueness,
+        view::View,
         withid::{CreateOrUpdate, ValidatedRepositoryWithId, Validator},
     },
     utils::{
@@ -19,15 +28,25 @@ use crate::{
 pub struct User {
     pub name: String,
     admin: bool,
+    #[serde(flatten, default)]
+    meta: Metadata,
 }
 
 impl User {
     pub fn new(name: String) -> Self {
-        Self { name, admin: false }
+        Self {
+            name,
+            admin: false,
+            meta: Metadata::default(),
+        }
     }
 
     pub fn new_admin_user(name: String) -> Self {
-        Self { name, admin: true }
+        Self {
+            name,
+            admin: true,
+            meta: Metadata::default(),
+        }
     }
 
     pub fn is_admin(&self) -> bool {
@@ -35,6 +54,12 @@ impl User {
     }
 }
 
+impl Timestamped for User {
+    fn metadata(&self) -> &Metadata {
+        &self.meta
+    }
+}
+
 pub struct UserCfg {}
 
 impl CollectionConfig for UserCfg {
@@ -44,6 +69,13 @@ impl CollectionConfig for UserCfg {
 
     fn indexes() -> Indexes {
         Indexes::new()
+            .with(
+                Index::new("name")
+                    .with_option(IndexOption::Unique)
+                    .with_option(case_insensitive())
+                    .with_option(not_deleted()),
+            )
+            .with(search_index())
     }
 }
 
@@ -51,6 +83,54 @@ impl Model for User {
     type CollConf = UserCfg;
 }
 
+impl Schema for User {
+    const SEARCH_FIELDS: &'static [&'static str] = &["name"];
+}
+
+model_fields! {
+    pub UserFields for User {
+        name: String,
+        admin: bool,
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct UserSummary {
+    pub name: String,
+}
+
+impl View for UserSummary {
+    type Model = User;
+
+    fn projection() -> Option<Projection<User>> {
+        Some(Projection::new().include(User::fields().name))
+    }
+}
+
+impl ListConfig for User {
+    fn filterable() -> Vec<FilterableField<Self>> {
+        let f = Self::fields();
+        vec![
+            FilterableField::new(
+                f.name,
+                &[FilterOp::Eq, FilterOp::Prefix, FilterOp::Contains],
+            ),
+            FilterableField::new(f.admin, &[FilterOp::Eq]),
+            FilterableField::new(
+                created_at_field(),
+                &[FilterOp::Gt, FilterOp::Gte, FilterOp::Lt, FilterOp::Lte],
+            ),
+        ]
+    }
+
+    fn sortable() -> Vec<&'static str> {
+        vec![
+            Self::fields().name.name(),
+            created_at_field::<Self>().name(),
+        ]
+    }
+}
+
 pub struct UserValidator {}
 
 #[async_trait(?Send)]
@@ -63,9 +143,56 @@ impl Validator for UserValidator {
         model: &'_ Self::Model,
         ctx: &'_ Self::Ctx,
     ) -> Result<()> {
-        validate_uniqueness! (<User, name>, cu, model, ctx);
+        validate_uniqueness! (<User, name>, cu, model, ctx, Some(case_insensitive_collation()));
         Ok(())
     }
 }
 
-pub type UserRepository = ValidatedRepositoryWithId<User, Context, UserValidator>;
+pub struct UserDeleteCfg {}
+
+impl DeleteConfig for UserDeleteCfg {
+    fn soft_delete() -> bool {
+        true
+    }
+
+    fn purge_after_days() -> Option<i64> {
+        Some(30)
+    }
+}
+
+pub type UserRepository = ValidatedRepositoryWithId<User, Context, UserValidator, UserDeleteCfg>;
+
+#[cfg(test)]
+#[derive(Default)]
+pub struct UserFactory {
+    name: Option<String>,
+    admin: bool,
+}
+
+#[cfg(test)]
+impl UserFactory {
+    pub fn name(mut self, name: impl AsRef<str>) -> Self {
+        self.name = Some(String::from(name.as_ref()));
+        self
+    }
+
+    pub fn admin(mut self) -> Self {
+        self.admin = true;
+        self
+    }
+}
+
+#[cfg(test)]
+impl crate::mongo::test_util::Factory for UserFactory {
+    type Repo = UserRepository;
+
+    fn build(self) -> User {
+        User {
+            name: self
+                .name
+                .unwrap_or_else(|| format!("user{}", crate::mongo::test_util::sequence())),
+            admin: self.admin,
+            meta: Metadata::default(),
+        }
+    }
+}